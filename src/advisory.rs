@@ -0,0 +1,370 @@
+//! # Security advisories
+//!
+//! Offline machines are patched in maintenance windows, which are easier to
+//! justify with the vulnerabilities they close. `apt-remote get <NAME>
+//! --advisories` fetches what the distributions' security teams published
+//! about the source packages of an image:
+//!
+//! - for Debian's archives, the CVEs of the Debian security tracker behind
+//!   its DSAs (`https://security-tracker.debian.org/tracker/data/json`), with
+//!   the version fixing each in every release;
+//! - for Ubuntu's archives, the CVEs of Ubuntu's security API
+//!   (`https://ubuntu.com/security/cves.json`), with the versions released
+//!   to fix them and the USNs announcing them.
+//!
+//! Only the image's source packages are kept, in the image's
+//! `advisories.json`. `apt-remote advisories <NAME>` then reports, without
+//! network access, the CVEs the image fixes on the target: those fixed by a
+//! version newer than the one the target had when the image was planned
+//! (the selections of `uri.lock`, see [`crate::lock`]) and no newer than
+//! the image's.
+
+use crate::{
+    changelog::Source,
+    dry_run,
+    exit::{Classify, ExitCode},
+    lock::{self, LockFile},
+    resolve::compare_versions,
+    state, tls,
+    uri::UriFile,
+};
+
+use anyhow::{Context, Result};
+use reqwest::blocking::Client;
+use serde::{
+    Deserialize, Serialize,
+    de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor},
+};
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt, fs,
+    path::Path,
+    time::Duration,
+};
+
+/// File of an image holding its advisories.
+pub const FILE_NAME: &str = "advisories.json";
+
+/// The Debian security tracker's data.
+const DEBIAN_URL: &str = "https://security-tracker.debian.org/tracker/data/json";
+
+/// Ubuntu's CVE API.
+const UBUNTU_URL: &str = "https://ubuntu.com/security/cves.json";
+
+/// CVEs asked of Ubuntu's API at a time.
+const UBUNTU_PAGE: usize = 100;
+
+/// A vulnerability of a source package.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Advisory {
+    /// The CVE identifier.
+    pub id: String,
+    /// Versions fixing it, in any release.
+    pub fixed: Vec<String>,
+    /// Security notices announcing the fix (USNs).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notices: Vec<String>,
+    /// Summary of the vulnerability.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl Advisory {
+    /// Whether upgrading from `installed` to `version` fixes the
+    /// vulnerability: a version fixing it is newer than `installed` and no
+    /// newer than `version`.
+    ///
+    /// ```
+    /// use apt_remote::advisory::Advisory;
+    ///
+    /// let advisory = Advisory {
+    ///     id: "CVE-2023-38545".into(),
+    ///     fixed: vec!["7.88.1-10+deb12u4".into(), "8.3.0-1".into()],
+    ///     notices: vec![],
+    ///     description: None,
+    /// };
+    /// assert!(advisory.fixed_between("7.88.1-10", "7.88.1-10+deb12u5"));
+    /// assert!(!advisory.fixed_between("7.88.1-10+deb12u4", "7.88.1-10+deb12u5"));
+    /// assert!(!advisory.fixed_between("7.74.0-1.3", "7.88.1-10"));
+    /// ```
+    pub fn fixed_between(&self, installed: &str, version: &str) -> bool {
+        self.fixed.iter().any(|fixed| {
+            compare_versions(fixed, installed) == Ordering::Greater && compare_versions(fixed, version) != Ordering::Greater
+        })
+    }
+}
+
+/// The advisories of an image's source packages.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Advisories {
+    /// When they were fetched (Unix time).
+    pub fetched_at: u64,
+    /// Advisories by source package.
+    pub sources: BTreeMap<String, Vec<Advisory>>,
+}
+
+impl Advisories {
+    /// The advisories stored in the image in `cache_dir`.
+    ///
+    /// # Errors
+    /// Returns an error if they were not fetched, or cannot be read.
+    pub fn load(cache_dir: &Path) -> Result<Advisories> {
+        let path = cache_dir.join(FILE_NAME);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+            .classify(ExitCode::Cache)?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+            .classify(ExitCode::Cache)
+    }
+
+    /// Store the advisories in the image in `cache_dir`.
+    ///
+    /// # Errors
+    /// Returns an error if they cannot be written.
+    pub fn save(&self, cache_dir: &Path) -> Result<()> {
+        let path = cache_dir.join(FILE_NAME);
+        if dry_run::skip("write", path.display().to_string()) {
+            return Ok(());
+        }
+        let json = serde_json::to_string_pretty(self).context("Failed to serialize advisories")?;
+        fs::write(&path, json).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Fetch the advisories of the source packages of `uri_file`.
+///
+/// Packages from archives other than Debian's and Ubuntu's are left out.
+///
+/// # Errors
+/// Returns an error if a security service cannot be reached or answers
+/// with unexpected data.
+pub fn fetch(uri_file: &UriFile) -> Result<Advisories> {
+    let (mut debian, mut ubuntu) = (BTreeSet::new(), BTreeSet::new());
+    for pkg in uri_file.packages.values() {
+        if let Some(source) = Source::of(&pkg.uri) {
+            if source.ubuntu { &mut ubuntu } else { &mut debian }.insert(source.name);
+        }
+    }
+    let client = tls::load()?
+        .blocking(Client::builder().timeout(Duration::from_secs(300)))
+        .build()
+        .context("Failed to build client")?;
+
+    let mut sources = BTreeMap::new();
+    if !debian.is_empty() {
+        tracing::info!("Fetching the Debian security tracker's data for {} source packages", debian.len());
+        let response = client
+            .get(DEBIAN_URL)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .context("Failed to fetch the Debian security tracker's data")
+            .classify(ExitCode::Download)?;
+        // The data covers the whole archive: only the image's sources are kept
+        let mut reader = serde_json::Deserializer::from_reader(response);
+        sources.extend(
+            Tracker { wanted: &debian }
+                .deserialize(&mut reader)
+                .context("The Debian security tracker's data is not valid")
+                .classify(ExitCode::Download)?,
+        );
+    }
+    for source in &ubuntu {
+        tracing::info!("Fetching Ubuntu's CVEs of {source}");
+        sources.insert(source.clone(), fetch_ubuntu(&client, source)?);
+    }
+    Ok(Advisories { fetched_at: state::now(), sources })
+}
+
+/// A package of an image and the advisories it fixes on the target.
+#[derive(Debug, Serialize)]
+pub struct Fix<'a> {
+    /// The binary package.
+    pub package: String,
+    /// Version on the target when the image was planned.
+    pub installed: String,
+    /// Version in the image.
+    pub version: String,
+    /// The vulnerabilities fixed.
+    pub advisories: Vec<&'a Advisory>,
+}
+
+/// The advisories the packages of `uri_file` fix, given the versions the
+/// target had as recorded in `lock`. Packages new to the target fix nothing.
+pub fn fixes<'a>(advisories: &'a Advisories, uri_file: &UriFile, lock: &LockFile) -> Vec<Fix<'a>> {
+    let mut fixes = Vec::new();
+    for fname in &uri_file.install_order {
+        let (Some(pkg), Some((package, version))) = (uri_file.packages.get(fname), lock::parse_deb_name(fname)) else {
+            continue;
+        };
+        let Some(source) = Source::of(&pkg.uri) else { continue };
+        let Some(known) = advisories.sources.get(&source.name) else { continue };
+
+        // Selections name multi-arch packages with their architecture
+        let arch = fname.trim_end_matches(".deb").rsplit('_').next().unwrap_or_default();
+        let selection = lock.selections.get(&package).or_else(|| lock.selections.get(&format!("{package}:{arch}")));
+        let Some(installed) = selection.and_then(|selection| selection.version.clone()) else { continue };
+
+        let fixed = known.iter().filter(|advisory| advisory.fixed_between(&installed, &version)).collect::<Vec<_>>();
+        if !fixed.is_empty() {
+            fixes.push(Fix { package, installed, version, advisories: fixed });
+        }
+    }
+    fixes
+}
+
+/// A CVE of the Debian security tracker, as far as it is used.
+#[derive(Deserialize)]
+struct TrackerCve {
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    releases: HashMap<String, TrackerRelease>,
+}
+
+/// The state of a CVE in one Debian release.
+#[derive(Deserialize)]
+struct TrackerRelease {
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    fixed_version: Option<String>,
+}
+
+/// Reads the tracker's map of source packages, keeping the `wanted` ones.
+struct Tracker<'a> {
+    wanted: &'a BTreeSet<String>,
+}
+
+impl<'de> DeserializeSeed<'de> for Tracker<'_> {
+    type Value = BTreeMap<String, Vec<Advisory>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Tracker<'_> {
+    type Value = BTreeMap<String, Vec<Advisory>>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of source packages")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut sources = BTreeMap::new();
+        while let Some(source) = map.next_key::<String>()? {
+            if !self.wanted.contains(&source) {
+                map.next_value::<IgnoredAny>()?;
+                continue;
+            }
+            let cves: BTreeMap<String, TrackerCve> = map.next_value()?;
+            let advisories = cves
+                .into_iter()
+                .filter(|(id, _)| id.starts_with("CVE-"))
+                .filter_map(|(id, cve)| {
+                    // "0" marks releases the vulnerability never affected
+                    let fixed = cve
+                        .releases
+                        .into_values()
+                        .filter(|release| release.status == "resolved")
+                        .filter_map(|release| release.fixed_version)
+                        .filter(|version| version != "0")
+                        .collect::<BTreeSet<_>>();
+                    (!fixed.is_empty()).then(|| Advisory {
+                        id,
+                        fixed: fixed.into_iter().collect(),
+                        notices: Vec::new(),
+                        description: cve.description,
+                    })
+                })
+                .collect();
+            sources.insert(source, advisories);
+        }
+        Ok(sources)
+    }
+}
+
+/// A page of Ubuntu's CVE API.
+#[derive(Deserialize)]
+struct UbuntuPage {
+    #[serde(default)]
+    cves: Vec<UbuntuCve>,
+    #[serde(default)]
+    total_results: usize,
+}
+
+/// A CVE of Ubuntu's API, as far as it is used.
+#[derive(Deserialize)]
+struct UbuntuCve {
+    id: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    notices_ids: Vec<String>,
+    #[serde(default)]
+    packages: Vec<UbuntuPackage>,
+}
+
+/// The state of a CVE in one source package of Ubuntu.
+#[derive(Deserialize)]
+struct UbuntuPackage {
+    name: String,
+    #[serde(default)]
+    statuses: Vec<UbuntuStatus>,
+}
+
+/// The state of a CVE in one Ubuntu release: `released` with the fixing
+/// version as its description.
+#[derive(Deserialize)]
+struct UbuntuStatus {
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+/// The advisories of Ubuntu source package `source`.
+fn fetch_ubuntu(client: &Client, source: &str) -> Result<Vec<Advisory>> {
+    let mut advisories = Vec::new();
+    let mut offset = 0;
+    loop {
+        let page = client
+            .get(UBUNTU_URL)
+            .query(&[("package", source), ("limit", &UBUNTU_PAGE.to_string()), ("offset", &offset.to_string())])
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .with_context(|| format!("Failed to fetch Ubuntu's CVEs of {source}"))
+            .classify(ExitCode::Download)?;
+        let page: UbuntuPage = serde_json::from_str(&page)
+            .with_context(|| format!("Ubuntu's CVEs of {source} are not valid"))
+            .classify(ExitCode::Download)?;
+        let count = page.cves.len();
+        for cve in page.cves {
+            let fixed = cve
+                .packages
+                .iter()
+                .filter(|package| package.name == source)
+                .flat_map(|package| &package.statuses)
+                .filter(|status| status.status == "released")
+                .filter_map(|status| status.description.clone())
+                .filter(|version| !version.is_empty())
+                .collect::<BTreeSet<_>>();
+            if !fixed.is_empty() {
+                advisories.push(Advisory {
+                    id: cve.id,
+                    fixed: fixed.into_iter().collect(),
+                    notices: cve.notices_ids,
+                    description: cve.description,
+                });
+            }
+        }
+        offset += count;
+        if count == 0 || offset >= page.total_results {
+            return Ok(advisories);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! # Ansible module mode
+//!
+//! `apt-remote-ansible` runs one pipeline step (see [`crate::pipeline`]) as
+//! an Ansible module, so air-gapped hosts can be maintained from existing
+//! playbooks. It reads the module arguments as JSON, from the file Ansible
+//! passes to binary modules or from stdin, runs the step without prompting,
+//! and prints a single JSON result with `changed`, `failed` and `msg`:
+//!
+//! ```yaml
+//! - name: Install the security image
+//!   apt_remote:
+//!     step: install
+//!     image: security
+//!     target: "admin@{{ inventory_hostname }}"
+//!   delegate_to: localhost
+//! ```
+//!
+//! The arguments are the step's options as in a pipeline, plus `image`,
+//! `target`, and optionally `config` and `profile` to select the
+//! configuration file and profile. Ansible's check mode is a dry run (see
+//! [`crate::dry_run`]), and a step is reported as changed if it would have
+//! changed anything. The events the step emitted (see [`crate::output`]) are
+//! included in the result as `events`.
+
+use crate::{
+    commands::run::run_step,
+    config::{self, Config},
+    error::Error,
+    exit::{Classify, ExitCode},
+    output::{self, OutputFormat},
+    pipeline::Step,
+    progress::ProgressMode,
+    uri,
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::{Map, Value, json};
+
+use std::{fs, path::PathBuf};
+
+/// The arguments of one module invocation.
+#[derive(Debug)]
+pub struct ModuleArgs {
+    /// Cache image name.
+    pub image: String,
+    /// Target of the step, if it needs one (default: `target` in config.toml).
+    pub target: Option<String>,
+    /// The step to run.
+    pub step: Step,
+    /// Configuration file (default: `~/.config/apt-remote/config.toml`).
+    pub config: Option<PathBuf>,
+    /// Configuration profile.
+    pub profile: Option<String>,
+    /// Whether Ansible runs in check mode.
+    pub check_mode: bool,
+}
+
+impl ModuleArgs {
+    /// Parse module arguments, given directly or wrapped in
+    /// `ANSIBLE_MODULE_ARGS` as Ansible writes them to a module's stdin.
+    ///
+    /// Ansible's own `_ansible_*` arguments are ignored, except for
+    /// `_ansible_check_mode`.
+    ///
+    /// ```
+    /// use apt_remote::ansible::ModuleArgs;
+    ///
+    /// let args = ModuleArgs::parse(
+    ///     r#"{"ANSIBLE_MODULE_ARGS": {"step": "install", "image": "web", "reinstall": true, "_ansible_check_mode": true}}"#,
+    /// )
+    /// .unwrap();
+    /// assert_eq!(args.image, "web");
+    /// assert_eq!(args.step.name(), "install");
+    /// assert!(args.check_mode);
+    /// assert!(ModuleArgs::parse(r#"{"step": "install"}"#).is_err());
+    /// ```
+    ///
+    /// # Errors
+    /// Returns an error if the input is not a JSON object, has no `image`,
+    /// or does not describe a valid step (see [`Step::has_one_mode`]).
+    pub fn parse(input: &str) -> Result<ModuleArgs> {
+        let value: Value = serde_json::from_str(input).context("Module arguments are not valid JSON")?;
+        let mut args = match value {
+            Value::Object(mut object) => match object.remove("ANSIBLE_MODULE_ARGS") {
+                Some(Value::Object(args)) => args,
+                Some(_) => return Err(anyhow!("ANSIBLE_MODULE_ARGS is not an object")),
+                None => object,
+            },
+            _ => return Err(anyhow!("Module arguments are not a JSON object")),
+        };
+
+        let check_mode = args.get("_ansible_check_mode").and_then(Value::as_bool).unwrap_or(false);
+        args.retain(|key, _| !key.starts_with("_ansible_"));
+        let mut string = |key: &str| match args.remove(key) {
+            Some(Value::String(value)) => Ok(Some(value)),
+            None | Some(Value::Null) => Ok(None),
+            Some(_) => Err(anyhow!("Module argument '{key}' must be a string")),
+        };
+        let image = string("image")?.or(string("name")?).context("Module argument 'image' is required")?;
+        let target = string("target")?;
+        let config = string("config")?.map(PathBuf::from);
+        let profile = string("profile")?;
+        let step: Step = serde_json::from_value(Value::Object(args)).context("Invalid step in the module arguments")?;
+        if !step.has_one_mode() {
+            return Err(anyhow!("A set step must set exactly one of install, fix, update or upgrade"));
+        }
+        Ok(ModuleArgs { image, target, step, config, profile, check_mode })
+    }
+
+    /// The configuration the step runs with: never prompting, with events
+    /// captured rather than printed, and as a dry run in check mode.
+    ///
+    /// # Errors
+    /// Returns an error if the configuration file or profile cannot be loaded.
+    pub fn configuration(&self) -> Result<Config> {
+        let mut config = Config::load_profile(self.config.as_deref(), self.profile.as_deref())?;
+        config.output = OutputFormat::Json;
+        config.progress = ProgressMode::None;
+        config.quiet = true;
+        config.assume_yes = true;
+        config.batch = true;
+        config.dry_run |= self.check_mode;
+        Ok(config)
+    }
+}
+
+/// Run the module on `input` and return its result.
+///
+/// Failures are reported in the result (`failed`, with the failure class,
+/// exit code and hint of the error), never returned.
+pub fn run(input: &str) -> Value {
+    output::capture();
+    let outcome = ModuleArgs::parse(input).classify(ExitCode::Usage).and_then(|args| {
+        config::init(args.configuration()?);
+        run_module(&args)
+    });
+    match outcome {
+        Ok(result) => Value::Object(result),
+        Err(e) => {
+            let events = output::take_captured();
+            let class = ExitCode::of(&e);
+            let typed = Error::find(&e);
+            json!({
+                "changed": false,
+                "failed": true,
+                "msg": format!("{e:#}"),
+                "class": class,
+                "exit_code": class.code(),
+                "kind": typed.map(Error::kind),
+                "hint": typed.and_then(Error::hint),
+                "events": events,
+            })
+        }
+    }
+}
+
+/// Run the step of `args` and describe its outcome, with the events it
+/// emitted.
+fn run_module(args: &ModuleArgs) -> Result<Map<String, Value>> {
+    let plan = || -> Option<Vec<u8>> { fs::read(uri::path_in(&config::cache_dir().ok()?.join(&args.image))).ok() };
+    let plan_before = plan();
+
+    run_step(&args.step, &args.image, &args.target)?;
+
+    let events = output::take_captured();
+    let changed = if args.check_mode {
+        events.iter().any(|event| event["event"] == "dry_run")
+    } else {
+        match &args.step {
+            Step::Set { .. } => plan() != plan_before,
+            Step::Get { .. } => events
+                .iter()
+                .any(|event| event["event"] == "download" && !matches!(event["status"].as_str(), Some("cached" | "failed"))),
+            Step::Install { .. } => events
+                .iter()
+                .any(|event| event["event"] == "result" && matches!(event["status"].as_str(), Some("installed" | "staged"))),
+            Step::Update => events.iter().any(|event| event["event"] == "result" && event["status"] != "skipped"),
+            Step::Export { .. } => true,
+            Step::Check | Step::Status => false,
+        }
+    };
+
+    let on = args.target.as_ref().map(|target| format!(" on {target}")).unwrap_or_default();
+    let msg = match (args.check_mode, changed) {
+        (true, true) => format!("{} {}{on} would make changes", args.step.name(), args.image),
+        (_, true) => format!("{} {}{on} made changes", args.step.name(), args.image),
+        (_, false) => format!("{} {}{on}: nothing to change", args.step.name(), args.image),
+    };
+    let mut result = Map::new();
+    result.insert("changed".to_string(), Value::Bool(changed));
+    result.insert("failed".to_string(), Value::Bool(false));
+    result.insert("msg".to_string(), Value::String(msg));
+    result.insert("events".to_string(), Value::Array(events));
+    Ok(result)
+}
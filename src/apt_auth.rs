@@ -0,0 +1,314 @@
+//! # Credentials of authenticated repositories
+//!
+//! Private repositories, such as an artifact server, are authenticated with
+//! HTTP basic auth, as APT does, rather than with a password in the URI.
+//! The repositories of Ubuntu Pro, such as ESM (`esm.ubuntu.com`), only
+//! serve entitled machines. `pro attach` gives the target a bearer token,
+//! which APT finds in `/etc/apt/auth.conf.d/90ubuntu-advantage`:
+//!
+//! ```text
+//! machine esm.ubuntu.com/apps/ubuntu/ login bearer password <token>
+//! ```
+//!
+//! `get`, `check` and `mirror` authenticate their requests the same way,
+//! with entries in APT's `auth.conf` format (see `apt_auth.conf(5)`) from,
+//! in this order:
+//!
+//! 1. a local file, `~/.config/apt-remote/auth.conf` by default (`apt_auth`
+//!    in config.toml, or `--apt-auth`), then the files of the `auth.conf.d`
+//!    directory next to it (`<file>.d`), in name order, as APT reads
+//!    `/etc/apt/auth.conf.d`;
+//! 2. `~/.netrc` (or the file `NETRC` names), whose `machine` entries are
+//!    for every URI of the host, over HTTP or HTTPS, as with curl (its
+//!    `default` entry is ignored);
+//! 3. the image's `auth.conf`, which `set` copies from the target when the
+//!    plan has files of Ubuntu Pro. Reading the target's credentials takes
+//!    root, so it is skipped when the local files already have credentials
+//!    for every such file.
+//!
+//! ```text
+//! # ~/.config/apt-remote/auth.conf.d/artifacts.conf
+//! machine artifacts.example.com/debian/ login ci password s3cr3t
+//! ```
+//!
+//! As with APT, an entry without a scheme only applies to `https://` URIs,
+//! and a path restricts it to the URIs below it. The image's `auth.conf` is
+//! readable only by its owner, only holds the entries the plan needs, and is
+//! not exported with the image. Every password read is registered with
+//! [`crate::redact`].
+
+use crate::{
+    config, dry_run,
+    exit::{Classify, ExitCode},
+    redact,
+};
+
+use anyhow::{Context, Result};
+use url::Url;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Name of the image's credentials file.
+pub const FILE_NAME: &str = "auth.conf";
+
+/// Command printing the target's APT credentials (run as root).
+pub const TARGET_QUERY: &str = "cat /etc/apt/auth.conf /etc/apt/auth.conf.d/* 2>/dev/null";
+
+/// Hosts of the Ubuntu Pro repositories.
+const PRO_HOSTS: [&str; 1] = ["esm.ubuntu.com"];
+
+/// The entries of an `auth.conf` file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuthFile {
+    /// Entries, the first matching one being used.
+    pub entries: Vec<Machine>,
+}
+
+/// Credentials for the URIs of one `machine`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Machine {
+    /// `[scheme://]host[:port][/path]` the credentials are for.
+    pub machine: String,
+    /// User name (`bearer` for Ubuntu Pro tokens).
+    pub login: String,
+    /// Password or token.
+    pub password: String,
+}
+
+impl Machine {
+    /// Whether the credentials are for `url`.
+    ///
+    /// ```
+    /// use apt_remote::apt_auth::Machine;
+    /// use url::Url;
+    ///
+    /// let esm = Machine { machine: "esm.ubuntu.com/apps/ubuntu/".into(), login: "bearer".into(), password: "t".into() };
+    /// assert!(esm.matches(&Url::parse("https://esm.ubuntu.com/apps/ubuntu/pool/main/c/curl/x.deb").unwrap()));
+    /// assert!(!esm.matches(&Url::parse("http://esm.ubuntu.com/apps/ubuntu/pool/main/c/curl/x.deb").unwrap()));
+    /// assert!(!esm.matches(&Url::parse("https://esm.ubuntu.com/infra/ubuntu/pool/main/c/curl/x.deb").unwrap()));
+    /// ```
+    pub fn matches(&self, url: &Url) -> bool {
+        let rest = match self.machine.split_once("://") {
+            Some((scheme, rest)) if scheme == url.scheme() => rest,
+            Some(_) => return false,
+            None if url.scheme() == "https" => &self.machine,
+            None => return false,
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().ok()),
+            None => (authority, None),
+        };
+        url.host_str() == Some(host)
+            && port.is_none_or(|port| url.port_or_known_default() == Some(port))
+            && url.path().trim_start_matches('/').starts_with(path)
+    }
+}
+
+impl AuthFile {
+    /// Parse the contents of an `auth.conf` file.
+    ///
+    /// Keywords other than `machine`, `login` and `password` are ignored, and
+    /// so are entries without a password.
+    ///
+    /// ```
+    /// use apt_remote::apt_auth::AuthFile;
+    ///
+    /// let auth = AuthFile::parse(
+    ///     "# Written by ubuntu-advantage-tools\n\
+    ///      machine esm.ubuntu.com/apps/ubuntu/ login bearer password s3cr3t\n\
+    ///      machine example.org\n  login me\n  password pw # comment\n",
+    /// );
+    /// assert_eq!(auth.entries.len(), 2);
+    /// assert_eq!(auth.entries[1].login, "me");
+    /// assert_eq!(auth.entries[1].password, "pw");
+    /// ```
+    pub fn parse(content: &str) -> AuthFile {
+        let mut entries = Vec::new();
+        let mut current: Option<Machine> = None;
+        let mut tokens = content
+            .lines()
+            .flat_map(|line| line.split('#').next().unwrap_or_default().split_whitespace());
+        while let Some(keyword) = tokens.next() {
+            // netrc's catch-all entry has no value, and is not used
+            if keyword == "default" {
+                entries.extend(current.take());
+                continue;
+            }
+            let Some(value) = tokens.next() else { break };
+            match keyword {
+                "machine" => {
+                    entries.extend(current.take());
+                    current = Some(Machine { machine: value.to_string(), login: String::new(), password: String::new() });
+                }
+                "login" => current.iter_mut().for_each(|machine| machine.login = value.to_string()),
+                "password" => current.iter_mut().for_each(|machine| machine.password = value.to_string()),
+                _ => {}
+            }
+        }
+        entries.extend(current);
+        entries.retain(|machine| !machine.password.is_empty());
+        for machine in &entries {
+            redact::register(&machine.password);
+        }
+        AuthFile { entries }
+    }
+
+    /// Parse the contents of a `.netrc` file, whose entries are for every
+    /// URI of their host, over HTTP or HTTPS.
+    ///
+    /// ```
+    /// use apt_remote::apt_auth::AuthFile;
+    ///
+    /// let auth = AuthFile::parse_netrc("machine artifacts.example.com login ci password pw\ndefault login anonymous password x\n");
+    /// assert!(auth.find("http://artifacts.example.com/debian/pool/x.deb").is_some());
+    /// assert!(auth.find("https://artifacts.example.com/debian/pool/x.deb").is_some());
+    /// assert!(auth.find("https://deb.debian.org/debian/pool/x.deb").is_none());
+    /// ```
+    pub fn parse_netrc(content: &str) -> AuthFile {
+        let entries = AuthFile::parse(content)
+            .entries
+            .into_iter()
+            .flat_map(|machine| {
+                ["https", "http"].map(|scheme| Machine { machine: format!("{scheme}://{}", machine.machine), ..machine.clone() })
+            })
+            .collect();
+        AuthFile { entries }
+    }
+
+    /// Load an `auth.conf` file, empty if it does not exist.
+    ///
+    /// # Errors
+    /// Returns an error if the file exists but cannot be read.
+    pub fn load(path: &Path) -> Result<AuthFile> {
+        if !path.exists() {
+            return Ok(AuthFile::default());
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+            .classify(ExitCode::Config)?;
+        Ok(AuthFile::parse(&content))
+    }
+
+    /// Save the entries to `path`, readable only by this user.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be written.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if dry_run::skip("write", path.display().to_string()) {
+            return Ok(());
+        }
+        let content = self
+            .entries
+            .iter()
+            .map(|machine| format!("machine {} login {} password {}\n", machine.machine, machine.login, machine.password))
+            .collect::<String>();
+        fs::write(path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+
+    /// The credentials for `uri`, if any.
+    pub fn find(&self, uri: &str) -> Option<&Machine> {
+        let url = Url::parse(uri).ok()?;
+        self.entries.iter().find(|machine| machine.matches(&url))
+    }
+
+    /// Only the entries used by some of `uris`.
+    pub fn retain_for(mut self, uris: &[&str]) -> AuthFile {
+        let urls = uris.iter().filter_map(|uri| Url::parse(uri).ok()).collect::<Vec<_>>();
+        self.entries.retain(|machine| urls.iter().any(|url| machine.matches(url)));
+        self
+    }
+
+    /// Append the entries of `other`, after these.
+    pub fn extend(&mut self, other: AuthFile) {
+        self.entries.extend(other.entries);
+    }
+
+    /// Authenticate `request` for `url` with its credentials, if any.
+    pub fn authorize(&self, request: reqwest::RequestBuilder, url: &str) -> reqwest::RequestBuilder {
+        match self.find(url) {
+            Some(machine) => request.basic_auth(&machine.login, Some(&machine.password)),
+            None => request,
+        }
+    }
+
+    /// Authenticate a blocking `request` for `url` with its credentials, if any.
+    pub fn authorize_blocking(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+        url: &str,
+    ) -> reqwest::blocking::RequestBuilder {
+        match self.find(url) {
+            Some(machine) => request.basic_auth(&machine.login, Some(&machine.password)),
+            None => request,
+        }
+    }
+}
+
+/// Whether `uri` is a file of the Ubuntu Pro repositories, which are only
+/// served with credentials.
+pub fn needs_credentials(uri: &str) -> bool {
+    Url::parse(uri).is_ok_and(|url| url.host_str().is_some_and(|host| PRO_HOSTS.contains(&host)))
+}
+
+/// Default location of the local credentials file.
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("apt-remote").join(FILE_NAME))
+}
+
+/// Location of the user's `.netrc` file.
+fn netrc_path() -> Option<PathBuf> {
+    std::env::var_os("NETRC").map(PathBuf::from).or_else(|| dirs::home_dir().map(|home| home.join(".netrc")))
+}
+
+/// The local credentials: the credentials file and the files of the
+/// `auth.conf.d` directory next to it, then `~/.netrc` (empty if there are
+/// none).
+///
+/// # Errors
+/// Returns an error if one of the files exists but cannot be read.
+pub fn local() -> Result<AuthFile> {
+    let mut auth = AuthFile::default();
+    if let Some(path) = config::get().apt_auth.clone().or_else(default_path) {
+        auth.extend(AuthFile::load(&path)?);
+
+        // APT reads the files without an extension or ending in .conf
+        let mut dir = path.clone().into_os_string();
+        dir.push(".d");
+        let mut parts = fs::read_dir(dir)
+            .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        parts.retain(|part| part.is_file() && part.extension().is_none_or(|extension| extension == "conf"));
+        parts.sort();
+        for part in parts {
+            auth.extend(AuthFile::load(&part)?);
+        }
+    }
+    if let Some(path) = netrc_path().filter(|path| path.is_file()) {
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+            .classify(ExitCode::Config)?;
+        auth.extend(AuthFile::parse_netrc(&content));
+    }
+    Ok(auth)
+}
+
+/// The credentials `get` uses for the image in `cache_dir`: the local ones,
+/// then those `set` copied from the target.
+///
+/// # Errors
+/// Returns an error if one of the files exists but cannot be read.
+pub fn for_image(cache_dir: &Path) -> Result<AuthFile> {
+    let mut auth = local()?;
+    auth.extend(AuthFile::load(&cache_dir.join(FILE_NAME))?);
+    Ok(auth)
+}
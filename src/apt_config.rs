@@ -0,0 +1,107 @@
+//! # APT configuration of a target
+//!
+//! Containers and unusual images can move APT's state and cache directories
+//! (`Dir`, `Dir::State::lists`, `Dir::Cache::archives`) or enable several
+//! architectures (`APT::Architectures`). The target probe (see
+//! [`crate::probe`]) asks the target's `apt-config` for them, so `set`,
+//! `update` and `install` use the directories APT itself reads. The installer written by `export` does the
+//! same on the offline machine.
+
+use serde::{Deserialize, Serialize};
+
+/// Command printing the configuration parsed by [`AptConfig::parse`].
+///
+/// `/d` makes `apt-config` resolve a directory against its parents
+/// (`Dir::State::lists` against `Dir::State` and `Dir`), as APT does.
+pub const QUERY: &str = "apt-config shell LISTS Dir::State::lists/d ARCHIVES Dir::Cache::archives/d ARCH APT::Architecture 2>/dev/null; \
+                     apt-config dump 2>/dev/null | grep '^APT::Architectures::'; true";
+
+/// Directories and architectures of a target's APT.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AptConfig {
+    /// Directory of the package lists (`/var/lib/apt/lists` by default).
+    pub lists: String,
+    /// Directory of downloaded packages (`/var/cache/apt/archives` by default).
+    pub archives: String,
+    /// Native architecture (`APT::Architecture`), empty if unknown.
+    pub arch: String,
+    /// Every architecture APT installs packages for, native first.
+    pub architectures: Vec<String>,
+}
+
+impl Default for AptConfig {
+    fn default() -> Self {
+        AptConfig {
+            lists: "/var/lib/apt/lists".to_string(),
+            archives: "/var/cache/apt/archives".to_string(),
+            arch: String::new(),
+            architectures: Vec::new(),
+        }
+    }
+}
+
+impl AptConfig {
+    /// Fill in what `apt-config` did not report from the output of
+    /// `dpkg --print-architecture` and `dpkg --print-foreign-architectures`.
+    ///
+    /// ```
+    /// use apt_remote::apt_config::AptConfig;
+    ///
+    /// let config = AptConfig::parse("").complete("arm64\n", "armhf\n");
+    /// assert_eq!(config.architectures, ["arm64", "armhf"]);
+    /// ```
+    pub fn complete(mut self, arch: &str, foreign: &str) -> AptConfig {
+        if self.arch.is_empty() {
+            self.arch = arch.trim().to_string();
+        }
+        if self.architectures.is_empty() {
+            self.architectures =
+                std::iter::once(self.arch.clone()).chain(foreign.split_whitespace().map(str::to_string)).collect();
+        }
+        self
+    }
+
+    /// Parse the output of `apt-config shell` and `apt-config dump`.
+    ///
+    /// ```
+    /// use apt_remote::apt_config::AptConfig;
+    ///
+    /// let config = AptConfig::parse(
+    ///     "LISTS='/srv/root/var/lib/apt/lists/'\n\
+    ///      ARCH='amd64'\n\
+    ///      APT::Architectures:: \"amd64\";\n\
+    ///      APT::Architectures:: \"i386\";\n",
+    /// );
+    /// assert_eq!(config.lists, "/srv/root/var/lib/apt/lists");
+    /// assert_eq!(config.archives, "/var/cache/apt/archives");
+    /// assert_eq!(config.architectures, ["amd64", "i386"]);
+    /// ```
+    pub fn parse(output: &str) -> AptConfig {
+        let mut config = AptConfig::default();
+        for line in output.lines().map(str::trim) {
+            if let Some(arch) = line.strip_prefix("APT::Architectures:: ") {
+                let arch = arch.trim_end_matches(';').trim_matches('"');
+                if !arch.is_empty() && !config.architectures.iter().any(|a| a == arch) {
+                    config.architectures.push(arch.to_string());
+                }
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else { continue };
+            // `apt-config shell` quotes values as 'value', with ' written as '\''
+            let value = value.trim_matches('\'').replace("'\\''", "'");
+            let dir = value.trim_end_matches('/');
+            match name {
+                "LISTS" if !dir.is_empty() => config.lists = dir.to_string(),
+                "ARCHIVES" if !dir.is_empty() => config.archives = dir.to_string(),
+                "ARCH" => config.arch = value,
+                _ => {}
+            }
+        }
+        // The native architecture comes first
+        if let Some(native) = config.architectures.iter().position(|a| *a == config.arch) {
+            config.architectures[..=native].rotate_right(1);
+        }
+        config
+    }
+}
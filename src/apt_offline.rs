@@ -0,0 +1,257 @@
+//! # apt-offline interoperability
+//!
+//! Sites that already move updates with [apt-offline] can mix it with
+//! apt-remote:
+//!
+//! - `apt-remote import <FILE>.sig` turns a signature written by
+//!   `apt-offline set` on the offline machine into plans, ready for `get`:
+//!   packages go to the image `<NAME>` and package lists to `<NAME>`, or to
+//!   `<NAME>-lists` if the signature asks for both;
+//! - `apt-remote import <FILE>.zip --name <NAME>` fills the image `<NAME>`
+//!   with the files of a bundle written by `apt-offline get`;
+//! - `apt-remote export <NAME> <DEST> --format apt-offline-sig` writes the
+//!   image's plan as a signature for `apt-offline get`, and `--format
+//!   apt-offline` writes its downloaded files as a bundle for `apt-offline
+//!   install`.
+//!
+//! A signature holds the lines `apt-get --print-uris` prints: the quoted
+//! URI, the name APT gives the file, its size and its checksum. Bundles are
+//! ZIP archives of those files (see [`crate::zip`]).
+//!
+//! [apt-offline]: https://github.com/rickysarraf/apt-offline
+
+use crate::config;
+use crate::uri::{self, Checksum, ChecksumKind, ImageMeta, PackageEntry, RemoteMode, SCHEMA_VERSION, UriFile};
+
+use anyhow::{Context, Result, anyhow};
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use xz2::read::XzDecoder;
+
+/// Compression extensions of package lists.
+const COMPRESSIONS: [&str; 5] = [".xz", ".gz", ".bz2", ".lzma", ".zst"];
+
+/// The files an apt-offline signature asks for.
+#[derive(Debug, Default)]
+pub struct Signature {
+    /// Package lists, named after their URI as in `apt-remote set --update`.
+    pub lists: Vec<(String, PackageEntry)>,
+    /// Packages, named as in APT's archives directory.
+    pub packages: Vec<(String, PackageEntry)>,
+}
+
+impl Signature {
+    /// Parse the content of a signature file.
+    ///
+    /// ```
+    /// use apt_remote::apt_offline::Signature;
+    ///
+    /// let sig = Signature::parse(
+    ///     "'http://deb.debian.org/debian/dists/bookworm/InRelease' deb.debian.org_debian_dists_bookworm_InRelease 0 \n\
+    ///      'http://deb.debian.org/debian/pool/main/c/curl/curl_7.88.1-10_amd64.deb' curl_7.88.1-10_amd64.deb 315000 SHA256:ab12\n",
+    /// )
+    /// .unwrap();
+    /// assert_eq!(sig.lists[0].0, "deb.debian.org_debian_dists_bookworm_InRelease");
+    /// assert_eq!(sig.packages[0].0, "curl_7.88.1-10_amd64.deb");
+    /// assert_eq!(sig.packages[0].1.size, 315000);
+    /// ```
+    ///
+    /// # Errors
+    /// Returns an error if a line has no valid URI, size or checksum.
+    pub fn parse(content: &str) -> Result<Signature> {
+        let mut signature = Signature::default();
+        for (number, line) in content.lines().enumerate().filter(|(_, line)| line.starts_with('\'')) {
+            let parse = || -> Result<(bool, String, PackageEntry)> {
+                let mut parts = line.split_whitespace();
+                let uri = parts.next().unwrap_or_default().trim_matches('\'').to_string();
+                url::Url::parse(&uri).with_context(|| format!("Invalid URI {uri}"))?;
+                let dest = parts.next().ok_or_else(|| anyhow!("missing file name"))?;
+                let size = parts.next().ok_or_else(|| anyhow!("missing size"))?.parse::<u64>().context("invalid size")?;
+                let checksum = match parts.next() {
+                    Some(field) => Some(parse_checksum(field)?),
+                    None => None,
+                };
+                let is_package = dest.ends_with(".deb");
+                let fname = if is_package {
+                    uri::archive_file_name(dest)
+                } else {
+                    uri.split("//").nth(1).unwrap_or_default().replace('/', "_")
+                };
+                Ok((is_package, fname, PackageEntry { uri, size, checksum, installed_size: None }))
+            };
+            let (is_package, fname, entry) = parse().with_context(|| format!("Invalid signature line {}", number + 1))?;
+            if is_package {
+                signature.packages.push((fname, entry));
+            } else {
+                signature.lists.push((fname, entry));
+            }
+        }
+        Ok(signature)
+    }
+
+    /// The architecture of the signature's packages: the first one that is
+    /// not `all`, or `all` if every package is.
+    pub fn arch(&self) -> String {
+        let archs = self.packages.iter().filter_map(|(fname, _)| fname.strip_suffix(".deb")?.rsplit('_').next());
+        let mut archs = archs.peekable();
+        let first = archs.peek().copied().unwrap_or_default().to_string();
+        archs.find(|&arch| arch != "all").map_or(first, str::to_string)
+    }
+}
+
+/// The plan of `files` in `mode`, with packages in the order the
+/// signature lists them. Files listed twice are only kept once.
+///
+/// # Errors
+/// Returns [`crate::Error::NameCollision`] if different files have the
+/// same name.
+pub fn plan(files: Vec<(String, PackageEntry)>, mode: RemoteMode, arch: String) -> Result<UriFile> {
+    let mut install_order = Vec::new();
+    let mut packages = Default::default();
+    let mut total_size = 0;
+    for (fname, entry) in files {
+        let size = entry.size;
+        if uri::add_package(&mut packages, fname.clone(), entry)? && mode != RemoteMode::Update {
+            total_size += size;
+            install_order.push(fname);
+        }
+    }
+    let total_size = (mode != RemoteMode::Update).then_some(total_size);
+    Ok(UriFile { schema_version: SCHEMA_VERSION, mode, arch, total_size, install_order, meta: ImageMeta::default(), packages, sources: Vec::new() })
+}
+
+/// Parse a `KIND:VALUE` checksum field, as `apt-get --print-uris` prints it.
+fn parse_checksum(field: &str) -> Result<Checksum> {
+    let (kind, value) = field.split_once(':').ok_or_else(|| anyhow!("invalid checksum {field}"))?;
+    let kind = kind.to_lowercase();
+    let kind = ChecksumKind::new(&format!("{}sum", kind.strip_suffix("sum").unwrap_or(&kind)))
+        .with_context(|| format!("unsupported checksum kind {kind}"))?;
+    Ok(Checksum { kind, value: value.to_string() })
+}
+
+/// Write the plan of `uri_file` as an apt-offline signature.
+///
+/// Lists are named as APT names them in its lists directory, without their
+/// compression extension.
+pub fn signature(uri_file: &UriFile) -> String {
+    let names = match uri_file.mode {
+        RemoteMode::Update => {
+            let mut names = uri_file.packages.keys().collect::<Vec<_>>();
+            names.sort();
+            names
+        }
+        RemoteMode::Install | RemoteMode::Upgrade => uri_file.install_order.iter().collect(),
+    };
+
+    let mut content = String::new();
+    for fname in names {
+        let pkg = &uri_file.packages[fname];
+        let checksum = match &pkg.checksum {
+            Some(Checksum { kind: ChecksumKind::SHA256, value }) => format!("SHA256:{value}"),
+            Some(Checksum { kind: ChecksumKind::MD5, value }) => format!("MD5Sum:{value}"),
+            None => String::new(),
+        };
+        let dest = match uri_file.mode {
+            RemoteMode::Update => list_stem(fname),
+            RemoteMode::Install | RemoteMode::Upgrade => fname,
+        };
+        content.push_str(&format!("'{}' {dest} {} {checksum}\n", pkg.uri, pkg.size));
+    }
+    content
+}
+
+/// A list's name without its compression extension.
+///
+/// ```
+/// use apt_remote::apt_offline::list_stem;
+///
+/// assert_eq!(list_stem("deb.debian.org_debian_dists_bookworm_main_binary-amd64_Packages.xz"),
+///            "deb.debian.org_debian_dists_bookworm_main_binary-amd64_Packages");
+/// assert_eq!(list_stem("deb.debian.org_debian_dists_bookworm_InRelease"), "deb.debian.org_debian_dists_bookworm_InRelease");
+/// ```
+pub fn list_stem(name: &str) -> &str {
+    COMPRESSIONS.iter().find_map(|ext| name.strip_suffix(ext)).unwrap_or(name)
+}
+
+/// The file of `uri_file` that a bundle entry named `name` holds, if any.
+/// Lists match whatever their compression.
+pub fn plan_file<'a>(uri_file: &'a UriFile, name: &str) -> Option<&'a str> {
+    let name = name.rsplit('/').next().unwrap_or(name);
+    match uri_file.mode {
+        RemoteMode::Update => {
+            uri_file.packages.keys().find(|fname| list_stem(fname) == list_stem(name)).map(String::as_str)
+        }
+        RemoteMode::Install | RemoteMode::Upgrade => {
+            let name = uri::archive_file_name(name);
+            uri_file.packages.get_key_value(&name).map(|(fname, _)| fname.as_str())
+        }
+    }
+}
+
+/// Copy the list at `src` to `dest`, uncompressing it unless `dest` keeps
+/// a compression extension. `.gz` and `.bz2` lists are uncompressed with
+/// `gzip` or `bzip2`, if they are installed.
+///
+/// Lists of a bundle are not trusted: like `get` (see
+/// [`crate::commands::get`]), a list may not grow past `max_index_size`.
+///
+/// # Errors
+/// Returns an error if the list cannot be read, written or uncompressed, or
+/// is larger than `max_index_size` once uncompressed.
+pub fn unpack_list(src: &Path, dest: &Path) -> Result<()> {
+    let mut magic = [0u8; 6];
+    let read = File::open(src)?.read(&mut magic)?;
+    let keeps_extension = list_stem(&dest.to_string_lossy()) != dest.to_string_lossy();
+    let tool = match &magic[..read] {
+        _ if keeps_extension => None,
+        [0xfd, b'7', b'z', b'X', b'Z', 0] => {
+            let decoder = XzDecoder::new_multi_decoder(BufReader::new(File::open(src)?));
+            return copy_limited(decoder, src, dest);
+        }
+        [0x1f, 0x8b, ..] => Some("gzip"),
+        [b'B', b'Z', b'h', ..] => Some("bzip2"),
+        _ => None,
+    };
+    let Some(tool) = tool else {
+        return copy_limited(File::open(src)?, src, dest);
+    };
+    let mut child = Command::new(tool)
+        .arg("-dc")
+        .stdin(File::open(src)?)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("{} is compressed: install {tool} to uncompress it", src.display()))?;
+    let copied = copy_limited(child.stdout.take().unwrap(), src, dest);
+    if copied.is_err() {
+        child.kill().ok();
+    }
+    let status = child.wait()?;
+    copied?;
+    if !status.success() {
+        anyhow::bail!("{tool} failed to uncompress {}", src.display());
+    }
+    Ok(())
+}
+
+/// Copy the uncompressed list `src` read from `reader` to `dest`, removing
+/// `dest` again if it grows past `max_index_size`.
+fn copy_limited(reader: impl Read, src: &Path, dest: &Path) -> Result<()> {
+    // Stop one byte past the limit, which is enough to tell it was exceeded
+    let limit = config::max_index_bytes();
+    let mut out = BufWriter::new(File::create(dest)?);
+    let written = std::io::copy(&mut reader.take(limit + 1), &mut out)
+        .with_context(|| format!("Failed to uncompress {}", src.display()))?;
+    drop(out);
+    if written > limit {
+        fs::remove_file(dest).ok();
+        anyhow::bail!("{} uncompresses to more than the limit of {limit} bytes", src.display());
+    }
+    Ok(())
+}
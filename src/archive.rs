@@ -0,0 +1,104 @@
+//! # Local partial archive mirror for apt-remote
+//!
+//! This module defines the on-disk layout of the partial archive mirror kept
+//! in the `mirror/` directory of the cache. Files are stored under
+//! `<host>/<url path>`, exactly as they appear on the upstream archive, so
+//! any package or index URI recorded in a `uri.toml` file can be mapped to
+//! its mirrored copy without further metadata.
+//!
+//! The mirror is populated by `apt-remote mirror` (suite indices) and, for
+//! archives that have been mirrored, by `apt-remote get` (pool files).
+
+use crate::{config, uri};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Locate the root directory of the local mirror.
+///
+/// # Errors
+/// Returns an error if the platform cache directory cannot be determined.
+pub fn root() -> Result<PathBuf> {
+    Ok(config::cache_dir()?.join("mirror"))
+}
+
+/// Map an archive URI onto its location in the local mirror.
+///
+/// Returns `None` if the URI cannot be parsed or has no host.
+pub fn local_path(uri: &str) -> Option<PathBuf> {
+    let url = Url::parse(uri).ok()?;
+    let mut path = root().ok()?.join(url.host_str()?);
+    for segment in url.path_segments()? {
+        // Files are stored under their decoded name, as a web server maps them
+        let segment = match uri::decode_file_name(segment) {
+            decoded if decoded.contains(['/', '\0']) => segment.to_string(),
+            decoded => decoded,
+        };
+        // Never allow a URI to escape the mirror directory
+        if segment.is_empty() || segment == "." || segment == ".." {
+            continue;
+        }
+        path.push(segment);
+    }
+    Some(path)
+}
+
+/// Returns `true` if the archive host of `uri` has been mirrored locally.
+pub fn is_mirrored(uri: &str) -> bool {
+    Url::parse(uri)
+        .ok()
+        .and_then(|url| Some(root().ok()?.join(url.host_str()?)))
+        .is_some_and(|dir| dir.is_dir())
+}
+
+/// Copy a downloaded file into the mirror at the location for `uri`.
+///
+/// # Errors
+/// Returns an error if the mirror directory cannot be created or the copy fails.
+pub fn store(uri: &str, file: &Path) -> Result<()> {
+    let dest = local_path(uri).context("URI cannot be mapped into the mirror")?;
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(file, &dest)
+        .with_context(|| format!("Failed to store {} in mirror", dest.display()))?;
+    Ok(())
+}
+
+/// Compute the hex-encoded SHA256 digest of a file.
+///
+/// # Errors
+/// Returns an error if the file cannot be read.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Hex-encode a byte slice.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decode a hex string.
+///
+/// # Errors
+/// Returns an error if `text` is not an even number of hex digits.
+pub fn unhex(text: &str) -> Result<Vec<u8>> {
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .context("Invalid hex value")
+        })
+        .collect()
+}
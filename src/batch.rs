@@ -0,0 +1,104 @@
+//! # Batched remote queries
+//!
+//! Every remote command costs a round trip, which adds up on slow or distant
+//! links. A [`Batch`] runs several independent commands as one composite
+//! command, each one's output preceded by a marker line, and splits the
+//! output back into one section per command.
+//!
+//! Commands in a batch run in order in the same shell; one that fails does
+//! not stop the others, and its section holds whatever it printed.
+
+use crate::ssh::RemoteExecutor;
+
+use anyhow::Result;
+
+use std::collections::HashMap;
+
+/// Marker starting each section of a batch's output.
+const SECTION: &str = "@@apt-remote:";
+
+/// Commands to run in a single round trip.
+#[derive(Debug, Default)]
+pub struct Batch {
+    commands: Vec<(String, String)>,
+}
+
+impl Batch {
+    /// An empty batch.
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    /// Add `cmd`, whose output becomes section `name`.
+    pub fn add(&mut self, name: &str, cmd: impl Into<String>) -> &mut Batch {
+        self.commands.push((name.to_string(), cmd.into()));
+        self
+    }
+
+    /// The composite command running every command of the batch.
+    pub fn script(&self) -> String {
+        let mut script = self
+            .commands
+            .iter()
+            // Output without a final newline must not hide the next marker
+            .map(|(name, cmd)| format!("printf '\\n{SECTION}{name}\\n'; {cmd}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        script.push_str("; true");
+        script
+    }
+
+    /// Run the batch on the target.
+    ///
+    /// # Errors
+    /// Returns an error if the target cannot be queried.
+    pub fn run(&self, session: &dyn RemoteExecutor) -> Result<Sections> {
+        Ok(Sections::parse(&session.query(&self.script())?))
+    }
+}
+
+/// The output of a [`Batch`], by section.
+#[derive(Debug, Default)]
+pub struct Sections {
+    sections: HashMap<String, String>,
+}
+
+impl Sections {
+    /// Split the output of [`Batch::script`] into sections.
+    ///
+    /// ```
+    /// use apt_remote::batch::{Batch, Sections};
+    ///
+    /// let mut batch = Batch::new();
+    /// batch.add("user", "whoami").add("arch", "dpkg --print-architecture");
+    /// let sections = Sections::parse("\n@@apt-remote:user\nadmin\n\n@@apt-remote:arch\narm64\n");
+    /// assert_eq!(sections.get("user"), "admin");
+    /// assert_eq!(sections.get("arch"), "arm64");
+    /// assert_eq!(sections.get("missing"), "");
+    /// ```
+    pub fn parse(output: &str) -> Sections {
+        let mut sections = HashMap::<String, String>::new();
+        let mut current = None;
+        for line in output.lines() {
+            match line.trim().strip_prefix(SECTION) {
+                Some(name) => current = Some(name.to_string()),
+                None => {
+                    if let Some(name) = &current {
+                        let section = sections.entry(name.clone()).or_default();
+                        section.push_str(line);
+                        section.push('\n');
+                    }
+                }
+            }
+        }
+        Sections { sections }
+    }
+
+    /// The output of section `name` without leading and trailing line
+    /// breaks; empty if it printed nothing.
+    ///
+    /// Other whitespace is kept, since it can separate empty fields.
+    pub fn get(&self, name: &str) -> &str {
+        self.sections.get(name).map_or("", |text| text.trim_matches(['\n', '\r']))
+    }
+}
@@ -0,0 +1,31 @@
+//! # `apt-remote-ansible`
+//!
+//! Entry point of the Ansible module (see [`apt_remote::ansible`]). Ansible
+//! runs binary modules with the path of a file holding their arguments;
+//! without one, the arguments are read from stdin.
+
+use apt_remote::ansible;
+
+use std::io::Read;
+
+/// Read the module arguments, run the module, and print its result.
+///
+/// Exits with status 1 if the module failed.
+fn main() {
+    let input = match std::env::args_os().nth(1) {
+        Some(path) => std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read module arguments from {}: {e}", path.to_string_lossy())),
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input).map(|_| input).map_err(|e| format!("Failed to read module arguments: {e}"))
+        }
+    };
+    let result = match input {
+        Ok(input) => ansible::run(&input),
+        Err(msg) => serde_json::json!({ "changed": false, "failed": true, "msg": msg }),
+    };
+    println!("{result}");
+    if result["failed"] == true {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,236 @@
+//! # Package changelogs
+//!
+//! Reviewers of a change want to see what the packages of an image change.
+//! `apt-remote get <NAME> --changelogs` fetches the changelog of the source
+//! package of every planned `.deb`, as `apt changelog` would, from the
+//! services publishing them:
+//!
+//! - Debian's archives (including snapshot.debian.org) from
+//!   `https://metadata.ftp-master.debian.org/changelogs`;
+//! - Ubuntu's archives from `https://changelogs.ubuntu.com/changelogs`.
+//!
+//! The source package and its version are read from the file's location in
+//! the archive pool (`pool/<component>/<prefix>/<source>/`) and name; a
+//! binary-only rebuild (`+b1`) has the changelog of its source version.
+//! Packages of other repositories, and of Ubuntu Pro, have no published
+//! changelog. Changelogs are stored in the image's `changelogs/` directory,
+//! one per source package and version, and `apt-remote changelog` shows
+//! them offline.
+
+use crate::{apt_auth, dry_run, output, theme, tls, uri::{self, UriFile}, resolve::compare_versions};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use rayon::prelude::*;
+use reqwest::blocking::Client;
+use serde_json::json;
+use url::Url;
+
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Directory of an image holding its changelogs.
+pub const DIR: &str = "changelogs";
+
+/// Where Debian publishes the changelogs of its archives.
+const DEBIAN_URL: &str = "https://metadata.ftp-master.debian.org/changelogs";
+
+/// Where Ubuntu publishes the changelogs of its archives.
+const UBUNTU_URL: &str = "https://changelogs.ubuntu.com/changelogs";
+
+/// The source package a `.deb` was built from.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Source {
+    /// Name of the source package.
+    pub name: String,
+    /// Its version, without epoch.
+    pub version: String,
+    /// Archive component (`main`, `universe`, ...).
+    component: String,
+    /// Pool directory of the source (`c`, `libc`, ...).
+    prefix: String,
+    /// Whether the package comes from Ubuntu's archives rather than Debian's.
+    pub ubuntu: bool,
+}
+
+impl Source {
+    /// The source of the `.deb` at `uri`, if it comes from an archive whose
+    /// changelogs are published.
+    ///
+    /// ```
+    /// use apt_remote::changelog::Source;
+    ///
+    /// let source = Source::of("http://deb.debian.org/debian/pool/main/g/gcc-12/g%2b%2b-12_12.2.0-14_amd64.deb").unwrap();
+    /// assert_eq!(source.url(), "https://metadata.ftp-master.debian.org/changelogs/main/g/gcc-12/gcc-12_12.2.0-14_changelog");
+    ///
+    /// let source = Source::of("http://security.debian.org/debian-security/pool/updates/main/c/curl/libcurl4_7.88.1-10%2bdeb12u5%2bb1_amd64.deb").unwrap();
+    /// assert_eq!(source.file_name(), "curl_7.88.1-10+deb12u5.changelog");
+    ///
+    /// let source = Source::of("http://archive.ubuntu.com/ubuntu/pool/main/c/curl/curl_8.5.0-2ubuntu10.6_amd64.deb").unwrap();
+    /// assert_eq!(source.url(), "https://changelogs.ubuntu.com/changelogs/pool/main/c/curl/curl_8.5.0-2ubuntu10.6/changelog");
+    ///
+    /// assert_eq!(Source::of("https://ppa.launchpadcontent.net/deadsnakes/ppa/ubuntu/pool/main/p/python3.12/x_1_all.deb"), None);
+    /// ```
+    pub fn of(uri: &str) -> Option<Source> {
+        let url = Url::parse(uri).ok()?;
+        let host = url.host_str()?;
+        let ubuntu = if host == "debian.org" || host.ends_with(".debian.org") {
+            false
+        } else if host.ends_with(".ubuntu.com") && !apt_auth::needs_credentials(uri) {
+            true
+        } else {
+            return None;
+        };
+
+        // .../pool/<component...>/<prefix>/<source>/<name>_<version>_<arch>.deb
+        let segments = url.path_segments()?.collect::<Vec<_>>();
+        let pool = segments.iter().position(|segment| *segment == "pool")?;
+        let [.., component, prefix, name, file] = &segments[pool + 1..] else { return None };
+        let file = uri::decode_file_name(file);
+        let version = file.strip_suffix(".deb")?.split('_').nth(1)?;
+
+        // The changelog is that of the source version (no epoch, no binNMU)
+        let version = version.split_once(':').map_or(version, |(_, version)| version);
+        let version = match version.rsplit_once("+b") {
+            Some((source, rebuild)) if !rebuild.is_empty() && rebuild.chars().all(|c| c.is_ascii_digit()) => source,
+            _ => version,
+        };
+        Some(Source {
+            name: uri::decode_file_name(name),
+            version: version.to_string(),
+            component: component.to_string(),
+            prefix: prefix.to_string(),
+            ubuntu,
+        })
+    }
+
+    /// Where the changelog is published.
+    pub fn url(&self) -> String {
+        let Source { name, version, component, prefix, .. } = self;
+        if self.ubuntu {
+            format!("{UBUNTU_URL}/pool/{component}/{prefix}/{name}/{name}_{version}/changelog")
+        } else {
+            format!("{DEBIAN_URL}/{component}/{prefix}/{name}/{name}_{version}_changelog")
+        }
+    }
+
+    /// Name of the changelog in the image's [`DIR`].
+    pub fn file_name(&self) -> String {
+        format!("{}_{}.changelog", self.name, self.version)
+    }
+}
+
+/// Changelogs fetched for an image.
+#[derive(Debug, Default)]
+pub struct Fetched {
+    /// Changelogs fetched, or already in the image.
+    pub fetched: usize,
+    /// Changelogs that could not be fetched.
+    pub failed: usize,
+    /// Packages from archives whose changelogs are not published.
+    pub unavailable: usize,
+}
+
+/// Fetch the changelogs of the packages of `uri_file` into the image's
+/// `cache_dir`, skipping those already there.
+///
+/// Changelogs that cannot be fetched are reported, not returned.
+///
+/// # Errors
+/// Returns an error if the HTTP client cannot be built or a changelog
+/// cannot be written.
+pub fn fetch(uri_file: &UriFile, cache_dir: &Path) -> Result<Fetched> {
+    let dir = cache_dir.join(DIR);
+    let mut result = Fetched::default();
+    let mut sources = BTreeMap::new();
+    for pkg in uri_file.install_order.iter().filter_map(|fname| uri_file.packages.get(fname)) {
+        match Source::of(&pkg.uri) {
+            Some(source) => {
+                sources.insert(source.file_name(), source);
+            }
+            None => result.unavailable += 1,
+        }
+    }
+    if sources.is_empty() || dry_run::skip("download", format!("{} changelogs -> {}", sources.len(), dir.display())) {
+        return Ok(result);
+    }
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let client = tls::load()?
+        .blocking(Client::builder().timeout(Duration::from_secs(60)))
+        .build()
+        .context("Failed to build client")?;
+    let outcomes = sources
+        .into_par_iter()
+        .map(|(file_name, source)| -> Result<bool> {
+            let path = dir.join(&file_name);
+            if path.is_file() {
+                return Ok(true);
+            }
+            let url = source.url();
+            tracing::debug!(url, "GET");
+            let response = client.get(&url).send().and_then(|r| r.error_for_status()).and_then(|r| r.text());
+            match response {
+                Ok(text) => {
+                    fs::write(&path, text).with_context(|| format!("Failed to write {}", path.display()))?;
+                    output::emit("changelog", json!({ "source": source.name, "version": source.version, "ok": true }));
+                    Ok(true)
+                }
+                Err(e) => {
+                    output::print(format!(
+                        "{} {}: {}",
+                        theme::failure("✗").bold(),
+                        theme::failure(format!("No changelog of {} {}", source.name, source.version)),
+                        e.to_string().dimmed()
+                    ));
+                    output::emit(
+                        "changelog",
+                        json!({ "source": source.name, "version": source.version, "ok": false, "error": e.to_string() }),
+                    );
+                    Ok(false)
+                }
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    result.fetched = outcomes.iter().filter(|ok| **ok).count();
+    result.failed = outcomes.len() - result.fetched;
+    Ok(result)
+}
+
+/// The stored changelog of package `fname` of the image in `cache_dir`, if
+/// its changelog is published.
+pub fn path(uri_file: &UriFile, cache_dir: &Path, fname: &str) -> Option<PathBuf> {
+    let source = Source::of(&uri_file.packages.get(fname)?.uri)?;
+    Some(cache_dir.join(DIR).join(source.file_name()))
+}
+
+/// The entries of `changelog` newer than `version`.
+///
+/// ```
+/// use apt_remote::changelog::since;
+///
+/// let changelog = "curl (7.88.1-10+deb12u5) bookworm-security; urgency=medium\n\n  * Fix CVE.\n\n -- A <a@b>  Mon, 1 Jan 2024 00:00:00 +0000\n\n\
+///                  curl (7.88.1-10) unstable; urgency=medium\n\n  * Upload.\n";
+/// assert!(since(changelog, "7.88.1-10").contains("Fix CVE"));
+/// assert!(!since(changelog, "7.88.1-10").contains("Upload"));
+/// assert_eq!(since(changelog, "1:0"), "");
+/// ```
+pub fn since<'a>(changelog: &'a str, version: &str) -> &'a str {
+    // Entries start with "<source> (<version>) <distributions>; ..." in the first column
+    let mut offset = 0;
+    for line in changelog.split_inclusive('\n') {
+        let entry_version = (!line.starts_with([' ', '\t']))
+            .then(|| line.split_once(" (")?.1.split_once(')').map(|(version, _)| version))
+            .flatten();
+        if entry_version.is_some_and(|entry| compare_versions(entry, version) != Ordering::Greater) {
+            break;
+        }
+        offset += line.len();
+    }
+    &changelog[..offset]
+}
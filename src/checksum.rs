@@ -0,0 +1,112 @@
+//! # Checksum tools on the target
+//!
+//! Uploaded files are verified on the target itself. Stripped-down systems
+//! do not always have GNU coreutils, so [`Tools::from_available`] picks each
+//! algorithm's tool in turn:
+//!
+//! | Tool                       | SHA-256 | MD5 |
+//! |----------------------------|---------|-----|
+//! | `sha256sum`, `md5sum`      | ✓       | ✓   |
+//! | `busybox`                  | ✓       | ✓   |
+//! | `shasum` (Perl)            | ✓       |     |
+//! | `openssl dgst`             | ✓       | ✓   |
+//! | `python3` (`hashlib`)      | ✓       | ✓   |
+//!
+//! Their output formats differ, so [`parse_digest`] takes the first word of
+//! the right length made of hex digits.
+
+use crate::uri::ChecksumKind;
+
+/// Tools probed on the target (see [`crate::probe`]), in order of preference.
+pub const CANDIDATES: [&str; 6] = ["sha256sum", "md5sum", "busybox", "shasum", "openssl", "python3"];
+
+/// Commands that print the digest of the files given as arguments.
+#[derive(Debug, Default)]
+pub struct Tools {
+    sha256: Option<String>,
+    md5: Option<String>,
+}
+
+impl Tools {
+    /// The checksum tools among the tools `found` on the target.
+    pub fn from_available(found: &[String]) -> Tools {
+        let first = |kind| commands(kind).into_iter().find(|(tool, _)| found.iter().any(|f| f == tool)).map(|(_, cmd)| cmd);
+        Tools { sha256: first(&ChecksumKind::SHA256), md5: first(&ChecksumKind::MD5) }
+    }
+
+    /// A shell command computing `kind` digests of `args` with the first
+    /// tool the target turns out to have, for when its tools are not known
+    /// yet.
+    ///
+    /// ```
+    /// use apt_remote::checksum::Tools;
+    /// use apt_remote::uri::ChecksumKind;
+    ///
+    /// let script = Tools::first_found(&ChecksumKind::MD5, "a.deb");
+    /// assert!(script.starts_with("if command -v md5sum >/dev/null 2>&1; then md5sum a.deb; elif "));
+    /// assert!(script.ends_with("; fi"));
+    /// ```
+    pub fn first_found(kind: &ChecksumKind, args: &str) -> String {
+        let branches = commands(kind)
+            .into_iter()
+            .map(|(tool, cmd)| format!("if command -v {tool} >/dev/null 2>&1; then {cmd} {args}"))
+            .collect::<Vec<_>>();
+        format!("{}; fi", branches.join("; el"))
+    }
+
+    /// The command line prefix computing `kind` digests, if the target has a
+    /// tool for it.
+    pub fn command(&self, kind: &ChecksumKind) -> Option<&str> {
+        match kind {
+            ChecksumKind::SHA256 => self.sha256.as_deref(),
+            ChecksumKind::MD5 => self.md5.as_deref(),
+        }
+    }
+}
+
+/// The commands computing `kind` digests and the tool each needs, in order
+/// of preference.
+fn commands(kind: &ChecksumKind) -> Vec<(&'static str, String)> {
+    let python = |algorithm: &str| {
+        format!(
+            "python3 -c 'import hashlib,sys;[print(hashlib.{algorithm}(open(f,\"rb\").read()).hexdigest(),f) for f in sys.argv[1:]]'"
+        )
+    };
+    match kind {
+        ChecksumKind::SHA256 => vec![
+            ("sha256sum", "sha256sum".to_string()),
+            ("busybox", "busybox sha256sum".to_string()),
+            ("shasum", "shasum -a 256".to_string()),
+            ("openssl", "openssl dgst -sha256 -r".to_string()),
+            ("python3", python("sha256")),
+        ],
+        ChecksumKind::MD5 => vec![
+            ("md5sum", "md5sum".to_string()),
+            ("busybox", "busybox md5sum".to_string()),
+            ("openssl", "openssl dgst -md5 -r".to_string()),
+            ("python3", python("md5")),
+        ],
+    }
+}
+
+/// The first `kind` digest in the output of a checksum tool, in lowercase.
+///
+/// ```
+/// use apt_remote::checksum::parse_digest;
+/// use apt_remote::uri::ChecksumKind;
+///
+/// let md5 = "d41d8cd98f00b204e9800998ecf8427e";
+/// assert_eq!(parse_digest(&format!("{md5}  ./a.deb\n"), &ChecksumKind::MD5).as_deref(), Some(md5));
+/// assert_eq!(parse_digest(&format!("{md5} *./a.deb\n"), &ChecksumKind::MD5).as_deref(), Some(md5));
+/// assert_eq!(parse_digest("md5sum: ./a.deb: No such file", &ChecksumKind::MD5), None);
+/// ```
+pub fn parse_digest(output: &str, kind: &ChecksumKind) -> Option<String> {
+    let length = match kind {
+        ChecksumKind::SHA256 => 64,
+        ChecksumKind::MD5 => 32,
+    };
+    output
+        .split(|c: char| c.is_whitespace() || c == '=')
+        .find(|word| word.len() == length && word.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(str::to_ascii_lowercase)
+}
@@ -0,0 +1,99 @@
+//! # `apt-remote advisories` command
+//!
+//! Reports the known vulnerabilities an image fixes on its target, from the
+//! advisories fetched by `apt-remote get <NAME> --advisories` and the
+//! versions the target had when the image was planned (see
+//! [`crate::advisory`]). Nothing is fetched: the report can be made on the
+//! offline side, from an imported bundle.
+
+use crate::{
+    advisory::{self, Advisories},
+    config,
+    exit::{Classify, ExitCode},
+    lock::LockFile,
+    output, theme,
+    uri::{self, UriFile},
+};
+
+use anyhow::{Context, Result, anyhow};
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+use std::collections::BTreeSet;
+
+/// Longest description shown, in characters.
+const DESCRIPTION_WIDTH: usize = 100;
+
+/// CLI arguments for the `apt-remote advisories` subcommand.
+#[derive(Args)]
+pub struct AdvisoriesArgs {
+    /// Cache image name (required)
+    pub name: String,
+}
+
+/// Executes the `advisories` subcommand.
+///
+/// # Errors
+/// Returns an error if the image, its `uri.lock` or its advisories cannot
+/// be loaded.
+pub fn run(args: AdvisoriesArgs) -> Result<()> {
+    let name = &args.name;
+    let cache_dir = config::cache_dir()?.join(name);
+    let uri_file = UriFile::load(uri::path_in(&cache_dir)).context("Failed to load uri.toml metadata")?;
+    let lock = LockFile::load(cache_dir.join("uri.lock")).classify(ExitCode::Cache)?;
+    let advisories = Advisories::load(&cache_dir)
+        .map_err(|_| anyhow!("No advisories in {name}: run `apt-remote get {name} --advisories`"))
+        .classify(ExitCode::Cache)?;
+
+    // Plans made from a suite's indices do not know what the target has
+    if lock.selections.is_empty() {
+        let message = format!("uri.lock of {name} does not record the target's packages, so no fix can be reported");
+        output::print(format!("{} {}", theme::warning("!").bold(), theme::warning(&message)));
+        output::emit("warning", json!({ "message": message }));
+    }
+
+    let fixes = advisory::fixes(&advisories, &uri_file, &lock);
+    let cves = fixes
+        .iter()
+        .flat_map(|fix| &fix.advisories)
+        .map(|advisory| advisory.id.as_str())
+        .collect::<BTreeSet<_>>();
+    output::print(format!(
+        "{} fixes {} known vulnerabilities in {} packages on its target:\n",
+        name.bold(),
+        cves.len(),
+        fixes.len()
+    ));
+    for fix in &fixes {
+        output::print(format!("  {} {} → {}", fix.package.bold(), fix.installed, theme::success(&fix.version)));
+        for advisory in &fix.advisories {
+            let notices = if advisory.notices.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", advisory.notices.join(", "))
+            };
+            output::print(format!("      {}{notices}", theme::accent(&advisory.id)));
+            if let Some(description) = &advisory.description {
+                let line = description.lines().next().unwrap_or_default();
+                let short = match line.char_indices().nth(DESCRIPTION_WIDTH) {
+                    Some((end, _)) => format!("{}…", &line[..end]),
+                    None => line.to_string(),
+                };
+                output::print(format!("        {}", short.dimmed()));
+            }
+        }
+    }
+
+    output::emit(
+        "result",
+        json!({
+            "command": "advisories",
+            "name": name,
+            "fetched_at": advisories.fetched_at,
+            "cves": cves,
+            "fixes": fixes,
+        }),
+    );
+    Ok(())
+}
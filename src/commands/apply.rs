@@ -0,0 +1,155 @@
+//! # `apt-remote apply` command
+//!
+//! This subcommand converges every image and target listed in a manifest
+//! (see [`crate::manifest`]). For each image it resolves the plan with
+//! `set`, downloads it with `get`, and then runs `install` (or `update` for
+//! package-list images) on each of its targets.
+//!
+//! A failing entry does not stop the run: the remaining images and targets
+//! are still applied, and each entry's status is reported at the end.
+
+use crate::{
+    error::Error,
+    manifest::{ImageSpec, Manifest},
+    output, theme, tr,
+};
+use super::{get, install, set, update};
+
+use anyhow::Result;
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+use std::path::PathBuf;
+
+/// CLI arguments for the `apt-remote apply` subcommand.
+#[derive(Args)]
+#[command(override_usage = "apt-remote apply <MANIFEST>")]
+pub struct ApplyArgs {
+    /// Manifest file listing images and their targets (required)
+    pub manifest: PathBuf,
+}
+
+/// Status of one manifest entry after the run.
+struct Entry {
+    image: String,
+    target: Option<String>,
+    step: &'static str,
+    error: Option<anyhow::Error>,
+}
+
+/// Executes the `apply` subcommand.
+///
+/// Loads the manifest and applies each image in name order, continuing past
+/// failed entries. When an image cannot be resolved or downloaded, its
+/// targets are not attempted.
+///
+/// # Errors
+/// Returns an error if the manifest cannot be loaded, or if any entry failed.
+pub fn run(args: ApplyArgs) -> Result<()> {
+    let manifest = Manifest::load(&args.manifest)?;
+
+    let mut entries = Vec::new();
+    for (name, spec) in &manifest.images {
+        apply_image(name, spec, &mut entries);
+    }
+
+    // Per-entry status
+    output::print(format!("\n{}", tr!("Apply summary:").bold()));
+    for entry in &entries {
+        let label = match &entry.target {
+            Some(target) => tr!("{step} {image} on {target}", step = entry.step, image = entry.image, target = target),
+            None => format!("{} {}", entry.step, entry.image),
+        };
+        match &entry.error {
+            None => output::print(format!("\t{} {label}", theme::success("✓"))),
+            Some(e) => output::print(format!("\t{} {label}: {e}", theme::failure("✗"))),
+        }
+        output::emit(
+            "apply",
+            json!({
+                "image": entry.image,
+                "target": entry.target,
+                "step": entry.step,
+                "status": if entry.error.is_none() { "ok" } else { "failed" },
+                "error": entry.error.as_ref().map(|e| format!("{e:#}")),
+            }),
+        );
+    }
+
+    let failed = entries.iter().filter(|entry| entry.error.is_some()).count();
+    output::emit(
+        "result",
+        json!({
+            "command": "apply",
+            "manifest": args.manifest,
+            "entries": entries.len(),
+            "failed": failed,
+        }),
+    );
+
+    if failed > 0 {
+        return Err(Error::ApplyFailed { failed, total: entries.len() }.into());
+    }
+    Ok(())
+}
+
+/// Resolve, download and install one image, recording each step in `entries`.
+///
+/// # Arguments
+/// * `name` - Cache image name.
+/// * `spec` - The image's manifest entry.
+/// * `entries` - Status list the steps are appended to.
+fn apply_image(name: &str, spec: &ImageSpec, entries: &mut Vec<Entry>) {
+    output::print(format!("{}", theme::accent(tr!("Applying {name}...", name = name)).bold()));
+
+    // Resolve the plan on one target, then download it
+    let resolve_on = spec.resolve_target().cloned();
+    let set = set::run(set::SetArgs {
+        name: name.to_string(),
+        target: resolve_on.clone(),
+        install: spec.install.clone(),
+        fix: spec.fix,
+        update: spec.update,
+        upgrade: spec.upgrade,
+        ppa: Vec::new(),
+        snapshot: None,
+        preseed: None,
+        distro: None,
+        meta: Default::default(),
+    });
+    let resolved = set.is_ok();
+    entries.push(Entry { image: name.to_string(), target: resolve_on, step: "set", error: set.err() });
+    if !resolved {
+        return;
+    }
+
+    let get = get::run(get::GetArgs { name: name.to_string(), adopt: None, changelogs: false, advisories: false });
+    let downloaded = get.is_ok();
+    entries.push(Entry { image: name.to_string(), target: None, step: "get", error: get.err() });
+    if !downloaded {
+        return;
+    }
+
+    // Package-list images replace the targets' lists, others install
+    for target in &spec.targets {
+        let (step, outcome) = if spec.update {
+            let args = update::UpdateArgs { name: name.to_string(), target: Some(target.clone()) };
+            ("update", update::run(args))
+        } else {
+            let args = install::InstallArgs {
+                name: name.to_string(),
+                target: Some(target.clone()),
+                reinstall: false,
+                verify_hashes: false,
+            };
+            ("install", install::run(args))
+        };
+        entries.push(Entry {
+            image: name.to_string(),
+            target: Some(target.clone()),
+            step,
+            error: outcome.err(),
+        });
+    }
+}
@@ -0,0 +1,72 @@
+//! # `apt-remote changelog` command
+//!
+//! Shows the changelog of a package of an image, as fetched by `apt-remote
+//! get <NAME> --changelogs` (see [`crate::changelog`]), without network
+//! access. With `--since`, only the entries newer than the given version,
+//! such as the one installed on the target, are shown.
+
+use crate::{
+    changelog,
+    exit::{Classify, ExitCode},
+    config, output,
+    uri::{self, UriFile},
+};
+
+use anyhow::{Context, Result, anyhow};
+use clap::Args;
+use serde_json::json;
+
+use std::fs;
+
+/// CLI arguments for the `apt-remote changelog` subcommand.
+///
+/// Example:
+/// ```bash
+/// apt-remote changelog <NAME> curl --since 7.88.1-10
+/// ```
+#[derive(Args)]
+pub struct ChangelogArgs {
+    /// Cache image name (required)
+    pub name: String,
+
+    /// Package of the image (required)
+    pub package: String,
+
+    /// Only show the entries newer than this version
+    #[arg(long, value_name = "VERSION")]
+    pub since: Option<String>,
+}
+
+/// Executes the `changelog` subcommand.
+///
+/// # Errors
+/// Returns an error if the image has no such package, or its changelog was
+/// not fetched.
+pub fn run(args: ChangelogArgs) -> Result<()> {
+    let name = &args.name;
+    let package = &args.package;
+    let cache_dir = config::cache_dir()?.join(name);
+    let uri_file = UriFile::load(uri::path_in(&cache_dir)).context("Failed to load uri.toml metadata")?;
+
+    // Package files are named `<package>_<version>_<arch>.deb`
+    let fname = uri_file
+        .install_order
+        .iter()
+        .find(|fname| fname.split('_').next() == Some(package.as_str()))
+        .ok_or_else(|| anyhow!("{name} has no package {package}"))
+        .classify(ExitCode::Cache)?;
+    let path = changelog::path(&uri_file, &cache_dir, fname)
+        .ok_or_else(|| anyhow!("The changelog of {package} is not published by its archive"))
+        .classify(ExitCode::Cache)?;
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("No changelog of {package} in {name}: run `apt-remote get {name} --changelogs`"))
+        .classify(ExitCode::Cache)?;
+
+    let shown = match &args.since {
+        Some(version) => changelog::since(&content, version),
+        None => &content,
+    };
+    output::print(shown.trim_end());
+    output::emit("result", json!({ "command": "changelog", "name": name, "package": package, "changelog": shown }));
+    Ok(())
+}
@@ -0,0 +1,231 @@
+//! # `apt-remote check` command
+//!
+//! Re-queries the mirrors recorded in a cache image's `uri.toml` and reports
+//! whether any of its files have been superseded or removed upstream since
+//! the plan was generated by `apt-remote set`.
+//!
+//! Package images (Install/Upgrade mode) are checked with HTTP `HEAD`
+//! requests: a missing file means the pool no longer carries that version,
+//! and a changed `Content-Length` means the file was rebuilt. Source images
+//! (Update mode) additionally compare the `Date:` field of each
+//! `Release`/`InRelease` file against the copy downloaded by `apt-remote get`.
+
+use crate::{
+    apt_auth::{self, AuthFile},
+    config, flock, output, progress, theme, tls,
+    uri::{self, PackageEntry, RemoteMode, UriFile},
+};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use colored::Colorize;
+use rayon::prelude::*;
+use reqwest::{StatusCode, blocking::Client, header::CONTENT_LENGTH};
+use serde_json::{Value, json};
+
+use std::{fs, path::Path, time::Duration};
+
+/// CLI arguments for the `apt-remote check` subcommand.
+#[derive(Args)]
+pub struct CheckArgs {
+    /// Cache image name (required)
+    pub name: String,
+}
+
+/// Upstream state of a single file recorded in `uri.toml`.
+enum Freshness {
+    /// The file is still served as recorded.
+    Current,
+    /// The mirror no longer serves the file.
+    Removed,
+    /// The file is still served but its size differs from the recorded one.
+    Changed { expected: u64, actual: u64 },
+    /// A newer `Release` file has been published since the image was fetched.
+    Republished { cached: String, upstream: String },
+    /// The mirror could not be queried.
+    Unreachable(String),
+}
+
+impl Freshness {
+    /// Structured representation used for JSON output.
+    fn to_json(&self) -> Value {
+        match self {
+            Freshness::Current => json!({ "status": "current" }),
+            Freshness::Removed => json!({ "status": "removed" }),
+            Freshness::Changed { expected, actual } => {
+                json!({ "status": "changed", "expected_size": expected, "actual_size": actual })
+            }
+            Freshness::Republished { cached, upstream } => {
+                json!({ "status": "republished", "cached_date": cached, "upstream_date": upstream })
+            }
+            Freshness::Unreachable(e) => json!({ "status": "unreachable", "error": e }),
+        }
+    }
+}
+
+/// Executes the `check` subcommand.
+///
+/// Loads `uri.toml` for the given image, queries every recorded URI in
+/// parallel and prints a report of files that are no longer current.
+///
+/// # Errors
+/// Returns an error if `uri.toml` cannot be loaded or the HTTP client
+/// cannot be built. Per-file request failures are reported, not returned.
+pub fn run(args: CheckArgs) -> Result<()> {
+    let name = &args.name;
+
+    // Locate cache directory for the given image
+    let cache_dir = config::cache_dir()?.join(name);
+
+    // Hold the image for the rest of the command
+    let _lock = flock::lock_image(&cache_dir)?;
+
+    // Load metadata from uri.toml
+    let uri_file = UriFile::load(uri::path_in(&cache_dir))
+        .context("Failed to load uri.toml metadata")?;
+
+    // Downloaded sources are needed to compare Release dates
+    let sources_dir = cache_dir.join("sources");
+
+    // HTTP client for metadata queries (30-second timeout)
+    let client = tls::load()?
+        .blocking(Client::builder().timeout(Duration::from_secs(30)))
+        .build()
+        .context("Failed to build client")?;
+    let auth = apt_auth::for_image(&cache_dir)?;
+
+    // Set up progress spinner
+    let spinner = progress::reporter().spinner();
+        spinner.message(&format!("{}", theme::accent(format!("Checking {name} against mirrors...")).bold()));
+
+    // Query every recorded file in parallel
+    let mut results: Vec<(&String, Freshness)> = uri_file
+        .packages
+        .par_iter()
+        .map(|(fname, pkg)| {
+            let freshness = match uri_file.mode {
+                RemoteMode::Update if is_release_file(fname) => {
+                    check_release(&client, &auth, pkg, &sources_dir.join(fname))
+                }
+                RemoteMode::Update => check_exists(&client, &auth, pkg, false),
+                RemoteMode::Install | RemoteMode::Upgrade => check_exists(&client, &auth, pkg, true),
+            };
+            (fname, freshness)
+        })
+        .collect();
+    results.sort_by(|a, b| a.0.cmp(b.0));
+
+    spinner.clear();
+
+    // Report every file that is no longer current
+    let mut stale = 0;
+    for (fname, freshness) in &results {
+        let mut event = freshness.to_json();
+        event["file"] = Value::from(fname.as_str());
+        output::emit("check", event);
+
+        let line = match freshness {
+            Freshness::Current => continue,
+            Freshness::Removed => format!("{} {}", theme::failure("✗").bold(), theme::failure(format!("{fname}: removed upstream"))),
+            Freshness::Changed { expected, actual } => format!(
+                "{} {}",
+                theme::failure("✗").bold(),
+                theme::failure(format!("{fname}: size changed upstream ({expected} → {actual} bytes)"))
+            ),
+            Freshness::Republished { cached, upstream } => format!(
+                "{} {}",
+                theme::failure("✗").bold(),
+                theme::failure(format!("{fname}: republished upstream ({cached} → {upstream})"))
+            ),
+            Freshness::Unreachable(e) => format!(
+                "{} {}: {}",
+                theme::warning("?").bold(),
+                theme::warning(format!("{fname}: could not be checked")),
+                e.dimmed()
+            ),
+        };
+        if !matches!(freshness, Freshness::Unreachable(_)) {
+            stale += 1;
+        }
+        output::print(format!("\t{line}"));
+    }
+
+    if stale == 0 {
+        output::print(format!("{} {}", theme::success("✓").bold(), theme::success(format!("{name} is up to date"))));
+    } else {
+        output::print(format!(
+            "\n{} {}",
+            theme::failure("✗").bold(),
+            theme::failure(format!("{stale} of {} files in {name} are out of date: re-run 'apt-remote set'", results.len()))
+        ));
+    }
+    output::print("\n");
+    output::emit(
+        "result",
+        json!({ "command": "check", "name": name, "files": results.len(), "stale": stale }),
+    );
+
+    Ok(())
+}
+
+/// Returns `true` if the file name refers to a suite `Release` or `InRelease` file.
+fn is_release_file(fname: &str) -> bool {
+    fname.ends_with("_Release") || fname.ends_with("_InRelease")
+}
+
+/// Check that a recorded URI is still served, optionally comparing its size.
+fn check_exists(client: &Client, auth: &AuthFile, pkg: &PackageEntry, compare_size: bool) -> Freshness {
+    let response = match auth.authorize_blocking(client.head(&pkg.uri), &pkg.uri).send() {
+        Ok(response) => response,
+        Err(e) => return Freshness::Unreachable(e.to_string()),
+    };
+
+    match response.status() {
+        StatusCode::NOT_FOUND | StatusCode::GONE => Freshness::Removed,
+        status if !status.is_success() => Freshness::Unreachable(status.to_string()),
+        _ => {
+            // Some mirrors omit Content-Length on HEAD; treat that as current
+            let actual = response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            match actual {
+                Some(actual) if compare_size && pkg.size != 0 && actual != pkg.size => {
+                    Freshness::Changed { expected: pkg.size, actual }
+                }
+                _ => Freshness::Current,
+            }
+        }
+    }
+}
+
+/// Compare the `Date:` field of an upstream `Release` file with the cached copy.
+fn check_release(client: &Client, auth: &AuthFile, pkg: &PackageEntry, cached_path: &Path) -> Freshness {
+    // Without a cached copy only existence can be checked
+    let Some(cached) = fs::read_to_string(cached_path).ok().and_then(|s| release_date(&s)) else {
+        return check_exists(client, auth, pkg, false);
+    };
+
+    let response = match auth.authorize_blocking(client.get(&pkg.uri), &pkg.uri).send().and_then(|r| r.error_for_status()) {
+        Ok(response) => response,
+        Err(e) if matches!(e.status(), Some(StatusCode::NOT_FOUND | StatusCode::GONE)) => {
+            return Freshness::Removed;
+        }
+        Err(e) => return Freshness::Unreachable(e.to_string()),
+    };
+
+    match response.text().ok().and_then(|s| release_date(&s)) {
+        Some(upstream) if upstream != cached => Freshness::Republished { cached, upstream },
+        Some(_) => Freshness::Current,
+        None => Freshness::Unreachable("Release file has no Date field".to_string()),
+    }
+}
+
+/// Extract the value of the `Date:` field from a `Release` file.
+fn release_date(content: &str) -> Option<String> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("Date:"))
+        .map(|date| date.trim().to_string())
+}
@@ -1,15 +1,49 @@
+//! # `apt-remote clear` command
+//!
+//! Removes every image, the shared package pool, the local mirror and the
+//! state files kept alongside them (such as `daemon.toml`) from the
+//! apt-remote cache directory. What is known about targets (see
+//! [`config::STATE_DIR`]) is kept.
+
+use crate::{config, dry_run, flock, output};
+
 use anyhow::{Context, Result};
+use serde_json::json;
 use std::fs;
 
+/// Executes the `clear` subcommand.
+///
+/// # Errors
+/// Returns an error if the cache directory cannot be read or an entry
+/// cannot be removed. A cache directory that does not exist is empty.
 pub fn run() -> Result<()> {
-    let cache_dir = dirs::cache_dir()
-        .context("Failed to locate cache directory")?
-        .join("apt-remote");
+    let cache_dir = config::cache_dir()?;
+    if !cache_dir.exists() {
+        output::emit("result", json!({ "command": "clear", "path": cache_dir }));
+        return Ok(());
+    }
+
+    // Never remove an image another process is working on
+    let _locks = flock::lock_all_images()?;
 
-    // Remove each directory in ~/.cache/apt-remote
-    for entry in fs::read_dir(cache_dir)? {
-        fs::remove_dir_all(entry?.path())?;
+    // Remove each entry in ~/.cache/apt-remote
+    for entry in fs::read_dir(&cache_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name() == config::STATE_DIR {
+            continue;
+        }
+        if dry_run::skip("delete", path.display().to_string()) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .with_context(|| format!("Failed to remove {}", path.display()))?;
     }
 
+    output::emit("result", json!({ "command": "clear", "path": cache_dir }));
     Ok(())
 }
@@ -0,0 +1,217 @@
+//! # `apt-remote completions` command
+//!
+//! Prints a completion script for bash, zsh or fish. The script does not
+//! list the commands and flags itself: it asks `apt-remote` for the
+//! candidates each time, through the hidden `__complete` command (see
+//! [`complete`]), so it also completes what only exists at runtime:
+//!
+//! - the names of cached images, for the `<NAME>` of `get`, `install`,
+//!   `update` and the other commands on one image;
+//! - known targets for `--target`: the configured default, the hosts and
+//!   `@groups` of the inventory (see [`crate::inventory`]), and the targets
+//!   images were used on.
+//!
+//! ```bash
+//! apt-remote completions bash > /etc/bash_completion.d/apt-remote
+//! apt-remote completions zsh > "${fpath[1]}/_apt-remote"
+//! apt-remote completions fish > ~/.config/fish/completions/apt-remote.fish
+//! ```
+
+use crate::{config, inventory, state::ImageState, uri};
+
+use anyhow::Result;
+use clap::{Args, Command, ValueEnum};
+
+use std::{collections::BTreeSet, fs};
+
+/// Shells completion scripts are written for.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Shell {
+    /// GNU bash.
+    Bash,
+    /// The Z shell.
+    Zsh,
+    /// The friendly interactive shell.
+    Fish,
+}
+
+/// CLI arguments for the `apt-remote completions` subcommand.
+#[derive(Args)]
+#[command(override_usage = "apt-remote completions <SHELL>")]
+pub struct CompletionsArgs {
+    /// Shell to write the completion script for
+    #[arg(value_enum)]
+    pub shell: Shell,
+}
+
+/// CLI arguments for the hidden `apt-remote __complete` subcommand.
+#[derive(Args)]
+pub struct CompleteArgs {
+    /// The words of the command line up to the cursor, the last one being
+    /// the word completed (empty at the start of a new word)
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub words: Vec<String>,
+}
+
+/// Executes the `completions` subcommand: prints the script for `args.shell`.
+pub fn run(args: CompletionsArgs) -> Result<()> {
+    let script = match args.shell {
+        Shell::Bash => BASH,
+        Shell::Zsh => ZSH,
+        Shell::Fish => FISH,
+    };
+    print!("{script}");
+    Ok(())
+}
+
+/// Completion script for bash.
+const BASH: &str = r#"# bash completion for apt-remote
+_apt_remote() {
+    local IFS=$'\n'
+    COMPREPLY=($(apt-remote __complete -- "${COMP_WORDS[@]:0:COMP_CWORD+1}" 2>/dev/null))
+}
+complete -o default -F _apt_remote apt-remote
+"#;
+
+/// Completion script for zsh.
+const ZSH: &str = r#"#compdef apt-remote
+_apt_remote() {
+    local -a candidates
+    candidates=(${(f)"$(apt-remote __complete -- "${(@)words[1,CURRENT]}" 2>/dev/null)"})
+    if (( ${#candidates} )); then
+        compadd -a candidates
+    else
+        _files
+    fi
+}
+compdef _apt_remote apt-remote
+"#;
+
+/// Completion script for fish.
+const FISH: &str = r#"# fish completion for apt-remote
+complete -c apt-remote -f -a '(apt-remote __complete -- (commandline -opc) (commandline -ct) 2>/dev/null)'
+"#;
+
+/// Executes the hidden `__complete` subcommand: prints the candidates for
+/// the last of `args.words`, one per line, for the command line `cli`.
+///
+/// Candidates are subcommands before one is given, flags for a word
+/// starting with `-`, targets after `--target`, and images for the first
+/// positional argument of a subcommand that takes an image name. Nothing is
+/// printed where there is nothing to offer, and failures (such as an
+/// invalid inventory) are not reported, so the shell can fall back to file
+/// names.
+pub fn complete(cli: Command, args: CompleteArgs) -> Result<()> {
+    let mut words = args.words;
+    let current = words.pop().unwrap_or_default();
+    let candidates = candidates(&cli, words.get(1..).unwrap_or_default(), &current);
+    for candidate in candidates.into_iter().filter(|candidate| candidate.starts_with(&current)) {
+        println!("{candidate}");
+    }
+    Ok(())
+}
+
+/// What may follow `words` (the words after the program name) in `current`.
+fn candidates(cli: &Command, words: &[String], current: &str) -> Vec<String> {
+    let mut subcommand: Option<&Command> = None;
+    let mut positionals = 0;
+    let mut takes_value = false;
+    let mut after_target = false;
+    for word in words {
+        if takes_value {
+            takes_value = false;
+            continue;
+        }
+        if word.starts_with('-') && word.len() > 1 {
+            // A value given with `=` is part of the flag's word
+            if !word.contains('=') {
+                let flag = find_flag(cli, subcommand, word);
+                takes_value = flag.is_some_and(|arg| arg.get_action().takes_values());
+                after_target = takes_value && flag.is_some_and(|arg| arg.get_id() == "target");
+            }
+            continue;
+        }
+        match subcommand {
+            None => subcommand = cli.find_subcommand(word),
+            Some(_) => positionals += 1,
+        }
+    }
+
+    if takes_value {
+        return if after_target { targets() } else { Vec::new() };
+    }
+    if current.starts_with("--target=") {
+        return targets().into_iter().map(|target| format!("--target={target}")).collect();
+    }
+    if current.starts_with('-') {
+        return flags(cli, subcommand);
+    }
+    let Some(subcommand) = subcommand else {
+        return cli.get_subcommands().filter(|sub| !sub.is_hide_set()).map(|sub| sub.get_name().to_string()).collect();
+    };
+    match subcommand.get_positionals().nth(positionals) {
+        Some(arg) if arg.get_id() == "name" => images(),
+        _ => Vec::new(),
+    }
+}
+
+/// The flag `word` names, of `subcommand` or a global one of `cli`.
+fn find_flag<'a>(cli: &'a Command, subcommand: Option<&'a Command>, word: &str) -> Option<&'a clap::Arg> {
+    let matches = |arg: &&clap::Arg| match word.strip_prefix("--") {
+        Some(long) => arg.get_long() == Some(long),
+        None => word.chars().nth(1).is_some_and(|short| arg.get_short() == Some(short)),
+    };
+    subcommand
+        .and_then(|subcommand| subcommand.get_arguments().find(matches))
+        .or_else(|| cli.get_arguments().find(matches))
+}
+
+/// The long flags of `subcommand` and the global ones of `cli`.
+fn flags(cli: &Command, subcommand: Option<&Command>) -> Vec<String> {
+    let global = cli.get_arguments().filter(|arg| subcommand.is_none() || arg.is_global_set());
+    let own = subcommand.into_iter().flat_map(Command::get_arguments);
+    own.chain(global)
+        .filter(|arg| !arg.is_hide_set())
+        .filter_map(|arg| arg.get_long().map(|long| format!("--{long}")))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Names of the images in the cache.
+fn images() -> Vec<String> {
+    let Ok(entries) = config::cache_dir().and_then(|dir| Ok(fs::read_dir(dir)?)) else {
+        return Vec::new();
+    };
+    let mut names = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| uri::path_in(&entry.path()).is_file())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+/// Known targets: the configured default, the hosts and groups of the
+/// inventory, and the targets images were used on.
+fn targets() -> Vec<String> {
+    let mut targets = BTreeSet::new();
+    targets.extend(config::get().target.clone());
+    if let Ok(inventory) = inventory::get() {
+        if let Ok(all) = inventory.group("all") {
+            targets.extend(all);
+        }
+        if !inventory.hosts.is_empty() {
+            targets.insert("@all".to_string());
+        }
+        targets.extend(inventory.groups.keys().map(|group| format!("@{group}")));
+    }
+    if let Ok(cache_dir) = config::cache_dir() {
+        for image in images() {
+            if let Ok(state) = ImageState::load(&cache_dir.join(image)) {
+                targets.extend(state.targets.into_keys());
+            }
+        }
+    }
+    targets.into_iter().collect()
+}
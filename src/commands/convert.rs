@@ -0,0 +1,76 @@
+//! # `apt-remote convert` command
+//!
+//! Switches the metadata of a cache image between `uri.toml` and the
+//! compact `uri.json` that `set` writes for very large images (see
+//! [`crate::uri`]), for instance to read or edit a large plan as TOML.
+//! `set` picks the format by size again when it next records the image.
+
+use crate::{config, dry_run, flock, output, signing, theme, uri::{self, UriFile}};
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use colored::Colorize;
+use serde_json::json;
+
+/// Format of image metadata.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum MetadataFormat {
+    /// `uri.toml`.
+    Toml,
+    /// `uri.json`.
+    Json,
+}
+
+/// CLI arguments for the `apt-remote convert` subcommand.
+#[derive(Args)]
+pub struct ConvertArgs {
+    /// Cache image name (required)
+    pub name: String,
+
+    /// Format to convert the image's metadata to
+    #[arg(long, value_enum)]
+    pub to: MetadataFormat,
+}
+
+/// Executes the `convert` subcommand.
+///
+/// # Errors
+/// Returns an error if the image's metadata cannot be loaded or written, or
+/// if the image is signed, since the signature covers the metadata file.
+pub fn run(args: ConvertArgs) -> Result<()> {
+    let name = &args.name;
+
+    // Locate cache directory for the given image
+    let cache_dir = config::cache_dir()?.join(name);
+
+    // Hold the image for the rest of the command
+    let _lock = flock::lock_image(&cache_dir)?;
+
+    let uri_path = uri::path_in(&cache_dir);
+    let uri_file = UriFile::load(&uri_path).context("Failed to load uri.toml metadata")?;
+    if signing::verify_image(&cache_dir)?.is_some() {
+        anyhow::bail!("{name} is signed: converting it would break its signature");
+    }
+
+    let file_name = match args.to {
+        MetadataFormat::Toml => uri::TOML_FILE,
+        MetadataFormat::Json => uri::JSON_FILE,
+    };
+    let written = if uri_path.file_name().is_some_and(|f| f == file_name) {
+        uri_path
+    } else {
+        let written = uri_file.save_as(&cache_dir, file_name)?;
+        if dry_run::enabled() {
+            return Ok(());
+        }
+        written
+    };
+
+    output::print(format!(
+        "{} {}\n",
+        theme::success("✓").bold(),
+        theme::success(format!("{name} uses {file_name} ({} packages)", uri_file.packages.len()))
+    ));
+    output::emit("result", json!({ "command": "convert", "name": name, "path": written }));
+    Ok(())
+}
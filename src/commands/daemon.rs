@@ -0,0 +1,388 @@
+//! # `apt-remote daemon` command
+//!
+//! Keeps update-mode images fresh without anyone running `get`: every
+//! `interval` minutes, the package lists of each image in the `[daemon]`
+//! table of `config.toml` are downloaded again, and pushed with `update` to
+//! the image's targets whose lists are older:
+//!
+//! ```toml
+//! [daemon]
+//! interval = 360                  # minutes between rounds (default: 1440)
+//!
+//! [daemon.images.bookworm-lists]
+//! push = ["@kiosks", "admin@gateway"]
+//! ```
+//!
+//! With `--once`, a single round is run and the command exits, for a
+//! systemd timer or cron. The previous lists are kept until the new ones
+//! are complete, so a mirror that fails halfway never leaves an image
+//! with part of its lists.
+//!
+//! What each round did is recorded in `daemon.toml` in the cache directory:
+//! when every image was refreshed and its lists last changed, and which
+//! lists every target last received. Targets that cannot be reached (a
+//! laptop that is off, a kiosk out of range) are tried again in the next
+//! round, and targets that are up to date are left alone.
+//!
+//! ```toml
+//! [images.bookworm-lists]
+//! refreshed_at = 1760457600
+//! changed_at = 1760371200
+//! digest = "5d41402abc4b2a76…"
+//!
+//! [images.bookworm-lists.targets."admin@kiosk-1"]
+//! pushed_at = 1760457612
+//! digest = "5d41402abc4b2a76…"
+//!
+//! [images.bookworm-lists.targets."admin@kiosk-2"]
+//! digest = "9e107d9d372bb682…"
+//! error = "Failed to connect to kiosk-2:22"
+//! ```
+//!
+//! Pushing replaces the targets' lists without asking, so it needs
+//! `--yes` or `--batch`.
+
+use crate::{
+    archive,
+    commands::{
+        get::{self, GetArgs},
+        update::{self, UpdateArgs},
+    },
+    config::{self, DaemonConfig},
+    crypt, dry_run,
+    error::Error,
+    exit::{Classify, ExitCode},
+    flock, inventory, output, signing, state, theme,
+    uri::{self, RemoteMode, UriFile},
+};
+
+use anyhow::{Context, Result, anyhow};
+use clap::Args;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+/// CLI arguments for the `apt-remote daemon` subcommand.
+#[derive(Args)]
+pub struct DaemonArgs {
+    /// Run a single round and exit, for systemd timers and cron
+    #[arg(long)]
+    pub once: bool,
+
+    /// Minutes between rounds, instead of `interval` in the [daemon] table
+    #[arg(long, value_name = "MINUTES", conflicts_with = "once")]
+    pub interval: Option<u64>,
+}
+
+/// Representation of `daemon.toml`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DaemonState {
+    /// What was done with each image, keyed by name.
+    pub images: BTreeMap<String, ImageRecord>,
+}
+
+/// The last refresh of an image, and what its targets received.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageRecord {
+    /// Unix time of the last successful refresh.
+    pub refreshed_at: Option<u64>,
+    /// Unix time at which a refresh last brought different lists.
+    pub changed_at: Option<u64>,
+    /// Digest of the image's current lists.
+    pub digest: Option<String>,
+    /// Why the last refresh failed, if it did.
+    pub error: Option<String>,
+    /// Lists pushed to each target, keyed by `user@host`.
+    pub targets: BTreeMap<String, PushRecord>,
+}
+
+/// The last push of an image's lists to one target.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PushRecord {
+    /// Unix time of the last successful push.
+    pub pushed_at: Option<u64>,
+    /// Digest of the lists the target last received.
+    pub digest: Option<String>,
+    /// Why the last push failed, if it did.
+    pub error: Option<String>,
+}
+
+impl DaemonState {
+    /// Location of `daemon.toml` in the cache directory.
+    ///
+    /// # Errors
+    /// Returns an error if the cache directory cannot be determined.
+    pub fn path() -> Result<PathBuf> {
+        Ok(config::cache_dir()?.join("daemon.toml"))
+    }
+
+    /// Load the daemon state (empty if there is none).
+    ///
+    /// # Errors
+    /// Returns an error if an existing `daemon.toml` cannot be read or parsed.
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.is_file() {
+            return Ok(DaemonState::default());
+        }
+        let content = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Failed to parse TOML from {}", path.display()))
+    }
+
+    /// Save the daemon state.
+    ///
+    /// # Errors
+    /// Returns an error if serialization fails or the file cannot be written.
+    pub fn save(&self) -> Result<()> {
+        // Nothing was done in a dry run, so there is nothing to record
+        if dry_run::enabled() {
+            return Ok(());
+        }
+        let path = Self::path()?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        let toml_str = toml::to_string(self).context("Failed to serialize DaemonState to TOML")?;
+        fs::write(&path, toml_str).with_context(|| format!("Failed to write to {}", path.display()))
+    }
+}
+
+/// Executes the `daemon` subcommand.
+///
+/// Runs a round every `interval` minutes until interrupted; a failed
+/// refresh or push is logged and retried in the next round.
+///
+/// # Errors
+/// Returns an error if the `[daemon]` table lists no image, if pushes are
+/// configured without `--yes` or `--batch`, or if `daemon.toml` cannot be
+/// written. With `--once`, returns [`Error::DaemonFailed`] if anything in
+/// the round failed.
+pub fn run(args: DaemonArgs) -> Result<()> {
+    let config = config::get();
+    let daemon = &config.daemon;
+    if daemon.images.is_empty() {
+        return Err(anyhow!("No images to refresh: add them to the [daemon] table of config.toml"))
+            .classify(ExitCode::Config);
+    }
+
+    // Nobody is there to confirm that the targets' lists may be replaced
+    let pushes = daemon.images.values().any(|image| !image.push.is_empty());
+    if pushes && !(config.assume_yes || config.batch || dry_run::enabled()) {
+        return Err(Error::ConfirmationRequired.into());
+    }
+
+    let interval = args.interval.unwrap_or(daemon.interval).max(1);
+    loop {
+        let outcome = round(daemon);
+        if args.once {
+            return outcome;
+        }
+        if let Err(e) = outcome {
+            tracing::error!("{e:#}");
+        }
+        output::print(format!("Next round in {interval} min\n"));
+        thread::sleep(Duration::from_secs(interval * 60));
+    }
+}
+
+/// Refresh every image of the `[daemon]` table and push it to its targets.
+fn round(daemon: &DaemonConfig) -> Result<()> {
+    let mut state = DaemonState::load()?;
+    let (mut failed, mut total) = (0, 0);
+
+    for (name, image) in &daemon.images {
+        output::print(format!("{} {}", theme::accent("Refreshing").bold(), name.bold()));
+        let record = state.images.entry(name.clone()).or_default();
+
+        // Step 1: download the lists again
+        total += 1;
+        match refresh(name) {
+            Ok(digest) => {
+                let now = state::now();
+                let changed = record.digest.as_ref() != Some(&digest);
+                if changed {
+                    record.changed_at = Some(now);
+                }
+                record.refreshed_at = Some(now);
+                record.digest = Some(digest);
+                record.error = None;
+                let message = if changed { format!("Refreshed {name}") } else { format!("{name} is unchanged") };
+                output::print(format!("{} {}", theme::success("✓").bold(), theme::success(message)));
+                output::emit("daemon", json!({ "image": name, "step": "refresh", "status": "ok", "changed": changed }));
+            }
+            Err(e) => {
+                // Targets may still be behind the lists downloaded before
+                failed += 1;
+                record.error = Some(format!("{e:#}"));
+                output::print(format!(
+                    "{} {}:\n{}",
+                    theme::failure("✗").bold(),
+                    theme::failure(format!("Failed to refresh {name}")),
+                    format!("{e:#}").dimmed()
+                ));
+                output::emit("daemon", json!({ "image": name, "step": "refresh", "status": "failed", "error": format!("{e:#}") }));
+            }
+        }
+        state.save()?;
+
+        // Step 2: push the lists to the targets that do not have them
+        let Some(digest) = state.images[name].digest.clone() else {
+            continue;
+        };
+        for target in expand(&image.push, &mut failed, &mut total) {
+            let push = state.images.get_mut(name).unwrap().targets.entry(target.clone()).or_default();
+            if push.digest.as_ref() == Some(&digest) {
+                output::print(format!("{} {target} is up to date", theme::success("✓").bold()));
+                continue;
+            }
+
+            total += 1;
+            match update::run(UpdateArgs { name: name.clone(), target: Some(target.clone()) }) {
+                Ok(()) => {
+                    push.pushed_at = Some(state::now());
+                    push.digest = Some(digest.clone());
+                    push.error = None;
+                    output::emit("daemon", json!({ "image": name, "step": "push", "target": target, "status": "ok" }));
+                }
+                Err(e) => {
+                    failed += 1;
+                    push.error = Some(format!("{e:#}"));
+                    output::print(format!(
+                        "{} {}:\n{}",
+                        theme::failure("✗").bold(),
+                        theme::failure(format!("Failed to push {name} to {target}")),
+                        format!("{e:#}").dimmed()
+                    ));
+                    output::emit(
+                        "daemon",
+                        json!({ "image": name, "step": "push", "target": target, "status": "failed", "error": format!("{e:#}") }),
+                    );
+                }
+            }
+            state.save()?;
+        }
+        output::print("");
+    }
+
+    if failed > 0 {
+        return Err(Error::DaemonFailed { failed, total }.into());
+    }
+    Ok(())
+}
+
+/// The `user@host` targets of `push`, with `@group` entries expanded. A
+/// group that cannot be resolved counts as a failed step.
+fn expand(push: &[String], failed: &mut usize, total: &mut usize) -> Vec<String> {
+    let mut targets = Vec::new();
+    for entry in push {
+        let Some(group) = entry.strip_prefix('@') else {
+            targets.push(entry.clone());
+            continue;
+        };
+        match inventory::get().and_then(|inventory| inventory.group(group)) {
+            Ok(members) => targets.extend(members),
+            Err(e) => {
+                *failed += 1;
+                *total += 1;
+                output::print(format!("{} {}", theme::failure("✗").bold(), theme::failure(format!("{e:#}"))));
+            }
+        }
+    }
+    targets
+}
+
+/// Download the lists of the update-mode image `name` again, and return
+/// the digest of its lists.
+///
+/// The previous lists are moved aside while `get` runs, since it skips
+/// files that are already there, and are put back if a list they had
+/// could not be downloaded.
+fn refresh(name: &str) -> Result<String> {
+    let cache_dir = config::cache_dir()?.join(name);
+    let uri_file = UriFile::load(uri::path_in(&cache_dir))?;
+    if uri_file.mode != RemoteMode::Update {
+        return Err(anyhow!("{name} is not an update-mode image: record its lists with 'apt-remote set --update'"))
+            .classify(ExitCode::Config);
+    }
+    if dry_run::skip("refresh", name) {
+        return digest(&cache_dir, name);
+    }
+
+    let sources = cache_dir.join("sources");
+    let previous = cache_dir.join("sources.previous");
+    {
+        let _lock = flock::lock_image(&cache_dir)?;
+        if previous.exists() {
+            fs::remove_dir_all(&previous)?;
+        }
+        if sources.is_dir() {
+            fs::rename(&sources, &previous)?;
+        }
+    }
+
+    let fetched = get::run(GetArgs { name: name.to_string(), adopt: None, changelogs: false, advisories: false }).and_then(|()| {
+        // Lists that were there before must still be there
+        let missing = file_names(&previous)?.difference(&file_names(&sources)?).cloned().collect::<Vec<_>>();
+        if !missing.is_empty() {
+            anyhow::bail!("{} lists could not be downloaded: {}", missing.len(), missing.join(", "));
+        }
+        Ok(())
+    });
+
+    let _lock = flock::lock_image(&cache_dir)?;
+    if let Err(e) = fetched {
+        if previous.is_dir() {
+            if sources.exists() {
+                fs::remove_dir_all(&sources)?;
+            }
+            fs::rename(&previous, &sources)?;
+        }
+        return Err(e.context(format!("Kept the previous lists of {name}")));
+    }
+    if previous.exists() {
+        fs::remove_dir_all(&previous)?;
+    }
+
+    // The lists no longer match the image's signature
+    signing::forget(&cache_dir)?;
+    digest(&cache_dir, name)
+}
+
+/// Names of the files in `dir` (none if it does not exist).
+fn file_names(dir: &Path) -> Result<BTreeSet<String>> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(BTreeSet::new());
+    };
+    let mut names = BTreeSet::new();
+    for entry in entries {
+        names.insert(entry?.file_name().to_string_lossy().into_owned());
+    }
+    Ok(names)
+}
+
+/// Digest of the lists of image `name`: the SHA-256 of every list's name
+/// and content.
+fn digest(cache_dir: &Path, name: &str) -> Result<String> {
+    let sources = cache_dir.join("sources");
+    if !sources.is_dir() {
+        return Err(Error::ImageNotDownloaded { name: name.to_string() }.into());
+    }
+    let key = crypt::unlock(cache_dir, name)?;
+    let mut hasher = Sha256::new();
+    for fname in file_names(&sources)? {
+        let plain = crypt::Plaintext::of(key.as_ref(), &sources.join(&fname))?;
+        hasher.update(fname.as_bytes());
+        hasher.update(archive::sha256_file(plain.path())?.as_bytes());
+    }
+    Ok(archive::hex(&hasher.finalize()))
+}
@@ -0,0 +1,55 @@
+//! # `apt-remote decrypt` command
+//!
+//! Turns off encryption at rest for a cache image (see [`crate::crypt`]),
+//! decrypting its downloaded files in place.
+
+use crate::{config, crypt, dry_run, flock, output, theme};
+
+use anyhow::Result;
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+/// CLI arguments for the `apt-remote decrypt` subcommand.
+#[derive(Args)]
+pub struct DecryptArgs {
+    /// Cache image name (required)
+    pub name: String,
+}
+
+/// Executes the `decrypt` subcommand.
+///
+/// Images that are not encrypted are left as they are.
+///
+/// # Errors
+/// Returns an error if no passphrase is available or it is wrong, or if a
+/// file cannot be decrypted.
+pub fn run(args: DecryptArgs) -> Result<()> {
+    let name = &args.name;
+
+    // Locate cache directory for the given image
+    let cache_dir = config::cache_dir()?.join(name);
+
+    // Hold the image for the rest of the command
+    let _lock = flock::lock_image(&cache_dir)?;
+
+    if !crypt::is_encrypted(&cache_dir) {
+        output::print(format!("{name} is not encrypted"));
+        output::emit("result", json!({ "command": "decrypt", "name": name, "files": 0 }));
+        return Ok(());
+    }
+    if dry_run::skip("decrypt", cache_dir.display().to_string()) {
+        return Ok(());
+    }
+
+    let decrypted = crypt::decrypt_image(&cache_dir, name)?;
+
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Decrypted {name} ({decrypted} files)"))
+    ));
+    output::print("\n");
+    output::emit("result", json!({ "command": "decrypt", "name": name, "files": decrypted }));
+    Ok(())
+}
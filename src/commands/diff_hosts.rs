@@ -0,0 +1,165 @@
+//! # `apt-remote diff-hosts` command
+//!
+//! Compares the installed packages of two targets, such as a device lagging
+//! behind and a reference machine. The package selections of both are read
+//! over SSH (as `set` records them in `uri.lock`, see [`crate::lock`]), and
+//! every package whose version differs, or that only one of them has, is
+//! reported. The first target is the reference: the packages the second
+//! lacks or has older versions of are printed as the `apt-remote set`
+//! command of a catch-up image bringing it in line.
+
+use crate::{
+    exit::{Classify, ExitCode},
+    lock::{self, Selection},
+    output,
+    resolve::compare_versions,
+    theme, transport,
+};
+
+use anyhow::{Result, anyhow};
+use clap::Args;
+use colored::Colorize;
+use serde::Serialize;
+use serde_json::json;
+
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// CLI arguments for the `apt-remote diff-hosts` subcommand.
+///
+/// Example:
+/// ```bash
+/// apt-remote diff-hosts --target admin@reference --target admin@kiosk-07
+/// ```
+#[derive(Args)]
+pub struct DiffHostsArgs {
+    /// The reference target, then the target compared with it (user@host, given twice)
+    #[arg(short, long = "target", value_name = "user@host", required = true)]
+    pub targets: Vec<String>,
+}
+
+/// A package whose version differs between the two targets.
+#[derive(Debug, Serialize)]
+pub struct Drift {
+    /// Package name, with the architecture for multi-arch packages.
+    pub package: String,
+    /// Version on the reference target, if installed there.
+    pub reference: Option<String>,
+    /// Version on the compared target, if installed there.
+    pub compared: Option<String>,
+}
+
+/// Executes the `diff-hosts` subcommand.
+///
+/// # Errors
+/// Returns an error unless exactly two targets are given, or if either
+/// cannot be queried.
+pub fn run(args: DiffHostsArgs) -> Result<()> {
+    let [reference, compared] = &args.targets[..] else {
+        return Err(anyhow!("diff-hosts compares two targets: give --target twice")).classify(ExitCode::Usage);
+    };
+    if reference.starts_with('@') || compared.starts_with('@') {
+        return Err(anyhow!("diff-hosts compares two hosts, not groups")).classify(ExitCode::Usage);
+    }
+    let wanted = installed(reference)?;
+    let actual = installed(compared)?;
+
+    // Every package installed on either target, in name order
+    let mut packages = wanted.keys().chain(actual.keys()).collect::<Vec<_>>();
+    packages.sort();
+    packages.dedup();
+    let (mut behind, mut ahead, mut missing, mut extra) = (vec![], vec![], vec![], vec![]);
+    for package in packages {
+        let drift = Drift {
+            package: package.clone(),
+            reference: wanted.get(package).cloned(),
+            compared: actual.get(package).cloned(),
+        };
+        match (&drift.reference, &drift.compared) {
+            (Some(reference), Some(compared)) => match compare_versions(compared, reference) {
+                Ordering::Less => behind.push(drift),
+                Ordering::Greater => ahead.push(drift),
+                Ordering::Equal => {}
+            },
+            (Some(_), None) => missing.push(drift),
+            (None, Some(_)) => extra.push(drift),
+            (None, None) => {}
+        }
+    }
+
+    output::print(format!(
+        "{} compared with {}: {} behind, {} ahead, {} missing, {} only on {compared}\n",
+        compared.bold(),
+        reference.bold(),
+        behind.len(),
+        ahead.len(),
+        missing.len(),
+        extra.len()
+    ));
+    let version = |version: &Option<String>| version.clone().unwrap_or_default();
+    for drift in &behind {
+        output::print(format!(
+            "  {} {} {} → {}",
+            theme::warning("↓"),
+            drift.package.bold(),
+            version(&drift.compared),
+            version(&drift.reference)
+        ));
+    }
+    for drift in &ahead {
+        output::print(format!(
+            "  {} {} {} (reference has {})",
+            theme::accent("↑"),
+            drift.package.bold(),
+            version(&drift.compared),
+            version(&drift.reference)
+        ));
+    }
+    for drift in &missing {
+        output::print(format!("  {} {} {}", theme::failure("+"), drift.package.bold(), version(&drift.reference)));
+    }
+    for drift in &extra {
+        output::print(format!("  {} {} {}", "-".dimmed(), drift.package.bold(), version(&drift.compared)));
+    }
+
+    // The catch-up image installs the reference's versions of what lags or lacks
+    let catch_up = behind
+        .iter()
+        .chain(&missing)
+        .map(|drift| format!("{}={}", drift.package, version(&drift.reference)))
+        .collect::<Vec<_>>();
+    if catch_up.is_empty() {
+        output::print(format!("\n{} {}", theme::success("✓").bold(), theme::success(format!("{compared} is in line with {reference}"))));
+    } else {
+        output::print(format!("\nTo bring {compared} in line with {reference}:\n"));
+        output::print(format!("  apt-remote set <NAME> --target {compared} --install {}", catch_up.join(" ")));
+    }
+
+    output::emit(
+        "result",
+        json!({
+            "command": "diff-hosts",
+            "reference": reference,
+            "target": compared,
+            "behind": behind,
+            "ahead": ahead,
+            "missing": missing,
+            "extra": extra,
+            "catch_up": catch_up,
+        }),
+    );
+    Ok(())
+}
+
+/// The installed packages of `target` and their versions.
+fn installed(target: &str) -> Result<BTreeMap<String, String>> {
+    let session = transport::connect(target)?;
+    let output = session.query(lock::SELECTIONS_QUERY)?;
+    let selections = lock::parse_selections(&output);
+    if selections.is_empty() {
+        return Err(anyhow!("{target} lists no installed packages: is dpkg-query available?")).classify(ExitCode::Remote);
+    }
+    Ok(selections
+        .into_iter()
+        .filter_map(|(package, Selection { version, .. })| Some((package, version?)))
+        .collect())
+}
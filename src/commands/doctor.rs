@@ -0,0 +1,111 @@
+//! # `apt-remote doctor` command
+//!
+//! Probes a target again (see [`crate::probe`]) and reports whether it has
+//! what apt-remote needs: APT and `dpkg`, a SHA-256 tool to verify uploads,
+//! `sudo` unless packages are staged with `--rootless`, free space, and on
+//! derivative distributions the keyring of the vendor's repositories (see
+//! [`crate::derivative`]). The new probe replaces the cached one that other
+//! commands use.
+
+use crate::{
+    commands::set::format_size,
+    inventory, output,
+    probe::{self, Probe},
+    staging, theme, transport,
+    uri::ChecksumKind,
+};
+
+use anyhow::Result;
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+/// CLI arguments for the `apt-remote doctor` subcommand.
+#[derive(Args)]
+pub struct DoctorArgs {
+    /// Remote target SSH (user@host or @group), defaults to `target` in config.toml
+    #[arg(short, long)]
+    pub target: Option<String>,
+}
+
+/// Executes the `doctor` subcommand.
+///
+/// With an `@group` target, every member of the inventory group is probed
+/// in turn (see [`crate::inventory`]).
+///
+/// # Errors
+/// Returns an error if a target cannot be probed, or lacks something
+/// apt-remote needs.
+pub fn run(args: DoctorArgs) -> Result<()> {
+    inventory::for_each_target(args.target.as_ref(), doctor)
+}
+
+/// Probe and report a single `user@host` target.
+fn doctor(target: &str) -> Result<()> {
+    let session = transport::connect(target)?;
+    let probe = probe::refresh(&*session, target)?;
+
+    output::print(format!("{} ({})", target.bold(), describe(&probe)));
+    let tools = probe.checksum_tools();
+    let mut checks = vec![
+        (!probe.apt_version.is_empty(), format!("APT {}", probe.apt_version), "apt-get not found".to_string()),
+        (!probe.dpkg_version.is_empty(), format!("dpkg {}", probe.dpkg_version), "dpkg not found".to_string()),
+        (
+            tools.command(&ChecksumKind::SHA256).is_some(),
+            format!("SHA-256 checksums with {}", tools.command(&ChecksumKind::SHA256).unwrap_or_default()),
+            "no SHA-256 tool: uploads cannot be verified".to_string(),
+        ),
+        (
+            probe.has("sudo") || probe.has("doas") || staging::enabled() || !session.needs_sudo(),
+            match (probe.has("sudo"), probe.has("doas"), session.needs_sudo()) {
+                (true, _, _) => "sudo".to_string(),
+                (false, true, _) => "doas".to_string(),
+                (false, false, false) => "no sudo needed on this target".to_string(),
+                (false, false, true) => "no sudo needed with --rootless".to_string(),
+            },
+            "neither sudo nor doas found: stage the packages with --rootless, or pass --escalation su".to_string(),
+        ),
+    ];
+    if let Some(derivative) = probe.derivative
+        && let Some(keyring) = derivative.keyring()
+    {
+        checks.push((
+            probe.missing_keyring().is_none(),
+            format!("{derivative} keyring ({keyring})"),
+            format!("{keyring} is not installed: APT ignores the lists of {derivative}'s repositories"),
+        ));
+    }
+    let mut problems = 0;
+    for (ok, found, missing) in &checks {
+        if *ok {
+            output::print(format!("\t{} {found}", theme::success("✓").bold()));
+        } else {
+            problems += 1;
+            output::print(format!("\t{} {}", theme::failure("✗").bold(), theme::failure(missing)));
+        }
+    }
+    output::print(format!("\tArchitectures: {}", probe.apt.architectures.join(", ")));
+    output::print(format!("\tPackage lists: {}", probe.apt.lists));
+    output::print(format!("\tPackage cache: {}", probe.apt.archives));
+    for (path, kib) in &probe.free_kib {
+        output::print(format!("\tFree in {path}: {}", format_size(kib * 1024)));
+    }
+    output::print("");
+
+    output::emit("probe", json!({ "target": target, "probe": probe, "problems": problems }));
+    if problems > 0 {
+        anyhow::bail!("{target} failed {problems} of {} checks", checks.len());
+    }
+    Ok(())
+}
+
+/// A one-line summary of the target's system, such as
+/// `bookworm, systemd, admin` or `Raspberry Pi OS bookworm, systemd, pi`.
+pub fn describe(probe: &Probe) -> String {
+    let codename = if probe.codename.is_empty() { "unknown release" } else { &probe.codename };
+    let init = if probe.init.is_empty() { "unknown init" } else { &probe.init };
+    match probe.derivative {
+        Some(derivative) => format!("{derivative} {codename}, {init}, {}", probe.user),
+        None => format!("{codename}, {init}, {}", probe.user),
+    }
+}
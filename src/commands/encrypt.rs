@@ -0,0 +1,56 @@
+//! # `apt-remote encrypt` command
+//!
+//! Turns on encryption at rest for a cache image (see [`crate::crypt`]):
+//! asks for a new passphrase, then encrypts the files already downloaded.
+//! Files fetched by later runs of `get` are encrypted as they arrive. Run
+//! `apt-remote gc` afterwards to drop the plaintext copies kept in the
+//! shared pool.
+
+use crate::{config, crypt, dry_run, flock, output, theme, uri::{self, UriFile}};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+/// CLI arguments for the `apt-remote encrypt` subcommand.
+#[derive(Args)]
+pub struct EncryptArgs {
+    /// Cache image name (required)
+    pub name: String,
+}
+
+/// Executes the `encrypt` subcommand.
+///
+/// An image that is already encrypted is unlocked with its passphrase, and
+/// only files left unencrypted (by an interrupted run) are encrypted.
+///
+/// # Errors
+/// Returns an error if the image has no `uri.toml`, if no passphrase is
+/// available or it is wrong, or if a file cannot be encrypted.
+pub fn run(args: EncryptArgs) -> Result<()> {
+    let name = &args.name;
+
+    // Locate cache directory for the given image
+    let cache_dir = config::cache_dir()?.join(name);
+
+    // Hold the image for the rest of the command
+    let _lock = flock::lock_image(&cache_dir)?;
+
+    // Only images recorded with `set` can be encrypted
+    UriFile::load(uri::path_in(&cache_dir)).context("Failed to load uri.toml metadata")?;
+    if dry_run::skip("encrypt", cache_dir.display().to_string()) {
+        return Ok(());
+    }
+
+    let (_, encrypted) = crypt::encrypt_image(&cache_dir, name)?;
+
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Encrypted {name} ({encrypted} files)"))
+    ));
+    output::print("\tRun 'apt-remote gc' to remove plaintext copies from the package pool\n");
+    output::emit("result", json!({ "command": "encrypt", "name": name, "files": encrypted }));
+    Ok(())
+}
@@ -0,0 +1,551 @@
+//! # `apt-remote export` command
+//!
+//! Copies a cached image (its `uri.toml`, `uri.lock` and downloaded `debs/`
+//! or `sources/`) to a destination directory such as a mounted USB stick.
+//!
+//! Files of encrypted images (see [`crate::crypt`]) are decrypted into the
+//! bundle, so it can be used on the target without apt-remote.
+//!
+//! With a signing key (`signing_key` in `config.toml`, or `--sign <KEY>`),
+//! the bundle also gets a signed `manifest.toml` that `apt-remote import`
+//! checks on the offline side (see [`crate::signing`]).
+//!
+//! With `--installer`, a POSIX shell script is generated alongside the
+//! bundle. Running it as root on the offline target verifies checksums and
+//! installs the packages with `dpkg`, or places the package lists under
+//! `/var/lib/apt/lists`, without requiring SSH access from a machine
+//! running apt-remote. It can be run straight from the USB stick, and
+//! writes a report of each run back to it for the courier to return.
+//!
+//! With `--format apt-offline`, the downloaded files are written as a
+//! bundle for `apt-offline install` instead, and with `--format
+//! apt-offline-sig` the plan is written as a signature for `apt-offline get`
+//! (see [`crate::apt_offline`]).
+
+use crate::{
+    advisory, apt_offline, archive, config, crypt, dry_run,
+    error::Error,
+    flock, lock, output, preseed, signing,
+    ssh::shell_quote,
+    theme,
+    uri::{self, ChecksumKind, RemoteMode, UriFile},
+    zip,
+};
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use colored::Colorize;
+use serde_json::json;
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// File name of the generated installer script inside the bundle.
+pub(crate) const INSTALLER_NAME: &str = "install.sh";
+
+/// What `apt-remote export` writes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    /// A bundle directory for `apt-remote import`.
+    #[default]
+    AptRemote,
+    /// A ZIP bundle for `apt-offline install`.
+    AptOffline,
+    /// The plan as a signature for `apt-offline get`.
+    AptOfflineSig,
+}
+
+/// CLI arguments for the `apt-remote export` subcommand.
+#[derive(Args)]
+#[command(override_usage = "apt-remote export <NAME> <DEST> [--installer] [--sign <KEY>]\n       \
+                            apt-remote export <NAME> <DEST> --format <apt-offline|apt-offline-sig>")]
+pub struct ExportArgs {
+    /// Cache image name (required)
+    pub name: String,
+
+    /// Destination directory (the bundle is written to <DEST>/<NAME>)
+    pub dest: PathBuf,
+
+    /// Include a self-contained POSIX shell installer in the bundle
+    #[arg(long)]
+    pub installer: bool,
+
+    /// Sign the bundle with this private key, defaults to `signing_key` in config.toml
+    #[arg(long, value_name = "KEY")]
+    pub sign: Option<PathBuf>,
+
+    /// What to write: an apt-remote bundle, an apt-offline bundle (<DEST>/<NAME>.zip) or signature (<DEST>/<NAME>.sig)
+    #[arg(long, value_enum, default_value = "apt-remote", conflicts_with_all = ["installer", "sign"])]
+    pub format: ExportFormat,
+}
+
+/// Executes the `export` subcommand.
+///
+/// Copies the image from the local cache into `<DEST>/<NAME>` and, if
+/// requested, writes an `install.sh` that applies it on the target. The
+/// bundle is signed last when a signing key is configured.
+///
+/// # Errors
+/// Returns an error if the image has not been downloaded, or if copying
+/// the bundle or writing the installer fails.
+pub fn run(args: ExportArgs) -> Result<()> {
+    let name = &args.name;
+
+    // Locate cache directory for the given image
+    let cache_dir = config::cache_dir()?.join(name);
+
+    // Hold the image for the rest of the command
+    let _lock = flock::lock_image(&cache_dir)?;
+
+    // Load metadata from uri.toml
+    let uri_path = uri::path_in(&cache_dir);
+    let uri_file = UriFile::load(&uri_path)
+        .context("Failed to load uri.toml metadata")?;
+
+    // A signature only needs the plan
+    if args.format == ExportFormat::AptOfflineSig {
+        return export_signature(name, &args.dest, &uri_file);
+    }
+
+    // Only downloaded images can be exported
+    let data_dir = match uri_file.mode {
+        RemoteMode::Install | RemoteMode::Upgrade => "debs",
+        RemoteMode::Update => "sources",
+    };
+    if !cache_dir.join(data_dir).is_dir() {
+        return Err(Error::ImageNotDownloaded { name: name.clone() }.into());
+    }
+
+    if args.format == ExportFormat::AptOffline {
+        return export_apt_offline(name, &cache_dir, &args.dest, &uri_file, data_dir);
+    }
+
+    // Copy uri.toml, uri.lock and the data directory into the bundle
+    let bundle_dir = args.dest.join(name);
+    if dry_run::skip("export", format!("{} -> {}", cache_dir.display(), bundle_dir.display())) {
+        output::emit("result", json!({ "command": "export", "name": name, "path": bundle_dir, "dry_run": true }));
+        return Ok(());
+    }
+    let key = crypt::unlock(&cache_dir, name)?;
+    fs::create_dir_all(&bundle_dir)
+        .with_context(|| format!("Failed to create {}", bundle_dir.display()))?;
+    signing::forget(&bundle_dir)?;
+    for stale in [uri::TOML_FILE, uri::JSON_FILE].map(|f| bundle_dir.join(f)).iter().filter(|f| f.is_file()) {
+        fs::remove_file(stale)?;
+    }
+    fs::copy(&uri_path, bundle_dir.join(uri_path.file_name().unwrap()))?;
+    for file in ["uri.lock", advisory::FILE_NAME, preseed::FILE_NAME] {
+        if cache_dir.join(file).is_file() {
+            fs::copy(cache_dir.join(file), bundle_dir.join(file))?;
+        }
+    }
+    copy_dir(&cache_dir.join(data_dir), &bundle_dir.join(data_dir), key.as_ref())?;
+
+    // Optionally generate the target-side installer
+    if args.installer {
+        let script_path = bundle_dir.join(INSTALLER_NAME);
+        let lists = match uri_file.mode {
+            RemoteMode::Update => list_digests(&bundle_dir.join(data_dir), None)?,
+            RemoteMode::Install | RemoteMode::Upgrade => BTreeMap::new(),
+        };
+        fs::write(&script_path, installer_script(name, &uri_file, &lists))
+            .with_context(|| format!("Failed to write {}", script_path.display()))?;
+        set_executable(&script_path)?;
+    }
+
+    // Sign the finished bundle, so its files cannot change unnoticed
+    let signing_key = args.sign.clone().or_else(|| config::get().signing_key.clone());
+    let signed_by = match &signing_key {
+        Some(key) => Some(signing::sign_bundle(&bundle_dir, name, key)?),
+        None => None,
+    };
+
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Exported {name} to {}", bundle_dir.display()))
+    ));
+    if let Some(key) = &signed_by {
+        output::print(format!("\tSigned with key {key}"));
+    }
+    if args.installer {
+        output::print(format!(
+            "\tRun 'sh {}' as root on the target (with --check to only verify the bundle)",
+            bundle_dir.join(INSTALLER_NAME).display()
+        ));
+    }
+    output::print("\n");
+    output::emit(
+        "result",
+        json!({
+            "command": "export",
+            "name": name,
+            "path": bundle_dir,
+            "installer": args.installer.then(|| bundle_dir.join(INSTALLER_NAME)),
+            "signed_by": signed_by,
+        }),
+    );
+
+    Ok(())
+}
+
+/// Write the plan of `name` as `<DEST>/<NAME>.sig`.
+///
+/// # Errors
+/// Returns an error if the signature cannot be written.
+fn export_signature(name: &str, dest: &Path, uri_file: &UriFile) -> Result<()> {
+    let path = dest.join(format!("{name}.sig"));
+    if dry_run::skip("export", format!("{name} -> {}", path.display())) {
+        output::emit("result", json!({ "command": "export", "name": name, "path": path, "dry_run": true }));
+        return Ok(());
+    }
+    fs::create_dir_all(dest).with_context(|| format!("Failed to create {}", dest.display()))?;
+    fs::write(&path, apt_offline::signature(uri_file)).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Exported the plan of {name} to {}", path.display()))
+    ));
+    output::print(format!("\tFetch it with 'apt-offline get {}'", path.display()));
+    output::print("\n");
+    output::emit("result", json!({ "command": "export", "name": name, "path": path, "format": "apt-offline-sig" }));
+    Ok(())
+}
+
+/// Write the downloaded files of `name` as `<DEST>/<NAME>.zip`, decrypted
+/// if the image is encrypted. Lists are stored under the names APT gives
+/// them, uncompressed as `get` leaves them.
+///
+/// # Errors
+/// Returns an error if a file is missing or cannot be decrypted, or the
+/// bundle cannot be written.
+fn export_apt_offline(name: &str, cache_dir: &Path, dest: &Path, uri_file: &UriFile, data_dir: &str) -> Result<()> {
+    let path = dest.join(format!("{name}.zip"));
+    if dry_run::skip("export", format!("{} -> {}", cache_dir.display(), path.display())) {
+        output::emit("result", json!({ "command": "export", "name": name, "path": path, "dry_run": true }));
+        return Ok(());
+    }
+    let key = crypt::unlock(cache_dir, name)?;
+
+    // Files are added in plan order, under their file name
+    let files = match uri_file.mode {
+        RemoteMode::Install | RemoteMode::Upgrade => uri_file.install_order.clone(),
+        RemoteMode::Update => {
+            let mut lists = uri_file
+                .packages
+                .keys()
+                .map(|fname| fname.strip_suffix(".xz").unwrap_or(fname).to_string())
+                .collect::<Vec<_>>();
+            lists.sort();
+            lists
+        }
+    };
+    let mut plaintexts = Vec::with_capacity(files.len());
+    for fname in &files {
+        let file = cache_dir.join(data_dir).join(fname);
+        if !file.is_file() {
+            return Err(Error::ImageNotDownloaded { name: name.to_string() }.into());
+        }
+        plaintexts.push(crypt::Plaintext::of(key.as_ref(), &file)?);
+    }
+    let entries = files
+        .iter()
+        .zip(&plaintexts)
+        .map(|(fname, plain)| (fname.clone(), plain.path().to_path_buf()))
+        .collect::<Vec<_>>();
+    fs::create_dir_all(dest).with_context(|| format!("Failed to create {}", dest.display()))?;
+    zip::write(&path, &entries)?;
+
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Exported {name} to {} ({} files)", path.display(), entries.len()))
+    ));
+    output::print(format!("\tInstall it with 'apt-offline install {}'", path.display()));
+    output::print("\n");
+    output::emit(
+        "result",
+        json!({ "command": "export", "name": name, "path": path, "format": "apt-offline", "files": entries.len() }),
+    );
+    Ok(())
+}
+
+/// Recursively copy the contents of `src` into `dest`, decrypting files
+/// with `key` if the image is encrypted.
+fn copy_dir(src: &Path, dest: &Path, key: Option<&crypt::Key>) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src).with_context(|| format!("Failed to read {}", src.display()))? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target, key)?;
+        } else {
+            let plain = crypt::Plaintext::of(key, &entry.path())?;
+            fs::copy(plain.path(), &target)
+                .with_context(|| format!("Failed to copy {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// The SHA256 digest of every package list in `sources_dir`, by file name,
+/// for the installer to check. Lists of encrypted images are decrypted with
+/// `key` first.
+///
+/// # Errors
+/// Returns an error if a list cannot be read.
+pub(crate) fn list_digests(sources_dir: &Path, key: Option<&crypt::Key>) -> Result<BTreeMap<String, String>> {
+    let mut digests = BTreeMap::new();
+    for entry in fs::read_dir(sources_dir).with_context(|| format!("Failed to read {}", sources_dir.display()))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let plain = crypt::Plaintext::of(key, &entry.path())?;
+        digests.insert(entry.file_name().to_string_lossy().into_owned(), archive::sha256_file(plain.path())?);
+    }
+    Ok(digests)
+}
+
+/// Mark the installer as executable where the platform supports it.
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Shell function of the installer verifying a file: `check <sha256|md5>
+/// <digest> <file>`. It uses the first tool available, as minimal systems
+/// may lack coreutils (see [`crate::checksum`]).
+const CHECK_FUNCTION: &str = r#"
+check() {
+    if command -v "${1}sum" >/dev/null 2>&1; then
+        set -- "$(${1}sum "$3")" "$2" "$3"
+    elif command -v busybox >/dev/null 2>&1; then
+        set -- "$(busybox "${1}sum" "$3")" "$2" "$3"
+    elif [ "$1" = sha256 ] && command -v shasum >/dev/null 2>&1; then
+        set -- "$(shasum -a 256 "$3")" "$2" "$3"
+    elif command -v openssl >/dev/null 2>&1; then
+        set -- "$(openssl dgst "-$1" -r "$3")" "$2" "$3"
+    else
+        echo "install.sh: no tool to compute $1 checksums" >&2
+        exit 1
+    fi
+    if [ "${1%% *}" != "$2" ]; then
+        echo "install.sh: checksum mismatch: $3" >&2
+        exit 1
+    fi
+}
+
+"#;
+
+/// `text` for a comment of the installer: control characters, which could
+/// end the comment and start a command, are replaced with `?`.
+fn comment_text(text: &str) -> String {
+    text.chars().map(|c| if c.is_control() { '?' } else { c }).collect()
+}
+
+/// Generate the POSIX shell installer for an image.
+///
+/// Package images check that the target's architecture matches, verify
+/// every recorded checksum, install the packages in `install_order` with
+/// `dpkg -i` (skipping those already installed at the bundled version),
+/// after loading the bundle's debconf preseed if it has one, run
+/// `dpkg --configure -a`, and copy the `.deb` files into APT's archives
+/// directory. Source images replace the lists directory (keeping a
+/// `lists.old` backup) and regenerate the APT cache, mirroring `apt-remote
+/// update`; the lists are checked against `lists`, their SHA256 digests by
+/// file name recorded on export, and only those are copied. Both
+/// directories are read from `apt-config` on the machine running the
+/// installer.
+///
+/// With `--check`, the installer only verifies the bundle and needs no
+/// root. Every run writes a report (the host, the outcome, and the state of
+/// each package) to `reports/` next to the script, or to `--report <DIR>`,
+/// when that directory is writable, so a courier carries it back.
+pub(crate) fn installer_script(name: &str, uri_file: &UriFile, lists: &BTreeMap<String, String>) -> String {
+    let mut script = format!(
+        r#"#!/bin/sh
+# Installer for apt-remote image '{name}' ({arch}).
+# Generated by apt-remote {version}; run as root from any directory, such
+# as the USB stick the bundle was carried on:
+#
+#   sh install.sh [--check] [--report <DIR>]
+#
+# --check only verifies the bundle. A report of the run is written to <DIR>
+# (reports/ next to this script by default) if it is writable.
+set -eu
+
+BUNDLE_DIR=$(CDPATH= cd -- "$(dirname -- "$0")" && pwd)
+CHECK_ONLY=no
+REPORT_DIR=$BUNDLE_DIR/reports
+while [ $# -gt 0 ]; do
+    case $1 in
+        --check) CHECK_ONLY=yes ;;
+        --report) REPORT_DIR=${{2:?--report needs a directory}}; shift ;;
+        *) echo "usage: sh install.sh [--check] [--report <DIR>]" >&2; exit 2 ;;
+    esac
+    shift
+done
+
+if [ "$CHECK_ONLY" = no ] && [ "$(id -u)" -ne 0 ]; then
+    echo "install.sh: must be run as root (or with --check)" >&2
+    exit 1
+fi
+
+# Report the run, unless the report directory is read-only
+HOST=$(hostname 2>/dev/null || uname -n)
+REPORT=/dev/null
+if mkdir -p "$REPORT_DIR" 2>/dev/null && [ -w "$REPORT_DIR" ]; then
+    REPORT=$REPORT_DIR/$HOST-$(date +%Y%m%dT%H%M%S).txt
+fi
+report() {{
+    printf '%s\n' "$@" >> "$REPORT"
+}}
+report {image} "host=$HOST" "date=$(date -u +%Y-%m-%dT%H:%M:%SZ)"
+RESULT=failed
+trap 'report "result=$RESULT"' EXIT
+
+# APT's directories, which containers and unusual images may move
+LISTS=/var/lib/apt/lists/ ARCHIVES=/var/cache/apt/archives/
+eval "$(apt-config shell LISTS Dir::State::lists/d ARCHIVES Dir::Cache::archives/d 2>/dev/null)" || true
+LISTS=${{LISTS%/}} ARCHIVES=${{ARCHIVES%/}}
+"#,
+        name = comment_text(name),
+        arch = comment_text(&uri_file.arch),
+        version = env!("CARGO_PKG_VERSION"),
+        image = shell_quote(&format!("image={name}")),
+    );
+
+    match uri_file.mode {
+        RemoteMode::Install | RemoteMode::Upgrade => {
+            if !matches!(uri_file.arch.as_str(), "" | "all") {
+                let arch = shell_quote(&uri_file.arch);
+                script.push_str(&format!(
+                    "\nARCH=$(dpkg --print-architecture)\n\
+                     if [ \"$ARCH\" != {arch} ] && ! dpkg --print-foreign-architectures | grep -qx {arch}; then\n    \
+                         echo \"install.sh: this bundle is for \"{arch}\", not $ARCH\" >&2\n    \
+                         exit 1\n\
+                     fi\n"
+                ));
+            }
+            script.push_str(CHECK_FUNCTION);
+            script.push_str("cd \"$BUNDLE_DIR/debs\"\n\necho \"Verifying checksums...\"\n");
+            for fname in &uri_file.install_order {
+                let Some(checksum) = uri_file.packages.get(fname).and_then(|p| p.checksum.as_ref()) else {
+                    continue;
+                };
+                let algorithm = match checksum.kind {
+                    ChecksumKind::SHA256 => "sha256",
+                    ChecksumKind::MD5 => "md5",
+                };
+                script.push_str(&format!("check {algorithm} {} {}\n", checksum.value, shell_quote(fname)));
+            }
+            script.push_str(&format!(
+                "report verified={count}\n\
+                 if [ \"$CHECK_ONLY\" = yes ]; then\n    \
+                     echo \"The {count} packages are intact\"\n    \
+                     RESULT=checked\n    \
+                     exit 0\n\
+                 fi\n",
+                count = uri_file.install_order.len(),
+            ));
+
+            // Packages are identified as `name:arch` to tell Multi-Arch copies apart
+            let packages = uri_file
+                .install_order
+                .iter()
+                .filter_map(|fname| {
+                    let (package, version) = lock::parse_deb_name(fname)?;
+                    let package = match fname.strip_suffix(".deb")?.rsplit('_').next() {
+                        Some(arch) if arch != "all" => format!("{package}:{arch}"),
+                        _ => package,
+                    };
+                    Some((fname, shell_quote(&package), shell_quote(&version)))
+                })
+                .collect::<Vec<_>>();
+            script.push_str(
+                "\n# Packages already installed at the bundled version are skipped, so that\n\
+                 # the bundle can be applied again\n\
+                 installed() {\n    \
+                     [ \"$(dpkg-query --show --showformat='${Status} ${Version}' \"$1\" 2>/dev/null)\" = \"install ok installed $2\" ]\n\
+                 }\n",
+            );
+            script.push_str(&format!(
+                "\n# Answer debconf's questions before the packages ask them\n\
+                 if [ -f \"$BUNDLE_DIR/{preseed}\" ]; then\n    \
+                     debconf-set-selections \"$BUNDLE_DIR/{preseed}\"\n\
+                 fi\n",
+                preseed = preseed::FILE_NAME,
+            ));
+            script.push_str(&format!("\necho {}\n", shell_quote(&format!("Installing {name}..."))));
+            for (fname, package, version) in &packages {
+                script.push_str(&format!("installed {package} {version} || dpkg -i {} || true\n", shell_quote(fname)));
+            }
+
+            script.push_str(
+                "dpkg --configure -a\n\n\
+                 # Keep the packages available to apt, leaving the bundle intact\n\
+                 cp ./*.deb \"$ARCHIVES\"/\n",
+            );
+
+            script.push_str("\n# Record the outcome of every package\nFAILED=0\n");
+            for (_, package, version) in &packages {
+                script.push_str(&format!(
+                    "if installed {package} {version}; then report {package}={version}; \
+                     else report {package}=failed; FAILED=$((FAILED + 1)); fi\n"
+                ));
+            }
+            script.push_str(
+                "if [ \"$FAILED\" -gt 0 ]; then\n    \
+                     echo \"install.sh: $FAILED packages were not installed\" >&2\n    \
+                     exit 1\n\
+                 fi\n\
+                 RESULT=installed\n",
+            );
+        }
+        RemoteMode::Update => {
+            let sources = uri_file.sources.iter().map(|source| format!("{}\n", source.install_command())).collect::<String>();
+            script.push_str(CHECK_FUNCTION);
+            script.push_str("cd \"$BUNDLE_DIR/sources\"\n\necho \"Verifying checksums...\"\n");
+            for (fname, digest) in lists {
+                script.push_str(&format!("check sha256 {digest} {}\n", shell_quote(fname)));
+            }
+            script.push_str(&format!(
+                "report lists={count}\n\
+                 if [ \"$CHECK_ONLY\" = yes ]; then\n    \
+                     echo \"The {count} package lists are intact\"\n    \
+                     RESULT=checked\n    \
+                     exit 0\n\
+                 fi\n\n\
+                 echo \"Replacing $LISTS...\"\n\
+                 rm -rf \"$LISTS.old\"\n\
+                 mv \"$LISTS\" \"$LISTS.old\"\n\
+                 mkdir -p \"$LISTS/partial\"\n\
+                 touch \"$LISTS/lock\"\n",
+                count = lists.len(),
+            ));
+            // Only the verified lists are copied, whatever else the directory holds
+            for fname in lists.keys() {
+                script.push_str(&format!("cp {} \"$LISTS\"/\n", shell_quote(fname)));
+            }
+            if !sources.is_empty() {
+                script.push_str(&format!("\n# Sources the lists need, with their keys\n{sources}\n"));
+            }
+            script.push_str("apt-cache gencaches\nRESULT=updated\n");
+        }
+    }
+
+    script.push_str(&format!("\necho {}\n", shell_quote(&format!("Applied {name}"))));
+    script
+}
@@ -0,0 +1,76 @@
+//! # `apt-remote gc` command
+//!
+//! Removes objects from the shared package pool that are no longer
+//! referenced by the `uri.toml` of any cache image.
+
+use crate::{commands::set::format_size, dry_run, flock, output, pool, theme};
+
+use anyhow::Result;
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+use std::fs;
+
+/// CLI arguments for the `apt-remote gc` subcommand.
+#[derive(Args)]
+pub struct GcArgs {
+    /// Only report what would be removed and how much space it would free
+    /// (set from the global `--dry-run` flag on the command line)
+    #[arg(skip)]
+    pub dry_run: bool,
+}
+
+/// Executes the `gc` subcommand.
+///
+/// Collects the SHA256 digests referenced by every image, then removes (or,
+/// with `--dry-run`, lists) each pool object outside that set.
+///
+/// # Errors
+/// Returns an error if any image's `uri.toml` cannot be loaded, or if the
+/// pool cannot be read or an object cannot be removed.
+pub fn run(args: GcArgs) -> Result<()> {
+    let dry_run = args.dry_run || dry_run::enabled();
+
+    // No image may gain pool references while the pool is swept
+    let _locks = flock::lock_all_images()?;
+
+    let referenced = pool::referenced()?;
+
+    // Unreferenced objects are garbage
+    let garbage = pool::objects()?
+        .into_iter()
+        .filter(|object| !referenced.contains(&object.digest.to_lowercase()))
+        .collect::<Vec<_>>();
+    let reclaimable: u64 = garbage.iter().map(|object| object.size).sum();
+
+    for object in &garbage {
+        if dry_run {
+            output::print(format!("\t{} ({})", object.digest, format_size(object.size)));
+        } else {
+            fs::remove_file(&object.path)?;
+        }
+        output::emit(
+            "gc",
+            json!({ "digest": object.digest, "size": object.size, "removed": !dry_run }),
+        );
+    }
+
+    let summary = if dry_run {
+        format!("{} unreferenced objects, {} reclaimable", garbage.len(), format_size(reclaimable))
+    } else {
+        format!("Removed {} unreferenced objects, freed {}", garbage.len(), format_size(reclaimable))
+    };
+    output::print(format!("{} {}", theme::success("✓").bold(), theme::success(summary)));
+    output::emit(
+        "result",
+        json!({
+            "command": "gc",
+            "dry_run": dry_run,
+            "objects": garbage.len(),
+            "reclaimable": reclaimable,
+        }),
+    );
+
+    Ok(())
+}
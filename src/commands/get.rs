@@ -5,23 +5,72 @@
 //!
 //! Supports both package `.deb` downloads (Install/Upgrade mode)
 //! and APT source metadata downloads (Update mode), including automatic
-//! decompression of `.xz` files.
+//! decompression of `.xz` files. Files already present in the local
+//! partial mirror (see `apt-remote mirror`) are copied instead of downloaded,
+//! and `.deb` files are shared between images through the package pool.
+//! With `--adopt`, packages already present in a local apt archive (by
+//! default `/var/cache/apt/archives`) are copied after checksum verification.
+//!
+//! Compressed package lists are uncompressed with a size limit, so a
+//! corrupted or malicious `.xz` file cannot fill the disk: the size listed
+//! in the suite's `Release` file (fetched before the lists), and never more
+//! than `max_index_size`. A list exceeding its limit is discarded.
+//!
+//! Every fetched file must have the size `uri.toml` records for it (and a
+//! download the length its response announced), so truncated transfers are
+//! caught even for files without a checksum; a mismatching file is
+//! discarded and counted as failed.
+//!
+//! When the image was recorded again by `set`, the plan is first compared
+//! with the packages already cached: files the plan no longer lists are
+//! removed, files it changed are fetched again, and the delta is reported.
+//!
+//! Redirects are followed by [`crate::redirect`], which remembers the mirror
+//! a redirector such as `deb.debian.org` chose for the rest of the run and
+//! asks the redirector again when that mirror fails.
+//!
+//! Files of encrypted images (see [`crate::crypt`]) are encrypted as soon as
+//! they arrive, and are not added to the pool or the mirror.
+//!
+//! With `--changelogs`, the changelogs of the packages are fetched too (see
+//! [`crate::changelog`]), and with `--advisories`, the Debian and Ubuntu
+//! security advisories of their source packages (see [`crate::advisory`]).
+//!
+//! Repositories that need credentials, such as Ubuntu Pro's, are
+//! authenticated as APT does (see [`crate::apt_auth`]).
+//!
+//! Downloads run on a `tokio` runtime, at most `jobs` at a time, and are
+//! streamed to disk; hashing, copying and decompression run on the blocking
+//! pool so they overlap with the transfers still in flight.
 
-use crate::uri::{UriFile, RemoteMode};
+use crate::{
+    advisory,
+    apt_auth::{self, AuthFile},
+    archive, changelog, config, crypt, dry_run,
+    error::Error,
+    flock, output, policy, pool,
+    progress::{self, Progress, ProgressReporter},
+    redirect::{self, Mirrors},
+    state::ImageState,
+    summary::{self, Outcome},
+    theme, tls,
+    uri::{self, Checksum, ChecksumKind, UriFile, RemoteMode},
+};
 
 use anyhow::{Context, Result};
 use clap::Args;
 use colored::Colorize;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use rayon::prelude::*;
-use reqwest::blocking::Client;
+use reqwest::{Client, redirect::Policy};
+use serde_json::json;
+use tokio::{io::AsyncWriteExt, sync::Semaphore, task::{self, JoinSet}};
 use xz2::read::XzDecoder;
 
 use std::{
+    collections::{HashMap, HashSet},
     fs::{self, File},
-    path::Path,
-    io::{BufReader, BufWriter, Write},
-    sync::Arc,
+    path::{Path, PathBuf},
+    io::{BufReader, BufWriter, Read},
+    sync::{Arc, Mutex, OnceLock, atomic::{AtomicUsize, Ordering}},
     time::Duration,
 };
 
@@ -29,153 +78,647 @@ use std::{
 #[derive(Args)]
 pub struct GetArgs {
     /// Cache image name (required)
-    name: String,
+    pub name: String,
+
+    /// Copy verified packages from a local apt archive instead of downloading them
+    #[arg(long, value_name = "DIR", num_args = 0..=1, default_missing_value = "/var/cache/apt/archives")]
+    pub adopt: Option<PathBuf>,
+
+    /// Also fetch the changelog of every package, for `apt-remote changelog`
+    #[arg(long)]
+    pub changelogs: bool,
+
+    /// Also fetch the security advisories of the packages, for `apt-remote advisories`
+    #[arg(long)]
+    pub advisories: bool,
 }
 
 /// Executes the `get` subcommand.
 ///
 /// Reads `uri.toml` from the local cache, downloads all listed packages
 /// (or source lists) into a `debs/` or `sources/` directory, and decompresses
-/// `.xz` files if in Update mode.
+/// `.xz` files if in Update mode. Packages are taken from the shared pool,
+/// an adopted local archive, or the local mirror before the network.
 ///
 /// # Errors
 /// Returns an error if reading `uri.toml` fails, creating directories fails,
-/// or downloading files encounters unrecoverable issues.
+/// downloading files encounters unrecoverable issues, or any package of an
+/// install or upgrade image could not be downloaded.
 pub fn run(args: GetArgs) -> Result<()> {
     let name = &args.name;
 
     // Locate cache directory for the given image
-    let cache_dir = dirs::cache_dir()
-        .context("Failed to locate cache directory")?
-        .join("apt-remote")
-        .join(name);
+    let cache_dir = config::cache_dir()?.join(name);
+
+    // Hold the image for the rest of the command
+    let _lock = flock::lock_image(&cache_dir)?;
 
     // Load metadata from uri.toml
-    let uri_file_path = cache_dir.join("uri.toml");
+    let uri_file_path = uri::path_in(&cache_dir);
     let uri_file = UriFile::load(&uri_file_path).context("Failed to load uri.toml metadata")?;
-    
+
+    // The image may have been recorded before a policy was enabled
+    policy::check_https(uri_file.packages.values().map(|pkg| pkg.uri.as_str()))?;
+    if uri_file.mode != RemoteMode::Update {
+        policy::check_strong_hash(&uri_file.packages)?;
+    }
+
+    // Encrypted images only receive encrypted files (nothing is written in a dry run)
+    let key = if dry_run::enabled() {
+        None
+    } else if crypt::wanted() && !crypt::is_encrypted(&cache_dir) {
+        Some(crypt::encrypt_image(&cache_dir, name)?.0)
+    } else {
+        crypt::unlock(&cache_dir, name)?
+    };
+
     // Determine target directory based on operation mode
     let dir = match uri_file.mode {
         RemoteMode::Install | RemoteMode::Upgrade => "debs",
         RemoteMode::Update => "sources",
     };
     let download_dir = cache_dir.join(dir);
-    fs::create_dir_all(&download_dir)?;
-
-    // HTTP client for downloads (5-minute timeout)
-    let client = Arc::new(
-        Client::builder()
-            .timeout(Duration::from_secs(300))
-            .build()
-            .context("Failed to build client")?,
-    );
+    if !dry_run::skip("create", download_dir.display().to_string()) {
+        fs::create_dir_all(&download_dir)?;
+    }
+
+    // Fetch only what a regenerated plan added or changed
+    let mut state = ImageState::load(&cache_dir)?;
+    let changed = if uri_file.mode == RemoteMode::Update {
+        HashSet::new()
+    } else {
+        let encrypted = key.is_some() || crypt::is_encrypted(&cache_dir);
+        apply_delta(&uri_file, &download_dir, encrypted, &mut state)?
+    };
+
+    // HTTP client for downloads (5-minute timeout, configured CAs and client
+    // certificate); redirects are followed by `redirect::get`
+    let client = tls::load()?
+        .client(Client::builder().timeout(Duration::from_secs(300)).redirect(Policy::none()))
+        .build()
+        .context("Failed to build client")?;
 
     // Shared progress tracker for multiple downloads
-    let progress = Arc::new(MultiProgress::new());
+    let progress = progress::reporter();
 
     // Overall progress bar (counts completed packages)
-    let progress_overall = progress.add(ProgressBar::new(uri_file.packages.len() as u64));
-    progress_overall.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "[{elapsed_precise}] {msg} [{wide_bar:.bold.cyan}] {pos}/{len} ({eta} remaining)",
-            )
-            .unwrap()
-            .progress_chars("##-"),
-    );
-    progress_overall.enable_steady_tick(Duration::from_millis(100));
-    progress_overall.set_message(format!("Downloading {name}..."));
+    let progress_overall = progress.bar(uri_file.packages.len() as u64);
+    progress_overall.message(&format!("Downloading {name}..."));
+
+    let job = Arc::new(Job {
+        client,
+        mirrors: Mirrors::default(),
+        auth: apt_auth::for_image(&cache_dir)?,
+        progress,
+        progress_overall: Arc::clone(&progress_overall),
+        uri_file,
+        download_dir: download_dir.clone(),
+        adopt: args.adopt,
+        key,
+        failed: AtomicUsize::new(0),
+        changed,
+        index_sizes: OnceLock::new(),
+        state: Mutex::new(state),
+    });
 
-    // Parallel download of each package
-    uri_file
+    // Release files come first: they give the expected size of every list
+    let (releases, files): (Vec<_>, Vec<_>) = job
+        .uri_file
         .packages
-        .par_iter()
-        .try_for_each(|(fname, pkg)| -> Result<()> {
-            let dest = download_dir.join(fname);
+        .iter()
+        .partition(|(_, pkg)| job.uri_file.mode == RemoteMode::Update && is_release(&pkg.uri));
+    let batches = [releases, files].map(|batch| batch.into_iter().map(|(fname, _)| fname.clone()).collect::<Vec<_>>());
 
-            if dest.exists() {
-                return Ok(()); // Skip if file already exists
+    // Download every package concurrently, at most `jobs` at a time
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start async runtime")?;
+    let phase = summary::phase("download");
+    let downloaded = runtime.block_on(async {
+        let permits = Arc::new(Semaphore::new(config::jobs()));
+        for batch in batches {
+            let mut tasks = JoinSet::new();
+            for fname in batch {
+                let job = Arc::clone(&job);
+                let permits = Arc::clone(&permits);
+                tasks.spawn(async move {
+                    let _permit = permits.acquire_owned().await?;
+                    fetch(job, fname).await
+                });
+            }
+            while let Some(result) = tasks.join_next().await {
+                result.context("Download task panicked")??;
             }
+            if job.index_sizes.get().is_none() {
+                job.index_sizes.set(index_sizes(&job)?).ok();
+            }
+        }
+        anyhow::Ok(())
+    });
+    drop(phase);
+
+    // Record what is in the cache, even if the run was cut short
+    job.state.lock().unwrap().save(&cache_dir)?;
+    downloaded?;
+
+    // Mark overall progress as complete
+    progress_overall.finish(&format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Downloaded {}", name))
+    ));
 
-            let client = Arc::clone(&client);
-            let progress = Arc::clone(&progress);
-            let progress_overall = progress_overall.clone();
+    // Changelogs for reviewers, which the image can do without
+    if args.changelogs && job.uri_file.mode != RemoteMode::Update {
+        let fetched = changelog::fetch(&job.uri_file, &cache_dir)?;
+        let mut message = format!("Fetched {} changelogs", fetched.fetched);
+        if fetched.failed > 0 {
+            message.push_str(&format!(", {} failed", fetched.failed));
+        }
+        if fetched.unavailable > 0 {
+            message.push_str(&format!(", {} packages from archives without published changelogs", fetched.unavailable));
+        }
+        output::print(format!("\n{message}"));
+    }
+    if args.advisories && job.uri_file.mode != RemoteMode::Update {
+        let advisories = advisory::fetch(&job.uri_file)?;
+        advisories.save(&cache_dir)?;
+        let count = advisories.sources.values().map(Vec::len).sum::<usize>();
+        output::print(format!("\nFetched {count} advisories of {} source packages", advisories.sources.len()));
+    }
 
-            // Spinner for individual file download
-            let spinner = progress.add(ProgressBar::new_spinner());
-            spinner.set_style(
-                ProgressStyle::with_template("\t{spinner:.bold.cyan} {msg}")
-                    .unwrap()
-                    .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏"),
-            );
-            spinner.set_message(format!("{} {}", "Downloading".cyan().bold(), fname.bold()));
-            spinner.enable_steady_tick(std::time::Duration::from_millis(80));
+    output::print("\n");
+    output::emit(
+        "result",
+        json!({
+            "command": "get",
+            "name": name,
+            "path": download_dir,
+            "files": job.uri_file.packages.len(),
+            "failed": job.failed.load(Ordering::Relaxed),
+        }),
+    );
 
-            // Request file from URI
-            let response = client.get(&pkg.uri).send();
-
-            // Handle network errors
-            if let Err(e) = response {
-                spinner.finish_with_message(format!(
-                    "{} {}:\n{}",
-                    "✗".red().bold(),
-                    format!("Failed to download {}", fname).red(),
-                    e.to_string().dimmed()
-                ));
-                return Ok(()); // Skip instead of failing whole run
-            }
+    // Missing packages make the image unusable; missing lists are tolerated
+    let failed = job.failed.load(Ordering::Relaxed);
+    if failed > 0 && job.uri_file.mode != RemoteMode::Update {
+        return Err(Error::DownloadFailed {
+            name: name.clone(),
+            failed,
+            total: job.uri_file.packages.len(),
+        }
+        .into());
+    }
+    Ok(())
+}
 
-            let response = response?.error_for_status();
+/// State shared by all download tasks of a `get` run.
+struct Job {
+    /// HTTP client for downloads.
+    client: Client,
+    /// Mirrors redirectors sent earlier downloads to.
+    mirrors: Mirrors,
+    /// Credentials of authenticated repositories.
+    auth: AuthFile,
+    /// Reporter of the per-file spinners.
+    progress: Arc<dyn ProgressReporter>,
+    /// Overall progress bar (counts completed packages).
+    progress_overall: Arc<dyn Progress>,
+    /// Metadata of the image being downloaded.
+    uri_file: UriFile,
+    /// Directory receiving the files.
+    download_dir: PathBuf,
+    /// Local apt archive to adopt packages from, if any.
+    adopt: Option<PathBuf>,
+    /// Key of an encrypted image.
+    key: Option<crypt::Key>,
+    /// Number of files that could not be fetched.
+    failed: AtomicUsize,
+    /// Cached files whose plan entry changed, fetched again.
+    changed: HashSet<String>,
+    /// Uncompressed size of package lists by URI, from the Release files.
+    index_sizes: OnceLock<HashMap<String, u64>>,
+    /// State of the image, updated as files arrive.
+    state: Mutex<ImageState>,
+}
 
-            // Handle HTTP errors
-            if let Err(e) = response {
-                if uri_file.mode == RemoteMode::Install {
-                    spinner.finish_with_message(format!(
+/// Fetch a single file of the image into the download directory.
+///
+/// Local sources and post-processing (pool insertion, `.xz` decompression)
+/// run on the blocking thread pool, so they overlap with other downloads.
+///
+/// # Errors
+/// Returns an error on local I/O failures. Network and HTTP errors are
+/// reported and counted in [`Job::failed`] instead.
+async fn fetch(job: Arc<Job>, fname: String) -> Result<()> {
+    let pkg = &job.uri_file.packages[&fname];
+    let dest = job.download_dir.join(&fname);
+
+    if dest.exists() && !job.changed.contains(&fname) {
+        let mut state = job.state.lock().unwrap();
+        state.files.entry(fname.clone()).or_insert_with(|| "cached".to_string());
+        state.fetched.entry(fname.clone()).or_insert_with(|| pkg.fingerprint().to_string());
+        drop(state);
+        summary::record(Outcome::Skipped);
+        output::emit("download", json!({ "file": fname, "status": "cached" }));
+        return Ok(()); // Skip if file already exists
+    }
+    if dry_run::skip("download", format!("{} -> {}", pkg.uri, dest.display())) {
+        return Ok(());
+    }
+
+    // Spinner for individual file download
+    let spinner = job.progress.task();
+    spinner.message(&format!("{} {}", theme::accent("Downloading").bold(), fname.bold()));
+
+    // Satisfy the file from the shared pool, an adopted archive or the local mirror when possible
+    let local = {
+        let (job, fname, dest, spinner) = (Arc::clone(&job), fname.clone(), dest.clone(), spinner.clone());
+        task::spawn_blocking(move || fetch_local(&job, &fname, &dest, &*spinner)).await??
+    };
+
+    let status = match local {
+        Some(status) => status,
+        None => {
+            // Request file from URI
+            tracing::debug!(uri = pkg.uri, "GET");
+            let response = match redirect::get(&job.client, &job.mirrors, &job.auth, &pkg.uri).await {
+                Ok(response) => response,
+                // Handle network errors
+                Err(e) => {
+                    spinner.finish(&format!(
                         "{} {}:\n{}",
-                        "✗".red().bold(),
-                        format!("Bad response for {}", name).red(),
+                        theme::failure("✗").bold(),
+                        theme::failure(format!("Failed to download {}", fname)),
                         e.to_string().dimmed()
                     ));
+                    job.failed.fetch_add(1, Ordering::Relaxed);
+                    summary::record(Outcome::Failed);
+                    output::emit(
+                        "download",
+                        json!({ "file": fname, "status": "failed", "error": e.to_string() }),
+                    );
+                    return Ok(()); // Skip instead of failing whole run
                 }
+            };
+
+            // Handle HTTP errors
+            let mut response = match response.error_for_status() {
+                Ok(response) => response,
+                Err(e) => {
+                    if job.uri_file.mode == RemoteMode::Install {
+                        spinner.finish(&format!(
+                            "{} {}:\n{}",
+                            theme::failure("✗").bold(),
+                            theme::failure(format!("Bad response for {}", fname)),
+                            e.to_string().dimmed()
+                        ));
+                    }
+                    job.failed.fetch_add(1, Ordering::Relaxed);
+                    summary::record(Outcome::Failed);
+                    output::emit(
+                        "download",
+                        json!({ "file": fname, "status": "failed", "error": e.to_string() }),
+                    );
+                    return Ok(());
+                }
+            };
+
+            // Stream the body to a partial file, so interrupted downloads are not kept
+            let partial = job.download_dir.join(format!("{fname}.part"));
+            let mut file = tokio::fs::File::create(&partial).await?;
+            let length = response.content_length();
+            let mut received = 0;
+            let mut truncated = None;
+            loop {
+                match response.chunk().await {
+                    Ok(Some(chunk)) => {
+                        file.write_all(&chunk).await?;
+                        received += chunk.len() as u64;
+                        summary::downloaded(chunk.len() as u64);
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        truncated = Some(format!("transfer interrupted after {received} bytes: {e}"));
+                        break;
+                    }
+                }
+            }
+            file.flush().await?;
+            drop(file);
+
+            // Truncated transfers are failures of this file, not of the run
+            let expected = length.filter(|&length| length != received);
+            if let Some(reason) = truncated.or(expected.map(|length| format!("received {received} of {length} bytes"))) {
+                tokio::fs::remove_file(&partial).await?;
+                reject(&job, &*spinner, &fname, &reason);
                 return Ok(());
             }
+            tokio::fs::rename(&partial, &dest).await?;
+            "downloaded"
+        }
+    };
 
-            // Save downloaded file to disk
-            let extension = dest.extension().unwrap().to_str().unwrap();
-            let mut file = File::create(&dest)?;
-            file.write_all(&response?.bytes()?)?;
+    // Catch truncated files, even those without a checksum (lists have no recorded size)
+    let size = tokio::fs::metadata(&dest).await?.len();
+    if pkg.size > 0 && size != pkg.size {
+        tokio::fs::remove_file(&dest).await?;
+        reject(&job, &*spinner, &fname, &format!("{size} bytes instead of {}", pkg.size));
+        return Ok(());
+    }
 
-            // Auto-decompress .xz files if in Update mode
-            if uri_file.mode == RemoteMode::Update && extension == "xz" {
-                spinner.set_message(format!("{} {}", "Uncompressing".cyan().bold(), fname.bold()));
-                 
-                let original_path = Path::new(&dest);
-                let output_path = original_path.with_extension(""); // removes .xz
+    // Share the file, and uncompress source lists, off the async workers
+    let rejected = {
+        let (job, fname, dest, spinner) = (Arc::clone(&job), fname.clone(), dest.clone(), spinner.clone());
+        task::spawn_blocking(move || finish_file(&job, &fname, &dest, status, &*spinner)).await??
+    };
+    if let Some(reason) = rejected {
+        reject(&job, &*spinner, &fname, &reason);
+        return Ok(());
+    }
 
-                let input_file = File::open(&original_path)?;
-                let mut decoder = XzDecoder::new_multi_decoder(BufReader::new(input_file));
+    spinner.clear();
+    job.progress_overall.inc(1);
+    let mut state = job.state.lock().unwrap();
+    state.files.insert(fname.clone(), status.to_string());
+    state.fetched.insert(fname.clone(), pkg.fingerprint().to_string());
+    drop(state);
+    summary::record(Outcome::Succeeded);
+    output::emit("download", json!({ "file": fname, "status": status }));
+    Ok(())
+}
 
-                let output_file = File::create(&output_path)?;
-                let mut writer = BufWriter::new(output_file);
+/// Report a fetched file that was discarded, and count it as failed.
+fn reject(job: &Job, spinner: &dyn Progress, fname: &str, reason: &str) {
+    spinner.finish(&format!(
+        "{} {}:\n{}",
+        theme::failure("✗").bold(),
+        theme::failure(format!("Discarded {}", fname)),
+        reason.dimmed()
+    ));
+    job.failed.fetch_add(1, Ordering::Relaxed);
+    summary::record(Outcome::Failed);
+    output::emit("download", json!({ "file": fname, "status": "failed", "error": reason }));
+}
 
-                std::io::copy(&mut decoder, &mut writer)?;
+/// Compare the plan with the packages already in `dir`, report the delta,
+/// and remove the files the plan no longer needs.
+///
+/// A cached file changed if it was fetched for another checksum, or if its
+/// size differs from the plan's (encrypted files are larger than the package,
+/// so only the recorded checksum is compared for them). Changed files are
+/// removed too, and their uploads forgotten.
+///
+/// Returns the names of the changed files, which have to be fetched again.
+///
+/// # Errors
+/// Returns an error if `dir` cannot be read or a file cannot be removed.
+fn apply_delta(uri_file: &UriFile, dir: &Path, encrypted: bool, state: &mut ImageState) -> Result<HashSet<String>> {
+    // Sort the cached files into kept and stale ones
+    let mut cached = vec![];
+    let mut removed = vec![];
+    let mut stale = vec![];
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(fname) = path.file_name().and_then(|f| f.to_str()).map(str::to_string) else {
+                continue;
+            };
+            if !path.is_file() {
+                continue;
+            }
+            match fname.strip_suffix(".part") {
+                _ if uri_file.packages.contains_key(&fname) => cached.push(fname),
+                // Partial downloads are resumed from scratch, or dropped with their package
+                Some(base) if uri_file.packages.contains_key(base) => {}
+                Some(_) => stale.push(path),
+                None => {
+                    removed.push(fname);
+                    stale.push(path);
+                }
+            }
+        }
+    }
+    cached.sort();
+    removed.sort();
 
-                // Remove original compressed file
-                std::fs::remove_file(&original_path)?;
+    let is_changed = |fname: &String| {
+        let pkg = &uri_file.packages[fname];
+        let refetch = state.fetched.get(fname).is_some_and(|sum| sum != pkg.fingerprint());
+        let resized = !encrypted && fs::metadata(dir.join(fname)).is_ok_and(|m| m.len() != pkg.size);
+        refetch || resized
+    };
+    let changed = cached.iter().filter(|fname| is_changed(fname)).cloned().collect::<Vec<_>>();
+    let mut added = uri_file
+        .packages
+        .keys()
+        .filter(|fname| !cached.contains(fname))
+        .cloned()
+        .collect::<Vec<_>>();
+    added.sort();
+
+    // Report against the previous content of the image (nothing to compare on a first run)
+    if !cached.is_empty() || !removed.is_empty() {
+        if !(added.is_empty() && changed.is_empty() && removed.is_empty()) {
+            output::print("Changes since the last download:");
+        }
+        for (sign, files) in [("+", &added), ("~", &changed), ("-", &removed)] {
+            for fname in files {
+                let line = format!("\t{sign} {fname}");
+                let line = match sign {
+                    "+" => theme::success(line),
+                    "~" => theme::warning(line),
+                    _ => theme::failure(line),
+                };
+                output::print(line.to_string());
             }
+        }
+        output::print(format!(
+            "{} new, {} changed, {} removed, {} unchanged\n",
+            added.len(),
+            changed.len(),
+            removed.len(),
+            cached.len() - changed.len()
+        ));
+    }
+    output::emit(
+        "delta",
+        json!({
+            "added": added,
+            "changed": changed,
+            "removed": removed,
+            "unchanged": cached.len() - changed.len(),
+        }),
+    );
 
-            spinner.finish_and_clear();
-            progress_overall.inc(1);
-            Ok(())
-        })?;
+    // Drop what the plan no longer needs, and the outdated copies of changed files
+    for path in stale.into_iter().chain(changed.iter().map(|fname| dir.join(fname))) {
+        if !dry_run::skip("remove", path.display().to_string()) {
+            fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+    }
+    for fname in removed.iter().chain(&changed) {
+        state.forget_file(fname);
+    }
+    Ok(changed.into_iter().collect())
+}
 
-    // Mark overall progress as complete
-    progress_overall.finish_with_message(format!(
-        "{} {}",
-        "✓".green().bold(),
-        format!("Downloaded {}", name).green()
-    ));
-    
-    println!("\n");
-    Ok(())
+/// Populate `dest` from the shared pool, an adopted archive or the local mirror.
+///
+/// Returns the download status (`pooled`, `adopted` or `mirrored`), or
+/// `None` if the file has to be downloaded.
+fn fetch_local(job: &Job, fname: &str, dest: &Path, spinner: &dyn Progress) -> Result<Option<&'static str>> {
+    let pkg = &job.uri_file.packages[fname];
+    let mode = &job.uri_file.mode;
+
+    // SHA256 of the package, used to share it through the pool
+    let sha256 = pkg
+        .checksum
+        .as_ref()
+        .filter(|c| *mode != RemoteMode::Update && matches!(c.kind, ChecksumKind::SHA256))
+        .map(|c| c.value.as_str());
+
+    if let Some(digest) = sha256 && pool::link_into(digest, dest)? {
+        spinner.message(&format!("{} {}", theme::accent("Linking").bold(), fname.bold()));
+        return Ok(Some("pooled"));
+    }
+    if let (Some(dir), Some(checksum)) = (&job.adopt, &pkg.checksum)
+        && *mode != RemoteMode::Update
+        && adopt(&dir.join(fname), checksum, dest)?
+    {
+        spinner.message(&format!("{} {}", theme::accent("Adopted").bold(), fname.bold()));
+        return Ok(Some("adopted"));
+    }
+    if let Some(mirrored) = archive::local_path(&pkg.uri).filter(|p| p.is_file()) {
+        spinner.message(&format!("{} {}", theme::accent("Copying").bold(), fname.bold()));
+        fs::copy(&mirrored, dest)?;
+        return Ok(Some("mirrored"));
+    }
+    Ok(None)
+}
+
+/// Post-process a fetched file: keep it in the mirror and pool, uncompress
+/// `.xz` source lists, and encrypt it for encrypted images.
+///
+/// Returns the reason a source list was discarded because it uncompressed
+/// to more than its size limit.
+fn finish_file(job: &Job, fname: &str, dest: &Path, status: &str, spinner: &dyn Progress) -> Result<Option<String>> {
+    let pkg = &job.uri_file.packages[fname];
+    let mode = &job.uri_file.mode;
+
+    if status == "downloaded" && job.key.is_none() {
+        // Keep pool files of mirrored archives for later images
+        if *mode != RemoteMode::Update && archive::is_mirrored(&pkg.uri) {
+            archive::store(&pkg.uri, dest).ok();
+        }
+
+        // Share the new file with other images through the pool
+        if let Some(checksum) = &pkg.checksum
+            && *mode != RemoteMode::Update
+            && matches!(checksum.kind, ChecksumKind::SHA256)
+        {
+            pool::insert(&checksum.value, dest).ok();
+        }
+    }
+
+    let extension = dest.extension().unwrap().to_str().unwrap();
+
+    // Auto-decompress .xz files if in Update mode
+    if *mode == RemoteMode::Update && extension == "xz" {
+        spinner.message(&format!("{} {}", theme::accent("Uncompressing").bold(), fname.bold()));
+
+        let output_path = dest.with_extension(""); // removes .xz
+
+        // Stop one byte past the limit, which is enough to tell it was exceeded
+        let limit = index_limit(job, &pkg.uri);
+        let input_file = File::open(dest)?;
+        let mut decoder = XzDecoder::new_multi_decoder(BufReader::new(input_file)).take(limit + 1);
+
+        let output_file = File::create(&output_path)?;
+        let mut writer = BufWriter::new(output_file);
+
+        let written = std::io::copy(&mut decoder, &mut writer)?;
+        std::io::Write::flush(&mut writer)?;
+        drop(writer);
+
+        // Remove original compressed file
+        fs::remove_file(dest)?;
+
+        if written > limit {
+            fs::remove_file(&output_path)?;
+            return Ok(Some(format!("uncompressed size exceeds the limit of {limit} bytes")));
+        }
+
+        if let Some(key) = &job.key {
+            key.encrypt_file(&output_path)?;
+        }
+        return Ok(None);
+    }
+
+    if let Some(key) = &job.key {
+        key.encrypt_file(dest)?;
+    }
+    Ok(None)
+}
+
+/// Whether `uri` is the `Release` or `InRelease` file of a suite.
+fn is_release(uri: &str) -> bool {
+    uri.ends_with("/InRelease") || uri.ends_with("/Release")
+}
+
+/// Largest size a compressed list at `uri` may uncompress to: its size in
+/// the suite's `Release` file, at most `max_index_size`.
+fn index_limit(job: &Job, uri: &str) -> u64 {
+    let cap = config::max_index_bytes();
+    let plain = uri.strip_suffix(".xz").unwrap_or(uri);
+    job.index_sizes
+        .get()
+        .and_then(|sizes| sizes.get(plain))
+        .map_or(cap, |&size| size.min(cap))
+}
+
+/// Uncompressed sizes of the lists of every downloaded Release file, keyed
+/// by URI.
+///
+/// # Errors
+/// Returns an error if a downloaded Release file cannot be read.
+fn index_sizes(job: &Job) -> Result<HashMap<String, u64>> {
+    let mut sizes = HashMap::new();
+    for (fname, pkg) in job.uri_file.packages.iter().filter(|(_, pkg)| is_release(&pkg.uri)) {
+        let path = job.download_dir.join(fname);
+        if !path.is_file() {
+            continue;
+        }
+        let plaintext = crypt::Plaintext::of(job.key.as_ref(), &path)?;
+        let content = fs::read_to_string(plaintext.path())
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let base = &pkg.uri[..=pkg.uri.rfind('/').unwrap_or_default()];
+
+        // Checksum lines read " <digest> <size> <path>", in every hash section
+        for line in content.lines().filter(|line| line.starts_with(' ')) {
+            if let [_, size, path] = line.split_whitespace().collect::<Vec<_>>()[..]
+                && let Ok(size) = size.parse::<u64>()
+            {
+                sizes.insert(format!("{base}{path}"), size);
+            }
+        }
+    }
+    Ok(sizes)
+}
+
+/// Copy a package from a local apt archive into the image if its checksum matches.
+///
+/// Returns `true` if `dest` was populated from `candidate`.
+///
+/// # Errors
+/// Returns an error if a matching candidate cannot be copied.
+fn adopt(candidate: &Path, checksum: &Checksum, dest: &Path) -> Result<bool> {
+    // Unreadable or mismatching candidates are simply downloaded instead
+    if !candidate.is_file() || !checksum.matches_file(candidate).unwrap_or(false) {
+        return Ok(false);
+    }
+    fs::copy(candidate, dest)
+        .with_context(|| format!("Failed to copy {}", candidate.display()))?;
+    Ok(true)
 }
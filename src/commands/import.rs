@@ -0,0 +1,382 @@
+//! # `apt-remote import` command
+//!
+//! Copies a bundle written by `apt-remote export` (for example from a USB
+//! stick) into the local cache as an image, ready for `install` or `update`.
+//!
+//! A signed bundle is only imported if its signature was made by one of the
+//! `trusted_keys` and every file matches the signed manifest (see
+//! [`crate::signing`]); files the manifest does not list are not imported.
+//! The manifest is kept with the image, so that `install` and `update` can
+//! check it again. Unsigned bundles are imported as they are, unless the
+//! `require_signature` policy is enabled.
+//!
+//! apt-offline signatures (`.sig`) are imported as plans, and apt-offline
+//! bundles (`.zip`) into the image whose plan they were fetched for (see
+//! [`crate::apt_offline`]).
+
+use crate::{
+    advisory,
+    apt_offline::{self, Signature},
+    commands::set::{self, format_size},
+    config, crypt, dry_run,
+    exit::{Classify, ExitCode},
+    flock,
+    lock::LockFile,
+    output, policy, preseed,
+    signing::{self, MANIFEST_FILE, SIGNATURE_FILE},
+    state::ImageState,
+    theme, tr,
+    uri::{self, RemoteMode, UriFile},
+    zip,
+};
+
+use anyhow::{Context, Result, anyhow};
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// CLI arguments for the `apt-remote import` subcommand.
+#[derive(Args)]
+#[command(override_usage = "apt-remote import <BUNDLE> [--name <NAME>]\n       \
+                            apt-remote import <FILE>.sig [--name <NAME>]\n       \
+                            apt-remote import <FILE>.zip --name <NAME>")]
+pub struct ImportArgs {
+    /// Bundle directory written by `apt-remote export`, or an apt-offline signature (.sig) or bundle (.zip) (required)
+    pub bundle: PathBuf,
+
+    /// Image name in the cache, defaults to the name the bundle was exported as (or the signature's file name)
+    #[arg(long)]
+    pub name: Option<String>,
+}
+
+/// Executes the `import` subcommand.
+///
+/// # Errors
+/// Returns an error if the bundle's signature or files do not check out, if
+/// it is unsigned but signatures are required, or if copying it fails.
+pub fn run(args: ImportArgs) -> Result<()> {
+    let bundle = &args.bundle;
+
+    // apt-offline files are told apart by their extension
+    if bundle.is_file() {
+        match bundle.extension().and_then(|ext| ext.to_str()) {
+            Some("sig") => return import_signature(&args),
+            Some("zip") => return import_apt_offline(&args),
+            _ => {}
+        }
+    }
+
+    // Check the signature before anything is read from the bundle
+    let signed = signing::verify(bundle)?;
+    let uri_file = UriFile::load(uri::path_in(bundle))
+        .with_context(|| format!("{} is not an apt-remote bundle", bundle.display()))
+        .classify(ExitCode::Cache)?;
+
+    // Files to import: the signed ones, or the image files of an unsigned bundle
+    let files = match &signed {
+        Some((manifest, _)) => {
+            let files = manifest.files.keys().filter(|f| f.as_str() != "install.sh").cloned().collect::<Vec<_>>();
+            for relative in &files {
+                manifest.check_file(bundle, relative, &bundle.join(safe_path(relative)?))?;
+            }
+            files
+        }
+        None => unsigned_files(bundle, &uri_file)?,
+    };
+
+    let name = match (&args.name, &signed) {
+        (Some(name), _) => name.clone(),
+        (None, Some((manifest, _))) => manifest.name.clone(),
+        (None, None) => bundle
+            .canonicalize()?
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .context("Cannot name the image: pass --name")?,
+    };
+    config::check_image_name(&name)?;
+    let cache_dir = config::cache_dir()?.join(&name);
+    if dry_run::skip("import", format!("{} -> {}", bundle.display(), cache_dir.display())) {
+        return Ok(());
+    }
+
+    // Hold the image for the rest of the command
+    fs::create_dir_all(&cache_dir).with_context(|| format!("Failed to create {}", cache_dir.display()))?;
+    let _lock = flock::lock_image(&cache_dir)?;
+
+    // Replace an existing image only after asking
+    if uri::path_in(&cache_dir).is_file() {
+        output::confirm(&tr!("Replace the cached image {name}?", name = name))?;
+        for stale in [uri::TOML_FILE, uri::JSON_FILE].map(|f| cache_dir.join(f)).iter().filter(|f| f.is_file()) {
+            fs::remove_file(stale)?;
+        }
+        for dir in ["debs", "sources"].map(|dir| cache_dir.join(dir)).iter().filter(|dir| dir.is_dir()) {
+            fs::remove_dir_all(dir)?;
+        }
+        signing::forget(&cache_dir)?;
+        if crypt::is_encrypted(&cache_dir) {
+            fs::remove_file(cache_dir.join(crypt::HEADER_FILE))?;
+        }
+    }
+
+    // Copy the files, then the signature that covers them
+    for relative in &files {
+        let dest = cache_dir.join(safe_path(relative)?);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(bundle.join(safe_path(relative)?), &dest)
+            .with_context(|| format!("Failed to import {relative}"))?;
+    }
+    if signed.is_some() {
+        fs::copy(bundle.join(MANIFEST_FILE), cache_dir.join(MANIFEST_FILE))?;
+        fs::copy(bundle.join(SIGNATURE_FILE), cache_dir.join(SIGNATURE_FILE))?;
+    }
+
+    // Record the imported files as cached
+    let mut state = ImageState::load(&cache_dir)?;
+    state.retain_plan(&uri_file);
+    for fname in files.iter().filter_map(|f| f.split_once('/')).map(|(_, fname)| fname) {
+        state.files.insert(fname.to_string(), "imported".to_string());
+    }
+    state.save(&cache_dir)?;
+
+    if crypt::wanted() {
+        crypt::encrypt_image(&cache_dir, &name)?;
+    }
+
+    let signed_by = signed.map(|(_, key)| key);
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Imported {name} ({} files)", files.len()))
+    ));
+    match &signed_by {
+        Some(key) => output::print(format!("\tSignature by key {key} verified")),
+        None => output::print(format!("\t{}", theme::warning("The bundle is not signed"))),
+    }
+    output::print("\n");
+    output::emit(
+        "result",
+        json!({
+            "command": "import",
+            "name": name,
+            "path": cache_dir,
+            "files": files.len(),
+            "signed_by": signed_by,
+        }),
+    );
+    Ok(())
+}
+
+/// Store the plans an apt-offline signature asks for: its packages in the
+/// image `<NAME>`, and its lists in `<NAME>` or, if it also has packages,
+/// in `<NAME>-lists`.
+///
+/// # Errors
+/// Returns an error if the signature cannot be read or parsed, lists no
+/// files, or a plan breaks the download policy or cannot be stored.
+fn import_signature(args: &ImportArgs) -> Result<()> {
+    let path = &args.bundle;
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let signature = Signature::parse(&content)
+        .with_context(|| format!("{} is not an apt-offline signature", path.display()))
+        .classify(ExitCode::Usage)?;
+    let name = match &args.name {
+        Some(name) => name.clone(),
+        None => path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).context("Cannot name the image: pass --name")?,
+    };
+
+    // One plan per mode
+    let arch = signature.arch();
+    let both = !signature.lists.is_empty() && !signature.packages.is_empty();
+    let mut plans = Vec::new();
+    if !signature.lists.is_empty() {
+        let lists_name = if both { format!("{name}-lists") } else { name.clone() };
+        plans.push((lists_name, apt_offline::plan(signature.lists, RemoteMode::Update, arch.clone())?));
+    }
+    if !signature.packages.is_empty() {
+        plans.push((name.clone(), apt_offline::plan(signature.packages, RemoteMode::Install, arch)?));
+    }
+    if plans.is_empty() {
+        return Err(anyhow!("{} lists no files", path.display())).classify(ExitCode::Usage);
+    }
+
+    // Refuse plain-HTTP sources and weak hashes before anything is recorded
+    for (_, uri_file) in &plans {
+        policy::check_https(uri_file.packages.values().map(|pkg| pkg.uri.as_str()))?;
+        if uri_file.mode != RemoteMode::Update {
+            policy::check_strong_hash(uri_file.packages.iter())?;
+        }
+    }
+
+    for (name, uri_file) in &plans {
+        let cache_dir = config::cache_dir()?.join(name);
+        if !dry_run::skip("create", cache_dir.display().to_string()) {
+            fs::create_dir_all(&cache_dir)?;
+        }
+        let _lock = flock::lock_image(&cache_dir)?;
+
+        if uri_file.mode == RemoteMode::Update {
+            output::print(format!("The following {} sources will be stored in {name}:\n", uri_file.packages.len()));
+            let mut uris = uri_file.packages.values().map(|pkg| pkg.uri.as_str()).collect::<Vec<_>>();
+            uris.sort();
+            for uri in uris {
+                output::print(format!("\t{uri}"));
+            }
+        } else {
+            output::print(format!("The following {} packages will be stored in {name}:\n", uri_file.install_order.len()));
+            for fname in &uri_file.install_order {
+                output::print(format!("\t{} ({})", fname, format_size(uri_file.packages[fname].size)));
+            }
+        }
+        set::save(name, &cache_dir, uri_file, &LockFile::new(uri_file, "", ""))?;
+    }
+
+    let names = plans.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Imported {} from {}", names.join(" and "), path.display()))
+    ));
+    output::print(format!("\tDownload with 'apt-remote get {}'", names.join("' and 'apt-remote get ")));
+    output::print("\n");
+    output::emit("result", json!({ "command": "import", "names": names, "path": path, "format": "apt-offline-sig" }));
+    Ok(())
+}
+
+/// Fill the image `--name` with the files of an apt-offline bundle that
+/// its plan lists. Packages are checked against the plan's checksums and
+/// lists are uncompressed, as `get` does.
+///
+/// # Errors
+/// Returns an error if no image is named, the image has no plan or is
+/// encrypted, the bundle cannot be read, or a package does not match its
+/// checksum.
+fn import_apt_offline(args: &ImportArgs) -> Result<()> {
+    let bundle = &args.bundle;
+    let name = args
+        .name
+        .as_ref()
+        .context("Name the image whose plan the bundle was fetched for with --name")
+        .classify(ExitCode::Usage)?;
+    let cache_dir = config::cache_dir()?.join(name);
+    let _lock = flock::lock_image(&cache_dir)?;
+    let uri_file = UriFile::load(uri::path_in(&cache_dir))
+        .with_context(|| format!("{name} has no plan: import the signature the bundle was fetched for first"))
+        .classify(ExitCode::Cache)?;
+    if crypt::is_encrypted(&cache_dir) {
+        return Err(anyhow!("{name} is encrypted: decrypt it before importing into it")).classify(ExitCode::Usage);
+    }
+    let archive = zip::Archive::open(bundle).classify(ExitCode::Usage)?;
+
+    let data_dir = cache_dir.join(match uri_file.mode {
+        RemoteMode::Install | RemoteMode::Upgrade => "debs",
+        RemoteMode::Update => "sources",
+    });
+    if dry_run::skip("import", format!("{} -> {}", bundle.display(), data_dir.display())) {
+        return Ok(());
+    }
+    fs::create_dir_all(&data_dir).with_context(|| format!("Failed to create {}", data_dir.display()))?;
+
+    // Extract each file the plan lists next to its destination, then move it there
+    let mut state = ImageState::load(&cache_dir)?;
+    let mut imported = 0;
+    let mut ignored = Vec::new();
+    let part = data_dir.join(".apt-offline.part");
+    for entry in &archive.entries {
+        let Some(fname) = apt_offline::plan_file(&uri_file, &entry.name) else {
+            ignored.push(entry.name.as_str());
+            continue;
+        };
+        let pkg = &uri_file.packages[fname];
+        archive.extract(entry, &part)?;
+        if uri_file.mode == RemoteMode::Update {
+            let dest = data_dir.join(fname.strip_suffix(".xz").unwrap_or(fname));
+            apt_offline::unpack_list(&part, &dest)?;
+            fs::remove_file(&part)?;
+        } else {
+            if let Some(checksum) = &pkg.checksum
+                && !checksum.matches_file(&part)?
+            {
+                fs::remove_file(&part)?;
+                return Err(anyhow!("{} in {} does not match its checksum", entry.name, bundle.display()))
+                    .classify(ExitCode::Checksum);
+            }
+            fs::rename(&part, data_dir.join(fname))?;
+        }
+        state.forget_file(fname);
+        state.files.insert(fname.to_string(), "imported".to_string());
+        imported += 1;
+    }
+    state.save(&cache_dir)?;
+
+    let missing = uri_file
+        .packages
+        .keys()
+        .filter(|fname| !data_dir.join(fname).is_file() && !data_dir.join(fname.strip_suffix(".xz").unwrap_or(fname)).is_file())
+        .count();
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Imported {imported} files from {} into {name}", bundle.display()))
+    ));
+    if !ignored.is_empty() {
+        output::print(format!("\t{}", theme::warning(format!("Not in the plan, ignored: {}", ignored.join(", ")))));
+    }
+    if missing > 0 {
+        output::print(format!("\t{}", theme::warning(format!("{missing} files of the plan are still missing: run 'apt-remote get {name}'"))));
+    }
+    output::print("\n");
+    output::emit(
+        "result",
+        json!({
+            "command": "import",
+            "name": name,
+            "path": cache_dir,
+            "format": "apt-offline",
+            "files": imported,
+            "ignored": ignored,
+            "missing": missing,
+        }),
+    );
+    Ok(())
+}
+
+/// The image files of an unsigned bundle, as `/`-separated relative paths.
+fn unsigned_files(bundle: &Path, uri_file: &UriFile) -> Result<Vec<String>> {
+    let uri_name = uri::path_in(bundle).file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let mut files = vec![uri_name];
+    for file in ["uri.lock", advisory::FILE_NAME, preseed::FILE_NAME] {
+        if bundle.join(file).is_file() {
+            files.push(file.to_string());
+        }
+    }
+    let data_dir = match uri_file.mode {
+        RemoteMode::Install | RemoteMode::Upgrade => "debs",
+        RemoteMode::Update => "sources",
+    };
+    let dir = bundle.join(data_dir);
+    for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(format!("{data_dir}/{}", entry.file_name().to_string_lossy()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Turn a manifest path into a relative path that stays inside the bundle.
+fn safe_path(relative: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(relative);
+    if path.components().all(|c| matches!(c, Component::Normal(_))) {
+        Ok(path)
+    } else {
+        Err(anyhow!("Refusing to import {relative}: path leaves the bundle")).classify(ExitCode::Checksum)
+    }
+}
@@ -7,17 +7,36 @@
 //! 2. Verifying checksums remotely.
 //! 3. Installing packages via `dpkg`.
 //! 4. Cleaning up temporary files on the remote system.
-
-use crate::ssh::{RemoteExecutor, SecureUpload, create_ssh_session};
-use crate::uri::{ChecksumKind, UriFile, RemoteMode};
+//!
+//! Packages of encrypted images (see [`crate::crypt`]) are decrypted into a
+//! private temporary directory one at a time as they are uploaded.
+//!
+//! With `--rootless`, steps 3 and 4 are left to an administrator: the
+//! packages are staged in the remote user's home with an `install.sh` (see
+//! [`crate::staging`]).
+//!
+//! The debconf preseed of an image (see [`crate::preseed`]) is loaded before
+//! step 3.
+
+use crate::{checksum, config, crypt, deb, dry_run, flock, footprint, inventory, output, policy, preseed, probe, progress, theme, tr};
+use crate::progress::ProgressReporter;
+use crate::secret;
+use crate::ssh::shell_quote;
+use crate::signing;
+use crate::staging;
+use crate::commands::set::format_size;
+use crate::error::Error;
+use crate::state::{self, ImageState, TargetState};
+use crate::summary::{self, Outcome};
+use crate::transport::{self, Transport};
+use crate::uri::{self, UriFile, RemoteMode};
 
 use anyhow::{Context, Result};
 use clap::Args;
 use colored::Colorize;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use ssh2::Session;
+use serde_json::json;
 
-use std::{path::Path, time::Duration};
+use std::{collections::BTreeMap, path::Path, sync::Mutex, thread};
 
 /// CLI arguments for the `apt-remote install` subcommand.
 ///
@@ -29,312 +48,619 @@ use std::{path::Path, time::Duration};
 #[command(override_usage="apt-remote install <NAME> --target <user@host>")]
 pub struct InstallArgs {
     /// Cache image name (required)
-    name: String,
+    pub name: String,
 
-    /// Remote target SSH (user@host)
+    /// Remote target SSH (user@host or @group), defaults to `target` in config.toml
     #[arg(short, long)]
-    target: String,
+    pub target: Option<String>,
+
+    /// Install every package, even those recorded as already installed on the target
+    #[arg(long)]
+    pub reinstall: bool,
+
+    /// Also compare the checksum of every cached package before uploading (sizes are always compared)
+    #[arg(long)]
+    pub verify_hashes: bool,
 }
 
 /// Executes the `install` subcommand.
 ///
 /// - Connects to the target machine via SSH.
+/// - Skips packages already installed on the target (unless `--reinstall`).
+/// - Lists the packages and asks for confirmation (unless `--yes`).
+/// - Checks the cached packages' sizes (and checksums with `--verify-hashes`).
 /// - Uploads cached `.deb` packages.
 /// - Verifies their checksums remotely.
 /// - Installs them using `dpkg`.
-/// - Moves them to `/var/cache/apt/archives` for APT use.
+/// - Moves them to APT's archives directory (`Dir::Cache::archives`).
 ///
 /// # Errors
-/// Fails if SSH connection, upload, checksum verification, or installation fails.
+/// Fails if SSH connection, upload, checksum verification, or installation
+/// fails. Install errors are reported only after the remote temporary
+/// directory has been cleaned up.
+///
+/// With an `@group` target, the image is installed on every member of the
+/// inventory group in turn (see [`crate::inventory`]).
 pub fn run(args: InstallArgs) -> Result<()> {
-    let name = &args.name;
-    let target = &args.target;
+    inventory::for_each_target(args.target.as_ref(), |target| install(&args, target))
+}
 
-    // Create SSH session to remote target
-    let session = create_ssh_session(target)?;
+/// Install image `args.name` on a single `user@host` target.
+fn install(args: &InstallArgs, target: &str) -> Result<()> {
+    let name = args.name.as_str();
+    let target = &target.to_string();
 
-    // Detect the remote username
-    let user = session.exec("whoami")?;
-    let user = user.trim();
+    // Create SSH session to remote target
+    let session = transport::connect(target)?;
 
-    // Prompt for sudo password
-    let password = rpassword::prompt_password(format!("[sudo] password for {}: ", user))
-        .ok()
-        .unwrap();
+    // What is known about the target: its user, APT directories and tools
+    let probe = probe::get(&*session, target)?;
+    let user = probe.user.as_str();
 
     // Locate local cache for this image
-    let cache_dir = dirs::cache_dir()
-        .context("Failed to get cache dir")?
-        .join("apt-remote")
-        .join(name);
+    let cache_dir = config::cache_dir()?.join(name);
+
+    // Hold the image for the rest of the command
+    let _lock = flock::lock_image(&cache_dir)?;
 
     // Load package metadata from uri.toml
-    let mut uri_file = UriFile::load(&cache_dir.join("uri.toml"))
+    let mut uri_file = UriFile::load(uri::path_in(&cache_dir))
         .context("Failed to load uri.toml metadata")?;
 
     // Prevent running install in Update mode (that’s handled by `apt-remote update`)
     if uri_file.mode == RemoteMode::Update {
-        println!("This uri file is in update mode: please run 'apt-remote update <NAME> --target <user@host>");
+        output::print("This uri file is in update mode: please run 'apt-remote update <NAME> --target <user@host>");
+        output::emit(
+            "result",
+            json!({ "command": "install", "name": name, "status": "skipped", "reason": "image is in update mode" }),
+        );
         return Ok(());
     }
 
-    // Prepare remote working directory
-    let remote_str = format!("/tmp/apt-remote/{name}");
+    // Skip packages this image already installed on the target
+    let mut state = ImageState::load(&cache_dir)?;
+    if !args.reinstall {
+        let installed = &state.target(target).installed;
+        let skipped = uri_file.install_order.iter().filter(|f| installed.contains(*f)).count();
+        (0..skipped).for_each(|_| summary::record(Outcome::Skipped));
+        if skipped > 0 {
+            uri_file.packages.retain(|fname, _| !installed.contains(fname));
+            uri_file.install_order.retain(|fname| !installed.contains(fname));
+            output::print(format!(
+                "{skipped} packages are already installed on {target} (pass --reinstall to install them again)\n"
+            ));
+        }
+        if uri_file.install_order.is_empty() {
+            output::print(format!("{} {}", theme::success("✓").bold(), theme::success(format!("{name} is already installed on {target}"))));
+            output::emit(
+                "result",
+                json!({ "command": "install", "name": name, "target": target, "status": "up_to_date" }),
+            );
+            return Ok(());
+        }
+    }
+
+    // The target must be the machine the image was set for
+    policy::check_host(&*session, target, &cache_dir)?;
+
+    // An imported bundle must still match its signature
+    signing::verify_image(&cache_dir)?;
+
+    // Unlock an encrypted image before asking anything else (nothing is uploaded in a dry run)
+    let key = if dry_run::enabled() { None } else { crypt::unlock(&cache_dir, name)? };
+
+    // Estimate what the packages add to the target once installed (staged
+    // packages are not installed)
+    let rootless = staging::enabled();
+    let estimate = if rootless { None } else { footprint::estimate(&*session, &uri_file)? };
+
+    // Show what will be installed and ask before touching the remote system
+    let verb = if rootless { "staged" } else { "installed" };
+    output::print(format!("The following {} packages will be {verb} on {target}:\n", uri_file.install_order.len()));
+    for fname in &uri_file.install_order {
+        let size = uri_file.packages.get(fname).map_or(0, |pkg| pkg.size);
+        output::print(format!("\t{fname} ({})", format_size(size)));
+    }
+    if let Some(total_size) = uri_file.total_size {
+        output::print(format!("\nTotal size: {}", format_size(total_size)));
+    }
+    if let Some(estimate) = &estimate {
+        let unknown = match estimate.unknown {
+            0 => String::new(),
+            n => format!(" (not counting {n} packages of unknown size)"),
+        };
+        output::print(format!("Additional disk space on {target}: {}{unknown}", format_size(estimate.growth())));
+    }
+    output::print("");
+    if !rootless {
+        output::confirm(&tr!("Install {name} on {target}?", name = name, target = target))?;
+    }
+
+    // Catch packages damaged or replaced since `get` before anything is sent
+    verify_cache(name, &uri_file, &cache_dir, key.as_ref(), args.verify_hashes)?;
+
+    // Sudo password, checked before use (not needed when nothing is run as root)
+    let password = if dry_run::enabled() || rootless {
+        String::new()
+    } else {
+        secret::sudo_password(&*session, user, target)?
+    };
+
+    // Prepare remote working directory (a fresh one in the user's home when staging)
+    let stage_dir = if rootless { Some(staging::dir(&*session, name)?) } else { None };
+    let remote_str = match &stage_dir {
+        Some(stage_dir) => {
+            session.exec(&format!("rm -rf {}", shell_quote(&format!("{stage_dir}/debs"))))?;
+            let target_state = state.target(target);
+            target_state.uploaded.clear();
+            target_state.verified.clear();
+            format!("{stage_dir}/debs")
+        }
+        None => format!("/tmp/apt-remote/{name}"),
+    };
     let remote_path = Path::new(&remote_str);
-    session.exec(&format!("mkdir -p {}", remote_str))?;
-    session.exec(&format!("cd {}", remote_str))?;
+    session.exec(&format!("mkdir -p {}", shell_quote(&remote_str)))?;
 
-    let progress = MultiProgress::new();
+    // Make sure the packages still to be uploaded fit, along with what they
+    // add to the root filesystem once installed
+    let needed = uri_file
+        .packages
+        .iter()
+        .filter(|(fname, _)| !state.target(target).uploaded.contains(*fname))
+        .map(|(_, pkg)| pkg.size)
+        .sum::<u64>();
+    let growth = estimate.as_ref().map_or(0, footprint::Estimate::growth);
+    let space = available_space(&*session, &[&remote_str, "/"])?;
+    let root = space.get(1);
+    if let Some((mount, available)) = space.first() {
+        // Both count against the same filesystem when the upload is on it
+        let shared = root.is_some_and(|(root_mount, _)| root_mount == mount);
+        let needed = if shared { needed + growth } else { needed };
+        if *available < needed {
+            return Err(Error::DiskSpace {
+                target: target.clone(),
+                path: remote_path.to_path_buf(),
+                needed,
+                available: *available,
+            }
+            .into());
+        }
+        if !shared
+            && let Some((_, available)) = root
+            && *available < growth
+        {
+            return Err(Error::DiskSpace {
+                target: target.clone(),
+                path: "/".into(),
+                needed: growth,
+                available: *available,
+            }
+            .into());
+        }
+    }
+    let progress = progress::reporter();
+    state.target(target).last_run = Some(state::now());
 
     // Step 1: Upload archive to remote host
-    upload_archive(
-        &session,
+    let phase = summary::phase("upload");
+    let uploaded = upload_archive(
+        &*session,
         name,
-        &user,
+        target,
         &mut uri_file,
         &cache_dir,
-        &remote_path,
-        &progress,
-    )?;
+        key.as_ref(),
+        remote_path,
+        &*progress,
+        state.target(target),
+    );
+    drop(phase);
+    state.save(&cache_dir)?;
+    uploaded?;
 
     // Step 2: Verify file checksums remotely
-    if let Err(err) = verify_remote_checksums(&session, &mut uri_file, &remote_path, &progress) {
-        // Return to home directory before exiting on error
-        session.exec("cd $HOME")?;
-        return Err(err);
+    let phase = summary::phase("verify");
+    let verified = verify_remote_checksums(&*session, target, &probe.checksum_tools(), &mut uri_file, remote_path, &*progress, state.target(target));
+    drop(phase);
+    state.save(&cache_dir)?;
+    verified?;
+
+    // Leave installing to an administrator when staging (the installer loads the preseed)
+    if let Some(stage_dir) = &stage_dir {
+        let preseed = cache_dir.join(preseed::FILE_NAME);
+        if preseed.is_file() {
+            session.upload(&preseed, &Path::new(stage_dir).join(preseed::FILE_NAME))?;
+        }
+        let script = staging::finish(&*session, name, target, &uri_file, &BTreeMap::new(), stage_dir)?;
+
+        // The staged files are not in the temporary directory of a later run
+        let target_state = state.target(target);
+        target_state.uploaded.clear();
+        target_state.verified.clear();
+        state.save(&cache_dir)?;
+
+        output::emit(
+            "result",
+            json!({ "command": "install", "name": name, "target": target, "status": "staged", "installer": script }),
+        );
+        return Ok(());
+    }
+
+    // Answer debconf's questions before the packages ask them
+    if preseed::apply(&*session, &password, &cache_dir, remote_path)? {
+        output::print(format!("{} {}", theme::success("✓").bold(), theme::success("Preseeded debconf")));
+        output::emit("preseed", json!({ "target": target, "ok": true }));
     }
 
     // Step 3: Install packages on remote host
-    install_archive(
-        &session,
+    let phase = summary::phase("install");
+    let failed = install_archive(
+        &*session,
         &password,
-        &name,
+        name,
         &mut uri_file,
-        &remote_path,
-        &progress,
-    )?;
+        remote_path,
+        &*progress,
+        state.target(target),
+    );
+    drop(phase);
+    state.save(&cache_dir)?;
+    let failed = failed?;
 
     // Step 4: Move packages to APT cache and clean up temp dir
-    session.sudo(
-        &format!(
-            "mv {} /var/cache/apt/archives",
-            remote_path.join("*").to_str().unwrap()
-        ),
-        &password,
-    )?;
-    session.exec(&format!("rm -rf {remote_str}"))?;
+    let _phase = summary::phase("cleanup");
+    session.sudo_in(&remote_str, &format!("mv ./* {}", shell_quote(&probe.apt.archives)), &password)?;
+    session.exec(&format!("rm -rf {}", shell_quote(&remote_str)))?;
+
+    // The temporary directory is gone, so nothing is left uploaded
+    let target_state = state.target(target);
+    target_state.uploaded.clear();
+    target_state.verified.clear();
+    state.save(&cache_dir)?;
+
+    if failed > 0 {
+        return Err(Error::InstallFailed { name: name.to_string(), target: target.clone(), failed }.into());
+    }
 
+    output::emit(
+        "result",
+        json!({ "command": "install", "name": name, "target": target, "status": "installed" }),
+    );
+    Ok(())
+}
+
+/// Compare the cached packages with the plan: every file must exist and have
+/// the size recorded in `uri.toml` and, with `hashes`, its checksum.
+///
+/// Encrypted files are compared with the size of their plaintext, and hashed
+/// after decryption (not at all in a dry run, which has no key).
+///
+/// # Errors
+/// Returns [`Error::InvalidPackage`] listing the files that do not match.
+fn verify_cache(name: &str, uri_file: &UriFile, cache_dir: &Path, key: Option<&crypt::Key>, hashes: bool) -> Result<()> {
+    let archive_path = cache_dir.join("debs");
+    let overhead = if crypt::is_encrypted(cache_dir) { crypt::OVERHEAD } else { 0 };
+    let reporter = progress::reporter();
+    let progress = reporter.bar(uri_file.install_order.len() as u64);
+    progress.message(&format!("Checking cached packages of {name}..."));
+
+    let mut invalid = Vec::new();
+    for fname in &uri_file.install_order {
+        let Some(pkg) = uri_file.packages.get(fname) else { continue };
+        let file_path = archive_path.join(fname);
+        let problem = match file_path.metadata() {
+            Err(_) => Some("missing from the cache".to_string()),
+            Ok(meta) if meta.len() != pkg.size + overhead => {
+                Some(format!("{} bytes instead of {}", meta.len().saturating_sub(overhead), pkg.size))
+            }
+            Ok(_) => match (&pkg.checksum, hashes && (overhead == 0 || key.is_some())) {
+                (Some(checksum), true) => {
+                    let matches = crypt::Plaintext::of(key, &file_path).and_then(|plain| checksum.matches_file(plain.path()));
+                    match matches {
+                        Ok(true) => None,
+                        Ok(false) => Some(format!("{:?} checksum mismatch", checksum.kind)),
+                        Err(e) => Some(e.to_string()),
+                    }
+                }
+                _ => None,
+            },
+        };
+        if let Some(problem) = problem {
+            reporter.task().finish(&format!(
+                "{} {}: {}",
+                theme::failure("✗").bold(),
+                theme::failure(format!("Invalid package: {fname}")),
+                problem.dimmed()
+            ));
+            output::emit("cache_check", json!({ "file": fname, "ok": false, "error": problem }));
+            invalid.push(fname.clone());
+        }
+        progress.inc(1);
+    }
+
+    if !invalid.is_empty() {
+        progress.clear();
+        return Err(Error::InvalidPackage { name: name.to_string(), files: invalid }.into());
+    }
+    progress.finish(&format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Checked {} cached packages", uri_file.install_order.len()))
+    ));
     Ok(())
 }
 
 /// Uploads all `.deb` packages from local cache to the remote system.
+///
+/// Files recorded as uploaded by an interrupted earlier run are not sent again.
+/// Up to `ssh.upload_jobs` files are uploaded at once, as far as the
+/// transport allows (see [`Transport::upload_jobs`]), so preparing a file
+/// overlaps with sending the others.
+#[allow(clippy::too_many_arguments)]
 fn upload_archive(
-    session: &Session,
+    session: &dyn Transport,
     name: &str,
-    user: &str,
+    target: &str,
     uri_file: &mut UriFile,
     cache_dir: &Path,
+    key: Option<&crypt::Key>,
     remote_path: &Path,
-    progress: &MultiProgress,
+    progress: &dyn ProgressReporter,
+    state: &mut TargetState,
 ) -> Result<()> {
-    let progress_upload = progress.add(ProgressBar::new(uri_file.packages.len() as u64));
-    progress_upload.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "[{elapsed_precise}] {msg:25} [{wide_bar:.bold.cyan}] {pos}/{len} ({eta} remaining)",
-            )
-            .unwrap()
-            .progress_chars("##-"),
-    );
-    progress_upload.enable_steady_tick(Duration::from_millis(100));
-    progress_upload.set_message(format!("Uploading {name} to {user}..."));
+    let progress_upload = progress.bar(uri_file.packages.len() as u64);
+    progress_upload.message(&format!("Uploading {name} to {target}..."));
 
     let archive_path = cache_dir.join("debs");
+    let state = Mutex::new(state);
+    let failed = Mutex::new(0);
+    let invalid = Mutex::new(Vec::new());
+
+    // Send one file over SCP
+    let send = |fname: &String| {
+        if state.lock().unwrap().uploaded.contains(fname) {
+            output::emit("upload", json!({ "file": fname, "ok": true, "resumed": true }));
+            progress_upload.inc(1);
+            return;
+        }
 
-    // Send each file over SCP
-    uri_file
-        .packages
-        .iter()
-        .for_each(|(fname, _)| {
-            let spinner = progress.add(ProgressBar::new_spinner());
-            spinner.set_style(
-                ProgressStyle::with_template("\t{spinner:.bold.cyan} {msg}")
-                    .unwrap()
-                    .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ "),
-            );
-            spinner.enable_steady_tick(Duration::from_millis(100));
-            spinner.set_message(format!("{fname}"));
-
-            let file_path = archive_path.join(fname);
-            let status = session.scp_upload(&file_path, &remote_path.join(fname));
-
-            if let Err(e) = status {
-                spinner.finish_with_message(format!(
-                    "{} {}: {}",
-                    "✗".red().bold(),
-                    format!("File not sent: {fname}").red(),
-                    e.to_string().dimmed()
-                ));
+        let file_path = archive_path.join(fname);
+        let spinner = progress.transfer(file_path.metadata().map_or(0, |m| m.len()));
+        spinner.message(fname);
+
+        // Damaged packages are caught here rather than by dpkg on the target
+        let status = crypt::Plaintext::of(key, &file_path).and_then(|plain| {
+            if let Some(problem) = deb::problem(plain.path(), fname)? {
+                return Ok(Some(problem));
             }
+            session.upload_with_progress(plain.path(), &remote_path.join(fname), &progress::bytes(&*spinner)).map(|_| None)
+        });
 
-            spinner.finish_and_clear();
-            progress_upload.inc(1);
+        if let Ok(Some(problem)) = &status {
+            spinner.finish(&format!(
+                "{} {}: {}",
+                theme::failure("✗").bold(),
+                theme::failure(format!("Invalid package: {fname}")),
+                problem.dimmed()
+            ));
+            output::emit("upload", json!({ "file": fname, "ok": false, "error": problem, "invalid": true }));
+            invalid.lock().unwrap().push(fname.clone());
+        } else if let Err(e) = status {
+            spinner.finish(&format!(
+                "{} {}: {}",
+                theme::failure("✗").bold(),
+                theme::failure(format!("File not sent: {fname}")),
+                e.to_string().dimmed()
+            ));
+            output::emit("upload", json!({ "file": fname, "ok": false, "error": e.to_string() }));
+            *failed.lock().unwrap() += 1;
+        } else {
+            output::emit("upload", json!({ "file": fname, "ok": true }));
+            state.lock().unwrap().uploaded.insert(fname.clone());
+            summary::uploaded(file_path.metadata().map_or(0, |m| m.len()));
+        }
+
+        spinner.clear();
+        progress_upload.inc(1);
+    };
+
+    // Up to `upload_jobs` files are sent at once, each taking the next file left
+    let queue = Mutex::new(uri_file.packages.keys());
+    thread::scope(|scope| {
+        for _ in 0..session.upload_jobs().min(uri_file.packages.len()) {
+            scope.spawn(|| {
+                loop {
+                    let next = queue.lock().unwrap().next();
+                    let Some(fname) = next else { break };
+                    send(fname);
+                }
+            });
+        }
     });
+    let failed = failed.into_inner().unwrap();
+    let invalid = invalid.into_inner().unwrap();
+
+    if !invalid.is_empty() {
+        return Err(Error::InvalidPackage { name: name.to_string(), files: invalid }.into());
+    }
+    if failed > 0 {
+        return Err(Error::UploadFailed { target: target.to_string(), failed }.into());
+    }
 
-    progress_upload.finish_with_message(format!(
+    progress_upload.finish(&format!(
         "{} {}",
-        "✓".green().bold(),
-        format!("Uploaded {name}").green()
+        theme::success("✓").bold(),
+        theme::success(format!("Uploaded {name}"))
     ));
     Ok(())
 }
 
 /// Verifies checksums of uploaded files on the remote host.
 ///
-/// Uses either `sha256sum` or `md5sum` based on the package metadata.
+/// Uses the SHA-256 or MD5 tool the target has (see [`checksum::Tools`]),
+/// based on the package metadata.
 fn verify_remote_checksums(
-    session: &ssh2::Session,
+    session: &dyn Transport,
+    target: &str,
+    tools: &checksum::Tools,
     uri_file: &mut UriFile,
     remote_path: &Path,
-    progress: &MultiProgress,
+    progress: &dyn ProgressReporter,
+    state: &mut TargetState,
 ) -> Result<()> {
-    let progress_verify = progress.add(ProgressBar::new(uri_file.packages.len() as u64));
-    progress_verify.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "[{elapsed_precise}] {msg:25} [{wide_bar:.bold.cyan}] {pos}/{len} ({eta} remaining)",
-            )
-            .unwrap()
-            .progress_chars("##-"),
-    );
-    progress_verify.enable_steady_tick(Duration::from_millis(100));
-    progress_verify.set_message(format!("Verifying checksums..."));
+    let progress_verify = progress.bar(uri_file.packages.len() as u64);
+    progress_verify.message("Verifying checksums...");
 
     let mut mismatches = Vec::new();
 
     // Check each file's checksum remotely
-    for (fname, pkg_info) in progress_verify.wrap_iter(&mut uri_file.packages.iter()) {
-        let spinner = progress.add(ProgressBar::new_spinner());
-        spinner.set_style(
-            ProgressStyle::with_template("\t{spinner:.bold.cyan} {msg}")
-                .unwrap()
-                .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ "),
-        );
-        spinner.enable_steady_tick(Duration::from_millis(100));
-        spinner.set_message(format!("{fname}"));
+    for (fname, pkg_info) in &uri_file.packages {
+        let spinner = progress.task();
+        spinner.message(fname);
 
-        let remote_path = remote_path.join(fname);
-        let expected_checksum = pkg_info.checksum.as_ref().unwrap().value.clone();
+        let expected = pkg_info.checksum.as_ref().unwrap();
+        let expected_checksum = expected.value.clone();
 
-        // Choose correct checksum tool
-        let checksum = match pkg_info.checksum.as_ref().unwrap().kind {
-            ChecksumKind::SHA256 => "sha256sum",
-            ChecksumKind::MD5 => "md5sum",
+        // Choose a checksum tool the target has
+        let Some(checksum) = tools.command(&expected.kind) else {
+            return Err(Error::NoChecksumTool { target: target.to_string(), kind: format!("{:?}", expected.kind) }.into());
         };
 
         let output = session
-            .exec(&format!("{checksum} {}", remote_path.to_str().unwrap()))
-            .context(format!("Failed to compute {checksum} for {fname}"))?;
+            .exec_in(remote_path.to_str().unwrap(), &format!("{checksum} ./{}", shell_quote(fname)))
+            .context(format!("Failed to compute the checksum of {fname}"))?;
 
         // Extract actual checksum from command output
-        let actual_checksum = output
-            .split_whitespace()
-            .next()
-            .unwrap_or("ERROR: checksum output unwrap failed.")
-            .to_string();
+        let actual_checksum = checksum::parse_digest(&output, &expected.kind)
+            .unwrap_or_else(|| format!("ERROR: no checksum in output: {}", output.trim()));
+
+        // Nothing was uploaded in a dry run
+        let ok = dry_run::enabled() || actual_checksum == expected_checksum;
+        output::emit(
+            "verify",
+            json!({ "file": fname, "ok": ok, "expected": expected_checksum, "actual": actual_checksum }),
+        );
 
-        if actual_checksum != expected_checksum {
+        if !ok {
+            // Send the file again on the next run
+            state.uploaded.remove(fname);
+            state.verified.remove(fname);
             mismatches.push((fname, expected_checksum, actual_checksum));
-            spinner.finish_with_message(format!(
+            spinner.finish(&format!(
                 "{} {}",
-                "✗".red().bold(),
-                format!("Checksum mismatch: {fname}").red()
+                theme::failure("✗").bold(),
+                theme::failure(format!("Checksum mismatch: {fname}"))
             ));
         } else {
-            spinner.finish_and_clear();
+            state.verified.insert(fname.clone());
+            spinner.clear();
         }
+        progress_verify.inc(1);
     }
 
     // Report result
     if mismatches.is_empty() {
-        progress_verify.finish_with_message(format!(
+        progress_verify.finish(&format!(
             "{} {}",
-            "✓".green().bold(),
-            "Checksums verified".green()
+            theme::success("✓").bold(),
+            theme::success("Checksums verified")
         ));
         Ok(())
     } else {
-        Err(anyhow::anyhow!("Remote checksum verification failed"))
+        Err(Error::ChecksumMismatch {
+            target: target.to_string(),
+            files: mismatches.into_iter().map(|(fname, _, _)| fname.clone()).collect(),
+        }
+        .into())
     }
 }
 
 /// Installs the uploaded packages on the remote host using `dpkg -i`.
+///
+/// Returns the number of packages that failed to install.
 fn install_archive(
-    session: &Session,
+    session: &dyn Transport,
     password: &str,
     name: &str,
     uri_file: &mut UriFile,
     remote_path: &Path,
-    progress: &MultiProgress,
-) -> Result<()> {
-    let progress_install = progress.add(ProgressBar::new(uri_file.packages.len() as u64));
-    progress_install.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "[{elapsed_precise}] {msg:25} [{wide_bar:.bold.cyan}] {pos}/{len} ({eta} remaining)",
-            )
-            .unwrap()
-            .progress_chars("##-"),
-    );
-    progress_install.set_message(format!("Installing {name}..."));
-    progress_install.enable_steady_tick(Duration::from_millis(100));
+    progress: &dyn ProgressReporter,
+    state: &mut TargetState,
+) -> Result<usize> {
+    let progress_install = progress.bar(uri_file.packages.len() as u64);
+    progress_install.message(&format!("Installing {name}..."));
+
+    let mut failed = 0;
 
     // Install packages in defined order
-    for fname in progress_install.wrap_iter(&mut uri_file.install_order.iter()) {
-        let spinner = progress.add(ProgressBar::new_spinner());
-        spinner.set_style(
-            ProgressStyle::with_template("\t{spinner:.bold.cyan} {msg}")
-                .unwrap()
-                .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ "),
-        );
-        spinner.enable_steady_tick(Duration::from_millis(100));
-        spinner.set_message(format!("{fname}"));
+    for fname in &uri_file.install_order {
+        let spinner = progress.task();
+        spinner.message(fname);
 
         let status = session
-            .sudo(
-                &format!("dpkg -i {}", remote_path.join(fname).to_str().unwrap()),
-                password,
-            )
+            .sudo_in(remote_path.to_str().unwrap(), &format!("dpkg -i ./{}", shell_quote(fname)), password)
             .context("dpkg install failed");
 
         if let Err(e) = status {
-            spinner.finish_with_message(format!(
+            spinner.finish(&format!(
                 "{} {}: {}",
-                "✗".red().bold(),
-                format!("File not installed: {fname}").red(),
+                theme::failure("✗").bold(),
+                theme::failure(format!("File not installed: {fname}")),
                 e.to_string().dimmed()
             ));
+            output::emit("install", json!({ "file": fname, "ok": false, "error": e.to_string() }));
+            summary::record(Outcome::Failed);
+            failed += 1;
+        } else {
+            output::emit("install", json!({ "file": fname, "ok": true }));
+            summary::record(Outcome::Succeeded);
+            state.installed.insert(fname.clone());
         }
 
-        spinner.finish_and_clear();
+        spinner.clear();
+        progress_install.inc(1);
     }
 
     // Final dpkg reconfiguration step
-    progress_install.set_message(format!("Reconfiguring {name}"));
-    if let Err(e) = session.sudo("dpkg --configure -a", &password) {
-        progress_install.finish_with_message(format!(
+    progress_install.message(&format!("Reconfiguring {name}"));
+    if let Err(e) = session.sudo("dpkg --configure -a", password) {
+        progress_install.finish(&format!(
             "{} {}: {}",
-            "✗".red().bold(),
-            "dpkg failed to reconfigure".red(),
+            theme::failure("✗").bold(),
+            theme::failure("dpkg failed to reconfigure"),
             e.to_string().dimmed()
         ));
+        output::emit("configure", json!({ "ok": false, "error": e.to_string() }));
+        failed += 1;
     } else {
-        progress_install.finish_with_message(format!(
+        output::emit("configure", json!({ "ok": true }));
+        progress_install.finish(&format!(
             "{} {}",
-            "✓".green().bold(),
-            format!("Installed and configured {name}").green()
+            theme::success("✓").bold(),
+            theme::success(format!("Installed and configured {name}"))
         ));
     }
-    println!("\n");
-    Ok(())
+    output::print("\n");
+    Ok(failed)
+}
+
+/// Mount point and free space in bytes of the filesystem holding each of
+/// `paths` on the remote host, in order.
+///
+/// Stops at the first path whose space is unknown, and returns nothing if
+/// `df` is unavailable.
+fn available_space(session: &dyn Transport, paths: &[&str]) -> Result<Vec<(String, u64)>> {
+    let paths = paths.iter().map(|path| shell_quote(path)).collect::<Vec<_>>();
+    let output = session.query(&format!("df -Pk {}", paths.join(" ")))?;
+    // `df -P` lines read "<fs> <blocks> <used> <available> <capacity> <mount>", in the order asked
+    let space = output
+        .lines()
+        .skip(1)
+        .map_while(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let kb = fields.get(3)?.parse::<u64>().ok()?;
+            Some((fields.get(5)?.to_string(), kb * 1024))
+        })
+        .collect();
+    Ok(space)
 }
@@ -0,0 +1,49 @@
+//! # `apt-remote keygen` command
+//!
+//! Creates an Ed25519 key pair for signing exported bundles (see
+//! [`crate::signing`]). The private key stays on the exporting machine; the
+//! public key is copied to the machines that import the bundles.
+
+use crate::{dry_run, output, signing, theme};
+
+use anyhow::Result;
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+use std::path::PathBuf;
+
+/// CLI arguments for the `apt-remote keygen` subcommand.
+#[derive(Args)]
+pub struct KeygenArgs {
+    /// Private key file to create (the public key is written next to it as <KEY>.pub)
+    #[arg(default_value = "~/.config/apt-remote/signing.key")]
+    pub key: PathBuf,
+}
+
+/// Executes the `keygen` subcommand.
+///
+/// # Errors
+/// Returns an error if either key file already exists or cannot be written.
+pub fn run(args: KeygenArgs) -> Result<()> {
+    let key = match (args.key.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => args.key.clone(),
+    };
+    let public = signing::public_path(&key);
+    if dry_run::skip("create", format!("{} and {}", key.display(), public.display())) {
+        return Ok(());
+    }
+
+    let id = signing::generate(&key)?;
+
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Created signing key {id}"))
+    ));
+    output::print(format!("\tPrivate key: {} (set `signing_key` to it)", key.display()));
+    output::print(format!("\tPublic key:  {} (add it to `trusted_keys` where bundles are imported)\n", public.display()));
+    output::emit("result", json!({ "command": "keygen", "key": id, "private": key, "public": public }));
+    Ok(())
+}
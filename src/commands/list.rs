@@ -0,0 +1,104 @@
+//! # `apt-remote list` command
+//!
+//! Lists the images in the cache with what `set` recorded about them: their
+//! mode and size, and the description, tags, intended target and creator
+//! given with `--description`, `--tag`, `--intended-target` and
+//! `--creator`. `--tag` only lists the images carrying every given tag.
+
+use crate::{
+    commands::{set::format_size, status::format_age},
+    config, output, state, theme,
+    uri::{self, UriFile},
+};
+
+use anyhow::Result;
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+use std::fs;
+
+/// CLI arguments for the `apt-remote list` subcommand.
+#[derive(Args)]
+pub struct ListArgs {
+    /// Only list images with this tag (repeatable or comma-separated: all must match)
+    #[arg(long = "tag", value_name = "TAG", value_delimiter = ',')]
+    pub tags: Vec<String>,
+}
+
+/// Executes the `list` subcommand.
+///
+/// Images whose `uri.toml` cannot be loaded are listed as unreadable.
+///
+/// # Errors
+/// Returns an error if the cache directory cannot be read.
+pub fn run(args: ListArgs) -> Result<()> {
+    let cache_dir = config::cache_dir()?;
+    let mut names = match fs::read_dir(&cache_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| uri::path_in(&entry.path()).is_file())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+
+    let now = state::now();
+    let mut images = Vec::new();
+    for name in names {
+        let uri_file = match UriFile::load(uri::path_in(&cache_dir.join(&name))) {
+            Ok(uri_file) => uri_file,
+            Err(e) => {
+                if args.tags.is_empty() {
+                    output::print(format!("{} {}", name.bold(), theme::failure(format!("(unreadable: {e})"))));
+                }
+                continue;
+            }
+        };
+        let meta = &uri_file.meta;
+        if !args.tags.iter().all(|tag| meta.tags.contains(tag)) {
+            continue;
+        }
+
+        let size = uri_file.total_size.map(|size| format!(", {}", format_size(size))).unwrap_or_default();
+        let tags = if meta.tags.is_empty() { String::new() } else { format!(" [{}]", meta.tags.join(", ")) };
+        output::print(format!(
+            "{} ({:?}, {} files{size}){}",
+            name.bold(),
+            uri_file.mode,
+            uri_file.packages.len(),
+            theme::accent(tags)
+        ));
+        if let Some(description) = &meta.description {
+            output::print(format!("\t{description}"));
+        }
+        let mut details = Vec::new();
+        if let Some(target) = &meta.intended_target {
+            details.push(format!("for {target}"));
+        }
+        if let Some(creator) = &meta.creator {
+            details.push(format!("by {creator}"));
+        }
+        if let Some(created) = meta.created {
+            details.push(format!("created {}", format_age(now.saturating_sub(created))));
+        }
+        if !details.is_empty() {
+            output::print(format!("\t{}", details.join(", ").dimmed()));
+        }
+
+        images.push(json!({
+            "name": name,
+            "mode": uri_file.mode,
+            "files": uri_file.packages.len(),
+            "total_size": uri_file.total_size,
+            "meta": uri_file.meta,
+        }));
+    }
+    if images.is_empty() {
+        output::print("No images");
+    }
+
+    output::emit("result", json!({ "command": "list", "images": images }));
+    Ok(())
+}
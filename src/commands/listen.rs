@@ -0,0 +1,200 @@
+//! # `apt-remote listen` command
+//!
+//! Field devices behind NAT or on a mobile network can dial out but cannot
+//! be reached. They can still be served: each device keeps an SSH tunnel
+//! open to the machine running apt-remote, forwarding a port of that
+//! machine to its own SSH server, and its inventory entry names that port
+//! (see [`crate::inventory`]):
+//!
+//! ```toml
+//! [hosts.kiosk-7]
+//! pull = 2207                     # the device runs: ssh -N -R 2207:localhost:22 tunnel@courier
+//! ```
+//!
+//! `apt-remote listen <NAME> --target @kiosks` then waits for the devices
+//! to dial in, and pushes the image to each one through its tunnel as soon
+//! as it is up: packages are installed with `install`, and lists replaced
+//! with `update`, depending on the image's mode. A device whose connection
+//! drops before it was served is served again when it dials in again. The
+//! command returns once every device was served, or when `--timeout`
+//! expires.
+//!
+//! While a tunnel is up, every other command reaches the device through it
+//! as well.
+
+use crate::{
+    commands::{
+        install::{self, InstallArgs},
+        update::{self, UpdateArgs},
+    },
+    config,
+    error::Error,
+    exit::{Classify, ExitCode},
+    inventory, output, theme,
+    uri::{self, RemoteMode, UriFile},
+};
+
+use anyhow::{Context, Result, anyhow};
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+use std::{
+    io::Read,
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often the tunnels of waiting devices are checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a tunnel may take to answer with an SSH banner.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// CLI arguments for the `apt-remote listen` subcommand.
+#[derive(Args)]
+pub struct ListenArgs {
+    /// Cache image name (required)
+    pub name: String,
+
+    /// Devices to wait for (user@host or @group), defaults to `target` in config.toml
+    #[arg(short, long)]
+    pub target: Option<String>,
+
+    /// Give up on devices that have not dialed in after this many minutes
+    #[arg(long, value_name = "MINUTES")]
+    pub timeout: Option<u64>,
+}
+
+/// Executes the `listen` subcommand.
+///
+/// # Errors
+/// Returns an error if the image has not been downloaded, if a target has
+/// no `pull` port in the inventory, or if a device failed or did not dial
+/// in before the timeout ([`Error::GroupFailed`] for a group).
+pub fn run(args: ListenArgs) -> Result<()> {
+    let name = &args.name;
+    let cache_dir = config::cache_dir()?.join(name);
+    let uri_file = UriFile::load(uri::path_in(&cache_dir)).context("Failed to load uri.toml metadata")?;
+    let dir = match uri_file.mode {
+        RemoteMode::Install | RemoteMode::Upgrade => "debs",
+        RemoteMode::Update => "sources",
+    };
+    if !cache_dir.join(dir).is_dir() {
+        return Err(Error::ImageNotDownloaded { name: name.clone() }.into());
+    }
+
+    // Every device must dial in through a port of its own
+    let target = config::target(args.target.as_ref())?;
+    let inventory = inventory::get()?;
+    let members = match target.strip_prefix('@') {
+        Some(group) => inventory.group(group)?,
+        None => vec![target.clone()],
+    };
+    let mut waiting = Vec::new();
+    for member in members {
+        let endpoint = inventory.endpoint(&member)?;
+        if !endpoint.pull {
+            return Err(anyhow!("{member} does not dial in: set `pull` in its inventory entry"))
+                .classify(ExitCode::Config);
+        }
+        waiting.push((member, endpoint.port));
+    }
+
+    let names = waiting.iter().map(|(member, _)| member.as_str()).collect::<Vec<_>>();
+    output::print(format!("{} for {} to dial in...", theme::accent("Waiting").bold(), names.join(", ")));
+    let deadline = args.timeout.map(|minutes| Instant::now() + Duration::from_secs(minutes * 60));
+    let mut outcomes = Vec::new();
+    loop {
+        let mut still_waiting = Vec::new();
+        for (member, port) in waiting {
+            if !dialed_in(port) {
+                still_waiting.push((member, port));
+                continue;
+            }
+
+            output::print(format!("\n{}", theme::accent(format!("{member} dialed in:")).bold()));
+            output::emit("listen", json!({ "target": member, "status": "connected" }));
+            match serve(name, &uri_file.mode, &member) {
+                // The tunnel went down: serve the device when it is back
+                Err(e) if ExitCode::of(&e) == ExitCode::Connect => {
+                    output::print(format!(
+                        "{} {}",
+                        theme::warning("!").bold(),
+                        theme::warning(format!("Lost {member} ({e:#}), waiting for it to dial in again"))
+                    ));
+                    still_waiting.push((member, port));
+                }
+                outcome => outcomes.push((member, outcome)),
+            }
+        }
+        waiting = still_waiting;
+
+        if waiting.is_empty() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // Report every device, served or not
+    output::print(format!("\n{}", format!("{target}:").bold()));
+    for (member, outcome) in &outcomes {
+        match outcome {
+            Ok(()) => output::print(format!("\t{} {member}", theme::success("✓"))),
+            Err(e) => output::print(format!("\t{} {member}: {e}", theme::failure("✗"))),
+        }
+        output::emit(
+            "listen",
+            json!({
+                "target": member,
+                "status": if outcome.is_ok() { "ok" } else { "failed" },
+                "error": outcome.as_ref().err().map(|e| format!("{e:#}")),
+            }),
+        );
+    }
+    for (member, _) in &waiting {
+        output::print(format!("\t{} {member}: did not dial in", theme::failure("✗")));
+        output::emit("listen", json!({ "target": member, "status": "timeout" }));
+    }
+
+    let failed = outcomes.iter().filter(|(_, outcome)| outcome.is_err()).count() + waiting.len();
+    let total = outcomes.len() + waiting.len();
+    if let Some(group) = target.strip_prefix('@') {
+        if failed > 0 {
+            return Err(Error::GroupFailed { group: group.to_string(), failed, total }.into());
+        }
+        return Ok(());
+    }
+    match outcomes.pop() {
+        Some((_, outcome)) => outcome,
+        None => Err(anyhow!("{target} did not dial in before the timeout")).classify(ExitCode::Connect),
+    }
+}
+
+/// Whether a device's SSH server answers through the tunnel on `port`.
+///
+/// The port of a tunnel that is down is closed; the port of a tunnel whose
+/// device has no SSH server running is closed again without a banner.
+fn dialed_in(port: u16) -> bool {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let Ok(mut stream) = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT) else {
+        return false;
+    };
+    stream.set_read_timeout(Some(PROBE_TIMEOUT)).ok();
+    let mut banner = [0u8; 4];
+    stream.read_exact(&mut banner).is_ok() && &banner == b"SSH-"
+}
+
+/// Push image `name` to `target`, as its mode requires.
+fn serve(name: &str, mode: &RemoteMode, target: &str) -> Result<()> {
+    match mode {
+        RemoteMode::Update => update::run(UpdateArgs { name: name.to_string(), target: Some(target.to_string()) }),
+        RemoteMode::Install | RemoteMode::Upgrade => install::run(InstallArgs {
+            name: name.to_string(),
+            target: Some(target.to_string()),
+            reinstall: false,
+            verify_hashes: false,
+        }),
+    }
+}
@@ -0,0 +1,266 @@
+//! # `apt-remote mirror` command
+//!
+//! Maintains an incremental partial mirror of a suite in the local cache.
+//!
+//! The suite's `InRelease`/`Release` files and the package indices for the
+//! requested components and architecture are downloaded into the mirror.
+//! Indices whose SHA256 already matches the `Release` file are skipped, so
+//! repeated runs only fetch what changed upstream. `apt-remote get` then
+//! satisfies matching URIs from the mirror instead of the internet, and
+//! stores any `.deb` it downloads from a mirrored archive in its pool.
+
+use crate::{
+    apt_auth, archive, dry_run,
+    exit::{Classify, ExitCode},
+    output, policy, progress,
+    summary::{self, Outcome},
+    theme, tls,
+};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use colored::Colorize;
+use rayon::prelude::*;
+use reqwest::blocking::Client;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use std::{fs, path::Path, sync::Arc, time::Duration};
+
+/// CLI arguments for the `apt-remote mirror` subcommand.
+#[derive(Args)]
+#[command(override_usage = "apt-remote mirror <SUITE> [--components <a,b,...>] [--arch <ARCH>] [--url <URL>]")]
+pub struct MirrorArgs {
+    /// Suite to mirror (e.g. bookworm, bookworm-updates)
+    pub suite: String,
+
+    /// Comma-separated list of components to mirror
+    #[arg(short, long, value_delimiter = ',', default_value = "main")]
+    pub components: Vec<String>,
+
+    /// Architecture of the package indices
+    #[arg(short, long, default_value = "amd64")]
+    pub arch: String,
+
+    /// Base URL of the upstream archive
+    #[arg(short, long, default_value = "http://deb.debian.org/debian")]
+    pub url: String,
+}
+
+/// A single index file listed in the `SHA256` section of a `Release` file.
+pub struct IndexEntry {
+    /// Path relative to `dists/<suite>/`.
+    pub path: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Expected hex-encoded SHA256 digest.
+    pub sha256: String,
+}
+
+/// Executes the `mirror` subcommand.
+///
+/// Downloads the suite's release files, selects the indices matching the
+/// requested components and architecture, and refreshes those that are
+/// missing or out of date in the local mirror.
+///
+/// # Errors
+/// Returns an error if the release file cannot be downloaded or parsed,
+/// or the mirror directory cannot be written.
+pub fn run(args: MirrorArgs) -> Result<()> {
+    let suite = &args.suite;
+    let mirrored = sync(suite, &args.components, &args.arch, &args.url)?;
+
+    output::print("\n");
+    output::emit(
+        "result",
+        json!({ "command": "mirror", "suite": suite, "indices": mirrored.indices, "updated": mirrored.updated }),
+    );
+    Ok(())
+}
+
+/// A suite refreshed by [`sync`].
+pub struct Mirrored {
+    /// Content of the suite's `InRelease` (or `Release`) file.
+    pub release: String,
+    /// URI of the release file.
+    pub release_uri: String,
+    /// Number of indices wanted.
+    pub indices: usize,
+    /// Number of indices downloaded again.
+    pub updated: usize,
+}
+
+/// Refresh the indices of `suite` for `components` and `arch` from the
+/// archive at `url` in the local mirror.
+///
+/// Indices that fail to download are reported, not returned.
+///
+/// # Errors
+/// Returns an error if the release file cannot be downloaded, or the mirror
+/// directory cannot be written.
+pub fn sync(suite: &str, components: &[String], arch: &str, url: &str) -> Result<Mirrored> {
+    let base = format!("{}/dists/{suite}", url.trim_end_matches('/'));
+    policy::check_https([url])?;
+
+    // HTTP client for downloads (5-minute timeout)
+    let client = Arc::new(
+        tls::load()?
+            .blocking(Client::builder().timeout(Duration::from_secs(300)))
+            .build()
+            .context("Failed to build client")?,
+    );
+
+    // Credentials of private archives (see `crate::apt_auth`)
+    let auth = apt_auth::local()?;
+
+    // Step 1: fetch the release files (InRelease preferred, Release as fallback)
+    let mut release = None;
+    for name in ["InRelease", "Release"] {
+        let uri = format!("{base}/{name}");
+        let Ok(response) = auth.authorize_blocking(client.get(&uri), &uri).send().and_then(|r| r.error_for_status()) else {
+            continue;
+        };
+        let content = response.text().with_context(|| format!("Failed to read {uri}"))?;
+        write_mirrored(&uri, content.as_bytes())?;
+        release.get_or_insert((content, uri));
+    }
+    let (release, release_uri) = release
+        .with_context(|| format!("No Release file found for {suite} at {base}"))
+        .classify(ExitCode::Download)?;
+
+    // Step 2: select the indices for the requested components/architecture
+    let indices = parse_release(&release)
+        .into_iter()
+        .filter(|entry| is_wanted(&entry.path, components, arch))
+        .collect::<Vec<_>>();
+
+    // Shared progress tracker for multiple downloads
+    let progress = progress::reporter();
+
+    let progress_overall = progress.bar(indices.len() as u64);
+    progress_overall.message(&format!("Mirroring {suite}..."));
+
+    // Step 3: refresh every index that is missing or out of date
+    let phase = summary::phase("mirror");
+    let updated = indices
+        .par_iter()
+        .map(|entry| -> Result<bool> {
+            let uri = format!("{base}/{}", entry.path);
+            let local = archive::local_path(&uri).context("Invalid archive URL")?;
+
+            // Skip indices already matching the Release file
+            if local.is_file() && archive::sha256_file(&local)? == entry.sha256 {
+                output::emit("index", json!({ "path": entry.path, "status": "current" }));
+                summary::record(Outcome::Skipped);
+                progress_overall.inc(1);
+                return Ok(false);
+            }
+            if dry_run::skip("download", format!("{uri} -> {}", local.display())) {
+                progress_overall.inc(1);
+                return Ok(false);
+            }
+
+            let spinner = progress.task();
+            spinner.message(&format!("{} {}", theme::accent("Downloading").bold(), entry.path.bold()));
+
+            let bytes = auth
+                .authorize_blocking(client.get(&uri), &uri)
+                .send()
+                .and_then(|r| r.error_for_status())
+                .and_then(|r| r.bytes());
+
+            let result = match bytes {
+                Ok(bytes) if archive::hex(&Sha256::digest(&bytes)) == entry.sha256 => {
+                    summary::downloaded(bytes.len() as u64);
+                    summary::record(Outcome::Succeeded);
+                    write_mirrored(&uri, &bytes)?;
+                    spinner.clear();
+                    output::emit("index", json!({ "path": entry.path, "status": "updated" }));
+                    true
+                }
+                Ok(_) => {
+                    spinner.finish(&format!(
+                        "{} {}",
+                        theme::failure("✗").bold(),
+                        theme::failure(format!("Checksum mismatch: {}", entry.path))
+                    ));
+                    output::emit("index", json!({ "path": entry.path, "status": "checksum_mismatch" }));
+                    summary::record(Outcome::Failed);
+                    false
+                }
+                Err(e) => {
+                    spinner.finish(&format!(
+                        "{} {}:\n{}",
+                        theme::failure("✗").bold(),
+                        theme::failure(format!("Failed to download {}", entry.path)),
+                        e.to_string().dimmed()
+                    ));
+                    output::emit(
+                        "index",
+                        json!({ "path": entry.path, "status": "failed", "error": e.to_string() }),
+                    );
+                    summary::record(Outcome::Failed);
+                    false
+                }
+            };
+
+            progress_overall.inc(1);
+            Ok(result)
+        })
+        .collect::<Result<Vec<bool>>>()?;
+    drop(phase);
+
+    let updated = updated.iter().filter(|&&u| u).count();
+    progress_overall.finish(&format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Mirrored {suite} ({updated} of {} indices updated)", indices.len()))
+    ));
+    Ok(Mirrored { release, release_uri, indices: indices.len(), updated })
+}
+
+/// Write `bytes` to the mirror location for `uri`, creating parent directories.
+fn write_mirrored(uri: &str, bytes: &[u8]) -> Result<()> {
+    let local = archive::local_path(uri).context("Invalid archive URL")?;
+    if dry_run::skip("write", local.display().to_string()) {
+        return Ok(());
+    }
+    if let Some(parent) = local.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&local, bytes).with_context(|| format!("Failed to write {}", local.display()))
+}
+
+/// Parse the `SHA256:` section of a `Release` (or `InRelease`) file.
+pub fn parse_release(content: &str) -> Vec<IndexEntry> {
+    content
+        .lines()
+        .skip_while(|line| !line.starts_with("SHA256:"))
+        .skip(1)
+        .take_while(|line| line.starts_with(' '))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let sha256 = fields.next()?.to_string();
+            let size = fields.next()?.parse().ok()?;
+            let path = fields.next()?.to_string();
+            Some(IndexEntry { path, size, sha256 })
+        })
+        .collect()
+}
+
+/// Returns `true` if an index path belongs to one of the requested components
+/// and architecture, and is the compressed variant apt actually downloads.
+fn is_wanted(path: &str, components: &[String], arch: &str) -> bool {
+    let path = Path::new(path);
+    let compressed = path.extension().is_some_and(|ext| ext == "xz");
+
+    components.iter().any(|component| {
+        let binary = format!("{component}/binary-{arch}/");
+        let binary_all = format!("{component}/binary-all/");
+        let i18n = format!("{component}/i18n/Translation-en");
+        let path = path.to_string_lossy();
+
+        (path.starts_with(&binary) || path.starts_with(&binary_all) || path.starts_with(&i18n))
+            && (compressed || path.ends_with("/Release"))
+    })
+}
@@ -1,5 +1,31 @@
+//! # apt-remote subcommands
+//!
+//! Each module implements one subcommand as an `*Args` struct (parsed by
+//! `clap` in the CLI, or built directly by library users) and a `run`
+//! function that performs the operation.
+
 pub mod set;
 pub mod get;
 pub mod install;
 pub mod update;
 pub mod clear;
+pub mod check;
+pub mod mirror;
+pub mod export;
+pub mod import;
+pub mod gc;
+pub mod status;
+pub mod list;
+pub mod changelog;
+pub mod advisories;
+pub mod diff_hosts;
+pub mod doctor;
+pub mod encrypt;
+pub mod decrypt;
+pub mod convert;
+pub mod keygen;
+pub mod apply;
+pub mod run;
+pub mod daemon;
+pub mod listen;
+pub mod completions;
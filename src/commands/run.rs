@@ -0,0 +1,128 @@
+//! # `apt-remote run` command
+//!
+//! This subcommand runs a pipeline from `config.toml` (see
+//! [`crate::pipeline`]): a fixed sequence of `set`, `get`, `check`,
+//! `install`, `update`, `export` and `status` steps on one image, so that a
+//! routine maintenance round is a single command.
+
+use crate::{
+    config,
+    exit::{Classify, ExitCode},
+    output,
+    pipeline::Step,
+    theme, tr,
+};
+use super::{check, export, get, install, set, status, update};
+
+use anyhow::{Context, Result, anyhow};
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+/// CLI arguments for the `apt-remote run` subcommand.
+#[derive(Args)]
+#[command(override_usage = "apt-remote run <PIPELINE> --target <user@host>")]
+pub struct RunArgs {
+    /// Pipeline name, from the [pipelines] table of config.toml (required)
+    pub pipeline: String,
+
+    /// Remote target SSH (user@host), defaults to `target` in config.toml
+    #[arg(short, long)]
+    pub target: Option<String>,
+
+    /// Cache image name, overriding the pipeline's `image`
+    #[arg(long)]
+    pub image: Option<String>,
+}
+
+/// Executes the `run` subcommand.
+///
+/// Runs the pipeline's steps in order, stopping at the first failure.
+///
+/// # Errors
+/// Returns an error if the pipeline is not defined, has no steps or has an
+/// invalid `set` step, or the error of the step that failed.
+pub fn run(args: RunArgs) -> Result<()> {
+    let pipeline = config::get()
+        .pipelines
+        .get(&args.pipeline)
+        .with_context(|| format!("No pipeline '{}' in the [pipelines] table of config.toml", args.pipeline))
+        .classify(ExitCode::Config)?;
+    if pipeline.steps.is_empty() {
+        return Err(anyhow!("Pipeline '{}' has no steps", args.pipeline)).classify(ExitCode::Config);
+    }
+    for step in &pipeline.steps {
+        if !step.has_one_mode() {
+            return Err(anyhow!(
+                "The set step of pipeline '{}' must set exactly one of install, fix, update or upgrade",
+                args.pipeline
+            ))
+            .classify(ExitCode::Config);
+        }
+    }
+    let name = args.image.as_ref().or(pipeline.image.as_ref()).unwrap_or(&args.pipeline);
+
+    let total = pipeline.steps.len();
+    for (i, step) in pipeline.steps.iter().enumerate() {
+        output::print(format!(
+            "{}",
+            theme::accent(format!("[{}/{total}] {} {name}", i + 1, step.name())).bold()
+        ));
+        let outcome = run_step(step, name, &args.target);
+        output::emit(
+            "step",
+            json!({
+                "pipeline": args.pipeline,
+                "step": step.name(),
+                "index": i + 1,
+                "status": if outcome.is_ok() { "ok" } else { "failed" },
+            }),
+        );
+        outcome.with_context(|| format!("Step {} ({}) of pipeline '{}' failed", i + 1, step.name(), args.pipeline))?;
+    }
+
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(tr!("Pipeline '{pipeline}' completed ({total} steps)", pipeline = args.pipeline, total = total))
+    ));
+    output::emit(
+        "result",
+        json!({ "command": "run", "pipeline": args.pipeline, "name": name, "steps": total }),
+    );
+    Ok(())
+}
+
+/// Run one pipeline step on image `name`.
+///
+/// # Errors
+/// Returns the error of the step's subcommand.
+pub fn run_step(step: &Step, name: &str, target: &Option<String>) -> Result<()> {
+    let name = name.to_string();
+    let target = target.clone();
+    match step.clone() {
+        Step::Set { install, fix, update, upgrade } => {
+            set::run(set::SetArgs {
+                name,
+                target,
+                install,
+                fix,
+                update,
+                upgrade,
+                ppa: Vec::new(),
+                snapshot: None,
+                preseed: None,
+                distro: None,
+                meta: Default::default(),
+            })
+        }
+        Step::Get { adopt } => get::run(get::GetArgs { name, adopt, changelogs: false, advisories: false }),
+        Step::Check => check::run(check::CheckArgs { name }),
+        Step::Install { reinstall, verify_hashes } => {
+            install::run(install::InstallArgs { name, target, reinstall, verify_hashes })
+        }
+        Step::Update => update::run(update::UpdateArgs { name, target }),
+        Step::Export { dest, installer } => export::run(export::ExportArgs { name, dest, installer, sign: None, format: export::ExportFormat::AptRemote }),
+        Step::Status => status::run(status::StatusArgs { name }),
+    }
+}
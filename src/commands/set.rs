@@ -4,20 +4,56 @@
 //! based on package information retrieved from a remote host via SSH.
 //!
 //! It supports installing packages, fixing broken dependencies,
-//! updating package lists, and upgrading packages.
+//! updating package lists, and upgrading packages. A `uri.lock` recording
+//! the exact versions, their suites and the target's Release file hashes is
+//! written alongside.
+//!
+//! With `--distro`, packages and lists are resolved without a target, from
+//! the suite's indices in the local mirror (see [`crate::resolve`]), for
+//! machines that cannot be reached yet.
+//!
+//! With `--ppa`, the lists of Launchpad PPAs are planned too, and added to
+//! the target by `update` (see [`crate::ppa`]).
+//!
+//! With `--snapshot`, the plan is pinned to a point in time of
+//! snapshot.debian.org (see [`crate::snapshot`]).
+//!
+//! With `--preseed`, a debconf preseed file is attached to the image, and
+//! loaded on the target by `install` (see [`crate::preseed`]).
 
 use crate::{
-    ssh::{RemoteExecutor, create_ssh_session},
-    uri::{Checksum, ChecksumKind, PackageEntry, UriFile, RemoteMode},
+    apt_auth::{self, AuthFile},
+    archive,
+    batch::Batch,
+    checksum, config,
+    commands::mirror,
+    derivative, dry_run,
+    error::Error,
+    flock, footprint,
+    lock::{self, HostIdentity, LockFile, ReleaseHash},
+    output, policy,
+    ppa::{self, Ppa},
+    preseed,
+    probe::Probe,
+    progress,
+    resolve::Index,
+    secret, signing, snapshot,
+    ssh::{RemoteExecutor, shell_quote},
+    theme,
+    transport,
+    state::{self, ImageState},
+    uri::{self, Checksum, ChecksumKind, ImageMeta, PackageEntry, UriFile, RemoteMode, SCHEMA_VERSION},
 };
 
 use anyhow::{anyhow, Context, Result};
 use clap::{ArgGroup, Args};
 use colored::Colorize;
-use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use xz2::read::XzDecoder;
 
-use std::{fs, collections::HashMap};
+use std::{fs, io::Read, collections::HashMap, path::{Path, PathBuf}};
 
 /// CLI arguments for the `apt-remote set` subcommand.
 #[derive(Args)]
@@ -27,45 +63,125 @@ use std::{fs, collections::HashMap};
         .args(&["install", "fix", "update", "upgrade"])
         .multiple(false),
     ),
-    override_usage = "apt-remote set <NAME> --target <user@host> (--install <packages...> | --fix | --update | --upgrade)",
+    override_usage = "apt-remote set <NAME> --target <user@host> (--install <packages...> | --fix | --update | --upgrade)\n       \
+                      apt-remote set <NAME> --distro <SUITE> [--arch <ARCH>] [--components <a,b,...>] (--install <packages...> | --update)",
 )]
 pub struct SetArgs {
     /// Cache image name (required)
-    name: String,
+    pub name: String,
 
-    /// Remote target SSH (user@host)
+    /// Remote target SSH (user@host), defaults to `target` in config.toml
     #[arg(short, long)]
-    target: String,
+    pub target: Option<String>,
 
     /// Packages to install
     #[arg(short, long, value_parser, num_args=1.., value_delimiter = ' ')]
-    install: Vec<String>,
+    pub install: Vec<String>,
 
     /// Flag to run "apt-get --fix-broken"
     #[arg(short, long)]
-    fix: bool,
+    pub fix: bool,
 
     /// Flag to run "apt-get update"
     #[arg(long)]
-    update: bool,
+    pub update: bool,
 
     /// Get upgradable packages
     #[arg(long)]
-    upgrade: bool,
+    pub upgrade: bool,
+
+    /// Add a Launchpad PPA to the target's lists (with --update, e.g. ppa:deadsnakes/ppa)
+    #[arg(long, value_parser = ppa::parse, requires = "update", conflicts_with = "distro")]
+    pub ppa: Vec<Ppa>,
+
+    /// Download from snapshot.debian.org as of this UTC time (e.g. 20250601T000000Z, or now)
+    #[arg(long, value_parser = snapshot::parse_time)]
+    pub snapshot: Option<String>,
+
+    /// Attach a debconf preseed file, loaded on the target before installing
+    #[arg(long, value_name = "FILE", conflicts_with = "update")]
+    pub preseed: Option<PathBuf>,
+
+    /// Resolve from a suite's package indices instead of a target
+    #[command(flatten)]
+    pub distro: Option<DistroArgs>,
+
+    /// What the image is for, and who made it
+    #[command(flatten)]
+    pub meta: MetaArgs,
+}
+
+/// Descriptive metadata of `apt-remote set`, kept from the previous plan of
+/// the image when not given again.
+#[derive(Args, Default)]
+pub struct MetaArgs {
+    /// Describe what the image is for
+    #[arg(long)]
+    pub description: Option<String>,
+
+    /// Label the image, to find it with `list --tag` (repeatable or comma-separated)
+    #[arg(long = "tag", value_name = "TAG", value_delimiter = ',')]
+    pub tags: Vec<String>,
+
+    /// The target or @group the image is meant for
+    #[arg(long, value_name = "TARGET")]
+    pub intended_target: Option<String>,
+
+    /// Who made the image (default: the local user)
+    #[arg(long)]
+    pub creator: Option<String>,
+}
+
+impl MetaArgs {
+    /// The metadata given, over the image's `previous` metadata.
+    fn apply(&self, previous: ImageMeta) -> ImageMeta {
+        let creator = self
+            .creator
+            .clone()
+            .or(previous.creator)
+            .or_else(|| std::env::var("USER").or_else(|_| std::env::var("USERNAME")).ok());
+        ImageMeta {
+            description: self.description.clone().or(previous.description),
+            tags: if self.tags.is_empty() { previous.tags } else { self.tags.clone() },
+            intended_target: self.intended_target.clone().or(previous.intended_target),
+            creator,
+            created: previous.created.or_else(|| Some(state::now())),
+        }
+    }
+}
+
+/// CLI arguments of `apt-remote set --distro`.
+#[derive(Args)]
+pub struct DistroArgs {
+    /// Resolve from the package indices of this suite instead of a target (e.g. bookworm)
+    #[arg(long, required = true, conflicts_with_all = ["target", "fix", "upgrade"])]
+    pub distro: String,
+
+    /// Architecture of the target (with --distro)
+    #[arg(long, default_value = "amd64")]
+    pub arch: String,
+
+    /// Comma-separated list of components to resolve from (with --distro)
+    #[arg(long, value_delimiter = ',', default_value = "main")]
+    pub components: Vec<String>,
+
+    /// Base URL of the archive (with --distro)
+    #[arg(long, default_value = "http://deb.debian.org/debian", conflicts_with = "snapshot")]
+    pub url: String,
 }
 
 /// Executes the `set` subcommand.
 ///
 /// Connects to a remote host, retrieves package URIs, sizes, and checksums,
-/// and saves them in a `uri.toml` file inside the local cache.
+/// and saves them in a `uri.toml` file inside the local cache, together with
+/// a `uri.lock` of the resolved versions.
 ///
 /// # Errors
 /// Returns an error if SSH connection fails, the remote command fails,
-/// or if writing `uri.toml` fails.
+/// or if writing `uri.toml` or `uri.lock` fails.
 pub fn run(args: SetArgs) -> Result<()> {
     // Extract basic args
     let name = &args.name;
-    let target = &args.target;
     let packages = &args.install;
 
     // Determine operation mode
@@ -78,30 +194,41 @@ pub fn run(args: SetArgs) -> Result<()> {
     };
 
     // Create cache directory for this image
-    let cache_dir = dirs::cache_dir()
-        .context("Failed to get cache directory")?
-        .join("apt-remote")
-        .join(name);
-    fs::create_dir_all(&cache_dir)?;
+    config::check_image_name(name)?;
+    let cache_dir = config::cache_dir()?.join(name);
+    if !dry_run::skip("create", cache_dir.display().to_string()) {
+        fs::create_dir_all(&cache_dir)?;
+    }
 
-    // Connect to the remote system
-    let session = create_ssh_session(target)?;
+    // Hold the image for the rest of the command
+    let _lock = flock::lock_image(&cache_dir)?;
+
+    // Metadata not given again is kept from the previous plan
+    let previous = UriFile::load(uri::path_in(&cache_dir)).map(|uri_file| uri_file.meta).unwrap_or_default();
+    let meta = args.meta.apply(previous);
+
+    // Answers to debconf's questions, kept with the image
+    if let Some(path) = &args.preseed {
+        preseed::attach(&cache_dir, path)?;
+    }
+
+    // Without a target, resolve from the suite's indices instead
+    if let Some(distro) = &args.distro {
+        let (mut uri_file, lock) = plan_from_indices(distro, args.snapshot.as_ref(), &mode, packages)?;
+        uri_file.meta = meta;
+        return save(name, &cache_dir, &uri_file, &lock);
+    }
+    let target = &config::target(args.target.as_ref())?;
 
-    // Detect remote architecture
-    let arch = session
-        .exec("dpkg --print-architecture")?
-        .trim()
-        .to_string();
+    // Keys of the PPAs to add, fetched before anything is asked of the target
+    let ppa_keys = args.ppa.iter().map(Ppa::fetch_key).collect::<Result<Vec<_>>>()?;
+
+    // Connect to the remote system
+    let session = transport::connect(target)?;
 
     // Set up progress spinner
-    let spinner = ProgressBar::new_spinner();
-    spinner.set_style(
-        ProgressStyle::with_template("{spinner:.cyan} {msg}")
-            .unwrap()
-            .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏"),
-    );
-    spinner.set_message(format!("{}", "Getting package info...".cyan().bold()));
-    spinner.enable_steady_tick(std::time::Duration::from_millis(200));
+    let spinner = progress::reporter().spinner();
+        spinner.message(&format!("{}", theme::accent("Getting package info...").bold()));
 
     // Build apt-get command string
     let mode_str = match mode {
@@ -115,34 +242,101 @@ pub fn run(args: SetArgs) -> Result<()> {
     };
     let fix = if args.fix { "-f" } else { "" };
     let pkg_list = packages.join(" ");
-    let cmd = format!("apt-get {mode_str} --print-uris {verbosity} {fix} {pkg_list}");
-
-    // Run command remotely and get output
-    let output = session.exec(&cmd)?;
-
-    spinner.finish();
+    let mut cmd = format!("apt-get {mode_str} --print-uris {verbosity} {fix} {pkg_list} 2>&1");
+    if !args.ppa.is_empty() {
+        // List the PPAs as if they were configured, from a copy of the target's sources
+        let lines = args.ppa.iter().map(|ppa| shell_quote(&ppa.plan_line("CODENAME"))).collect::<Vec<_>>().join(" ");
+        cmd = format!(
+            "parts=$(mktemp -d); PARTS=/etc/apt/sources.list.d/; \
+             eval \"$(apt-config shell PARTS Dir::Etc::sourceparts/d 2>/dev/null)\"; \
+             cp \"$PARTS\"* \"$parts\"/ 2>/dev/null; \
+             codename=$(. /etc/os-release; echo \"$VERSION_CODENAME\"); \
+             printf '%s\\n' {lines} | sed \"s/CODENAME/$codename/\" > \"$parts/apt-remote-ppa.list\"; \
+             {cmd} -o Dir::Etc::sourceparts=\"$parts\"; rm -rf \"$parts\""
+        );
+    }
+    tracing::info!("Resolving packages on {target}: {cmd}");
+
+    // Probe the target, resolve the plan and collect what uri.lock records
+    // in a single round trip
+    let mut batch = Batch::new();
+    Probe::add_to(&mut batch);
+    batch.add("plan", format!("plan=$({cmd}); printf '%s\\n' \"$plan\""));
+    if mode != RemoteMode::Update {
+        // The packages are the first `_`-separated field of the file names apt prints
+        batch.add(
+            "policy",
+            "printf '%s\\n' \"$plan\" | sed -n \"s/^'[^ ]* \\([^_ ]*\\)_.*/\\1/p\" | xargs -r apt-cache policy",
+        );
+        batch.add("candidates", footprint::CANDIDATES_QUERY);
+    }
+    batch
+        .add(
+            "releases",
+            format!(
+                "(LISTS=/var/lib/apt/lists/; eval \"$(apt-config shell LISTS Dir::State::lists/d 2>/dev/null)\"; {}) 2>/dev/null",
+                checksum::Tools::first_found(&ChecksumKind::SHA256, "\"$LISTS\"*Release")
+            ),
+        )
+        .add("machine-id", HostIdentity::MACHINE_ID_QUERY)
+        .add("hostname", HostIdentity::HOSTNAME_QUERY)
+        .add("selections", lock::SELECTIONS_QUERY);
+    let sections = batch.run(&*session)?;
+    let output = sections.get("plan");
+
+    // The probe comes for free, so the cached one is replaced
+    let probe = Probe::from_sections(&sections);
+    probe.save(target)?;
+    let arch = probe.apt.arch.clone();
+
+    spinner.clear();
+
+    // apt-get reports unresolvable plans (unknown packages, broken
+    // dependencies) as `E:` lines
+    let errors = output
+        .lines()
+        .filter_map(|line| line.strip_prefix("E: "))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        return Err(Error::PlanResolution { target: target.clone(), errors }.into());
+    }
     let mut total_size: u64 = 0;
 
+    // Installed size of the planned versions, by file name
+    let installed_sizes = footprint::parse_records(sections.get("candidates"));
+
     // Parse apt-get --print-uris output
     let pkg_data: Vec<Result<_>> = output
         .par_lines()
+        .filter(|line| line.starts_with('\''))
         .map(|line: &str| -> Result<_> {
             let mut parts = line.split(" ");
 
             // Extract URI
             let uri = parts.next().unwrap().replace("\'", "");
 
-            // Extract filename from URI
-            let filename = match url::Url::parse(&uri) {
-                Ok(url) => {
-                    let segments = url.path_segments().ok_or(anyhow!("Error parsing url."))?;
-                    segments.last().map(|s| s.to_string()).unwrap()
-                },
-                Err(e) => return Err(e.into())
+            // Packages of a derivative's vendor archive that moved are
+            // downloaded from its new host (lists keep the names APT expects)
+            let uri = match probe.derivative.and_then(|derivative| derivative.rewrite(&uri)) {
+                Some(moved) if mode != RemoteMode::Update => moved,
+                _ => uri,
+            };
+
+            // Packages of Debian's archives are pinned to the snapshot
+            // (lists keep the names APT expects)
+            let uri = match args.snapshot.as_ref().and_then(|time| snapshot::rewrite(&uri, time)) {
+                Some(pinned) if mode != RemoteMode::Update => pinned,
+                _ => uri,
             };
 
-            // Skip "dest" field
-            parts.next().unwrap();
+            // Use the name apt gives the file in its archives, which unlike
+            // the URI includes the epoch; fall back to the URI's file name
+            url::Url::parse(&uri)?;
+            let filename = match parts.next().unwrap() {
+                dest if dest.ends_with(".deb") => uri::archive_file_name(dest),
+                _ => uri::package_file_name(&uri).ok_or(anyhow!("Error parsing url."))?,
+            };
 
             // Extract file size
             let size = parts.next().unwrap().parse::<u64>()?;
@@ -155,31 +349,40 @@ pub fn run(args: SetArgs) -> Result<()> {
                 None
             } else {
                 let mut checksum_pair = checksum_maybe.split(":");
+                // `SHA256:` from current APT, `MD5Sum:` from old versions
                 let kind_str = checksum_pair.next().unwrap().to_string().to_lowercase();
-                let kind = ChecksumKind::new(&kind_str)
+                let kind = ChecksumKind::new(&format!("{}sum", kind_str.strip_suffix("sum").unwrap_or(&kind_str)))
                     .context(format!("{filename} has no valid checksum kind ({kind_str})"))?;
                 let value = checksum_pair.next().unwrap().to_string();
                 Some(Checksum { kind, value })
             };
 
+            let installed_size = installed_sizes.get(&filename).copied();
             Ok((
                 filename,
                 PackageEntry {
                     uri,
                     size,
                     checksum,
+                    installed_size,
                 },
             ))
         })
         .collect::<Vec<Result<_>>>();
 
+    // Refuse plain-HTTP sources before anything is recorded
+    policy::check_https(pkg_data.iter().filter_map(|pkg| pkg.as_ref().ok()).map(|(_, pkg)| pkg.uri.as_str()))?;
+    if mode != RemoteMode::Update {
+        policy::check_strong_hash(pkg_data.iter().filter_map(|pkg| pkg.as_ref().ok()).map(|(fname, pkg)| (fname, pkg)))?;
+    }
+
     // Prepare storage structures
     let mut install_order: Vec<String> = vec![];
     let mut packages: HashMap<String, PackageEntry> = Default::default();
 
     // Print summary
     let file_type = if args.update { "sources" } else { "packages" };
-    println!("The following {} {} will be stored:\n", pkg_data.len(), file_type);
+    output::print(format!("The following {} {} will be stored:\n", pkg_data.len(), file_type));
 
     // Store data depending on mode
     match mode {
@@ -187,8 +390,9 @@ pub fn run(args: SetArgs) -> Result<()> {
             for pkg_info in pkg_data {
                 if let Ok(pkg_info) = pkg_info {
                     let (_, pkg_entry) = pkg_info;
-                    println!("\t{}", pkg_entry.uri);
-                    packages.insert(pkg_entry.uri.split("//").nth(1).unwrap().replace("/", "_"), pkg_entry);
+                    output::print(format!("\t{}", pkg_entry.uri));
+                    let fname = pkg_entry.uri.split("//").nth(1).unwrap().replace("/", "_");
+                    uri::add_package(&mut packages, fname, pkg_entry)?;
                 } else {
                     continue;
                 }
@@ -198,10 +402,12 @@ pub fn run(args: SetArgs) -> Result<()> {
             for pkg_info in pkg_data {
                 if let Ok(pkg_info) = pkg_info {
                     let (fname, pkg_entry) = pkg_info;
-                    println!("\t{} ({})", fname, format_size(pkg_entry.size));
-                    total_size += pkg_entry.size;
-                    install_order.push(fname.clone());
-                    packages.insert(fname, pkg_entry);
+                    let size = pkg_entry.size;
+                    if uri::add_package(&mut packages, fname.clone(), pkg_entry)? {
+                        output::print(format!("\t{} ({})", fname, format_size(size)));
+                        total_size += size;
+                        install_order.push(fname);
+                    }
                 } else {
                     continue;
                 }
@@ -213,29 +419,263 @@ pub fn run(args: SetArgs) -> Result<()> {
     let total_size = if args.update { None } else { Some(total_size) };
 
     // Create UriFile struct
+    let sources = args.ppa.iter().zip(&ppa_keys).map(|(ppa, key)| ppa.source(&probe.codename, key)).collect();
     let uri_file = UriFile {
+        schema_version: SCHEMA_VERSION,
         mode,
         arch,
         total_size,
         install_order,
+        meta,
         packages,
+        sources,
+    };
+
+    // Files of Ubuntu Pro are only served with the target's credentials
+    copy_credentials(&*session, &probe.user, target, &uri_file, &cache_dir)?;
+
+    // Record how the plan was resolved in uri.lock
+    let mut lock = LockFile::new(&uri_file, sections.get("policy"), sections.get("releases"));
+    lock.architectures = probe.apt.architectures;
+    lock.host = HostIdentity::from_output(sections.get("machine-id"), sections.get("hostname"));
+    lock.selections = lock::parse_selections(sections.get("selections"));
+    lock.snapshot = args.snapshot.as_deref().map(snapshot::archive_url);
+
+    // Derivatives mix their vendor's repositories with Debian's or Ubuntu's
+    if let Some(derivative) = probe.derivative {
+        let releases = lock.releases.iter().map(|release| release.file.clone()).collect::<Vec<_>>();
+        let uris = uri_file.packages.values().map(|pkg| pkg.uri.as_str());
+        for warning in derivative.check_plan(target, uri_file.mode == RemoteMode::Update, uris, &releases) {
+            derivative::warn(derivative, target, &warning);
+        }
+    }
+
+    save(name, &cache_dir, &uri_file, &lock)
+}
+
+/// Resolve the plan from the indices of a suite, refreshed in the local
+/// mirror, instead of on a target.
+///
+/// # Errors
+/// Returns an error if the indices cannot be mirrored or read, or do not
+/// satisfy the requested packages.
+fn plan_from_indices(
+    distro: &DistroArgs,
+    snapshot: Option<&String>,
+    mode: &RemoteMode,
+    packages: &[String],
+) -> Result<(UriFile, LockFile)> {
+    let suite = &distro.distro;
+    let arch = &distro.arch;
+    let url = match snapshot {
+        Some(time) => snapshot::archive_url(time),
+        None => distro.url.trim_end_matches('/').to_string(),
+    };
+    let base = format!("{url}/dists/{suite}");
+
+    // Bring the suite's indices in the mirror up to date
+    let mirrored = mirror::sync(suite, &distro.components, arch, &url)?;
+    output::print("");
+
+    // Files are named as apt names them in its lists directory
+    let release = ReleaseHash {
+        file: mirrored.release_uri.split("//").nth(1).unwrap().replace('/', "_"),
+        sha256: archive::hex(&Sha256::digest(mirrored.release.as_bytes())),
+    };
+
+    let mut total_size = 0;
+    let mut install_order = vec![];
+    let mut entries = HashMap::new();
+    let mut suites = HashMap::new();
+    match mode {
+        RemoteMode::Update => {
+            // The lists `apt-get update` would download: the release file,
+            // and the package indices and translations of each component
+            let lists = mirror::parse_release(&mirrored.release)
+                .into_iter()
+                .filter(|entry| {
+                    distro.components.iter().any(|component| {
+                        entry.path == format!("{component}/binary-{arch}/Packages.xz")
+                            || entry.path == format!("{component}/i18n/Translation-en.xz")
+                    })
+                })
+                .map(|entry| PackageEntry {
+                    uri: format!("{base}/{}", entry.path),
+                    size: entry.size,
+                    checksum: Some(Checksum { kind: ChecksumKind::SHA256, value: entry.sha256 }),
+                    installed_size: None,
+                });
+            let release_entry = PackageEntry {
+                uri: mirrored.release_uri.clone(),
+                size: mirrored.release.len() as u64,
+                checksum: None,
+                installed_size: None,
+            };
+            let lists = std::iter::once(release_entry).chain(lists).collect::<Vec<_>>();
+            output::print(format!("The following {} sources will be stored:\n", lists.len()));
+            for entry in lists {
+                output::print(format!("\t{}", entry.uri));
+                let fname = entry.uri.split("//").nth(1).unwrap().replace('/', "_");
+                uri::add_package(&mut entries, fname, entry)?;
+            }
+        }
+        RemoteMode::Install | RemoteMode::Upgrade => {
+            let spinner = progress::reporter().spinner();
+            spinner.message(&format!("{}", theme::accent(format!("Resolving packages from {suite}/{arch}...")).bold()));
+
+            let mut index = Index::new();
+            for component in &distro.components {
+                let uri = format!("{base}/{component}/binary-{arch}/Packages.xz");
+                let local = archive::local_path(&uri).context("Invalid archive URL")?;
+                let mut content = String::new();
+                fs::File::open(&local)
+                    .map(XzDecoder::new)
+                    .and_then(|mut xz| xz.read_to_string(&mut content))
+                    .with_context(|| format!("Failed to read the {component} index of {suite} from {}", local.display()))?;
+                index.add(&content, &url, component);
+            }
+            tracing::info!("Resolving {packages:?} from {} packages in {suite}/{arch}", index.len());
+            let resolved = index
+                .resolve(packages)
+                .map_err(|errors| Error::IndexResolution { suite: format!("{suite}/{arch}"), errors })?;
+            spinner.clear();
+
+            output::print(format!("The following {} packages will be stored:\n", resolved.len()));
+            for package in resolved {
+                let fname = package.file_name();
+                let entry = PackageEntry {
+                    uri: package.uri(),
+                    size: package.size,
+                    checksum: package.sha256.clone().map(|value| Checksum { kind: ChecksumKind::SHA256, value }),
+                    installed_size: package.installed_size,
+                };
+                if uri::add_package(&mut entries, fname.clone(), entry)? {
+                    output::print(format!("\t{} ({})", fname, format_size(package.size)));
+                    total_size += package.size;
+                    suites.insert(fname.clone(), format!("{suite}/{}", package.component));
+                    install_order.push(fname);
+                }
+            }
+        }
+    }
+
+    // Refuse plain-HTTP sources before anything is recorded
+    policy::check_https(entries.values().map(|entry| entry.uri.as_str()))?;
+    if *mode != RemoteMode::Update {
+        policy::check_strong_hash(entries.iter())?;
+    }
+
+    let uri_file = UriFile {
+        schema_version: SCHEMA_VERSION,
+        mode: mode.clone(),
+        arch: arch.clone(),
+        total_size: (*mode != RemoteMode::Update).then_some(total_size),
+        install_order,
+        meta: ImageMeta::default(),
+        packages: entries,
+        sources: Vec::new(),
     };
 
+    // The versions come from the mirrored archive rather than the target's apt-cache policy
+    let mut lock = LockFile::new(&uri_file, "", "");
+    for (fname, locked) in &mut lock.packages {
+        locked.archive = Some(url.clone());
+        locked.suite = suites.remove(fname);
+    }
+    lock.architectures = vec![arch.clone()];
+    lock.releases = vec![release];
+    lock.snapshot = snapshot.map(|_| url.clone());
+    Ok((uri_file, lock))
+}
+
+/// Copy the target's credentials for the files of `uri_file` that need some
+/// (see [`crate::apt_auth`]) to the image's `auth.conf`, unless the local
+/// credentials have them all.
+///
+/// Reading the target's credentials takes root, so the sudo password of
+/// `user` is asked for if needed.
+///
+/// # Errors
+/// Returns an error if the credentials cannot be read or saved.
+fn copy_credentials(
+    session: &dyn RemoteExecutor,
+    user: &str,
+    target: &str,
+    uri_file: &UriFile,
+    cache_dir: &Path,
+) -> Result<()> {
+    let path = cache_dir.join(apt_auth::FILE_NAME);
+    let local = apt_auth::local()?;
+    let protected = uri_file
+        .packages
+        .values()
+        .map(|pkg| pkg.uri.as_str())
+        .filter(|uri| apt_auth::needs_credentials(uri))
+        .collect::<Vec<_>>();
+    if protected.iter().all(|uri| local.find(uri).is_some()) {
+        // A previous plan's credentials are not kept
+        if path.exists() && !dry_run::skip("remove", path.display().to_string()) {
+            fs::remove_file(&path)?;
+        }
+        return Ok(());
+    }
+
+    let password = if dry_run::enabled() { String::new() } else { secret::sudo_password(session, user, target)? };
+    let auth = AuthFile::parse(&session.sudo(apt_auth::TARGET_QUERY, &password)?).retain_for(&protected);
+    let missing = protected.iter().filter(|uri| local.find(uri).is_none() && auth.find(uri).is_none()).count();
+    if missing > 0 && !dry_run::enabled() {
+        let message = format!(
+            "{target} has no credentials for {missing} files of Ubuntu Pro: attach it with `pro attach`, or add them to {}",
+            apt_auth::default_path().unwrap_or_default().display()
+        );
+        output::print(format!("{} {}", theme::warning("!").bold(), theme::warning(&message)));
+        output::emit("warning", json!({ "target": target, "message": message }));
+    }
+    auth.save(&path)
+}
+
+/// Store a resolved plan and its lock in the image's `cache_dir`.
+///
+/// # Errors
+/// Returns an error if `uri.toml` or `uri.lock` cannot be written, or the
+/// image state cannot be updated.
+pub(crate) fn save(name: &str, cache_dir: &Path, uri_file: &UriFile, lock: &LockFile) -> Result<()> {
     // Print total size if applicable
-    if total_size.is_some() {
-        println!("\nTotal size: {}", format_size(total_size.unwrap()));
+    if let Some(total_size) = uri_file.total_size {
+        output::print(format!("\nTotal size: {}", format_size(total_size)));
+    }
+    let installed_size = uri_file.packages.values().filter_map(|pkg| pkg.installed_size).sum::<u64>();
+    if installed_size > 0 {
+        output::print(format!("Installed size: {}", format_size(installed_size)));
     }
-    println!("\n");
+    output::print("\n");
 
-    // Save uri.toml in cache
-    let uri_path = cache_dir.join("uri.toml");
-    uri_file.save(&uri_path)?;
+    // Save uri.toml in cache (uri.json for very large plans)
+    let uri_path = uri_file.save_in(cache_dir)?;
+
+    // A new plan is no longer the one an imported bundle was signed with
+    if !dry_run::enabled() {
+        signing::forget(cache_dir)?;
+    }
+
+    // Forget the state of files that are no longer part of the plan
+    let mut state = ImageState::load(cache_dir)?;
+    state.retain_plan(uri_file);
+    state.save(cache_dir)?;
+
+    // Record how the plan was resolved in uri.lock
+    lock.save(cache_dir.join("uri.lock"))?;
+
+    output::emit(
+        "plan",
+        json!({ "name": name, "path": uri_path, "plan": uri_file }),
+    );
 
     Ok(())
 }
 
 /// Format byte sizes into KB, MB, or GB.
-fn format_size(bytes: u64) -> String {
+pub fn format_size(bytes: u64) -> String {
     const KB: u64 = 1000;
     const MB: u64 = KB * 1000;
     const GB: u64 = MB * 1000;
@@ -0,0 +1,161 @@
+//! # `apt-remote status` command
+//!
+//! Reports what has been done with a cache image: how many of its files are
+//! in the local cache, and for every target it was used on, how many
+//! packages are installed and when the last run happened. The information
+//! comes from the image's `state.toml` (see [`crate::state`]), and what is
+//! known about each target from its cached probe (see [`crate::probe`]).
+//! The description, tags, intended target and creator given to `set` are
+//! shown first.
+
+use crate::{
+    commands::doctor,
+    config, flock, output,
+    probe::Probe,
+    state::{self, ImageState},
+    theme,
+    uri::{self, RemoteMode, UriFile},
+};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use colored::Colorize;
+use serde_json::json;
+
+/// CLI arguments for the `apt-remote status` subcommand.
+#[derive(Args)]
+pub struct StatusArgs {
+    /// Cache image name (required)
+    pub name: String,
+}
+
+/// Executes the `status` subcommand.
+///
+/// # Errors
+/// Returns an error if `uri.toml` or `state.toml` cannot be loaded.
+pub fn run(args: StatusArgs) -> Result<()> {
+    let name = &args.name;
+
+    // Locate cache directory for the given image
+    let cache_dir = config::cache_dir()?.join(name);
+
+    // Hold the image for the rest of the command
+    let _lock = flock::lock_image(&cache_dir)?;
+
+    // Load metadata from uri.toml
+    let uri_file = UriFile::load(uri::path_in(&cache_dir))
+        .context("Failed to load uri.toml metadata")?;
+    let state = ImageState::load(&cache_dir)?;
+
+    // Count the files actually present (source lists are stored uncompressed)
+    let (data_dir, kind) = match uri_file.mode {
+        RemoteMode::Install | RemoteMode::Upgrade => ("debs", "packages"),
+        RemoteMode::Update => ("sources", "lists"),
+    };
+    let data_dir = cache_dir.join(data_dir);
+    let total = uri_file.packages.len();
+    let cached = uri_file
+        .packages
+        .keys()
+        .filter(|fname| {
+            let path = data_dir.join(fname);
+            path.is_file() || (fname.ends_with(".xz") && path.with_extension("").is_file())
+        })
+        .count();
+
+    output::print(format!("{} ({:?}, {total} {kind})", name.bold(), uri_file.mode));
+    let meta = &uri_file.meta;
+    if let Some(description) = &meta.description {
+        output::print(format!("\t{description}"));
+    }
+    if !meta.tags.is_empty() {
+        output::print(format!("\tTags: {}", meta.tags.join(", ")));
+    }
+    if let Some(target) = &meta.intended_target {
+        output::print(format!("\tIntended for {target}"));
+    }
+    if let Some(creator) = &meta.creator {
+        let created = meta.created.map(|time| format!(" {}", format_age(state::now().saturating_sub(time)))).unwrap_or_default();
+        output::print(format!("\tCreated by {creator}{created}"));
+    }
+    let mark = if cached == total { theme::success("✓").bold() } else { theme::failure("✗").bold() };
+    output::print(format!("\t{mark} {cached} of {total} files in the local cache"));
+
+    let now = state::now();
+    let mut targets = Vec::new();
+    for (target, target_state) in &state.targets {
+        let last_run = target_state
+            .last_run
+            .map_or_else(|| "never".to_string(), |time| format_age(now.saturating_sub(time)));
+        let line = match uri_file.mode {
+            RemoteMode::Update => {
+                let updated = target_state
+                    .lists_updated
+                    .map_or_else(|| "never".to_string(), |time| format_age(now.saturating_sub(time)));
+                format!("{target}: package lists replaced {updated}")
+            }
+            RemoteMode::Install | RemoteMode::Upgrade => {
+                let installed = uri_file
+                    .install_order
+                    .iter()
+                    .filter(|fname| target_state.installed.contains(*fname))
+                    .count();
+                let pending = if target_state.uploaded.is_empty() {
+                    String::new()
+                } else {
+                    format!(", {} uploaded awaiting install", target_state.uploaded.len())
+                };
+                format!("{target}: {installed} of {} installed{pending}, last run {last_run}", uri_file.install_order.len())
+            }
+        };
+        output::print(format!("\t{line}"));
+        // What the last probe found, without connecting (see `apt-remote doctor`)
+        let probe = Probe::cached(target).ok().flatten();
+        if let Some(probe) = &probe {
+            output::print(format!(
+                "\t\t{}, APT {}, {}, probed {}",
+                doctor::describe(probe),
+                probe.apt_version,
+                probe.apt.architectures.join("+"),
+                format_age(now.saturating_sub(probe.probed_at))
+            ));
+        }
+        targets.push(json!({
+            "target": target,
+            "uploaded": target_state.uploaded.len(),
+            "verified": target_state.verified.len(),
+            "installed": target_state.installed.len(),
+            "lists_updated": target_state.lists_updated,
+            "last_run": target_state.last_run,
+            "probe": probe,
+        }));
+    }
+    if state.targets.is_empty() {
+        output::print("\tNot used on any target yet");
+    }
+    output::print("");
+
+    output::emit(
+        "result",
+        json!({
+            "command": "status",
+            "name": name,
+            "mode": uri_file.mode,
+            "meta": uri_file.meta,
+            "files": total,
+            "cached": cached,
+            "targets": targets,
+        }),
+    );
+    Ok(())
+}
+
+/// Format a number of seconds in the past as a human-readable age.
+pub(crate) fn format_age(secs: u64) -> String {
+    match secs {
+        s if s < 60 => "just now".to_string(),
+        s if s < 3600 => format!("{} minutes ago", s / 60),
+        s if s < 86400 => format!("{} hours ago", s / 3600),
+        s => format!("{} days ago", s / 86400),
+    }
+}
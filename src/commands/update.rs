@@ -1,26 +1,36 @@
-use crate::ssh::{create_ssh_session, RemoteExecutor, SecureUpload};
-
-use anyhow::{Context, Result};
+use crate::{config, crypt, derivative, dry_run, flock, inventory, output, policy, probe, progress, theme, tr};
+use crate::commands::export::list_digests;
+use crate::error::Error;
+use crate::secret;
+use crate::signing;
+use crate::ssh::shell_quote;
+use crate::staging;
+use crate::state::{self, ImageState};
+use crate::summary::{self, Outcome};
+use crate::transport;
+use crate::uri::{self, UriFile};
+
+use anyhow::Result;
 use clap::Args;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use colored::Colorize;
+use serde_json::json;
 
-use std::{time::Duration, path::Path};
+use std::path::Path;
 
 /// Arguments for the `apt-remote update` subcommand.
 /// 
 /// This command uploads local APT package list metadata to a remote machine 
-/// via SSH, replacing its `/var/lib/apt/lists` directory so that the remote 
+/// via SSH, replacing its package lists directory so that the remote 
 /// can perform up-to-date package operations without an internet connection.
 #[derive(Args)]
 #[command(override_usage="apt-remote install <NAME> --target <user@host>")]
 pub struct UpdateArgs {
     /// Cache image name (required)
-    name: String,
+    pub name: String,
 
-    /// Remote target SSH (user@host)
+    /// Remote target SSH (user@host or @group), defaults to `target` in config.toml
     #[arg(short, long)]
-    target: String,
+    pub target: Option<String>,
 }
 
 /// Runs the `update` command.
@@ -28,38 +38,32 @@ pub struct UpdateArgs {
 /// This function connects to the target machine over SSH, moves its APT list
 /// directory, uploads the locally cached APT sources, and regenerates the
 /// package cache so the remote system can run `apt` commands offline.
+/// The user is asked to confirm before the remote lists are replaced,
+/// unless `--yes` is given.
+///
+/// With an `@group` target, the lists are uploaded to every member of the
+/// inventory group in turn (see [`crate::inventory`]).
 pub fn run(args: UpdateArgs) -> Result<()> {
-    let name = &args.name;
-    let target = &args.target;
+    inventory::for_each_target(args.target.as_ref(), |target| update(&args.name, target))
+}
 
-    // Extract just the username portion from `user@host`
-    let user = target.split("@").nth(0).unwrap().trim();
+/// Upload the lists of image `name` to a single `user@host` target.
+fn update(name: &str, target: &str) -> Result<()> {
+    let target = &target.to_string();
 
     // Establish SSH session with the target
-    let session = create_ssh_session(target)?;
-
-    // Prompt the user for their sudo password (required for privileged operations)
-    let password = rpassword::prompt_password(format!("[sudo] password for {user}: "))
-        .ok()
-        .unwrap();
+    let session = transport::connect(target)?;
 
     // Path to the local cached "sources" directory for this image
-    let cache_dir = dirs::cache_dir()
-        .context("Failed to get cache dir")?
-        .join("apt-remote")
-        .join(name);
+    let cache_dir = config::cache_dir()?.join(name);
 
-    // Remote temporary path for metadata upload
-    let remote_str = format!("/tmp/apt-remote/{name}");
-    let remote_path = Path::new(&remote_str);
+    // Hold the image for the rest of the command
+    let _lock = flock::lock_image(&cache_dir)?;
 
     // Local sources directory where APT metadata is stored
     let source_path = cache_dir.join("sources");
     if !source_path.exists() {
-        return Err(anyhow::anyhow!(
-            "No sources metadata found for image '{}'",
-            name
-        ));
+        return Err(Error::ImageNotDownloaded { name: name.to_string() }.into());
     }
 
     // Collect a list of all metadata files to upload
@@ -68,43 +72,89 @@ pub fn run(args: UpdateArgs) -> Result<()> {
         .filter_map(|entry| {
             entry.ok().and_then(|e|
                 e.path().file_name()
-                 .and_then(|n| n.to_str().map(|s| String::from(s)))
+                 .and_then(|n| n.to_str().map(String::from))
             )
         }).collect::<Vec<String>>();
 
-    // Ensure the remote lists directory exists and is clean
-    session.exec(&format!("mkdir -p {remote_str}"))?;
-    session.sudo("mv /var/lib/apt/lists /var/lib/apt/lists.old", &password)?;
-    session.sudo("mkdir -p /var/lib/apt/lists/partial", &password)?;
-    session.sudo("touch /var/lib/apt/lists/lock", &password)?; // Prevent race conditions
+    // The target must be the machine the image was set for
+    policy::check_host(&*session, target, &cache_dir)?;
+
+    // An imported bundle must still match its signature
+    let manifest = signing::verify_image(&cache_dir)?;
+
+    // Unlock an encrypted image before asking anything else (nothing is uploaded in a dry run)
+    let key = if dry_run::enabled() { None } else { crypt::unlock(&cache_dir, name)? };
+
+    // Stage the lists in the user's home for an administrator instead of using sudo
+    let stage_dir = if staging::enabled() { Some(staging::dir(&*session, name)?) } else { None };
+
+    // Remote temporary path for metadata upload
+    let remote_str = match &stage_dir {
+        Some(stage_dir) => format!("{stage_dir}/sources"),
+        None => format!("/tmp/apt-remote/{name}"),
+    };
+    let remote_path = Path::new(&remote_str);
+
+    // The lists directory APT on the target reads (`Dir::State::lists`)
+    let probe = probe::get(&*session, target)?;
+    let lists = probe.apt.lists.clone();
+
+    // Lists of a derivative's vendor are ignored without its keyring
+    if let (Some(derivative), Some(keyring)) = (probe.derivative, probe.missing_keyring()) {
+        derivative::warn(
+            derivative,
+            target,
+            &tr!(
+                "{keyring} is not installed on {target}: APT ignores the lists of {name}'s repositories without it",
+                keyring = keyring,
+                target = target,
+                name = derivative
+            ),
+        );
+    }
+    let lists_dir = shell_quote(&lists);
+
+    let password = if let Some(stage_dir) = &stage_dir {
+        output::print(format!("{} lists from {name} will be staged in {stage_dir} on {target}.\n", srcs.len()));
+        session.exec(&format!("rm -rf {}", shell_quote(&remote_str)))?;
+        session.exec(&format!("mkdir -p {}", shell_quote(&remote_str)))?;
+        String::new()
+    } else {
+        // The remote lists are replaced wholesale, so ask first
+        output::print(format!(
+            "The package lists in {lists} on {target} will be replaced with {} lists from {name}.\n",
+            srcs.len()
+        ));
+        output::confirm(&tr!("Replace the package lists on {target}?", target = target))?;
+
+        // Sudo password, checked before use (required for privileged operations)
+        let password = if dry_run::enabled() {
+            String::new()
+        } else {
+            secret::sudo_password(&*session, &probe.user, target)?
+        };
+
+        // Ensure the remote lists directory exists and is clean
+        session.exec(&format!("mkdir -p {}", shell_quote(&remote_str)))?;
+        session.sudo(&format!("mv {lists_dir} {}", shell_quote(&format!("{lists}.old"))), &password)?;
+        session.sudo(&format!("mkdir -p {lists_dir}/partial"), &password)?;
+        session.sudo(&format!("touch {lists_dir}/lock"), &password)?; // Prevent race conditions
+        password
+    };
 
     // Set up progress bar for the upload process
-    let progress = MultiProgress::new();
+    let progress = progress::reporter();
 
-    let progress_overall = progress.add(ProgressBar::new(srcs.len() as u64));
-    progress_overall.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "[{elapsed_precise}] {msg} [{wide_bar:.bold.cyan}] {pos}/{len} ({eta} remaining)",
-            )
-            .unwrap()
-            .progress_chars("##-"),
-    );
-    progress_overall.enable_steady_tick(Duration::from_millis(100));
-    progress_overall.set_message(format!("Uploading package metadata to {target}..."));
+    let progress_overall = progress.bar(srcs.len() as u64);
+    progress_overall.message(&format!("Uploading package metadata to {target}..."));
 
     // Transfer all source list files to the remote temporary directory
+    let phase = summary::phase("upload");
     srcs.iter()
         .for_each(|fname| {
-            let spinner = progress.add(ProgressBar::new_spinner());
-            spinner.set_style(
-                ProgressStyle::with_template("\t{spinner:.bold.cyan} {msg}")
-                    .unwrap()
-                    .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ "),
-            );
-            spinner.enable_steady_tick(Duration::from_millis(100));
-
             let local_fpath = source_path.join(fname);
+            let spinner = progress.transfer(local_fpath.metadata().map_or(0, |m| m.len()));
+
             let remote_fpath = remote_path.join(fname);
 
             // Skip missing files (defensive check)
@@ -112,33 +162,82 @@ pub fn run(args: UpdateArgs) -> Result<()> {
                 return
             }
 
-            spinner.set_message(format!("{}", local_fpath.file_name().unwrap().to_str().unwrap()));
+            spinner.message(local_fpath.file_name().unwrap().to_str().unwrap());
             
             // Upload via SCP
-            let status = session.scp_upload(&local_fpath, &remote_fpath);
+            // Signed lists are checked against the manifest before they leave
+            let status = crypt::Plaintext::of(key.as_ref(), &local_fpath).and_then(|plain| {
+                if let Some(manifest) = &manifest {
+                    manifest.check_file(&cache_dir, &format!("sources/{fname}"), plain.path())?;
+                }
+                session.upload_with_progress(plain.path(), &remote_fpath, &progress::bytes(&*spinner))
+            });
 
             if let Err(e) = status {
-                spinner.finish_with_message(format!(
+                spinner.finish(&format!(
                     "{} {}: {}",
-                    "✗".red().bold(),
-                    format!("File not sent: {fname}").red(),
+                    theme::failure("✗").bold(),
+                    theme::failure(format!("File not sent: {fname}")),
                     e.to_string().dimmed()
                 ));
+                output::emit("upload", json!({ "file": fname, "ok": false, "error": e.to_string() }));
+                summary::record(Outcome::Failed);
+            } else {
+                output::emit("upload", json!({ "file": fname, "ok": true }));
+                summary::record(Outcome::Succeeded);
+                summary::uploaded(local_fpath.metadata().map_or(0, |m| m.len()));
             }
 
-            spinner.finish_and_clear();
+            spinner.clear();
             progress_overall.inc(1);
     });
+    drop(phase);
+
+    // Leave replacing the lists to an administrator when staging
+    if let Some(stage_dir) = &stage_dir {
+        let uri_file = UriFile::load(uri::path_in(&cache_dir))?;
+        progress_overall.clear();
+        let lists = list_digests(&cache_dir.join("sources"), key.as_ref())?;
+        let script = staging::finish(&*session, name, target, &uri_file, &lists, stage_dir)?;
+
+        let mut state = ImageState::load(&cache_dir)?;
+        state.target(target).last_run = Some(state::now());
+        state.save(&cache_dir)?;
+        output::emit(
+            "result",
+            json!({ "command": "update", "name": name, "target": target, "files": srcs.len(), "status": "staged", "installer": script }),
+        );
+        return Ok(());
+    }
 
     // Move uploaded lists into place and regenerate APT's cache
-    progress_overall.set_message("Generating cache...");
-    session.sudo(&format!("mv {remote_str}/* /var/lib/apt/lists"), &password)?;
+    let phase = summary::phase("gencaches");
+    progress_overall.message("Generating cache...");
+
+    // Sources the lists need (PPAs added with `set --ppa`), with their keys
+    let uri_file = UriFile::load(uri::path_in(&cache_dir))?;
+    for source in &uri_file.sources {
+        session.sudo(&format!("sh -c {}", shell_quote(&source.install_command())), &password)?;
+    }
+    session.sudo_in(&remote_str, &format!("mv ./* {lists_dir}"), &password)?;
     session.sudo("apt-cache gencaches", &password)?; // Creates pkgcache.bin and srcpkgcache.bin
-    progress_overall.finish_with_message(format!(
+    drop(phase);
+
+    // Remember when the target last received this image's lists
+    let mut state = ImageState::load(&cache_dir)?;
+    let target_state = state.target(target);
+    target_state.lists_updated = Some(state::now());
+    target_state.last_run = target_state.lists_updated;
+    state.save(&cache_dir)?;
+    progress_overall.finish(&format!(
         "{} {}", 
-        "✓ Updated".green().bold(), 
-        target.green().bold()
+        theme::success("✓ Updated").bold(), 
+        theme::success(target).bold()
     ));
 
+    output::emit(
+        "result",
+        json!({ "command": "update", "name": name, "target": target, "files": srcs.len() }),
+    );
     Ok(())
 }
@@ -0,0 +1,589 @@
+//! # Configuration for apt-remote
+//!
+//! This module loads persistent defaults from
+//! `~/.config/apt-remote/config.toml` and makes the effective configuration
+//! available to every subcommand. Command-line flags are merged on top of
+//! the file by the CLI before the configuration is installed with [`init`].
+//!
+//! ```toml
+//! cache_dir = "/srv/apt-remote"   # default: $HOME/.cache/apt-remote
+//! jobs = 8                        # parallel downloads (default: CPU count)
+//! target = "admin@kiosk"          # default --target for set/install/update
+//! color = "auto"                  # auto | always | never (auto honors NO_COLOR)
+//! progress = "auto"               # auto | fancy | plain | none
+//! ui = "plain"                    # plain | tui for @group runs, see `crate::dashboard`
+//! output = "human"                # human | json
+//! assume_yes = false              # skip confirmation prompts (like --yes)
+//! wait_for_lock = false           # queue behind other runs on an image (like --wait)
+//! batch = false                   # never prompt, for CI (like --batch)
+//! rootless = false                # stage images instead of using sudo, see `crate::staging`
+//! sudo_password_file = "/run/secrets/sudo" # see `crate::secret`
+//! askpass = "/usr/local/bin/fleet-askpass" # prints credentials, see `crate::secret`
+//! become_user = "maint"           # run privileged steps through `su - maint`, see `crate::ssh::Escalation`
+//! escalation = "doas"             # sudo | doas | su (default: sudo, else doas, else su)
+//! require_https = false           # refuse http:// URIs (like --require-https)
+//! require_strong_hash = "off"     # off | warn | deny packages without SHA-256
+//! require_signature = false       # refuse unsigned bundles (like --require-signature)
+//! bind_host = "warn"              # off | warn | deny installs on another machine
+//! signing_key = "~/.config/apt-remote/signing.key" # see `crate::signing`
+//! trusted_keys = ["/etc/apt-remote/courier.pub"]
+//! encrypt = false                 # encrypt images at rest, see `crate::crypt`
+//! max_index_size = 2048           # MiB a package list may uncompress to
+//! log_dir = "/var/log/apt-remote" # write a debug log of every run here
+//! locale = "de"                   # messages language (default: from LANG)
+//! locale_dir = "/srv/apt-remote/locale" # see `crate::i18n`
+//! inventory = "/etc/apt-remote/inventory.toml" # see `crate::inventory`
+//! apt_auth = "/etc/apt-remote/auth.conf" # repository credentials, see `crate::apt_auth`
+//!
+//! [ssh]
+//! port = 22
+//! connect_timeout = 10            # seconds
+//! connect_attempts = 3            # tries to connect, see `crate::reconnect`
+//! reconnect_attempts = 3          # reconnections during a command
+//! compress = false                # zlib compression of SSH traffic (like --compress)
+//! limit_rate = "500k"             # upload bandwidth, see `crate::throttle`
+//! upload_jobs = 4                 # files `install` uploads at once
+//! identity = "~/.ssh/fleet_ed25519" # key tried after the inventory's (like --identity)
+//! gssapi = false                  # Kerberos through the OpenSSH client, see `crate::openssh`
+//! persist = 600                   # keep connections open between runs, see `crate::openssh`
+//! config = "~/.ssh/config"        # host aliases, see `crate::ssh_config`
+//! jump = "admin@bastion"          # jump host of every target (like --jump)
+//! host_key_checking = "ask"       # or strict, accept-new, off, see `crate::known_hosts`
+//! known_hosts = "~/.ssh/known_hosts"
+//!
+//! [tls]                           # see `crate::tls`
+//! ca_bundle = "/etc/ssl/certs/internal-ca.pem"
+//!
+//! [hooks]                         # see `crate::hooks`
+//! post-install = "notify-send 'apt-remote' \"Installed $APT_REMOTE_IMAGE\""
+//!
+//! [notify]                        # see `crate::notify`
+//! webhook = "https://chat.example.com/hooks/apt-remote"
+//! on = "failure"
+//!
+//! [daemon]                        # see `crate::commands::daemon`
+//! interval = 360                  # minutes between rounds
+//! images.bookworm-lists.push = ["@kiosks"]
+//!
+//! [theme]                         # see `crate::theme`
+//! accent = "cyan"
+//! failure = "magenta"
+//!
+//! [pipelines.security]           # see `crate::pipeline`
+//! steps = [{ step = "set", upgrade = true }, { step = "get" }, { step = "install" }]
+//!
+//! [profiles.production]           # selected with --profile production
+//! target = "admin@kiosk-prod"
+//! assume_yes = false
+//! ssh.connect_timeout = 30
+//! hooks.pre-install = "change-ticket open --host \"$APT_REMOTE_TARGET\""
+//! ```
+//!
+//! A profile holds any of the keys above and is merged over the rest of the
+//! file when selected: tables such as `[ssh]` or `[hooks]` are merged key by
+//! key, other values are replaced.
+
+use crate::{
+    dashboard::UiMode,
+    error::Error,
+    exit::{Classify, ExitCode},
+    known_hosts::HostKeyChecking,
+    notify::NotifyConfig,
+    output::OutputFormat,
+    progress::ProgressMode,
+    pipeline::Pipeline,
+    policy::Enforcement,
+    ssh::EscalationTool,
+    theme::{self, Theme},
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+/// Effective configuration, installed once by [`init`].
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// When to colorize terminal output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    /// Colorize when writing to a terminal.
+    #[default]
+    Auto,
+    /// Always colorize.
+    Always,
+    /// Never colorize.
+    Never,
+}
+
+/// SSH connection defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SshConfig {
+    /// TCP port of the SSH server.
+    pub port: u16,
+    /// Connection timeout in seconds (`None` waits indefinitely).
+    pub connect_timeout: Option<u64>,
+    /// Attempts to connect before giving up, waiting 1s, 2s, 4s... in
+    /// between.
+    pub connect_attempts: u32,
+    /// Times a lost connection is re-established during a command (see
+    /// `crate::reconnect`).
+    pub reconnect_attempts: u32,
+    /// Compress SSH traffic with zlib, for slow links.
+    pub compress: bool,
+    /// Largest upload rate in bytes per second (see `crate::throttle`).
+    #[serde(deserialize_with = "crate::throttle::deserialize_rate")]
+    pub limit_rate: Option<u64>,
+    /// Number of files `install` uploads at once.
+    pub upload_jobs: usize,
+    /// Private key file tried after the host's inventory identity, before
+    /// the SSH agent.
+    pub identity: Option<PathBuf>,
+    /// Reach SSH targets through the OpenSSH client, authenticating with
+    /// GSSAPI (see `crate::openssh`).
+    pub gssapi: bool,
+    /// Forward the Kerberos ticket to the target.
+    pub gssapi_delegate: bool,
+    /// Seconds the connection to a target stays open after a run, for the
+    /// next one, through the OpenSSH client (see `crate::openssh`).
+    pub persist: Option<u64>,
+    /// OpenSSH client configuration read for host aliases (default:
+    /// `~/.ssh/config`, see `crate::ssh_config`).
+    pub config: Option<PathBuf>,
+    /// Jump hosts (`user@host[:port]`, comma-separated) every target is
+    /// reached through, unless the inventory or OpenSSH configuration name
+    /// others.
+    pub jump: Option<String>,
+    /// How server keys are checked against `known_hosts` (see
+    /// `crate::known_hosts`).
+    pub host_key_checking: HostKeyChecking,
+    /// File of known server keys (default: `~/.ssh/known_hosts`).
+    pub known_hosts: Option<PathBuf>,
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        SshConfig {
+            port: 22,
+            connect_timeout: None,
+            connect_attempts: 3,
+            reconnect_attempts: 3,
+            compress: false,
+            limit_rate: None,
+            upload_jobs: 4,
+            identity: None,
+            gssapi: false,
+            gssapi_delegate: false,
+            persist: None,
+            config: None,
+            jump: None,
+            host_key_checking: HostKeyChecking::Ask,
+            known_hosts: None,
+        }
+    }
+}
+
+/// TLS settings of the HTTP client fetching from mirrors.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM file of CA certificates trusted in addition to the system ones.
+    pub ca_bundle: Option<PathBuf>,
+    /// PEM file with the client certificate (and its chain) for mutual TLS.
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the client certificate's private key (default: `client_cert`).
+    pub client_key: Option<PathBuf>,
+}
+
+/// Settings of `apt-remote daemon` (see `crate::commands::daemon`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// Minutes between two rounds.
+    pub interval: u64,
+    /// Update-mode images to refresh, keyed by name.
+    pub images: BTreeMap<String, DaemonImage>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            interval: 1440,
+            images: BTreeMap::new(),
+        }
+    }
+}
+
+/// An image refreshed by `apt-remote daemon`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DaemonImage {
+    /// Targets (`user@host` or `@group`) the refreshed lists are pushed to.
+    pub push: Vec<String>,
+}
+
+/// Representation of `config.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Directory holding images, the package pool and the mirror.
+    pub cache_dir: Option<PathBuf>,
+    /// Number of parallel downloads (`None` uses one per CPU).
+    pub jobs: Option<usize>,
+    /// Default SSH target in `user@host` format.
+    pub target: Option<String>,
+    /// When to colorize output.
+    pub color: ColorChoice,
+    /// How to display progress.
+    pub progress: ProgressMode,
+    /// How to display runs on `@group` targets.
+    pub ui: UiMode,
+    /// Format of the output written to stdout.
+    pub output: OutputFormat,
+    /// Suppress human-readable output and progress (errors are still shown).
+    pub quiet: bool,
+    /// Answer confirmation prompts for destructive steps with "yes".
+    pub assume_yes: bool,
+    /// Wait for images locked by another process instead of failing.
+    pub wait_for_lock: bool,
+    /// Never prompt: credentials must be provided, confirmations are assumed.
+    pub batch: bool,
+    /// Stage images for an administrator instead of using `sudo` (see `crate::staging`).
+    pub rootless: bool,
+    /// File holding the SSH password (`-` for stdin, see `crate::secret`).
+    pub ssh_password_file: Option<PathBuf>,
+    /// File holding the sudo password.
+    pub sudo_password_file: Option<PathBuf>,
+    /// File holding the passphrase of the SSH private keys.
+    pub key_passphrase_file: Option<PathBuf>,
+    /// Program printing credentials that are not otherwise provided (see
+    /// `crate::secret`).
+    pub askpass: Option<PathBuf>,
+    /// Account privileged steps switch to with `su` (see `crate::ssh::Escalation`).
+    pub become_user: Option<String>,
+    /// Tool privileged steps become root with (default: detected, see
+    /// `crate::ssh::Escalation`).
+    pub escalation: Option<EscalationTool>,
+    /// File holding the passphrase of encrypted images.
+    pub passphrase_file: Option<PathBuf>,
+    /// Refuse plain-HTTP package and list URIs (see `crate::policy`).
+    pub require_https: bool,
+    /// Handling of packages without a SHA-256 checksum (see `crate::policy`).
+    pub require_strong_hash: Enforcement,
+    /// Refuse unsigned bundles and images (see `crate::signing`).
+    pub require_signature: bool,
+    /// Handling of targets that are not the machine an image was set for.
+    pub bind_host: Enforcement,
+    /// Private key bundles are signed with on export.
+    pub signing_key: Option<PathBuf>,
+    /// Public keys accepted for signed bundles and images; listing any also
+    /// refuses unsigned ones.
+    pub trusted_keys: Vec<PathBuf>,
+    /// Encrypt newly downloaded images at rest (see `crate::crypt`).
+    pub encrypt: bool,
+    /// Largest size in MiB a package list may uncompress to.
+    pub max_index_size: u64,
+    /// Report changes instead of making them (command line only).
+    #[serde(skip)]
+    pub dry_run: bool,
+    /// Directory receiving a debug log file for every run.
+    pub log_dir: Option<PathBuf>,
+    /// Language of messages (default: from `LC_ALL`, `LC_MESSAGES` or `LANG`).
+    pub locale: Option<String>,
+    /// Directory searched first for message catalogs.
+    pub locale_dir: Option<PathBuf>,
+    /// Inventory file (default: `~/.config/apt-remote/inventory.toml`).
+    pub inventory: Option<PathBuf>,
+    /// Credentials of authenticated repositories, in APT's `auth.conf`
+    /// format (default: `~/.config/apt-remote/auth.conf`).
+    pub apt_auth: Option<PathBuf>,
+    /// SSH connection defaults.
+    pub ssh: SshConfig,
+    /// TLS settings for mirrors (see `crate::tls`).
+    pub tls: TlsConfig,
+    /// Local commands run around subcommands, keyed by hook name.
+    pub hooks: BTreeMap<String, String>,
+    /// Notifications of finished commands (see `crate::notify`).
+    pub notify: NotifyConfig,
+    /// Periodic refresh of update-mode images (see `crate::commands::daemon`).
+    pub daemon: DaemonConfig,
+    /// Colors of human-readable output.
+    pub theme: Theme,
+    /// Named step sequences run with `apt-remote run`.
+    pub pipelines: BTreeMap<String, Pipeline>,
+    /// Named sets of overrides, selected with `--profile`.
+    pub profiles: BTreeMap<String, toml::Table>,
+    /// Name of the selected profile (command line only).
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cache_dir: None,
+            jobs: None,
+            target: None,
+            color: ColorChoice::Auto,
+            progress: ProgressMode::Auto,
+            ui: UiMode::Plain,
+            output: OutputFormat::Human,
+            quiet: false,
+            assume_yes: false,
+            wait_for_lock: false,
+            batch: false,
+            rootless: false,
+            ssh_password_file: None,
+            sudo_password_file: None,
+            key_passphrase_file: None,
+            askpass: None,
+            become_user: None,
+            escalation: None,
+            passphrase_file: None,
+            require_https: false,
+            require_strong_hash: Enforcement::Off,
+            require_signature: false,
+            bind_host: Enforcement::Warn,
+            signing_key: None,
+            trusted_keys: Vec::new(),
+            encrypt: false,
+            max_index_size: 2048,
+            dry_run: false,
+            log_dir: None,
+            locale: None,
+            locale_dir: None,
+            inventory: None,
+            apt_auth: None,
+            ssh: SshConfig::default(),
+            tls: TlsConfig::default(),
+            hooks: BTreeMap::new(),
+            notify: NotifyConfig::default(),
+            daemon: DaemonConfig::default(),
+            theme: Theme::default(),
+            pipelines: BTreeMap::new(),
+            profiles: BTreeMap::new(),
+            profile: None,
+        }
+    }
+}
+
+impl Config {
+    /// Default location of the configuration file.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("apt-remote").join("config.toml"))
+    }
+
+    /// Load the configuration file.
+    ///
+    /// # Arguments
+    /// * `path` - Explicit file to load. When `None`, the default location is
+    ///   used and a missing file yields the default configuration.
+    ///
+    /// # Errors
+    /// Returns an error if an explicitly given file is missing, or if the
+    /// file cannot be read or parsed.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        Self::load_profile(path, None)
+    }
+
+    /// Load the configuration file with a profile merged over it.
+    ///
+    /// # Arguments
+    /// * `path` - Explicit file to load. When `None`, the default location is
+    ///   used and a missing file yields the default configuration.
+    /// * `profile` - Name of a `[profiles.<name>]` table to apply.
+    ///
+    /// # Errors
+    /// Returns an error if an explicitly given file is missing, if the file
+    /// cannot be read or parsed, or if the profile is not defined in it.
+    pub fn load_profile(path: Option<&Path>, profile: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => Self::default_path().filter(|path| path.is_file()),
+        };
+
+        let mut table = match &path {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))
+                    .classify(ExitCode::Config)?;
+                toml::from_str::<toml::Table>(&content)
+                    .with_context(|| format!("Failed to parse TOML from {}", path.display()))
+                    .classify(ExitCode::Config)?
+            }
+            None => toml::Table::new(),
+        };
+
+        // Merge the selected profile over the top-level keys
+        if let Some(profile) = profile {
+            let overrides = table
+                .get("profiles")
+                .and_then(|profiles| profiles.get(profile))
+                .and_then(toml::Value::as_table)
+                .cloned()
+                .with_context(|| match &path {
+                    Some(path) => format!("Profile '{profile}' is not defined in {}", path.display()),
+                    None => format!("Profile '{profile}' is not defined (no configuration file)"),
+                })
+                .classify(ExitCode::Config)?;
+            merge(&mut table, overrides);
+        }
+
+        let mut config: Config = toml::Value::Table(table)
+            .try_into()
+            .with_context(|| match &path {
+                Some(path) => format!("Invalid configuration in {}", path.display()),
+                None => "Invalid configuration".to_string(),
+            })
+            .classify(ExitCode::Config)?;
+        config.profile = profile.map(String::from);
+        Ok(config)
+    }
+}
+
+/// Merge `overrides` into `table`, recursing into tables present in both.
+fn merge(table: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (table.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => merge(existing, value),
+            (_, value) => {
+                table.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Install the effective configuration for the rest of the process.
+///
+/// Applies process-wide settings: the color override (colors are always
+/// off in JSON mode, and off by default when `NO_COLOR` is set), which is
+/// shared with the progress bars, and the size of the global thread pool used for
+/// parallel downloads. Only the first call has an effect; later calls are
+/// ignored.
+pub fn init(config: Config) {
+    match config.color {
+        _ if config.output == OutputFormat::Json => colored::control::set_override(false),
+        ColorChoice::Always => colored::control::set_override(true),
+        ColorChoice::Never => colored::control::set_override(false),
+        ColorChoice::Auto if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) => {
+            colored::control::set_override(false)
+        }
+        ColorChoice::Auto => {}
+    }
+    console::set_colors_enabled(theme::enabled());
+    console::set_colors_enabled_stderr(theme::enabled());
+    if let Some(jobs) = config.jobs {
+        // Fails only if the pool was already started, in which case it is kept
+        rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global().ok();
+    }
+    CONFIG.set(config).ok();
+}
+
+/// The effective configuration (defaults if [`init`] was never called).
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Locate the apt-remote cache directory.
+///
+/// # Errors
+/// Returns an error if no directory is configured and the platform cache
+/// directory cannot be determined.
+pub fn cache_dir() -> Result<PathBuf> {
+    match &get().cache_dir {
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(dirs::cache_dir()
+            .context("Failed to locate cache directory")?
+            .join("apt-remote")),
+    }
+}
+
+/// Directory of the cache holding what apt-remote keeps about targets
+/// (probes, remembered ports) rather than about images. `clear` keeps it.
+pub const STATE_DIR: &str = ".state";
+
+/// Directories of the cache that are not images (see [`check_image_name`]).
+const RESERVED_NAMES: [&str; 2] = ["pool", "mirror"];
+
+/// Locate the directory of target state in the cache (see [`STATE_DIR`]).
+///
+/// # Errors
+/// Returns an error if the cache directory cannot be determined.
+pub fn state_dir() -> Result<PathBuf> {
+    Ok(cache_dir()?.join(STATE_DIR))
+}
+
+/// Check that `name` can name an image: it must stay inside the cache
+/// directory, and not be the package pool, the local mirror or the state
+/// directory, which `set` and `import` would otherwise write into.
+///
+/// ```
+/// use apt_remote::config::check_image_name;
+///
+/// assert!(check_image_name("web").is_ok());
+/// assert!(check_image_name("pool").is_err());
+/// assert!(check_image_name(".state").is_err());
+/// assert!(check_image_name("../web").is_err());
+/// ```
+///
+/// # Errors
+/// Returns a usage error naming the reserved name.
+pub fn check_image_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) || RESERVED_NAMES.contains(&name) {
+        return Err(anyhow::anyhow!("'{name}' cannot name an image: it is reserved in the cache directory"))
+            .classify(ExitCode::Usage);
+    }
+    Ok(())
+}
+
+/// Resolve the SSH target from a command-line value or the configured default.
+///
+/// A port given as `user@host:port` is remembered for the host, and the
+/// target is returned as `user@host` (see [`crate::inventory::remember_port`]).
+///
+/// # Errors
+/// Returns an error if neither is set, or the port is not valid.
+pub fn target(target: Option<&String>) -> Result<String> {
+    let target = target.or(get().target.as_ref()).ok_or(Error::NoTarget)?;
+    crate::inventory::remember_port(target)
+}
+
+/// Number of parallel downloads (one per CPU unless configured).
+pub fn jobs() -> usize {
+    get()
+        .jobs
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1)
+}
+
+/// Number of files uploaded at once (`ssh.upload_jobs`).
+pub fn upload_jobs() -> usize {
+    get().ssh.upload_jobs.max(1)
+}
+
+/// Largest size in bytes a package list may uncompress to
+/// (`max_index_size`).
+pub fn max_index_bytes() -> u64 {
+    get().max_index_size.saturating_mul(1024 * 1024)
+}
+
+/// The configured SSH connection timeout.
+pub fn connect_timeout() -> Option<Duration> {
+    get().ssh.connect_timeout.map(Duration::from_secs)
+}
+
+/// The private key given with `--identity` (`ssh.identity`), with `~`
+/// expanded.
+pub fn identity() -> Option<PathBuf> {
+    get().ssh.identity.as_deref().map(crate::inventory::expand_home)
+}
@@ -0,0 +1,416 @@
+//! # Encryption of cached images at rest
+//!
+//! An image's downloaded files (`debs/` or `sources/`) can be kept
+//! encrypted, so that a laptop or USB stick holding the cache does not
+//! reveal them. Encryption is per image and password based:
+//!
+//! - `encryption.toml` in the image directory holds the scrypt salt and a
+//!   check value that tells a wrong passphrase apart from a damaged file;
+//! - every file is encrypted with AES-256-GCM under the derived key, with a
+//!   random nonce, as `ARENC1 || nonce || ciphertext || tag`.
+//!
+//! `uri.toml`, `uri.lock` and `state.toml` stay readable, so `status`,
+//! `check` and `gc` work without the passphrase (`check` cannot compare the
+//! `Release` dates of encrypted lists, only whether they still exist). `apt-remote encrypt` turns
+//! encryption on for an image (and `encrypt = true` in `config.toml` does so
+//! for every image on its next `get`); files are then decrypted
+//! transparently, into a private temporary directory, when `install` and
+//! `update` upload them and when `export` writes a bundle.
+//!
+//! The passphrase is read from `--passphrase-file` or `APT_REMOTE_PASSPHRASE`,
+//! or asked for on the terminal (see [`crate::secret`]).
+
+use crate::{
+    archive::{hex, unhex},
+    config,
+    error::Error,
+    exit::{Classify, ExitCode},
+    secret::{self, Credential},
+    tr,
+};
+
+use anyhow::{Context, Result, anyhow};
+use openssl::{
+    rand::rand_bytes,
+    symm::{Cipher, Crypter, Mode},
+};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Name of the encryption header in an image directory.
+pub const HEADER_FILE: &str = "encryption.toml";
+/// Marker at the start of every encrypted file.
+const MAGIC: &[u8] = b"ARENC1";
+/// Length of the AES-GCM nonce.
+const NONCE_LEN: usize = 12;
+/// Length of the AES-GCM authentication tag.
+const TAG_LEN: usize = 16;
+/// Bytes an encrypted file has in addition to its plaintext.
+pub const OVERHEAD: u64 = (MAGIC.len() + NONCE_LEN + TAG_LEN) as u64;
+/// Plaintext whose encryption is stored in the header as a check value.
+const CHECK: &[u8] = b"apt-remote";
+/// scrypt cost parameters (N = 2^15, r = 8, p = 1).
+const SCRYPT: (u64, u64, u64) = (1 << 15, 8, 1);
+
+/// Representation of `encryption.toml`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    /// Format version.
+    version: u32,
+    /// Hex-encoded scrypt salt.
+    salt: String,
+    /// Hex-encoded encryption of a known value under the key.
+    check: String,
+}
+
+/// The key of an encrypted image.
+pub struct Key([u8; 32]);
+
+/// Whether the image in `image_dir` is encrypted.
+pub fn is_encrypted(image_dir: &Path) -> bool {
+    image_dir.join(HEADER_FILE).is_file()
+}
+
+/// Read the passphrase for image `name`, asking twice for a new one.
+fn passphrase(name: &str, confirm: bool) -> Result<String> {
+    if let Some(passphrase) = secret::provided(Credential::Passphrase)? {
+        return Ok(passphrase);
+    }
+    let passphrase = secret::ask(Credential::Passphrase, &tr!("Passphrase for {name}: ", name = name))?;
+    if confirm && secret::ask(Credential::Passphrase, &tr!("Repeat passphrase: "))? != passphrase {
+        return Err(anyhow!("The passphrases do not match")).classify(ExitCode::Cache);
+    }
+    Ok(passphrase)
+}
+
+/// Derive the key for `passphrase` and `salt`.
+fn derive(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let (n, r, p) = SCRYPT;
+    let mut key = [0; 32];
+    openssl::pkcs5::scrypt(passphrase.as_bytes(), salt, n, r, p, 64 * 1024 * 1024, &mut key)
+        .context("Failed to derive the encryption key")?;
+    Ok(Key(key))
+}
+
+/// Turn on encryption for the image in `image_dir`, asking for a new
+/// passphrase, and return its key.
+///
+/// # Errors
+/// Returns an error if no passphrase is available or the header cannot be
+/// written.
+pub fn enable(image_dir: &Path, name: &str) -> Result<Key> {
+    let mut salt = [0; 16];
+    rand_bytes(&mut salt)?;
+    let key = derive(&passphrase(name, true)?, &salt)?;
+
+    let header = Header { version: 1, salt: hex(&salt), check: hex(&key.seal(CHECK)?) };
+    let path = image_dir.join(HEADER_FILE);
+    fs::write(&path, toml::to_string_pretty(&header)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(key)
+}
+
+/// The key of the image in `image_dir`, or `None` if it is not encrypted.
+///
+/// # Errors
+/// Returns [`Error::WrongPassphrase`] if the passphrase does not match, or
+/// an error if no passphrase is available or the header is unreadable.
+pub fn unlock(image_dir: &Path, name: &str) -> Result<Option<Key>> {
+    let path = image_dir.join(HEADER_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let header: Header = toml::from_str(&content)
+        .with_context(|| format!("Failed to parse TOML from {}", path.display()))
+        .classify(ExitCode::Cache)?;
+
+    let key = derive(&passphrase(name, false)?, &unhex(&header.salt)?)?;
+    if key.open(&unhex(&header.check)?).ok().as_deref() != Some(CHECK) {
+        return Err(Error::WrongPassphrase { name: name.to_string() }.into());
+    }
+    Ok(Some(key))
+}
+
+/// Whether new downloads of images should be encrypted.
+pub fn wanted() -> bool {
+    config::get().encrypt
+}
+
+/// Whether the file at `path` was encrypted by apt-remote.
+fn is_sealed(path: &Path) -> bool {
+    let mut magic = [0; MAGIC.len()];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && magic == MAGIC
+}
+
+/// Downloaded files of the image in `image_dir`.
+fn data_files(image_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for dir in ["debs", "sources"].map(|dir| image_dir.join(dir)).iter().filter(|dir| dir.is_dir()) {
+        for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_none_or(|ext| ext != "part") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Encrypt the downloaded files of the image in `image_dir`.
+///
+/// Encryption is turned on first if needed; files that are already
+/// encrypted, for example by an interrupted earlier run, are left as they
+/// are. Returns the image's key and the number of files encrypted.
+///
+/// # Errors
+/// Returns an error if the passphrase is wrong or a file cannot be encrypted.
+pub fn encrypt_image(image_dir: &Path, name: &str) -> Result<(Key, usize)> {
+    let key = match unlock(image_dir, name)? {
+        Some(key) => key,
+        None => enable(image_dir, name)?,
+    };
+    let mut encrypted = 0;
+    for path in data_files(image_dir)?.iter().filter(|path| !is_sealed(path)) {
+        key.encrypt_file(path)?;
+        encrypted += 1;
+    }
+    Ok((key, encrypted))
+}
+
+/// Decrypt the downloaded files of the image in `image_dir` and turn
+/// encryption off. Returns the number of files decrypted.
+///
+/// # Errors
+/// Returns an error if the passphrase is wrong or a file cannot be
+/// decrypted; the image then stays encrypted.
+pub fn decrypt_image(image_dir: &Path, name: &str) -> Result<usize> {
+    let Some(key) = unlock(image_dir, name)? else {
+        return Ok(0);
+    };
+    let mut decrypted = 0;
+    for path in data_files(image_dir)?.iter().filter(|path| is_sealed(path)) {
+        let partial = partial_path(path);
+        key.decrypt_file(path, &partial)?;
+        fs::rename(&partial, path)?;
+        decrypted += 1;
+    }
+    fs::remove_file(image_dir.join(HEADER_FILE))?;
+    Ok(decrypted)
+}
+
+impl Key {
+    /// Create a cipher context with a fresh or given nonce.
+    fn crypter(&self, mode: Mode, nonce: &[u8]) -> Result<Crypter> {
+        Ok(Crypter::new(Cipher::aes_256_gcm(), mode, &self.0, Some(nonce))?)
+    }
+
+    /// Encrypt a small value in memory.
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut sealed = Vec::new();
+        self.encrypt(plaintext, &mut sealed)?;
+        Ok(sealed)
+    }
+
+    /// Decrypt a small value in memory.
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        self.decrypt(std::io::Cursor::new(sealed), &mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    /// Encrypt everything read from `input` into `output`.
+    fn encrypt(&self, mut input: impl Read, mut output: impl Write) -> Result<()> {
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        let mut crypter = self.crypter(Mode::Encrypt, &nonce)?;
+        output.write_all(MAGIC)?;
+        output.write_all(&nonce)?;
+
+        let mut buffer = vec![0; 64 * 1024];
+        let mut sealed = vec![0; buffer.len() + TAG_LEN];
+        loop {
+            let read = input.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            let written = crypter.update(&buffer[..read], &mut sealed)?;
+            output.write_all(&sealed[..written])?;
+        }
+        let written = crypter.finalize(&mut sealed)?;
+        output.write_all(&sealed[..written])?;
+
+        let mut tag = [0; TAG_LEN];
+        crypter.get_tag(&mut tag)?;
+        output.write_all(&tag)?;
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Decrypt an encrypted stream from `input` into `output`.
+    ///
+    /// The output must be discarded if this fails, since the tag is only
+    /// checked at the end.
+    fn decrypt(&self, mut input: impl Read + Seek, mut output: impl Write) -> Result<()> {
+        let len = input.seek(SeekFrom::End(0))?;
+        let header_len = (MAGIC.len() + NONCE_LEN) as u64;
+        if len < header_len + TAG_LEN as u64 {
+            return Err(anyhow!("File is too short to be encrypted"));
+        }
+
+        // The tag is at the end, the marker and nonce at the start
+        let mut tag = [0; TAG_LEN];
+        input.seek(SeekFrom::End(-(TAG_LEN as i64)))?;
+        input.read_exact(&mut tag)?;
+        input.seek(SeekFrom::Start(0))?;
+        let mut header = vec![0; header_len as usize];
+        input.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(anyhow!("File is not encrypted by apt-remote"));
+        }
+        let mut crypter = self.crypter(Mode::Decrypt, &header[MAGIC.len()..])?;
+        crypter.set_tag(&tag)?;
+
+        let mut remaining = len - header_len - TAG_LEN as u64;
+        let mut buffer = vec![0; 64 * 1024];
+        let mut plain = vec![0; buffer.len() + TAG_LEN];
+        while remaining > 0 {
+            let chunk = remaining.min(buffer.len() as u64) as usize;
+            input.read_exact(&mut buffer[..chunk])?;
+            let written = crypter.update(&buffer[..chunk], &mut plain)?;
+            output.write_all(&plain[..written])?;
+            remaining -= chunk as u64;
+        }
+        let written = crypter
+            .finalize(&mut plain)
+            .map_err(|_| anyhow!("Authentication failed: the file is damaged or was modified"))?;
+        output.write_all(&plain[..written])?;
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Encrypt a file in place.
+    ///
+    /// The encrypted copy replaces the file by renaming, so hard links to
+    /// the plaintext (in the pool) are not modified.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read or replaced.
+    pub fn encrypt_file(&self, path: &Path) -> Result<()> {
+        let partial = partial_path(path);
+        let input = BufReader::new(File::open(path).with_context(|| format!("Failed to open {}", path.display()))?);
+        self.encrypt(input, BufWriter::new(File::create(&partial)?))
+            .with_context(|| format!("Failed to encrypt {}", path.display()))?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Decrypt the file at `src` into `dest`.
+    ///
+    /// # Errors
+    /// Returns an error if `src` is not a valid encrypted file for this key,
+    /// in which case `dest` is removed.
+    pub fn decrypt_file(&self, src: &Path, dest: &Path) -> Result<()> {
+        let input = BufReader::new(File::open(src).with_context(|| format!("Failed to open {}", src.display()))?);
+        let outcome = File::create(dest)
+            .map_err(anyhow::Error::from)
+            .and_then(|output| self.decrypt(input, BufWriter::new(output)));
+        if outcome.is_err() {
+            fs::remove_file(dest).ok();
+        }
+        outcome
+            .with_context(|| format!("Failed to decrypt {}", src.display()))
+            .classify(ExitCode::Cache)
+    }
+
+    /// A plaintext view of `path` for reading or uploading, decrypted into
+    /// a private temporary directory.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be decrypted.
+    pub fn plaintext(&self, path: &Path) -> Result<Plaintext> {
+        // Left over from an interrupted `encrypt`
+        if !is_sealed(path) {
+            return Ok(Plaintext { path: path.to_path_buf(), temporary: false });
+        }
+        let dir = private_dir()?;
+        let dest = dir.join(path.file_name().context("Not a file")?);
+        if let Err(e) = self.decrypt_file(path, &dest) {
+            fs::remove_dir(&dir).ok();
+            return Err(e);
+        }
+        Ok(Plaintext { path: dest, temporary: true })
+    }
+}
+
+/// A readable file: the original, or a decrypted copy removed on drop,
+/// with its private directory.
+pub struct Plaintext {
+    path: PathBuf,
+    temporary: bool,
+}
+
+impl Plaintext {
+    /// A plaintext view of `path`, decrypted with `key` if the image is encrypted.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be decrypted.
+    pub fn of(key: Option<&Key>, path: &Path) -> Result<Plaintext> {
+        match key {
+            Some(key) => key.plaintext(path),
+            None => Ok(Plaintext { path: path.to_path_buf(), temporary: false }),
+        }
+    }
+
+    /// Location of the readable file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Plaintext {
+    fn drop(&mut self) {
+        if self.temporary {
+            fs::remove_file(&self.path).ok();
+            if let Some(dir) = self.path.parent() {
+                fs::remove_dir(dir).ok();
+            }
+        }
+    }
+}
+
+/// Temporary file next to `path` that replaces it once complete.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Create a new directory for a decrypted copy, readable only by this
+/// user, as `mkdtemp` does: its name is random, and it is created with mode
+/// 0700 in one step, so a directory someone else prepared is never used.
+fn private_dir() -> Result<PathBuf> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    for _ in 0..16 {
+        let mut random = [0u8; 8];
+        rand_bytes(&mut random)?;
+        let dir = std::env::temp_dir().join(format!("apt-remote-{}", hex(&random)));
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to create {}", dir.display())),
+        }
+    }
+    Err(anyhow!("Failed to create a private directory in {}", std::env::temp_dir().display()))
+}
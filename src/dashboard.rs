@@ -0,0 +1,633 @@
+//! # Fleet dashboard
+//!
+//! Running `install` or `update` on an `@group` target of 30 kiosks prints
+//! the progress of every host one after the other. With `--ui tui` (or
+//! `ui = "tui"` in `config.toml`) the run is shown as a table instead, one
+//! row per host, redrawn in place:
+//!
+//! ```text
+//! @kiosks: 12 of 30 done, 1 failed
+//!     ✓ admin@kiosk-01  Installed web on admin@kiosk-01
+//!     ✗ admin@kiosk-02  Failed to connect to kiosk-02:22: Connection timed out
+//!     ⠹ admin@kiosk-03  Uploading web [########------------] 5/12  curl_7.88.1-10_amd64.deb
+//!     · admin@kiosk-04  waiting
+//! ```
+//!
+//! The output of each host is kept with its row instead of being printed.
+//! The rows can be browsed with the arrow keys while the run goes on, and
+//! Enter shows the output of the selected host, following it as the host
+//! writes more. Once every host is done, the rows can be browsed again
+//! before the command exits.
+//!
+//! The dashboard is drawn on stdout, so it needs a terminal: in JSON or
+//! quiet mode, or when stdout is redirected, the output is printed as
+//! usual. Keys are read during the run by switching the terminal to
+//! non-canonical mode with `stty`; prompts (confirmations, passwords)
+//! pause the dashboard and restore the terminal while they are answered.
+
+use crate::{
+    config, output,
+    progress::{Progress, ProgressReporter},
+    redact, theme, tr,
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use colored::Colorize;
+use console::{Key, Term};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    io::{IsTerminal, Read},
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// The dashboard being drawn, if any.
+static BOARD: Mutex<Option<Board>> = Mutex::new(None);
+
+/// The settings of the terminal to restore, while keys are read from it
+/// during a run. Held while a key is read, so that a prompt does not
+/// restore the terminal in the middle of a read.
+static INPUT: Mutex<Option<String>> = Mutex::new(None);
+
+/// Frames of the spinner of running hosts.
+const SPINNER: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+/// Width of the progress bars, in characters.
+const BAR_WIDTH: u64 = 20;
+
+/// How multi-target runs are displayed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum UiMode {
+    /// Progress and output of each host, one after the other.
+    #[default]
+    Plain,
+    /// A table of the hosts, redrawn as they progress.
+    Tui,
+}
+
+/// Returns `true` if group runs are shown on the dashboard.
+pub fn enabled() -> bool {
+    let config = config::get();
+    config.ui == UiMode::Tui && !config.quiet && !output::is_json() && std::io::stdout().is_terminal()
+}
+
+/// Where a host is in the run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Waiting,
+    Running,
+    Done,
+    Failed,
+}
+
+/// One host of the dashboard.
+struct Row {
+    /// The host, as `user@host`.
+    target: String,
+    status: Status,
+    /// Message of the current phase.
+    phase: String,
+    /// Message of the item being worked on within the phase.
+    detail: String,
+    /// Steps of the current phase done, and its length (0 without a bar).
+    pos: u64,
+    len: u64,
+    /// The error the host failed with.
+    error: Option<String>,
+    /// Output lines of the host.
+    log: Vec<String>,
+}
+
+/// State of the dashboard, shared with the progress handles and the
+/// painter thread.
+struct Board {
+    /// Name of the group, without `@`.
+    group: String,
+    rows: Vec<Row>,
+    /// Index of the host being run.
+    current: Option<usize>,
+    /// Row selected while browsing.
+    selected: Option<usize>,
+    /// Row whose output is shown instead of the table.
+    log: Option<usize>,
+    /// First line of the output shown (`usize::MAX` follows its end).
+    offset: usize,
+    /// Lines drawn last, replaced by the next drawing.
+    drawn: usize,
+    /// Whether drawing is paused for a prompt.
+    paused: bool,
+    /// Frame of the spinner.
+    tick: usize,
+}
+
+impl Board {
+    /// The table, with the selected row highlighted while browsing.
+    fn table(&self, height: usize, width: usize) -> Vec<String> {
+        let selected = self.selected;
+        let finished = self.rows.iter().filter(|row| matches!(row.status, Status::Done | Status::Failed)).count();
+        let failed = self.rows.iter().filter(|row| row.status == Status::Failed).count();
+        let mut lines = vec![format!(
+            "{}: {}",
+            format!("@{}", self.group).bold(),
+            tr!(
+                "{done} of {total} done, {failed} failed",
+                done = finished,
+                total = self.rows.len(),
+                failed = failed
+            )
+        )];
+
+        // Keep the selected or running host in view on short terminals
+        let visible = height.saturating_sub(if selected.is_some() { 3 } else { 2 }).max(1);
+        let focus = selected.or(self.current).unwrap_or_default();
+        let start = focus.saturating_sub(visible / 2).min(self.rows.len().saturating_sub(visible));
+        let name_width = self.rows.iter().map(|row| row.target.len()).max().unwrap_or_default();
+        for (index, row) in self.rows.iter().enumerate().skip(start).take(visible) {
+            let (marker, text) = match row.status {
+                Status::Waiting => ("·".dimmed(), tr!("waiting").dimmed()),
+                Status::Running => (theme::accent(SPINNER[self.tick % SPINNER.len()]).bold(), row.progress().normal()),
+                Status::Done => (theme::success("✓").bold(), row.phase.normal()),
+                Status::Failed => (theme::failure("✗").bold(), theme::failure(row.error.as_deref().unwrap_or_default())),
+            };
+            let name = format!("{:name_width$}", row.target);
+            let (pointer, name) = if selected == Some(index) { ("›", name.bold().reversed()) } else { (" ", name.bold()) };
+            let line = format!("  {pointer} {marker} {name}  {text}");
+            lines.push(console::truncate_str(&line, width, "…").into_owned());
+        }
+        match (selected, self.current) {
+            (Some(_), Some(_)) => lines.push(format!("{}", tr!("↑/↓ select · Enter show output · q hide").dimmed())),
+            (Some(_), None) => lines.push(format!("{}", tr!("↑/↓ select · Enter show output · q quit").dimmed())),
+            (None, _) => {}
+        }
+        lines
+    }
+
+    /// The output of the host of row `index`, from line `self.offset`.
+    fn log_lines(&self, index: usize, height: usize, width: usize) -> Vec<String> {
+        let row = &self.rows[index];
+        let visible = log_height(height);
+        let offset = self.offset.min(row.log.len().saturating_sub(visible));
+
+        let mut lines = vec![format!("{}", format!("{}:", row.target).bold())];
+        if row.log.is_empty() {
+            lines.push(format!("    {}", tr!("(no output)").dimmed()));
+        }
+        lines.extend(
+            row.log[offset..]
+                .iter()
+                .take(visible)
+                .map(|line| console::truncate_str(&format!("    {line}"), width, "…").into_owned()),
+        );
+        lines.push(format!("{}", tr!("↑/↓ scroll · q back").dimmed()));
+        lines
+    }
+
+    /// Replace what was drawn last with `lines`.
+    fn paint(&mut self, lines: &[String]) {
+        let term = Term::buffered_stdout();
+        term.move_cursor_up(self.drawn).ok();
+        for line in lines {
+            term.clear_line().ok();
+            term.write_line(line).ok();
+        }
+        if lines.len() < self.drawn {
+            term.clear_to_end_of_screen().ok();
+        }
+        term.flush().ok();
+        self.drawn = lines.len();
+    }
+
+    /// Draw the table, or the output of the host looked at.
+    fn draw(&mut self) {
+        let (height, width) = Term::stdout().size();
+        let lines = match self.log {
+            Some(index) => self.log_lines(index, height.into(), width.into()),
+            None => self.table(height.into(), width.into()),
+        };
+        self.paint(&lines);
+    }
+
+    /// Act on a `key` pressed while browsing. Returns `false` once the user
+    /// stops browsing the table.
+    fn key(&mut self, key: &Key) -> bool {
+        let last = self.rows.len().saturating_sub(1);
+        if let Some(index) = self.log {
+            // Scrolling starts from the lines shown, and the end is followed again once reached
+            let visible = log_height(Term::stdout().size().0.into());
+            let end = self.rows[index].log.len().saturating_sub(visible);
+            let offset = self.offset.min(end);
+            self.offset = match *key {
+                Key::ArrowUp | Key::Char('k') => offset.saturating_sub(1),
+                Key::ArrowDown | Key::Char('j') => offset + 1,
+                Key::PageUp => offset.saturating_sub(visible),
+                Key::PageDown | Key::Char(' ') => offset + visible,
+                Key::Home => 0,
+                Key::End => usize::MAX,
+                Key::Char('q') | Key::Escape | Key::Enter | Key::ArrowLeft | Key::Backspace | Key::CtrlC => {
+                    self.log = None;
+                    self.offset
+                }
+                _ => self.offset,
+            };
+            if self.offset >= end && self.offset != 0 {
+                self.offset = usize::MAX;
+            }
+            return true;
+        }
+        let selected = self.selected.or(self.current).unwrap_or_default();
+        match *key {
+            Key::ArrowUp | Key::Char('k') if self.selected.is_some() => self.selected = Some(selected.saturating_sub(1)),
+            Key::ArrowDown | Key::Char('j') if self.selected.is_some() => self.selected = Some((selected + 1).min(last)),
+            Key::ArrowUp | Key::Char('k') | Key::ArrowDown | Key::Char('j') => self.selected = Some(selected),
+            Key::Home => self.selected = Some(0),
+            Key::End => self.selected = Some(last),
+            Key::Enter | Key::ArrowRight => {
+                self.selected = Some(selected);
+                self.log = Some(selected);
+                self.offset = usize::MAX;
+            }
+            Key::Char('q') | Key::Escape | Key::CtrlC => {
+                self.selected = None;
+                return false;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Browse the rows until the user quits.
+    fn browse(&mut self) {
+        let term = Term::stdout();
+        self.selected = Some(self.rows.iter().position(|row| row.status == Status::Failed).unwrap_or_default());
+        self.log = None;
+        loop {
+            self.draw();
+            match term.read_key() {
+                Ok(key) if self.key(&key) => {}
+                _ => break,
+            }
+        }
+        self.selected = None;
+        self.log = None;
+        self.draw();
+    }
+}
+
+/// Number of output lines shown on a terminal of `height` lines.
+fn log_height(height: usize) -> usize {
+    height.saturating_sub(3).max(1)
+}
+
+impl Row {
+    /// The phase of a running host, with its bar and current item.
+    fn progress(&self) -> String {
+        if self.phase.is_empty() && self.detail.is_empty() {
+            return format!("{}", tr!("running").dimmed());
+        }
+        let mut text = self.phase.clone();
+        if let Some(filled) = (self.pos.min(self.len) * BAR_WIDTH).checked_div(self.len) {
+            let filled = filled as usize;
+            let bar = format!("{}{}", "#".repeat(filled), "-".repeat(BAR_WIDTH as usize - filled));
+            text.push_str(&format!(" [{}] {}/{}", theme::accent(bar), self.pos, self.len));
+        }
+        if !self.detail.is_empty() {
+            text.push_str(&format!("  {}", self.detail.dimmed()));
+        }
+        text
+    }
+}
+
+/// A message as shown in a row: without colors, tabs and result markers.
+fn clean(message: &str) -> String {
+    let message = redact::redact(message);
+    let message = console::strip_ansi_codes(&message);
+    message.trim().trim_start_matches(['✓', '✗', '!', ' ']).lines().next().unwrap_or_default().to_string()
+}
+
+/// Run `f` on the row being run, if the dashboard is drawn.
+fn with_current<T>(f: impl FnOnce(&mut Row) -> T) -> Option<T> {
+    let mut board = BOARD.lock().unwrap();
+    let board = board.as_mut()?;
+    let index = board.current?;
+    Some(f(&mut board.rows[index]))
+}
+
+/// A group run shown on the dashboard.
+pub struct Dashboard {
+    painter: Option<JoinHandle<()>>,
+    /// Thread reading keys during the run, if there is a terminal to read.
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Dashboard {
+    /// Show the dashboard for the `members` of `group`.
+    pub fn start(group: &str, members: &[String]) -> Dashboard {
+        let rows = members
+            .iter()
+            .map(|target| Row {
+                target: target.clone(),
+                status: Status::Waiting,
+                phase: String::new(),
+                detail: String::new(),
+                pos: 0,
+                len: 0,
+                error: None,
+                log: Vec::new(),
+            })
+            .collect();
+        *BOARD.lock().unwrap() = Some(Board {
+            group: group.to_string(),
+            rows,
+            current: None,
+            selected: None,
+            log: None,
+            offset: 0,
+            drawn: 0,
+            paused: false,
+            tick: 0,
+        });
+
+        // Redraw ten times a second, until the board is closed
+        let painter = thread::spawn(|| {
+            loop {
+                if let Some(board) = BOARD.lock().unwrap().as_mut() {
+                    if !board.paused {
+                        board.tick += 1;
+                        board.draw();
+                    }
+                } else {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+
+        // Let the rows be browsed during the run
+        let reader = if std::io::stdin().is_terminal() && !config::get().batch {
+            *INPUT.lock().unwrap() = raw_input();
+            Some(thread::spawn(read_keys))
+        } else {
+            None
+        };
+        Dashboard { painter: Some(painter), reader }
+    }
+
+    /// Mark member `index` as running: output and progress go to its row.
+    pub fn begin(&self, index: usize) {
+        if let Some(board) = BOARD.lock().unwrap().as_mut() {
+            board.current = Some(index);
+            board.rows[index].status = Status::Running;
+        }
+    }
+
+    /// Record the outcome of member `index`.
+    pub fn end(&self, index: usize, outcome: &Result<()>) {
+        if let Some(board) = BOARD.lock().unwrap().as_mut() {
+            let row = &mut board.rows[index];
+            row.detail.clear();
+            match outcome {
+                Ok(()) => row.status = Status::Done,
+                Err(e) => {
+                    row.status = Status::Failed;
+                    row.error = Some(format!("{e:#}"));
+                    row.log.push(format!("{} {e:#}", theme::failure(tr!("Error:")).bold()));
+                }
+            }
+        }
+    }
+
+    /// Draw the final table, and let the user browse the output of the
+    /// hosts if there is a terminal to ask on.
+    pub fn close(mut self) {
+        let board = BOARD.lock().unwrap().take();
+        for thread in [self.painter.take(), self.reader.take()].into_iter().flatten() {
+            thread.join().ok();
+        }
+        stop_input();
+        let Some(mut board) = board else {
+            return;
+        };
+        board.current = None;
+        if std::io::stdin().is_terminal() && !config::get().batch {
+            board.browse();
+        } else {
+            board.selected = None;
+            board.log = None;
+            board.draw();
+        }
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        // Closed without `close` (on a panic): stop drawing and reading keys
+        BOARD.lock().unwrap().take();
+        stop_input();
+    }
+}
+
+/// Act on the keys pressed during the run, until the board is closed.
+fn read_keys() {
+    let mut buffer = [0; 64];
+    loop {
+        // Reads return after a tenth of a second without input
+        let read = {
+            let input = INPUT.lock().unwrap();
+            if input.is_some() { std::io::stdin().read(&mut buffer).ok() } else { None }
+        };
+        let mut guard = BOARD.lock().unwrap();
+        let Some(board) = guard.as_mut() else {
+            return;
+        };
+        let Some(read) = read else {
+            // Paused for a prompt
+            drop(guard);
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        };
+        for key in keys(&buffer[..read]) {
+            // Signals are off while keys are read: Ctrl-C interrupts the run as usual
+            if key == Key::CtrlC {
+                stop_input();
+                std::process::exit(130);
+            }
+            board.key(&key);
+        }
+        if read > 0 && !board.paused {
+            board.draw();
+        }
+    }
+}
+
+/// The keys in `bytes` read from a terminal.
+fn keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut rest = bytes;
+    while let Some((&first, tail)) = rest.split_first() {
+        let (key, len) = match (first, tail) {
+            (0x1b, [b'[' | b'O', code, more @ ..]) => match (code, more.first()) {
+                (b'A', _) => (Key::ArrowUp, 3),
+                (b'B', _) => (Key::ArrowDown, 3),
+                (b'C', _) => (Key::ArrowRight, 3),
+                (b'D', _) => (Key::ArrowLeft, 3),
+                (b'H', _) => (Key::Home, 3),
+                (b'F', _) => (Key::End, 3),
+                (b'1' | b'7', Some(b'~')) => (Key::Home, 4),
+                (b'4' | b'8', Some(b'~')) => (Key::End, 4),
+                (b'5', Some(b'~')) => (Key::PageUp, 4),
+                (b'6', Some(b'~')) => (Key::PageDown, 4),
+                _ => (Key::Unknown, 3),
+            },
+            (0x1b, _) => (Key::Escape, 1),
+            (b'\r' | b'\n', _) => (Key::Enter, 1),
+            (0x03, _) => (Key::CtrlC, 1),
+            (0x08 | 0x7f, _) => (Key::Backspace, 1),
+            (byte, _) => (Key::Char(char::from(byte)), 1),
+        };
+        keys.push(key);
+        rest = &rest[len.min(rest.len())..];
+    }
+    keys
+}
+
+/// Switch the terminal to non-canonical mode, without echo and signals, so
+/// that keys are read as they are pressed. Returns the settings to restore.
+fn raw_input() -> Option<String> {
+    let saved = Command::new("stty").arg("-g").stdin(Stdio::inherit()).output().ok().filter(|o| o.status.success())?;
+    let saved = String::from_utf8(saved.stdout).ok()?.trim().to_string();
+    let status = Command::new("stty")
+        .args(["-icanon", "-echo", "-isig", "min", "0", "time", "1"])
+        .stdin(Stdio::inherit())
+        .status()
+        .ok()?;
+    status.success().then_some(saved)
+}
+
+/// Stop reading keys, and restore the terminal.
+fn stop_input() {
+    if let Some(saved) = INPUT.lock().unwrap().take() {
+        Command::new("stty").arg(&saved).stdin(Stdio::inherit()).status().ok();
+    }
+}
+
+/// Keep an output `line` with the row being run instead of printing it.
+///
+/// Returns `false` if the dashboard is not drawn.
+pub fn capture(line: &str) -> bool {
+    with_current(|row| {
+        for line in line.lines() {
+            let line = line.replace('\t', "    ");
+            if line.trim().is_empty() && row.log.last().is_none_or(|last| last.trim().is_empty()) {
+                continue;
+            }
+            row.log.push(line);
+        }
+    })
+    .is_some()
+}
+
+/// A prompt in progress, during which the dashboard is not drawn.
+pub struct Paused {
+    /// Whether the dashboard was drawn.
+    drawn: bool,
+    /// Whether keys were read from the terminal.
+    keys: bool,
+}
+
+/// Stop drawing the dashboard and reading keys until the returned guard is
+/// dropped, so that a prompt can be answered.
+pub fn pause() -> Paused {
+    let mut board = BOARD.lock().unwrap();
+    let Some(board) = board.as_mut() else {
+        return Paused { drawn: false, keys: false };
+    };
+    board.paint(&[]);
+    board.paused = true;
+    let keys = INPUT.lock().unwrap().is_some();
+    stop_input();
+    Paused { drawn: true, keys }
+}
+
+impl Drop for Paused {
+    fn drop(&mut self) {
+        if self.keys {
+            *INPUT.lock().unwrap() = raw_input();
+        }
+        if self.drawn
+            && let Some(board) = BOARD.lock().unwrap().as_mut()
+        {
+            board.paused = false;
+        }
+    }
+}
+
+/// The reporter showing progress in the dashboard's rows, if it is drawn.
+pub fn reporter() -> Option<Arc<dyn ProgressReporter>> {
+    BOARD.lock().unwrap().is_some().then(|| Arc::new(Rows) as Arc<dyn ProgressReporter>)
+}
+
+/// Progress reporter of the dashboard.
+struct Rows;
+
+impl ProgressReporter for Rows {
+    fn bar(&self, len: u64) -> Arc<dyn Progress> {
+        with_current(|row| {
+            row.pos = 0;
+            row.len = len;
+        });
+        Arc::new(Cell { bar: true })
+    }
+
+    fn task(&self) -> Arc<dyn Progress> {
+        Arc::new(Cell { bar: false })
+    }
+
+    fn spinner(&self) -> Arc<dyn Progress> {
+        Arc::new(Cell { bar: false })
+    }
+}
+
+/// A bar (the phase of a row) or a task (its current item).
+struct Cell {
+    bar: bool,
+}
+
+impl Progress for Cell {
+    fn message(&self, msg: &str) {
+        let msg = clean(msg);
+        with_current(|row| if self.bar { row.phase = msg } else { row.detail = msg });
+    }
+
+    fn inc(&self, delta: u64) {
+        if self.bar {
+            with_current(|row| row.pos += delta);
+        }
+    }
+
+    fn finish(&self, msg: &str) {
+        let line = redact::redact(msg).trim().to_string();
+        let msg = clean(msg);
+        with_current(|row| {
+            if !line.is_empty() {
+                row.log.push(line);
+            }
+            if self.bar {
+                row.phase = msg;
+                row.pos = row.len;
+            } else {
+                row.detail.clear();
+            }
+        });
+    }
+
+    fn clear(&self) {
+        if !self.bar {
+            with_current(|row| row.detail.clear());
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! # Local `.deb` sanity checks
+//!
+//! `install` checks every cached package before uploading it, so a
+//! truncated or mislabeled file is caught on the workstation instead of
+//! failing in `dpkg` after the upload:
+//!
+//! - the file must be an `ar` archive whose members are `debian-binary`,
+//!   `control.tar[.xz|.gz|.zst]` and `data.tar[...]`, in that order;
+//! - every member must be complete (the file is not truncated or padded);
+//! - the `Package`, `Version` and `Architecture` fields of the control file
+//!   must match the file name apt recorded (`name_version_arch.deb`).
+//!
+//! Control archives compressed with xz, or not at all, are read in-process;
+//! gzip and zstd ones are read with the `gzip` and `zstd` tools when they
+//! are installed, and only the archive structure is checked otherwise.
+
+use crate::uri;
+
+use xz2::read::XzDecoder;
+
+use anyhow::{Context, Result};
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// Largest control archive that is read, uncompressed.
+const MAX_CONTROL_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Package identity from the control file of a `.deb`.
+#[derive(Debug)]
+pub struct Control {
+    /// `Package` field.
+    pub package: String,
+    /// `Version` field, including any epoch.
+    pub version: String,
+    /// `Architecture` field.
+    pub architecture: String,
+}
+
+/// What is wrong with the package at `path`, recorded as `fname`, if anything.
+///
+/// # Errors
+/// Returns an error only if the file cannot be read; problems with its
+/// content are returned as the description.
+pub fn problem(path: &Path, fname: &str) -> Result<Option<String>> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let control = match inspect(&mut file)? {
+        Ok(control) => control,
+        Err(problem) => return Ok(Some(problem)),
+    };
+    let (Some(control), Some((package, version, architecture))) = (control, parse_file_name(fname)) else {
+        return Ok(None);
+    };
+
+    // Pool file names leave out the epoch
+    let control_version = match version.contains(':') {
+        true => control.version.as_str(),
+        false => control.version.split_once(':').map_or(control.version.as_str(), |(_, v)| v),
+    };
+    let mismatches = [
+        ("Package", control.package.as_str(), package.as_str()),
+        ("Version", control_version, version.as_str()),
+        ("Architecture", control.architecture.as_str(), architecture.as_str()),
+    ]
+    .into_iter()
+    .filter(|(_, actual, expected)| actual != expected)
+    .map(|(field, actual, expected)| format!("{field} is {actual}, not {expected}"))
+    .collect::<Vec<_>>();
+    Ok((!mismatches.is_empty()).then(|| format!("control file does not match the file name: {}", mismatches.join(", "))))
+}
+
+/// Check the archive structure of a `.deb` and read its control file.
+///
+/// Returns the problem found, or the control fields (`None` if the control
+/// archive could not be decompressed here).
+fn inspect(file: &mut File) -> Result<std::result::Result<Option<Control>, String>> {
+    let length = file.metadata()?.len();
+    let mut magic = [0u8; 8];
+    if file.read_exact(&mut magic).is_err() || &magic != b"!<arch>\n" {
+        return Ok(Err("not a Debian package (no ar archive)".to_string()));
+    }
+
+    let mut members = Vec::new();
+    let mut control = None;
+    let mut offset = 8;
+    while offset < length {
+        let mut header = [0u8; 60];
+        if offset + 60 > length {
+            return Ok(Err(format!("truncated: the ar header at byte {offset} is incomplete")));
+        }
+        if file.read_exact(&mut header).is_err() || &header[58..] != b"`\n" {
+            return Ok(Err(format!("damaged ar header at byte {offset}")));
+        }
+        let name = String::from_utf8_lossy(&header[..16]).trim_end().trim_end_matches('/').to_string();
+        let Some(size) = std::str::from_utf8(&header[48..58]).ok().and_then(|s| s.trim().parse::<u64>().ok()) else {
+            return Ok(Err(format!("damaged ar header for {name}")));
+        };
+        let start = offset + 60;
+        let end = start + size;
+        if end > length {
+            return Ok(Err(format!("truncated: {name} ends at byte {end}, but the file has {length}")));
+        }
+
+        if members.is_empty() {
+            let mut version = vec![0u8; size.min(16) as usize];
+            file.read_exact(&mut version)?;
+            if name != "debian-binary" || !version.starts_with(b"2.") {
+                return Ok(Err("not a Debian package (no debian-binary 2.x member)".to_string()));
+            }
+        } else if let Some(compression) = name.strip_prefix("control.tar") {
+            let mut data = Vec::with_capacity(size as usize);
+            file.seek(SeekFrom::Start(start))?;
+            Read::by_ref(file).take(size).read_to_end(&mut data)?;
+            control = match uncompress(compression, data) {
+                Some(tar) => match control_from_tar(&tar) {
+                    Some(control) => Some(control),
+                    None => return Ok(Err("control.tar has no readable control file".to_string())),
+                },
+                None => None,
+            };
+        }
+        members.push(name);
+
+        // Members are padded to an even length
+        offset = end + (size % 2);
+        file.seek(SeekFrom::Start(offset.min(length)))?;
+    }
+
+    let control_member = members.get(1).is_some_and(|m| m.starts_with("control.tar"));
+    let data_member = members.get(2).is_some_and(|m| m.starts_with("data.tar"));
+    if !control_member || !data_member {
+        return Ok(Err(format!("unexpected members: {}", members.join(", "))));
+    }
+    Ok(Ok(control))
+}
+
+/// Uncompress a control archive with the given member suffix (`""`,
+/// `".xz"`, `".gz"` or `".zst"`). Returns `None` if that is not possible here.
+fn uncompress(compression: &str, data: Vec<u8>) -> Option<Vec<u8>> {
+    let mut tar = Vec::new();
+    match compression {
+        "" => return Some(data),
+        ".xz" => {
+            XzDecoder::new(data.as_slice()).take(MAX_CONTROL_SIZE).read_to_end(&mut tar).ok()?;
+        }
+        ".gz" | ".zst" => {
+            let tool = if compression == ".gz" { "gzip" } else { "zstd" };
+            let mut child = Command::new(tool)
+                .args(["-dc"])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut stdin = child.stdin.take()?;
+            let writer = std::thread::spawn(move || stdin.write_all(&data));
+            child.stdout.take()?.take(MAX_CONTROL_SIZE).read_to_end(&mut tar).ok()?;
+            writer.join().ok()?.ok();
+            if !child.wait().ok()?.success() {
+                return Some(Vec::new());
+            }
+        }
+        _ => return None,
+    }
+    Some(tar)
+}
+
+/// Find `./control` in a tar archive and parse its fields.
+fn control_from_tar(tar: &[u8]) -> Option<Control> {
+    let mut offset = 0;
+    while offset + 512 <= tar.len() {
+        let header = &tar[offset..offset + 512];
+        if header.iter().all(|&b| b == 0) {
+            return None;
+        }
+        let name = String::from_utf8_lossy(&header[..100]).trim_end_matches('\0').to_string();
+        let size = u64::from_str_radix(String::from_utf8_lossy(&header[124..136]).trim_matches(['\0', ' ']), 8).ok()?;
+        let start = offset + 512;
+        let end = start.checked_add(size as usize)?;
+        if matches!(name.as_str(), "./control" | "control") {
+            return parse_control(&String::from_utf8_lossy(tar.get(start..end)?));
+        }
+        offset = start + (size as usize).div_ceil(512) * 512;
+    }
+    None
+}
+
+/// Read the identity fields of a control file.
+fn parse_control(content: &str) -> Option<Control> {
+    let field = |name: &str| {
+        content
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .map(|value| value.trim().to_string())
+    };
+    Some(Control { package: field("Package")?, version: field("Version")?, architecture: field("Architecture")? })
+}
+
+/// Split an apt file name (`name_version_arch.deb`, percent-encoded) into
+/// package, version and architecture.
+fn parse_file_name(fname: &str) -> Option<(String, String, String)> {
+    let stem = fname.strip_suffix(".deb")?;
+    let mut parts = stem.split('_').map(uri::decode_file_name);
+    let (package, version, architecture) = (parts.next()?, parts.next()?, parts.next()?);
+    parts.next().is_none().then_some((package, version, architecture))
+}
@@ -0,0 +1,211 @@
+//! # Derivative distributions
+//!
+//! Raspbian, Raspberry Pi OS, Armbian and Linux Mint targets mix their
+//! vendor's repositories with Debian's or Ubuntu's. The target probe (see
+//! [`crate::probe`]) recognizes them from `/etc/os-release` and the files
+//! their vendors install, and apt-remote applies what is known about them:
+//!
+//! - packages from a vendor archive that moved are downloaded from its new
+//!   host (`archive.raspberrypi.org` is now `archive.raspberrypi.com`);
+//! - `doctor` and `update` check that the keyring APT verifies the vendor's
+//!   lists with is installed, since APT ignores lists it cannot verify;
+//! - `set` warns when the vendor's repositories are missing from the plan
+//!   or from the target's package lists, and when a Raspbian plan takes
+//!   packages from Debian's `armhf` port, which is built for newer CPUs than
+//!   Raspbian's.
+
+use crate::{output, theme, tr};
+
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
+
+use std::fmt;
+
+/// Command printing the vendor files and keyrings read by [`Derivative::detect`]
+/// and [`installed_keyrings`].
+pub const QUERY: &str = "for f in /etc/rpi-issue /etc/armbian-release; do test -e $f && echo $f; done; \
+                         dpkg-query --show --showformat='${Package} ${db:Status-Status}\\n' \
+                         raspbian-archive-keyring raspberrypi-archive-keyring linuxmint-keyring 2>/dev/null; true";
+
+/// A known derivative distribution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Derivative {
+    /// Raspbian, the ARMv6 rebuild of Debian (32-bit Raspberry Pi OS).
+    Raspbian,
+    /// Raspberry Pi OS on Debian's own ports (64-bit).
+    Raspios,
+    /// Armbian, for ARM single-board computers.
+    Armbian,
+    /// Linux Mint, based on Ubuntu.
+    Linuxmint,
+}
+
+impl Derivative {
+    /// Recognize the derivative from the target's `/etc/os-release` and the
+    /// output of [`QUERY`].
+    ///
+    /// ```
+    /// use apt_remote::derivative::Derivative;
+    ///
+    /// let os = "ID=debian\nVERSION_CODENAME=bookworm\n";
+    /// assert_eq!(Derivative::detect(os, "/etc/rpi-issue\n"), Some(Derivative::Raspios));
+    /// assert_eq!(Derivative::detect("ID=raspbian\n", "/etc/rpi-issue\n"), Some(Derivative::Raspbian));
+    /// assert_eq!(Derivative::detect("ID=linuxmint\nID_LIKE=\"ubuntu debian\"\n", ""), Some(Derivative::Linuxmint));
+    /// assert_eq!(Derivative::detect(os, ""), None);
+    /// ```
+    pub fn detect(os_release: &str, vendor: &str) -> Option<Derivative> {
+        let field = |name: &str| {
+            os_release
+                .lines()
+                .find_map(|line| line.trim().strip_prefix(name)?.strip_prefix('='))
+                .unwrap_or_default()
+                .trim_matches('"')
+        };
+        let has = |file: &str| vendor.lines().any(|line| line.trim() == file);
+        match field("ID") {
+            "raspbian" => Some(Derivative::Raspbian),
+            "linuxmint" => Some(Derivative::Linuxmint),
+            _ if has("/etc/armbian-release") || field("PRETTY_NAME").starts_with("Armbian") => Some(Derivative::Armbian),
+            _ if has("/etc/rpi-issue") => Some(Derivative::Raspios),
+            _ => None,
+        }
+    }
+
+    /// Hosts of the vendor's repositories.
+    pub fn vendor_hosts(self) -> &'static [&'static str] {
+        match self {
+            Derivative::Raspbian => &["raspbian.raspberrypi.org", "archive.raspbian.org", "archive.raspberrypi.org", "archive.raspberrypi.com"],
+            Derivative::Raspios => &["archive.raspberrypi.org", "archive.raspberrypi.com"],
+            Derivative::Armbian => &["apt.armbian.com", "beta.armbian.com"],
+            Derivative::Linuxmint => &["packages.linuxmint.com"],
+        }
+    }
+
+    /// Package holding the key the vendor's lists are signed with, if it
+    /// ships in a package of its own.
+    pub fn keyring(self) -> Option<&'static str> {
+        match self {
+            Derivative::Raspbian => Some("raspbian-archive-keyring"),
+            Derivative::Raspios => Some("raspberrypi-archive-keyring"),
+            Derivative::Armbian => None,
+            Derivative::Linuxmint => Some("linuxmint-keyring"),
+        }
+    }
+
+    /// Vendor hosts that moved, with their new host.
+    fn moved(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Derivative::Raspbian => &[
+                ("mirrordirector.raspbian.org", "raspbian.raspberrypi.org"),
+                ("archive.raspberrypi.org", "archive.raspberrypi.com"),
+            ],
+            Derivative::Raspios => &[("archive.raspberrypi.org", "archive.raspberrypi.com")],
+            Derivative::Armbian | Derivative::Linuxmint => &[],
+        }
+    }
+
+    /// Whether `uri` is on one of the vendor's repositories.
+    pub fn is_vendor(self, uri: &str) -> bool {
+        Url::parse(uri).is_ok_and(|url| url.host_str().is_some_and(|host| self.vendor_hosts().contains(&host)))
+    }
+
+    /// `uri` on the new host of a vendor archive that moved, if it is on
+    /// the old one.
+    ///
+    /// ```
+    /// use apt_remote::derivative::Derivative;
+    ///
+    /// let uri = "http://archive.raspberrypi.org/debian/pool/main/r/raspi-config/raspi-config_20231012_all.deb";
+    /// assert_eq!(
+    ///     Derivative::Raspios.rewrite(uri).as_deref(),
+    ///     Some("http://archive.raspberrypi.com/debian/pool/main/r/raspi-config/raspi-config_20231012_all.deb"),
+    /// );
+    /// assert_eq!(Derivative::Armbian.rewrite(uri), None);
+    /// ```
+    pub fn rewrite(self, uri: &str) -> Option<String> {
+        let mut url = Url::parse(uri).ok()?;
+        let (_, to) = self.moved().iter().find(|(from, _)| url.host_str() == Some(from))?;
+        url.set_host(Some(to)).ok()?;
+        Some(url.to_string())
+    }
+
+    /// What looks wrong with a plan resolved on `target`, given the URIs of
+    /// the plan and the Release files in the target's package lists.
+    ///
+    /// ```
+    /// use apt_remote::derivative::Derivative;
+    ///
+    /// let releases = ["raspbian.raspberrypi.org_raspbian_dists_bookworm_InRelease".to_string()];
+    /// let uris = ["http://deb.debian.org/debian/pool/main/c/curl/curl_7.88.1-10_armhf.deb"];
+    /// let warnings = Derivative::Raspbian.check_plan("pi@kiosk", false, uris, &releases);
+    /// assert_eq!(warnings.len(), 1);
+    /// assert!(warnings[0].contains("curl_7.88.1-10_armhf.deb"));
+    /// assert_eq!(Derivative::Raspbian.check_plan("pi@kiosk", true, uris, &releases).len(), 2);
+    /// ```
+    pub fn check_plan<'a>(self, target: &str, update: bool, uris: impl IntoIterator<Item = &'a str>, releases: &[String]) -> Vec<String> {
+        let uris = uris.into_iter().collect::<Vec<_>>();
+        let mut warnings = Vec::new();
+
+        // Lists are named after their URI, host first
+        let vendor_lists = releases.iter().any(|file| self.vendor_hosts().iter().any(|host| file.starts_with(&format!("{host}_"))));
+        if update && !uris.iter().any(|uri| self.is_vendor(uri)) {
+            warnings.push(tr!(
+                "No {name} repository is configured on {target}: its packages will not be updated",
+                name = self,
+                target = target
+            ));
+        } else if !update && !vendor_lists {
+            warnings.push(tr!(
+                "{target} has no package lists of {name}'s repositories, so its packages cannot be resolved: update its lists first (set --update)",
+                name = self,
+                target = target
+            ));
+        }
+
+        if self == Derivative::Raspbian {
+            let ported = uris
+                .iter()
+                .filter(|uri| uri.ends_with("_armhf.deb") && !self.is_vendor(uri))
+                .filter_map(|uri| uri.rsplit('/').next())
+                .collect::<Vec<_>>();
+            if !ported.is_empty() {
+                warnings.push(tr!(
+                    "Debian's armhf packages are built for ARMv7 and may not run on Raspbian: {files}",
+                    files = ported.join(", ")
+                ));
+            }
+        }
+        warnings
+    }
+}
+
+impl fmt::Display for Derivative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Derivative::Raspbian => "Raspbian",
+            Derivative::Raspios => "Raspberry Pi OS",
+            Derivative::Armbian => "Armbian",
+            Derivative::Linuxmint => "Linux Mint",
+        })
+    }
+}
+
+/// Print a warning about `target` running `derivative`, and emit it as a
+/// `warning` event.
+pub fn warn(derivative: Derivative, target: &str, message: &str) {
+    output::print(format!("{} {}", theme::warning("!").bold(), theme::warning(message)));
+    output::emit("warning", json!({ "target": target, "derivative": derivative, "message": message }));
+}
+
+/// The keyring packages of known derivatives installed on the target, from
+/// the output of [`QUERY`].
+pub fn installed_keyrings(vendor: &str) -> Vec<String> {
+    vendor
+        .lines()
+        .filter_map(|line| line.trim().strip_suffix(" installed"))
+        .map(str::to_string)
+        .collect()
+}
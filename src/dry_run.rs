@@ -0,0 +1,64 @@
+//! # Dry runs for apt-remote
+//!
+//! With the global `--dry-run` flag, every step that would change anything
+//! is reported instead of performed:
+//!
+//! - commands run on the remote host ([`crate::RemoteExecutor::exec`] and
+//!   `sudo`) and files uploaded to it;
+//! - files downloaded, written, copied or deleted in the local cache;
+//! - configured hooks.
+//!
+//! Read-only queries of the remote system (its architecture, the plan
+//! resolved by `apt-get --print-uris`, free disk space) still run through
+//! [`crate::RemoteExecutor::query`], so the report shows what a real run
+//! would do. Confirmation and password prompts are skipped.
+//!
+//! ```text
+//! $ apt-remote install web --target admin@kiosk --dry-run
+//! [dry-run] would run: mkdir -p /tmp/apt-remote/web
+//! [dry-run] would upload: /home/me/.cache/apt-remote/web/debs/curl_7.88.1-10_amd64.deb -> /tmp/apt-remote/web/curl_7.88.1-10_amd64.deb
+//! [dry-run] would run as root: dpkg -i /tmp/apt-remote/web/curl_7.88.1-10_amd64.deb
+//! ```
+//!
+//! In JSON mode each step is a `dry_run` event with `action` and `detail`
+//! fields.
+//!
+//! Library users whose configuration is already installed can [`force`] a
+//! dry run for the operations that follow (see [`crate::plan`]).
+
+use crate::{config, output, theme};
+
+use serde_json::json;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether a dry run is forced with [`force`], whatever the configuration.
+static FORCED: AtomicBool = AtomicBool::new(false);
+
+/// Whether this is a dry run.
+pub fn enabled() -> bool {
+    config::get().dry_run || FORCED.load(Ordering::Relaxed)
+}
+
+/// Make the following operations dry runs, or stop forcing them to be.
+pub fn force(enabled: bool) {
+    FORCED.store(enabled, Ordering::Relaxed);
+}
+
+/// Report a step that a dry run skips.
+///
+/// Returns `true` if this is a dry run and the caller must not perform the
+/// step.
+///
+/// # Arguments
+/// * `action` - What would be done (`run`, `upload`, `write`, ...).
+/// * `detail` - What it would be done to.
+pub fn skip(action: &str, detail: impl AsRef<str>) -> bool {
+    if !enabled() {
+        return false;
+    }
+    let detail = detail.as_ref();
+    output::print(format!("{} {action}: {detail}", theme::warning("[dry-run] would")));
+    output::emit("dry_run", json!({ "action": action, "detail": detail }));
+    true
+}
@@ -0,0 +1,521 @@
+//! # Typed errors for apt-remote
+//!
+//! Failures that a caller may want to handle specifically are reported as
+//! an [`Error`] at the root of the `anyhow` chain, carrying their details
+//! and a remediation hint. Library users can recover them with
+//! [`Error::find`] (or `anyhow::Error::downcast_ref`):
+//!
+//! ```no_run
+//! use apt_remote::{Error, commands::install};
+//!
+//! # fn main() {
+//! let args = install::InstallArgs { name: "web".into(), target: None, reinstall: false, verify_hashes: false };
+//! if let Err(e) = install::run(args) {
+//!     match Error::find(&e) {
+//!         Some(Error::SshAuth { target, .. }) => eprintln!("no credentials for {target}"),
+//!         Some(Error::DiskSpace { needed, available, .. }) => eprintln!("short by {}", needed - available),
+//!         _ => eprintln!("{e:#}"),
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! Every variant maps onto an [`ExitCode`]; failures without a variant are
+//! still classified with [`crate::exit::Classify`].
+
+use crate::{commands::set::format_size, exit::ExitCode, secret::Credential, tr};
+
+use std::{fmt, path::PathBuf};
+
+/// Boxed underlying cause of an [`Error`].
+pub type Source = Box<dyn std::error::Error + Send + Sync>;
+
+/// A failure with a specific cause and remediation.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// No target was given on the command line or in `config.toml`.
+    NoTarget,
+    /// The image has a plan but its files were never downloaded.
+    ImageNotDownloaded {
+        /// Cache image name.
+        name: String,
+    },
+    /// The image is locked by another apt-remote process.
+    ImageLocked {
+        /// Cache image name.
+        name: String,
+        /// Description of the process holding the lock.
+        holder: String,
+    },
+    /// A confirmation was needed but cannot be asked for.
+    ConfirmationRequired,
+    /// The user declined a confirmation prompt.
+    Declined,
+    /// A credential was needed but is not provided and cannot be asked for.
+    CredentialRequired {
+        /// The missing credential.
+        credential: Credential,
+    },
+    /// The SSH connection could not be established.
+    SshConnect {
+        /// SSH target (`user@host`).
+        target: String,
+        /// Resolution, TCP or handshake error.
+        source: Source,
+    },
+    /// The SSH server's key differs from the one recorded in `known_hosts`.
+    HostKeyChanged {
+        /// SSH target (`user@host`).
+        target: String,
+        /// The host's name in `known_hosts`.
+        host: String,
+        /// Fingerprint of the key presented.
+        fingerprint: String,
+    },
+    /// The SSH server's key is not in `known_hosts`, and may not be trusted.
+    HostKeyUnknown {
+        /// SSH target (`user@host`).
+        target: String,
+        /// The host's name in `known_hosts`.
+        host: String,
+        /// Fingerprint of the key presented.
+        fingerprint: String,
+    },
+    /// The SSH server rejected every authentication method tried.
+    SshAuth {
+        /// SSH target (`user@host`).
+        target: String,
+    },
+    /// `sudo` on the target rejected the password.
+    SudoAuth {
+        /// SSH target (`user@host`).
+        target: String,
+    },
+    /// The user may not use `sudo` on the target, whatever the password.
+    SudoDenied {
+        /// SSH target (`user@host`).
+        target: String,
+        /// Why, as `sudo` put it.
+        reason: String,
+    },
+    /// `apt-get` on the target could not resolve the requested plan.
+    PlanResolution {
+        /// SSH target (`user@host`).
+        target: String,
+        /// The `E:` lines reported by `apt-get`.
+        errors: Vec<String>,
+    },
+    /// The package indices of a suite do not satisfy the requested plan
+    /// (`apt-remote set --distro`).
+    IndexResolution {
+        /// Suite and architecture (`bookworm/arm64`).
+        suite: String,
+        /// The problems found.
+        errors: Vec<String>,
+    },
+    /// The target does not have enough free space for the upload.
+    DiskSpace {
+        /// SSH target (`user@host`).
+        target: String,
+        /// Directory that was checked.
+        path: PathBuf,
+        /// Bytes needed.
+        needed: u64,
+        /// Bytes available.
+        available: u64,
+    },
+    /// Some files of an image could not be downloaded.
+    DownloadFailed {
+        /// Cache image name.
+        name: String,
+        /// Number of files that failed.
+        failed: usize,
+        /// Number of files in the image.
+        total: usize,
+    },
+    /// Two different files of a plan have the same file name.
+    NameCollision {
+        /// The contested file name.
+        file: String,
+        /// URIs of the files.
+        uris: Vec<String>,
+    },
+    /// Cached packages are damaged or not the package they are named after.
+    InvalidPackage {
+        /// Cache image name.
+        name: String,
+        /// Names of the invalid files.
+        files: Vec<String>,
+    },
+    /// Some files could not be uploaded to the target.
+    UploadFailed {
+        /// SSH target (`user@host`).
+        target: String,
+        /// Number of files that failed.
+        failed: usize,
+    },
+    /// The target has no tool to compute a checksum kind.
+    NoChecksumTool {
+        /// SSH target (`user@host`).
+        target: String,
+        /// The checksum kind (`SHA256` or `MD5`).
+        kind: String,
+    },
+    /// Uploaded files did not match their recorded checksum.
+    ChecksumMismatch {
+        /// SSH target (`user@host`).
+        target: String,
+        /// Names of the mismatching files.
+        files: Vec<String>,
+    },
+    /// A signed bundle or image failed its signature check.
+    BadSignature {
+        /// Bundle or image directory.
+        path: PathBuf,
+        /// What did not check out.
+        reason: String,
+    },
+    /// `dpkg` failed for some packages.
+    InstallFailed {
+        /// Cache image name.
+        name: String,
+        /// SSH target (`user@host`).
+        target: String,
+        /// Number of `dpkg` errors.
+        failed: usize,
+    },
+    /// A configured hook exited unsuccessfully.
+    HookFailed {
+        /// Hook name (`pre-install`, ...).
+        hook: String,
+        /// How the hook exited.
+        status: String,
+    },
+    /// The passphrase of an encrypted image is wrong.
+    WrongPassphrase {
+        /// Cache image name.
+        name: String,
+    },
+    /// The passphrase of an encrypted SSH private key is wrong.
+    WrongKeyPassphrase {
+        /// The private key file.
+        identity: PathBuf,
+    },
+    /// A security policy from [`crate::policy`] refused the operation.
+    PolicyViolation {
+        /// Policy name (`require_https`, ...).
+        policy: &'static str,
+        /// What violates it (sources, files, ...).
+        items: Vec<String>,
+    },
+    /// The command failed on some members of an `@group` target.
+    GroupFailed {
+        /// Inventory group name.
+        group: String,
+        /// Number of members that failed.
+        failed: usize,
+        /// Number of members in the group.
+        total: usize,
+    },
+    /// Some entries of an `apply` manifest failed.
+    ApplyFailed {
+        /// Number of entries that failed.
+        failed: usize,
+        /// Number of entries attempted.
+        total: usize,
+    },
+    /// Some refreshes or pushes of a `daemon` round failed.
+    DaemonFailed {
+        /// Number of steps that failed.
+        failed: usize,
+        /// Number of steps attempted.
+        total: usize,
+    },
+}
+
+impl Error {
+    /// The typed error at the root of `error`'s chain, if any.
+    pub fn find(error: &anyhow::Error) -> Option<&Error> {
+        error.chain().find_map(|cause| cause.downcast_ref::<Error>())
+    }
+
+    /// The exit status for this failure.
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Error::NoTarget => ExitCode::Config,
+            Error::ImageNotDownloaded { .. } | Error::WrongPassphrase { .. } | Error::InvalidPackage { .. }
+            | Error::NameCollision { .. } => {
+                ExitCode::Cache
+            }
+            Error::ImageLocked { .. } => ExitCode::Locked,
+            Error::ConfirmationRequired | Error::Declined => ExitCode::Declined,
+            Error::SshConnect { .. } | Error::HostKeyChanged { .. } | Error::HostKeyUnknown { .. } => ExitCode::Connect,
+            Error::SshAuth { .. } | Error::WrongKeyPassphrase { .. } | Error::SudoAuth { .. } | Error::SudoDenied { .. } | Error::CredentialRequired { .. } => {
+                ExitCode::Auth
+            }
+            Error::PlanResolution { .. } | Error::DiskSpace { .. } | Error::NoChecksumTool { .. } => ExitCode::Remote,
+            Error::DownloadFailed { .. } => ExitCode::Download,
+            Error::UploadFailed { .. } => ExitCode::Upload,
+            Error::ChecksumMismatch { .. } | Error::BadSignature { .. } => ExitCode::Checksum,
+            Error::InstallFailed { .. } => ExitCode::Install,
+            Error::HookFailed { .. } => ExitCode::Hook,
+            Error::PolicyViolation { .. } => ExitCode::Policy,
+            Error::IndexResolution { .. } | Error::GroupFailed { .. } | Error::ApplyFailed { .. } | Error::DaemonFailed { .. } => {
+                ExitCode::Failure
+            }
+        }
+    }
+
+    /// Machine-readable name of the variant, as used in JSON output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::NoTarget => "no_target",
+            Error::ImageNotDownloaded { .. } => "image_not_downloaded",
+            Error::ImageLocked { .. } => "image_locked",
+            Error::ConfirmationRequired => "confirmation_required",
+            Error::Declined => "declined",
+            Error::CredentialRequired { .. } => "credential_required",
+            Error::SshConnect { .. } => "ssh_connect",
+            Error::HostKeyChanged { .. } => "host_key_changed",
+            Error::HostKeyUnknown { .. } => "host_key_unknown",
+            Error::SshAuth { .. } => "ssh_auth",
+            Error::SudoAuth { .. } => "sudo_auth",
+            Error::SudoDenied { .. } => "sudo_denied",
+            Error::PlanResolution { .. } => "plan_resolution",
+            Error::IndexResolution { .. } => "index_resolution",
+            Error::DiskSpace { .. } => "disk_space",
+            Error::DownloadFailed { .. } => "download_failed",
+            Error::NameCollision { .. } => "name_collision",
+            Error::InvalidPackage { .. } => "invalid_package",
+            Error::UploadFailed { .. } => "upload_failed",
+            Error::NoChecksumTool { .. } => "no_checksum_tool",
+            Error::ChecksumMismatch { .. } => "checksum_mismatch",
+            Error::BadSignature { .. } => "bad_signature",
+            Error::InstallFailed { .. } => "install_failed",
+            Error::HookFailed { .. } => "hook_failed",
+            Error::WrongPassphrase { .. } => "wrong_passphrase",
+            Error::WrongKeyPassphrase { .. } => "wrong_key_passphrase",
+            Error::PolicyViolation { .. } => "policy_violation",
+            Error::GroupFailed { .. } => "group_failed",
+            Error::ApplyFailed { .. } => "apply_failed",
+            Error::DaemonFailed { .. } => "daemon_failed",
+        }
+    }
+
+    /// What the user can do about this failure.
+    pub fn hint(&self) -> Option<String> {
+        let hint = match self {
+            Error::NoTarget => tr!("pass --target <user@host> or set `target` in config.toml"),
+            Error::ImageNotDownloaded { name } => tr!("run 'apt-remote get {name}' first", name = name),
+            Error::ImageLocked { .. } => tr!("try again later, or pass --wait to queue"),
+            Error::ConfirmationRequired => tr!("pass --yes to run non-interactively"),
+            Error::Declined => return None,
+            Error::CredentialRequired { credential } => tr!(
+                "pass {flag} <FILE> or --askpass <PROGRAM>, or set {env}",
+                flag = credential.flag(),
+                env = credential.env_var()
+            ),
+            Error::SshConnect { .. } => tr!("check the host name, that sshd is running and the `ssh.port` setting"),
+            Error::HostKeyChanged { host, .. } => tr!(
+                "if the target was reinstalled, remove its old key with 'ssh-keygen -R {host}' and connect again",
+                host = host
+            ),
+            Error::HostKeyUnknown { .. } => {
+                tr!("check the fingerprint and add the key with ssh, or set host_key_checking to ask (on a terminal) or accept-new")
+            }
+            Error::SshAuth { .. } => tr!("add a key to your SSH agent (ssh-add) or check the password"),
+            Error::SudoAuth { .. } => tr!(
+                "check the sudo password (--sudo-password-file or APT_REMOTE_SUDO_PASSWORD), and that the user may use sudo"
+            ),
+            Error::SudoDenied { .. } => {
+                tr!("ask an administrator to allow the user to run sudo, or stage the packages with --rootless")
+            }
+            Error::PlanResolution { .. } => {
+                tr!("check the package names, and that the target's package lists are current ('apt-remote update')")
+            }
+            Error::IndexResolution { .. } => {
+                tr!("check the package names, and pass --components for packages outside main")
+            }
+            Error::DiskSpace { path, .. } => tr!("free up space in {path} on the target", path = path.display()),
+            Error::DownloadFailed { name, .. } => tr!(
+                "re-run 'apt-remote get {name}' to retry, or 'apt-remote check {name}' if the mirror changed",
+                name = name
+            ),
+            Error::NameCollision { .. } => tr!(
+                "pin the package to one repository with an apt preference on the target, then run 'apt-remote set' again"
+            ),
+            Error::InvalidPackage { name, .. } => tr!(
+                "delete the listed files from the image's debs directory and run 'apt-remote get {name}' again",
+                name = name
+            ),
+            Error::UploadFailed { .. } => tr!("re-run the command: files already uploaded are not sent again"),
+            Error::NoChecksumTool { .. } => {
+                tr!("install coreutils, busybox, openssl or python3 on the target")
+            }
+            Error::ChecksumMismatch { .. } => tr!("re-run the command to upload the mismatching files again"),
+            Error::BadSignature { .. } => tr!(
+                "check that the exporting machine's public key is in `trusted_keys`, or export the bundle again"
+            ),
+            Error::InstallFailed { .. } => {
+                tr!("run with -vv to see dpkg's output, or 'dpkg --configure -a' on the target")
+            }
+            Error::HookFailed { hook, .. } => {
+                tr!("check the `{hook}` entry in the [hooks] table of config.toml", hook = hook)
+            }
+            Error::WrongPassphrase { .. } => {
+                tr!("check APT_REMOTE_PASSPHRASE, or enter the passphrase the image was encrypted with")
+            }
+            Error::WrongKeyPassphrase { .. } => {
+                tr!("check --key-passphrase-file or APT_REMOTE_KEY_PASSPHRASE, or load the key into your SSH agent (ssh-add)")
+            }
+            Error::PolicyViolation { policy: "require_https", .. } => {
+                tr!("use https:// sources (in the target's sources.list, or --url for mirror), or drop --require-https")
+            }
+            Error::PolicyViolation { policy: "require_strong_hash", .. } => tr!(
+                "use a mirror whose Packages index lists SHA256 checksums, or pass --require-strong-hash=warn"
+            ),
+            Error::PolicyViolation { policy: "require_signature", .. } => {
+                tr!("sign the bundle on export (signing_key or --sign), or drop --require-signature")
+            }
+            Error::PolicyViolation { policy: "bind_host", .. } => tr!(
+                "check the --target address, re-run 'apt-remote set' against this machine, or pass --bind-host=off"
+            ),
+            Error::PolicyViolation { policy, .. } => tr!("see the `{policy}` setting in config.toml", policy = policy),
+            Error::GroupFailed { .. } => {
+                tr!("re-run the command with --target user@host for each failed host, or for the group")
+            }
+            Error::ApplyFailed { .. } => {
+                tr!("fix the failed entries and re-run apply: packages already downloaded or installed are skipped")
+            }
+            Error::DaemonFailed { .. } => {
+                tr!("see daemon.toml in the cache directory: failed refreshes and pushes are retried in the next round")
+            }
+        };
+        Some(hint)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Error::NoTarget => tr!("No target given"),
+            Error::ImageNotDownloaded { name } => tr!("Image '{name}' has not been downloaded", name = name),
+            Error::ImageLocked { name, holder } => {
+                tr!("Image '{name}' is in use by {holder}", name = name, holder = holder)
+            }
+            Error::ConfirmationRequired => tr!("Confirmation required"),
+            Error::Declined => tr!("Aborted"),
+            Error::CredentialRequired { credential } => {
+                tr!("No {credential} was provided, and it cannot be asked for", credential = credential.label())
+            }
+            Error::SshConnect { target, .. } => tr!("Failed to connect to {target}", target = target),
+            Error::HostKeyChanged { target, fingerprint, .. } => tr!(
+                "The host key of {target} has changed ({fingerprint}): the connection may be intercepted",
+                target = target,
+                fingerprint = fingerprint
+            ),
+            Error::HostKeyUnknown { target, fingerprint, .. } => tr!(
+                "The host key of {target} is not in known_hosts ({fingerprint})",
+                target = target,
+                fingerprint = fingerprint
+            ),
+            Error::SshAuth { target } => tr!("Authentication failed for {target}", target = target),
+            Error::SudoAuth { target } => tr!("sudo rejected the password on {target}", target = target),
+            Error::SudoDenied { target, reason } => {
+                tr!("sudo is not allowed on {target}: {reason}", target = target, reason = reason)
+            }
+            Error::PlanResolution { target, errors } => tr!(
+                "apt-get could not resolve the plan on {target}: {errors}",
+                target = target,
+                errors = errors.join("; ")
+            ),
+            Error::IndexResolution { suite, errors } => tr!(
+                "The package indices of {suite} do not satisfy the plan: {errors}",
+                suite = suite,
+                errors = errors.join("; ")
+            ),
+            Error::DiskSpace { target, path, needed, available } => tr!(
+                "Not enough space in {path} on {target}: {needed} needed, {available} available",
+                path = path.display(),
+                target = target,
+                needed = format_size(*needed),
+                available = format_size(*available)
+            ),
+            Error::DownloadFailed { failed, total, .. } => {
+                tr!("{failed} of {total} packages could not be downloaded", failed = failed, total = total)
+            }
+            Error::NameCollision { file, uris } => tr!(
+                "Different files are named {file}: {uris}",
+                file = file,
+                uris = uris.join(", ")
+            ),
+            Error::InvalidPackage { name, files } => tr!(
+                "Cached packages of {name} are not valid: {files}",
+                name = name,
+                files = files.join(", ")
+            ),
+            Error::UploadFailed { target, failed } => {
+                tr!("{failed} files could not be uploaded to {target}", failed = failed, target = target)
+            }
+            Error::NoChecksumTool { target, kind } => {
+                tr!("{target} has no tool to compute {kind} checksums", target = target, kind = kind)
+            }
+            Error::ChecksumMismatch { target, files } => tr!(
+                "Remote checksum verification failed on {target}: {files}",
+                target = target,
+                files = files.join(", ")
+            ),
+            Error::BadSignature { path, reason } => tr!(
+                "Signature check failed for {path}: {reason}",
+                path = path.display(),
+                reason = reason
+            ),
+            Error::InstallFailed { name, target, failed } => tr!(
+                "Failed to install {name} on {target} ({failed} dpkg errors)",
+                name = name,
+                target = target,
+                failed = failed
+            ),
+            Error::HookFailed { hook, status } => tr!("{hook} hook failed ({status})", hook = hook, status = status),
+            Error::WrongPassphrase { name } => tr!("Wrong passphrase for image '{name}'", name = name),
+            Error::WrongKeyPassphrase { identity } => {
+                tr!("Wrong passphrase for key {path}", path = identity.display())
+            }
+            Error::PolicyViolation { policy: "require_https", items } => {
+                tr!("Plain HTTP sources are not allowed: {sources}", sources = items.join(", "))
+            }
+            Error::PolicyViolation { policy: "require_strong_hash", items } => {
+                tr!("Packages without a SHA-256 checksum are not allowed: {files}", files = items.join(", "))
+            }
+            Error::PolicyViolation { policy: "require_signature", items } => {
+                tr!("Unsigned bundles and images are not allowed: {items}", items = items.join(", "))
+            }
+            Error::PolicyViolation { policy, items } => {
+                tr!("Refused by the {policy} policy: {items}", policy = policy, items = items.join(", "))
+            }
+            Error::GroupFailed { group, failed, total } => tr!(
+                "Failed on {failed} of {total} hosts in @{group}",
+                failed = failed,
+                total = total,
+                group = group
+            ),
+            Error::ApplyFailed { failed, total } => {
+                tr!("{failed} of {total} manifest entries failed", failed = failed, total = total)
+            }
+            Error::DaemonFailed { failed, total } => {
+                tr!("{failed} of {total} refreshes and pushes failed", failed = failed, total = total)
+            }
+        };
+        f.write_str(&message)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::SshConnect { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! # Exit codes for apt-remote
+//!
+//! Every failure that aborts a command is classified so that scripts can
+//! tell failure classes apart from the process exit status alone:
+//!
+//! | Code | Meaning                                                   |
+//! |------|-----------------------------------------------------------|
+//! | 0    | Success                                                   |
+//! | 1    | Unclassified failure (e.g. local I/O)                     |
+//! | 2    | Invalid command-line usage                                |
+//! | 3    | Invalid configuration, or no target given                 |
+//! | 4    | Cache image missing, incomplete or unreadable             |
+//! | 5    | Declined at a confirmation prompt                         |
+//! | 6    | Cache image in use by another apt-remote process          |
+//! | 7    | A configured hook could not be run or failed              |
+//! | 8    | Refused by a security policy                              |
+//! | 10   | SSH connection failed (resolution, TCP or handshake)      |
+//! | 11   | SSH authentication failed, or a password is not provided  |
+//! | 12   | A command on the remote host failed                       |
+//! | 13   | Uploading files to the remote host failed                 |
+//! | 20   | Downloading files failed                                  |
+//! | 21   | Checksum or bundle signature verification failed          |
+//! | 30   | Installing packages on the remote host failed             |
+//!
+//! Typed failures ([`crate::Error`]) carry their own class; other errors
+//! are tagged where they arise with [`Classify::classify`]. The CLI looks
+//! the class up with [`ExitCode::of`] when the command fails. Context added
+//! on top of a classified error keeps its class.
+
+use crate::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+/// Process exit status for a class of failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitCode {
+    /// Unclassified failure.
+    Failure = 1,
+    /// Invalid command-line usage (reported by `clap`).
+    Usage = 2,
+    /// Invalid configuration, or no target given.
+    Config = 3,
+    /// Cache image missing, incomplete or unreadable.
+    Cache = 4,
+    /// Declined at a confirmation prompt.
+    Declined = 5,
+    /// Cache image in use by another apt-remote process.
+    Locked = 6,
+    /// A configured hook could not be run or failed.
+    Hook = 7,
+    /// Refused by a security policy.
+    Policy = 8,
+    /// SSH connection failed.
+    Connect = 10,
+    /// SSH authentication failed, or a needed password is not provided.
+    Auth = 11,
+    /// A command on the remote host failed.
+    Remote = 12,
+    /// Uploading files to the remote host failed.
+    Upload = 13,
+    /// Downloading files failed.
+    Download = 20,
+    /// Checksum or bundle signature verification failed.
+    Checksum = 21,
+    /// Installing packages on the remote host failed.
+    Install = 30,
+}
+
+impl ExitCode {
+    /// The numeric process exit status.
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// The class of an error, or [`ExitCode::Failure`] if it was never tagged.
+    ///
+    /// The outermost tag wins, so a command can reclassify a lower-level error.
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .chain()
+            .find_map(|cause| {
+                cause
+                    .downcast_ref::<Classified>()
+                    .map(|classified| classified.code)
+                    .or_else(|| cause.downcast_ref::<Error>().map(Error::exit_code))
+            })
+            .unwrap_or(ExitCode::Failure)
+    }
+}
+
+/// An error tagged with its [`ExitCode`].
+///
+/// Displays exactly like the wrapped error, so tagging never changes the
+/// message shown to the user.
+#[derive(Debug)]
+struct Classified {
+    code: ExitCode,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.error, f)
+    }
+}
+
+impl std::error::Error for Classified {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Tag errors with an [`ExitCode`].
+pub trait Classify<T> {
+    /// Tag the error, if any, with `code`.
+    fn classify(self, code: ExitCode) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> Classify<T> for Result<T, E> {
+    fn classify(self, code: ExitCode) -> anyhow::Result<T> {
+        self.map_err(|error| {
+            anyhow::Error::new(Classified {
+                code,
+                error: error.into(),
+            })
+        })
+    }
+}
@@ -0,0 +1,101 @@
+//! # Cache image locking for apt-remote
+//!
+//! Commands that read or write a cache image hold an exclusive advisory lock
+//! (`flock`) on the image's `.lock` file for as long as they run, so two
+//! invocations can never work on the same image at once. `gc` and `clear`
+//! lock every image before touching the shared cache.
+//!
+//! By default a command fails fast when the image is busy; with `--wait`
+//! (or `wait_for_lock = true` in config.toml) it queues until the other
+//! process is done. Locks are released automatically when the process
+//! exits, even if it crashes.
+
+use crate::{
+    config,
+    error::Error,
+    output, uri,
+};
+
+use anyhow::{Context, Result};
+
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::Path,
+};
+
+/// An exclusive lock on a cache image, released when dropped.
+pub struct ImageLock {
+    _file: Option<File>,
+}
+
+/// Lock the cache image stored in `dir`.
+///
+/// Images that do not exist yet are not locked, so that commands given a
+/// mistyped name do not leave empty image directories behind.
+///
+/// # Errors
+/// Returns an error if the image is locked by another process and waiting
+/// was not requested, or if the lock file cannot be opened.
+pub fn lock_image(dir: &Path) -> Result<ImageLock> {
+    if !dir.is_dir() {
+        return Ok(ImageLock { _file: None });
+    }
+
+    let path = dir.join(".lock");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            // The holder records its PID in the lock file
+            let holder = fs::read_to_string(&path)
+                .ok()
+                .map(|pid| pid.trim().to_string())
+                .filter(|pid| !pid.is_empty())
+                .map_or_else(|| "another apt-remote process".to_string(), |pid| format!("apt-remote process {pid}"));
+
+            if !config::get().wait_for_lock {
+                return Err(Error::ImageLocked { name: name.to_string(), holder }.into());
+            }
+            output::print(format!("Waiting for image '{name}' (in use by {holder})..."));
+            file.lock()
+                .with_context(|| format!("Failed to lock {}", path.display()))?;
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("Failed to lock {}", path.display()));
+        }
+    }
+
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    tracing::debug!("Locked image '{name}'");
+
+    Ok(ImageLock { _file: Some(file) })
+}
+
+/// Lock every cache image.
+///
+/// # Errors
+/// Returns an error if any image cannot be locked.
+pub fn lock_all_images() -> Result<Vec<ImageLock>> {
+    let Ok(entries) = fs::read_dir(config::cache_dir()?) else {
+        return Ok(Vec::new());
+    };
+
+    let mut locks = Vec::new();
+    for entry in entries {
+        let dir = entry?.path();
+        if uri::path_in(&dir).is_file() {
+            locks.push(lock_image(&dir)?);
+        }
+    }
+    Ok(locks)
+}
@@ -0,0 +1,169 @@
+//! # Installed-size estimates
+//!
+//! The download size of an image says little about the space it takes once
+//! installed: `.deb` files are compressed, and an upgrade replaces files
+//! that already take space. `set` records the `Installed-Size` of every
+//! planned package, from the target's `apt-cache` (or the suite's indices
+//! with `--distro`), and `install` estimates the space the image adds to the
+//! target's root filesystem: the installed size of the planned packages,
+//! less that of the versions they replace, as `dpkg-query` reports it at
+//! the time. `install` refuses to upload when the estimate and the uploaded
+//! files do not fit (see [`crate::error::Error::DiskSpace`]).
+//!
+//! The estimate ignores packages removed by the plan and files written by
+//! maintainer scripts, and `Installed-Size` is itself approximate, so leave
+//! some room.
+
+use crate::{ssh::{RemoteExecutor, shell_quote}, uri::{self, UriFile}};
+
+use anyhow::Result;
+
+use std::collections::{HashMap, HashSet};
+
+/// Shell command printing the `apt-cache` records of the packages named in
+/// `$plan`, the output of `apt-get --print-uris`.
+pub const CANDIDATES_QUERY: &str = "printf '%s\\n' \"$plan\" | sed -n \"s/^'[^ ]* \\([^_ ]*\\)_.*/\\1/p\" | sort -u \
+     | xargs -r apt-cache show 2>/dev/null | grep -E '^(Package|Version|Architecture|Installed-Size):'";
+
+/// The installed size of each package file listed in `records`, the
+/// `Package`, `Version`, `Architecture` and `Installed-Size` fields of
+/// `apt-cache show`, in bytes and by the name apt gives the file.
+///
+/// ```
+/// use apt_remote::footprint::parse_records;
+///
+/// let records = "Package: vim\nVersion: 2:9.0.1378-2\nInstalled-Size: 3778\nArchitecture: amd64\n\
+///                Package: curl\nVersion: 7.88.1-10\nInstalled-Size: 500\nArchitecture: amd64\n";
+/// let sizes = parse_records(records);
+/// assert_eq!(sizes["vim_2%3a9.0.1378-2_amd64.deb"], 3778 * 1024);
+/// assert_eq!(sizes["curl_7.88.1-10_amd64.deb"], 500 * 1024);
+/// ```
+pub fn parse_records(records: &str) -> HashMap<String, u64> {
+    let mut sizes = HashMap::new();
+    let mut record = HashMap::new();
+    let mut add = |record: &HashMap<&str, &str>| {
+        let (Some(name), Some(version), Some(arch), Some(kib)) =
+            (record.get("Package"), record.get("Version"), record.get("Architecture"), record.get("Installed-Size"))
+        else {
+            return;
+        };
+        if let Ok(kib) = kib.parse::<u64>() {
+            sizes.insert(uri::archive_file_name(&format!("{name}_{version}_{arch}.deb")), kib * 1024);
+        }
+    };
+    for line in records.lines() {
+        let Some((field, value)) = line.split_once(':') else { continue };
+        // Records start with their `Package` field
+        if field == "Package" {
+            add(&record);
+            record.clear();
+        }
+        record.insert(field, value.trim());
+    }
+    add(&record);
+    sizes
+}
+
+/// The space the packages of an image take once installed on a target.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Estimate {
+    /// Installed size of the planned packages, in bytes.
+    pub installed: u64,
+    /// Installed size of the versions they replace, in bytes.
+    pub replaced: u64,
+    /// Number of planned packages whose installed size is not known.
+    pub unknown: usize,
+}
+
+impl Estimate {
+    /// Bytes the image adds to the target.
+    pub fn growth(&self) -> u64 {
+        self.installed.saturating_sub(self.replaced)
+    }
+
+    /// Estimate the space `uri_file` takes on the target, given the output
+    /// of [`installed_query`] there.
+    ///
+    /// ```
+    /// use apt_remote::footprint::Estimate;
+    /// use apt_remote::uri::UriFile;
+    ///
+    /// let uri_file: UriFile = toml::from_str(r#"
+    ///     schema_version = 2
+    ///     mode = "Upgrade"
+    ///     arch = "amd64"
+    ///     install_order = ["curl_8.0-1_amd64.deb", "tzdata_2025a-1_all.deb"]
+    ///     [packages."curl_8.0-1_amd64.deb"]
+    ///     uri = "http://deb.debian.org/debian/pool/main/c/curl/curl_8.0-1_amd64.deb"
+    ///     size = 300000
+    ///     installed_size = 600000
+    ///     [packages."tzdata_2025a-1_all.deb"]
+    ///     uri = "http://deb.debian.org/debian/pool/main/t/tzdata/tzdata_2025a-1_all.deb"
+    ///     size = 250000
+    /// "#).unwrap();
+    /// let estimate = Estimate::from_installed(&uri_file, "curl amd64 installed 500\nlibfoo amd64 installed 9\n");
+    /// assert_eq!(estimate, Estimate { installed: 600000, replaced: 512000, unknown: 1 });
+    /// assert_eq!(estimate.growth(), 88000);
+    /// ```
+    pub fn from_installed(uri_file: &UriFile, installed: &str) -> Estimate {
+        // Installed size in bytes, by package name and architecture
+        let current = installed
+            .lines()
+            .filter_map(|line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [name, arch, "installed", kib] => Some(((name, arch), kib.parse::<u64>().ok()? * 1024)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut estimate = Estimate::default();
+        for fname in &uri_file.install_order {
+            match uri_file.packages.get(fname).and_then(|pkg| pkg.installed_size) {
+                Some(size) => estimate.installed += size,
+                None => estimate.unknown += 1,
+            }
+            if let Some(size) = name_and_arch(fname).and_then(|key| current.get(&key)) {
+                estimate.replaced += size;
+            }
+        }
+        estimate
+    }
+}
+
+/// Shell command listing the installed packages named in `uri_file`, with
+/// their architecture, status and installed size in KiB.
+pub fn installed_query(uri_file: &UriFile) -> String {
+    let names = uri_file
+        .install_order
+        .iter()
+        .filter_map(|fname| name_and_arch(fname).map(|(name, _)| name))
+        .collect::<HashSet<_>>();
+    let mut names = names.into_iter().map(shell_quote).collect::<Vec<_>>();
+    names.sort();
+    format!(
+        "dpkg-query -W -f='${{Package}} ${{Architecture}} ${{db:Status-Status}} ${{Installed-Size}}\\n' {} 2>/dev/null || true",
+        names.join(" ")
+    )
+}
+
+/// Estimate the space `uri_file` takes on the target of `session`, or
+/// `None` if the plan records no installed sizes (plans made before they
+/// were recorded, and update images).
+///
+/// # Errors
+/// Returns an error if the target cannot be queried.
+pub fn estimate(session: &dyn RemoteExecutor, uri_file: &UriFile) -> Result<Option<Estimate>> {
+    if uri_file.packages.values().all(|pkg| pkg.installed_size.is_none()) {
+        return Ok(None);
+    }
+    let installed = session.query(&installed_query(uri_file))?;
+    Ok(Some(Estimate::from_installed(uri_file, &installed)))
+}
+
+/// The package name and architecture of package file `fname`
+/// (`name_version_arch.deb`).
+fn name_and_arch(fname: &str) -> Option<(&str, &str)> {
+    let stem = fname.strip_suffix(".deb")?;
+    let (name, rest) = stem.split_once('_')?;
+    let (_, arch) = rest.rsplit_once('_')?;
+    Some((name, arch))
+}
@@ -0,0 +1,139 @@
+//! # User-defined hooks for apt-remote
+//!
+//! Hooks are shell commands configured in the `[hooks]` table of
+//! `config.toml` and run locally around every subcommand:
+//!
+//! ```toml
+//! [hooks]
+//! pre-install = "change-ticket open --host \"$APT_REMOTE_TARGET\""
+//! post-install = "change-ticket close --host \"$APT_REMOTE_TARGET\""
+//! on-failure = "notify-team \"apt-remote $APT_REMOTE_COMMAND failed: $APT_REMOTE_ERROR\""
+//! ```
+//!
+//! - `pre-<command>` runs before the command; if it fails, the command is
+//!   not run.
+//! - `post-<command>` runs after the command succeeded.
+//! - `on-failure-<command>`, then `on-failure`, run after the command
+//!   failed.
+//!
+//! Each hook is run with `sh -c` and receives its context as environment
+//! variables:
+//!
+//! | Variable                | Value                                         |
+//! |-------------------------|-----------------------------------------------|
+//! | `APT_REMOTE_COMMAND`    | Subcommand name (`get`, `install`, ...)       |
+//! | `APT_REMOTE_HOOK`       | Hook name (`pre-install`, `on-failure`, ...)  |
+//! | `APT_REMOTE_IMAGE`      | Cache image name, if the command has one      |
+//! | `APT_REMOTE_TARGET`     | SSH target, if the command has one            |
+//! | `APT_REMOTE_CACHE_DIR`  | The apt-remote cache directory                |
+//! | `APT_REMOTE_PROFILE`    | Configuration profile, if one was selected    |
+//! | `APT_REMOTE_OUTCOME`    | `pending`, `success` or `failure`             |
+//! | `APT_REMOTE_EXIT_CODE`  | Exit code of the failed command (failure)     |
+//! | `APT_REMOTE_ERROR`      | Error message of the failed command (failure) |
+//!
+//! Hook output is written to stderr so that it never mixes with
+//! `--output json` events on stdout.
+
+use crate::{
+    config, dry_run,
+    error::Error,
+    exit::{Classify, ExitCode},
+};
+
+use anyhow::{Context, Result};
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// What a hook is run for.
+pub struct HookContext {
+    /// Subcommand name.
+    pub command: &'static str,
+    /// Cache image name, if the command has one.
+    pub image: Option<String>,
+    /// SSH target, if the command has one.
+    pub target: Option<String>,
+}
+
+/// Run the `pre-<command>` hook, if configured.
+///
+/// # Errors
+/// Returns an error if the hook cannot be started or exits unsuccessfully.
+pub fn pre(ctx: &HookContext) -> Result<()> {
+    run_hook(&format!("pre-{}", ctx.command), ctx, None)
+}
+
+/// Run the `post-<command>` or failure hooks for a finished command.
+///
+/// Failures of these hooks are logged but do not change the outcome of the
+/// command.
+pub fn post(ctx: &HookContext, outcome: &Result<()>) {
+    let hooks = match outcome {
+        Ok(()) => vec![format!("post-{}", ctx.command)],
+        Err(_) => vec![format!("on-failure-{}", ctx.command), "on-failure".to_string()],
+    };
+    for hook in hooks {
+        if let Err(e) = run_hook(&hook, ctx, Some(outcome)) {
+            tracing::warn!("{e:#}");
+        }
+    }
+}
+
+/// Run the hook named `hook`, if configured.
+fn run_hook(hook: &str, ctx: &HookContext, outcome: Option<&Result<()>>) -> Result<()> {
+    let Some(script) = config::get().hooks.get(hook) else {
+        return Ok(());
+    };
+
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(script)
+        .env("APT_REMOTE_COMMAND", ctx.command)
+        .env("APT_REMOTE_HOOK", hook)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit());
+    if let Some(image) = &ctx.image {
+        command.env("APT_REMOTE_IMAGE", image);
+    }
+    if let Some(target) = &ctx.target {
+        command.env("APT_REMOTE_TARGET", target);
+    }
+    if let Ok(cache_dir) = config::cache_dir() {
+        command.env("APT_REMOTE_CACHE_DIR", cache_dir);
+    }
+    if let Some(profile) = &config::get().profile {
+        command.env("APT_REMOTE_PROFILE", profile);
+    }
+    match outcome {
+        None => {
+            command.env("APT_REMOTE_OUTCOME", "pending");
+        }
+        Some(Ok(())) => {
+            command.env("APT_REMOTE_OUTCOME", "success");
+        }
+        Some(Err(e)) => {
+            command
+                .env("APT_REMOTE_OUTCOME", "failure")
+                .env("APT_REMOTE_EXIT_CODE", ExitCode::of(e).code().to_string())
+                .env("APT_REMOTE_ERROR", format!("{e:#}"));
+        }
+    }
+
+    if dry_run::skip(&format!("run {hook} hook"), script) {
+        return Ok(());
+    }
+    tracing::info!("Running {hook} hook: {script}");
+    let output = command
+        .output()
+        .with_context(|| format!("Failed to run {hook} hook"))
+        .classify(ExitCode::Hook)?;
+    std::io::stderr().write_all(&output.stdout).ok();
+
+    if !output.status.success() {
+        return Err(Error::HookFailed { hook: hook.to_string(), status: output.status.to_string() }.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! # Localized messages for apt-remote
+//!
+//! User-facing prompts, errors and summaries are written in English and
+//! looked up in a message catalog for the user's locale before they are
+//! shown. A catalog is a TOML file mapping each English message (its
+//! *msgid*) to its translation; `{name}` placeholders are kept as they are
+//! and may be reordered:
+//!
+//! ```toml
+//! "Install {name} on {target}?" = "{name} auf {target} installieren?"
+//! "No target given" = "Kein Ziel angegeben"
+//! ```
+//!
+//! The locale is the `locale` key of `config.toml`, or else the first of
+//! `LC_ALL`, `LC_MESSAGES` and `LANG` that is set (`C` and `POSIX` mean
+//! English). For a locale such as `de_AT.UTF-8`, `de_AT.toml` and then
+//! `de.toml` are looked for in `locale_dir` (if configured),
+//! `~/.config/apt-remote/locale` and `/usr/share/apt-remote/locale`.
+//! Messages missing from the catalog, or with an empty translation, are
+//! shown in English. `locale/template.toml` in the source tree lists every
+//! translatable message.
+//!
+//! Messages are translated with the [`tr!`](crate::tr) macro:
+//!
+//! ```
+//! # use apt_remote::tr;
+//! let (name, target) = ("web", "admin@kiosk");
+//! let question = tr!("Install {name} on {target}?", name = name, target = target);
+//! # assert_eq!(question, "Install web on admin@kiosk?");
+//! ```
+
+use crate::config;
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::PathBuf,
+    sync::OnceLock,
+};
+
+/// Catalog of the user's locale, loaded on first use.
+static CATALOG: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Translate a message, with `{key}` placeholders replaced by values.
+///
+/// `tr!("...")` translates a plain message; `tr!("... {key} ...", key =
+/// value, ...)` also substitutes each placeholder with the value's
+/// `Display` output.
+#[macro_export]
+macro_rules! tr {
+    ($msgid:literal) => {
+        $crate::i18n::translate($msgid).to_string()
+    };
+    ($msgid:literal, $($key:ident = $value:expr),+ $(,)?) => {
+        $crate::i18n::substitute(
+            $crate::i18n::translate($msgid),
+            &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),+],
+        )
+    };
+}
+
+/// The user's locale, without encoding or modifier (`de_AT`), if not English.
+pub fn locale() -> Option<String> {
+    let locale = config::get().locale.clone().or_else(|| {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty())
+    })?;
+    let locale = locale.split(['.', '@']).next().unwrap_or_default();
+    match locale {
+        "" | "C" | "POSIX" => None,
+        locale => Some(locale.to_string()),
+    }
+}
+
+/// Directories searched for catalogs, in order.
+fn catalog_dirs() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    paths.extend(config::get().locale_dir.clone());
+    paths.extend(dirs::config_dir().map(|dir| dir.join("apt-remote").join("locale")));
+    paths.push(PathBuf::from("/usr/share/apt-remote/locale"));
+    paths
+}
+
+/// Load the catalog for the user's locale.
+///
+/// A catalog that cannot be parsed is reported in the log and ignored, so
+/// that a broken translation never prevents a run.
+fn load() -> HashMap<String, String> {
+    let Some(locale) = locale() else {
+        return HashMap::new();
+    };
+    let language = locale.split('_').next().unwrap_or_default();
+
+    let candidates = catalog_dirs()
+        .into_iter()
+        .flat_map(|dir| [dir.join(format!("{locale}.toml")), dir.join(format!("{language}.toml"))]);
+    for path in candidates.filter(|path| path.is_file()) {
+        let parsed = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| toml::from_str::<HashMap<String, String>>(&content).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(catalog) => {
+                tracing::debug!("Loaded message catalog {}", path.display());
+                return catalog.into_iter().filter(|(_, text)| !text.is_empty()).collect();
+            }
+            Err(e) => tracing::warn!("Ignoring message catalog {}: {e}", path.display()),
+        }
+    }
+    HashMap::new()
+}
+
+/// The translation of `msgid`, or `msgid` itself if it has none.
+pub fn translate(msgid: &'static str) -> &'static str {
+    CATALOG
+        .get_or_init(load)
+        .get(msgid)
+        .map_or(msgid, String::as_str)
+}
+
+/// Replace each `{key}` in `text` with its value.
+///
+/// Placeholders without a value are kept, and values are not searched for
+/// further placeholders.
+pub fn substitute(text: &str, args: &[(&str, &dyn Display)]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let value = placeholder
+            .find('}')
+            .and_then(|end| args.iter().find(|(key, _)| *key == &placeholder[1..end]).map(|arg| (end, arg.1)));
+        match value {
+            Some((end, value)) => {
+                result.push_str(&value.to_string());
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
@@ -0,0 +1,486 @@
+//! # Target inventory for apt-remote
+//!
+//! Fleets are easier to address by name than by `user@host`. An inventory
+//! file (`~/.config/apt-remote/inventory.toml` by default, or the
+//! `inventory` key of `config.toml`) lists hosts with their connection
+//! settings and sorts them into groups:
+//!
+//! ```toml
+//! [defaults]
+//! user = "admin"
+//!
+//! [hosts.kiosk-1]
+//! address = "10.0.0.11"
+//!
+//! [hosts.kiosk-2]
+//! address = "10.0.0.12"
+//! user = "ops"
+//! port = 2222
+//! identity = "~/.ssh/kiosk_ed25519"
+//!
+//! [hosts.kiosk-3]
+//! pull = 2203                     # dials out, see `crate::commands::listen`
+//!
+//! [hosts.plant-1]
+//! address = "192.168.50.7"
+//! jump = "admin@bastion.example.com"
+//! become_user = "maint"           # see `crate::ssh::Escalation`
+//!
+//! [hosts.router-1]
+//! address = "192.168.50.1"
+//! escalation = "doas"
+//!
+//! [groups]
+//! kiosks = ["kiosk-1", "kiosk-2", "kiosk-3"]
+//! ```
+//!
+//! `install` and `update` accept `--target @kiosks` (or `@all` for every
+//! host) and run once per member. A host listed in the inventory can also
+//! be given as `user@kiosk-1`, in which case its address, port and identity
+//! are still taken from the inventory.
+//!
+//! Any target can name its SSH port as `user@host:port` (`user@[::1]:2222`
+//! for IPv6 addresses). The port is remembered for the host in `.state/ports.toml`
+//! in the cache directory, so later commands reach it as `user@host`. An
+//! explicit port is used first, then the port of the host's inventory
+//! entry, the remembered port, and the defaults.
+//!
+//! Hosts are also looked up in the OpenSSH client configuration (see
+//! [`crate::ssh_config`]), whose `HostName`, `User`, `Port` and
+//! `IdentityFile` are used where neither the target nor the host's
+//! inventory entry set them, before the inventory's `[defaults]`. A host
+//! whose user is known this way can be given without one, as `mydevice`.
+//!
+//! Targets only reachable through a bastion are tunneled through the jump
+//! hosts of their inventory entry (`jump = "admin@bastion"`), or else their
+//! `ProxyJump`, the inventory's `[defaults]` or `--jump` (`ssh.jump`), in
+//! that order; `none` reaches a host directly. Several hops are separated
+//! by commas, and each is resolved like a target.
+//!
+//! How privileged commands become root (`become_user` and `escalation`) is
+//! taken from the host's inventory entry, or else the inventory's
+//! `[defaults]`, or `--become-user` and `--escalation` (`become_user` and
+//! `escalation` in config.toml). The first of these to set either is used
+//! as a whole, so a host with `escalation = "doas"` does not become the
+//! configured `become_user`.
+
+use crate::{
+    config,
+    dashboard::{self, Dashboard},
+    dry_run,
+    error::Error,
+    exit::{Classify, ExitCode},
+    output, report,
+    ssh::EscalationTool,
+    ssh_config, theme, transport,
+};
+
+use anyhow::{Context, Result, anyhow};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Inventory loaded on first use.
+static INVENTORY: OnceLock<Inventory> = OnceLock::new();
+
+/// Connection settings of one host.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Host {
+    /// Host name or IP address to connect to (default: the inventory name).
+    pub address: Option<String>,
+    /// SSH user name.
+    pub user: Option<String>,
+    /// TCP port of the SSH server (default: `ssh.port` in config.toml).
+    pub port: Option<u16>,
+    /// Private key file to authenticate with before trying the SSH agent.
+    pub identity: Option<PathBuf>,
+    /// Port of this machine the host forwards its SSH server to when it
+    /// dials out, for hosts that cannot be reached (see
+    /// `crate::commands::listen`).
+    pub pull: Option<u16>,
+    /// Jump hosts to reach the host through (`user@host[:port]`,
+    /// comma-separated, or `none`).
+    pub jump: Option<String>,
+    /// Account to `su -` to before running privileged commands.
+    pub become_user: Option<String>,
+    /// Tool privileged commands become root with.
+    pub escalation: Option<EscalationTool>,
+}
+
+/// Representation of the inventory file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Inventory {
+    /// Settings used for every host that does not set them.
+    pub defaults: Host,
+    /// Hosts by inventory name.
+    pub hosts: BTreeMap<String, Host>,
+    /// Groups of inventory host names.
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+/// Where and how to connect for a target.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    /// SSH user name.
+    pub user: String,
+    /// Host name or IP address.
+    pub address: String,
+    /// TCP port of the SSH server.
+    pub port: u16,
+    /// Private key file, if one is configured.
+    pub identity: Option<PathBuf>,
+    /// Whether the host is reached through the tunnel it dialed out with.
+    pub pull: bool,
+    /// Jump hosts to tunnel through, first hop first.
+    pub jump: Vec<String>,
+    /// Account to `su -` to before running privileged commands.
+    pub become_user: Option<String>,
+    /// Tool privileged commands become root with.
+    pub escalation: Option<EscalationTool>,
+}
+
+impl Inventory {
+    /// Default location of the inventory file.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("apt-remote").join("inventory.toml"))
+    }
+
+    /// Load an inventory file.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read or parsed, or if a group
+    /// names a host that is not in the inventory.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+            .classify(ExitCode::Config)?;
+        let inventory: Inventory = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse TOML from {}", path.display()))
+            .classify(ExitCode::Config)?;
+
+        for (group, members) in &inventory.groups {
+            if let Some(unknown) = members.iter().find(|host| !inventory.hosts.contains_key(*host)) {
+                return Err(anyhow!("Group '{group}' in {} lists unknown host '{unknown}'", path.display()))
+                    .classify(ExitCode::Config);
+            }
+        }
+        Ok(inventory)
+    }
+
+    /// The targets (`user@name`) of a group's members.
+    ///
+    /// # Errors
+    /// Returns an error if the group does not exist or a member has no user.
+    pub fn group(&self, group: &str) -> Result<Vec<String>> {
+        let members: Vec<&String> = match (group, self.groups.get(group)) {
+            (_, Some(members)) => members.iter().collect(),
+            ("all", None) => self.hosts.keys().collect(),
+            (_, None) => return Err(anyhow!("No group '{group}' in the inventory")).classify(ExitCode::Config),
+        };
+        members
+            .into_iter()
+            .map(|name| {
+                let user = self
+                    .user(name)?
+                    .with_context(|| format!("Host '{name}' in the inventory has no user"))
+                    .classify(ExitCode::Config)?;
+                Ok(format!("{user}@{name}"))
+            })
+            .collect()
+    }
+
+    /// Resolve a `user@host` target, applying the host's inventory entry
+    /// and OpenSSH configuration.
+    ///
+    /// # Errors
+    /// Returns an error if the target names no user and none is configured
+    /// for the host, or the OpenSSH configuration cannot be loaded.
+    pub fn endpoint(&self, target: &str) -> Result<Endpoint> {
+        let (user, name) = match target.split_once('@') {
+            Some((user, name)) => (Some(user.to_string()), name),
+            None => (None, target),
+        };
+        let (name, explicit) = split_port(name)?;
+        let user = match user {
+            Some(user) => user,
+            None => self
+                .user(name)?
+                .with_context(|| format!("Target '{target}' names no user: give it as user@host, or set a User for it"))
+                .classify(ExitCode::Config)?,
+        };
+        let host = self.hosts.get(name).cloned().unwrap_or_default();
+
+        // The most specific settings that say how to become root are used whole
+        let (become_user, escalation) = match [&host, &self.defaults]
+            .into_iter()
+            .find(|host| host.become_user.is_some() || host.escalation.is_some())
+        {
+            Some(host) => (host.become_user.clone(), host.escalation),
+            None => (config::get().become_user.clone(), config::get().escalation),
+        };
+
+        let openssh = ssh_config::get()?.host(name);
+        let jump = match host.pull {
+            Some(_) => None,
+            None => host
+                .jump
+                .or(openssh.proxy_jump)
+                .or_else(|| self.defaults.jump.clone())
+                .or_else(|| config::get().ssh.jump.clone()),
+        };
+
+        // A host that dials out is reached through the port it forwards here
+        let (address, port) = match host.pull {
+            Some(port) => ("127.0.0.1".to_string(), port),
+            None => (
+                host.address.or(openssh.host_name).unwrap_or_else(|| name.to_string()),
+                match explicit.or(host.port) {
+                    Some(port) => port,
+                    None => remembered_ports()?
+                        .get(name)
+                        .copied()
+                        .or(openssh.port)
+                        .or(self.defaults.port)
+                        .unwrap_or(config::get().ssh.port),
+                },
+            ),
+        };
+        Ok(Endpoint {
+            user,
+            address,
+            port,
+            identity: host
+                .identity
+                .map(|path| expand_home(&path))
+                .or(openssh.identity)
+                .or_else(|| self.defaults.identity.as_ref().map(|path| expand_home(path))),
+            pull: host.pull.is_some(),
+            jump: jump
+                .filter(|jump| jump != "none")
+                .map(|jump| jump.split(',').map(|hop| hop.trim().to_string()).filter(|hop| !hop.is_empty()).collect())
+                .unwrap_or_default(),
+            become_user,
+            escalation,
+        })
+    }
+
+    /// Resolve the jump host `hop` of a target reached as `user`, who is
+    /// also the hop's user if it names none and has none configured. The
+    /// hop is reached directly: jump hosts are chained by the target.
+    ///
+    /// # Errors
+    /// Returns an error if `hop` is not a valid target.
+    pub fn hop(&self, hop: &str, user: &str) -> Result<Endpoint> {
+        let mut endpoint = if !hop.contains('@') && self.user(split_port(hop)?.0)?.is_none() {
+            self.endpoint(&format!("{user}@{hop}"))?
+        } else {
+            self.endpoint(hop)?
+        };
+        endpoint.jump.clear();
+        Ok(endpoint)
+    }
+
+    /// The user of the host `name`, from its inventory entry, the OpenSSH
+    /// configuration or the inventory's defaults.
+    fn user(&self, name: &str) -> Result<Option<String>> {
+        let host = self.hosts.get(name).and_then(|host| host.user.clone());
+        Ok(match host {
+            Some(user) => Some(user),
+            None => ssh_config::get()?.host(name).user.or_else(|| self.defaults.user.clone()),
+        })
+    }
+}
+
+/// Split the port off a host given as `host:port` or `[address]:port`.
+///
+/// ```
+/// use apt_remote::inventory::split_port;
+///
+/// assert_eq!(split_port("kiosk:2222").unwrap(), ("kiosk", Some(2222)));
+/// assert_eq!(split_port("[fe80::1]:2222").unwrap(), ("fe80::1", Some(2222)));
+/// assert_eq!(split_port("fe80::1").unwrap(), ("fe80::1", None));
+/// assert_eq!(split_port("kiosk").unwrap(), ("kiosk", None));
+/// assert!(split_port("kiosk:ssh").is_err());
+/// ```
+///
+/// # Errors
+/// Returns an error if the port is not a number from 1 to 65535.
+pub fn split_port(host: &str) -> Result<(&str, Option<u16>)> {
+    let (host, port) = match host.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        Some((address, rest)) => (address, rest.strip_prefix(':')),
+        // Unbracketed IPv6 addresses have no port
+        None if host.matches(':').count() == 1 => {
+            let (host, port) = host.split_once(':').unwrap();
+            (host, Some(port))
+        }
+        None => (host, None),
+    };
+    let port = port
+        .map(|port| {
+            port.parse::<u16>()
+                .ok()
+                .filter(|port| *port > 0)
+                .with_context(|| format!("Invalid SSH port '{port}'"))
+        })
+        .transpose()
+        .classify(ExitCode::Config)?;
+    Ok((host, port))
+}
+
+/// Remember the port of a `user@host:port` (or `host:port`) target for the
+/// host, and return the target without it. Other targets, including those
+/// of other backends than SSH (see [`crate::transport`]), are returned as
+/// they are.
+///
+/// # Errors
+/// Returns an error if the port is not valid, or cannot be remembered.
+pub fn remember_port(target: &str) -> Result<String> {
+    if target.starts_with('@') || !transport::is_ssh(target) {
+        return Ok(target.to_string());
+    }
+    let (user, host) = match target.split_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, target),
+    };
+    let (name, Some(port)) = split_port(host)? else {
+        return Ok(target.to_string());
+    };
+    let mut ports = remembered_ports()?;
+    if ports.get(name) != Some(&port) && !dry_run::enabled() {
+        ports.insert(name.to_string(), port);
+        let path = ports_path()?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        let toml_str = toml::to_string(&ports).context("Failed to serialize ports to TOML")?;
+        fs::write(&path, toml_str).with_context(|| format!("Failed to write to {}", path.display()))?;
+    }
+    Ok(match user {
+        Some(user) => format!("{user}@{name}"),
+        None => name.to_string(),
+    })
+}
+
+/// Ports given for hosts in earlier commands, by host.
+fn remembered_ports() -> Result<BTreeMap<String, u16>> {
+    let path = ports_path()?;
+    if !path.is_file() {
+        return Ok(BTreeMap::new());
+    }
+    let content = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("Failed to parse TOML from {}", path.display()))
+}
+
+/// Location of the remembered ports.
+fn ports_path() -> Result<PathBuf> {
+    Ok(config::state_dir()?.join("ports.toml"))
+}
+
+/// Replace a leading `~` in `path` with the home directory.
+pub(crate) fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// The configured inventory (empty if there is no inventory file).
+///
+/// # Errors
+/// Returns an error if the inventory file exists but is invalid.
+pub fn get() -> Result<&'static Inventory> {
+    if let Some(inventory) = INVENTORY.get() {
+        return Ok(inventory);
+    }
+    let inventory = match &config::get().inventory {
+        Some(path) => Inventory::load(path)?,
+        None => match Inventory::default_path() {
+            Some(path) if path.is_file() => Inventory::load(&path)?,
+            _ => Inventory::default(),
+        },
+    };
+    Ok(INVENTORY.get_or_init(|| inventory))
+}
+
+/// Run `f` for a target, or for every member of an `@group` target.
+///
+/// Group members are processed in order; a failing member does not stop
+/// the others, and each member's outcome is reported at the end, as a
+/// matrix of the phases it went through (see [`crate::report`]), or shown
+/// on the fleet dashboard with `--ui tui` (see [`crate::dashboard`]). The
+/// result of every target is recorded for `--report`.
+///
+/// # Arguments
+/// * `target` - Command-line target, `None` for the configured default.
+/// * `f` - Operation to run for a single `user@host` target.
+///
+/// # Errors
+/// Returns the error of a single target, or [`Error::GroupFailed`] if any
+/// member of a group failed.
+pub fn for_each_target(target: Option<&String>, mut f: impl FnMut(&str) -> Result<()>) -> Result<()> {
+    let target = config::target(target)?;
+    let Some(group) = target.strip_prefix('@') else {
+        return report::track(None, &target, || f(&target)).0;
+    };
+
+    let members = get()?.group(group)?;
+    let dashboard = dashboard::enabled().then(|| Dashboard::start(group, &members));
+    let (outcomes, results): (Vec<_>, Vec<_>) = members
+        .iter()
+        .enumerate()
+        .map(|(index, member)| {
+            let Some(dashboard) = &dashboard else {
+                output::print(format!("{}", theme::accent(format!("{member}:")).bold()));
+                let (outcome, result) = report::track(Some(group), member, || f(member));
+                return ((member, outcome), result);
+            };
+            dashboard.begin(index);
+            let (outcome, result) = report::track(Some(group), member, || f(member));
+            dashboard.end(index, &outcome);
+            ((member, outcome), result)
+        })
+        .unzip();
+
+    // The dashboard already lists the outcome of every member, and hosts
+    // that went through phases are shown as a matrix of them
+    let shown = dashboard.is_some();
+    if let Some(dashboard) = dashboard {
+        dashboard.close();
+    }
+    let phased = results.iter().flat_map(|result| &result.phases).any(|phase| phase.name != report::PREPARE);
+    if phased {
+        report::matrix(group, &results);
+    } else if !shown {
+        output::print(format!("\n{}", format!("@{group}:").bold()));
+    }
+    for (member, outcome) in &outcomes {
+        if !shown && !phased {
+            match outcome {
+                Ok(()) => output::print(format!("\t{} {member}", theme::success("✓"))),
+                Err(e) => output::print(format!("\t{} {member}: {e}", theme::failure("✗"))),
+            }
+        }
+        output::emit(
+            "target",
+            json!({
+                "group": group,
+                "target": member,
+                "status": if outcome.is_ok() { "ok" } else { "failed" },
+                "error": outcome.as_ref().err().map(|e| format!("{e:#}")),
+            }),
+        );
+    }
+
+    let failed = outcomes.iter().filter(|(_, outcome)| outcome.is_err()).count();
+    if failed > 0 {
+        return Err(Error::GroupFailed { group: group.to_string(), failed, total: outcomes.len() }.into());
+    }
+    Ok(())
+}
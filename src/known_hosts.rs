@@ -0,0 +1,170 @@
+//! # Host key verification
+//!
+//! Every SSH server's key is checked against OpenSSH's `known_hosts` file
+//! (`~/.ssh/known_hosts`, or `known_hosts` in the `[ssh]` table of
+//! `config.toml`) before authenticating, as `ssh` does. How unknown keys
+//! are treated is set with `host_key_checking` in the `[ssh]` table:
+//!
+//! - `ask` (the default): the key's fingerprint is shown, and the key is
+//!   added to `known_hosts` once accepted (trust on first use). This is
+//!   asked even with `--yes` and `--dry-run`; without a terminal, with
+//!   `--batch` or JSON output, the key is refused as with `strict`.
+//! - `strict` (`--strict-host-key-checking`): only keys already in
+//!   `known_hosts` are accepted.
+//! - `accept-new`: unknown keys are added without asking, for unattended
+//!   runs.
+//! - `off`: keys are not checked.
+//!
+//! A key that differs from the one recorded for the host is refused in
+//! every mode but `off`: the host was reinstalled, or the connection is
+//! intercepted. Entries OpenSSH wrote with hashed host names are
+//! understood; `@cert-authority` and `@revoked` lines are ignored.
+
+use crate::{
+    config, dry_run,
+    error::Error,
+    output, theme, tr,
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use ssh2::{CheckResult, HashType, HostKeyType, KnownHostFileKind, Session};
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+/// How the keys of SSH servers are checked.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyChecking {
+    /// Ask before trusting an unknown key, and remember it.
+    #[default]
+    Ask,
+    /// Refuse unknown keys.
+    Strict,
+    /// Trust and remember unknown keys without asking.
+    AcceptNew,
+    /// Do not check keys.
+    Off,
+}
+
+/// Location of the `known_hosts` file.
+pub fn path() -> Option<PathBuf> {
+    config::get()
+        .ssh
+        .known_hosts
+        .clone()
+        .or_else(|| dirs::home_dir().map(|home| home.join(".ssh").join("known_hosts")))
+}
+
+/// The name of `host` in `known_hosts`: `[host]:port` unless the port is 22.
+///
+/// ```
+/// use apt_remote::known_hosts::entry_name;
+///
+/// assert_eq!(entry_name("kiosk-1", 22), "kiosk-1");
+/// assert_eq!(entry_name("10.0.0.11", 2222), "[10.0.0.11]:2222");
+/// ```
+pub fn entry_name(host: &str, port: u16) -> String {
+    if port == 22 { host.to_string() } else { format!("[{host}]:{port}") }
+}
+
+/// Check the key `session`'s server presented for `target`, reached at
+/// `host:port`, against `known_hosts`, and record it if it is new and
+/// trusted.
+///
+/// # Errors
+/// Returns [`Error::HostKeyChanged`] if the key differs from the recorded
+/// one, [`Error::HostKeyUnknown`] if an unknown key is refused, and an
+/// error if the key cannot be recorded.
+pub fn verify(session: &Session, target: &str, host: &str, port: u16) -> Result<()> {
+    let mode = config::get().ssh.host_key_checking;
+    if mode == HostKeyChecking::Off {
+        return Ok(());
+    }
+    let (key, kind) = session.host_key().context("The SSH server presented no host key")?;
+    let fingerprint = fingerprint(session);
+    let name = entry_name(host, port);
+
+    // Lines libssh2 does not understand are skipped rather than ending the file
+    let mut known = session.known_hosts()?;
+    let path = path();
+    if let Some(content) = path.as_ref().and_then(|path| fs::read_to_string(path).ok()) {
+        for line in content.lines().filter(|line| !line.trim_start().starts_with(['#', '@'])) {
+            known.read_str(line, KnownHostFileKind::OpenSSH).ok();
+        }
+    }
+    match known.check_port(host, port, key) {
+        CheckResult::Match => return Ok(()),
+        CheckResult::Mismatch => {
+            return Err(Error::HostKeyChanged { target: target.to_string(), host: name, fingerprint }.into());
+        }
+        CheckResult::NotFound | CheckResult::Failure => {}
+    }
+
+    let kind_name = key_type(kind);
+    match mode {
+        HostKeyChecking::Strict => {
+            return Err(Error::HostKeyUnknown { target: target.to_string(), host: name, fingerprint }.into());
+        }
+        HostKeyChecking::Ask if !output::can_ask() => {
+            return Err(Error::HostKeyUnknown { target: target.to_string(), host: name, fingerprint }.into());
+        }
+        HostKeyChecking::Ask => output::ask(&tr!(
+            "The authenticity of {host} can't be established.\n{kind} key fingerprint is {fingerprint}.\nTrust it and add it to known_hosts?",
+            host = name,
+            kind = kind_name.unwrap_or("unknown"),
+            fingerprint = fingerprint
+        ))?,
+        HostKeyChecking::AcceptNew | HostKeyChecking::Off => {}
+    }
+
+    // Keys of types OpenSSH cannot name are trusted for this session only
+    let (Some(path), Some(kind_name)) = (path, kind_name) else {
+        return Ok(());
+    };
+    output::print(format!(
+        "{} {}",
+        theme::warning("!").bold(),
+        theme::warning(tr!("Added the {kind} key of {host} to known_hosts", kind = kind_name, host = name))
+    ));
+    output::emit("host_key", json!({ "target": target, "host": name, "type": kind_name, "fingerprint": fingerprint }));
+    if dry_run::skip("write", path.display().to_string()) {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let line = format!("{name} {kind_name} {}\n", openssl::base64::encode_block(key));
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("Failed to add the host key of {name} to {}", path.display()))
+}
+
+/// The SHA256 fingerprint of the server key of `session`, as OpenSSH shows it.
+fn fingerprint(session: &Session) -> String {
+    let hash = session.host_key_hash(HashType::Sha256).unwrap_or_default();
+    format!("SHA256:{}", openssl::base64::encode_block(hash).trim_end_matches('='))
+}
+
+/// The OpenSSH name of a host key type.
+fn key_type(kind: HostKeyType) -> Option<&'static str> {
+    Some(match kind {
+        HostKeyType::Rsa => "ssh-rsa",
+        HostKeyType::Dss => "ssh-dss",
+        HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+        HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+        HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+        HostKeyType::Ed25519 => "ssh-ed25519",
+        HostKeyType::Unknown => return None,
+    })
+}
@@ -0,0 +1,150 @@
+//! # apt-remote
+//!
+//! `apt-remote` manages offline Debian package installation via SSH. It
+//! supports installing packages and updating source lists on a device
+//! without direct internet access.
+//!
+//! This crate is both the `apt-remote` command-line tool and a library that
+//! exposes the same operations for embedding in other tools.
+//!
+//! ## Features
+//! - Generate a `uri.toml` configuration file for package sources
+//! - Download packages and source list metadata
+//! - Install packages on a remote system over SSH
+//! - Update package lists on the remote system
+//! - Check cached images against the current mirrors
+//! - Maintain a local partial mirror of a suite
+//! - Export images for offline transfer, with an optional installer script
+//!   and a signed manifest, and import them on the offline side
+//! - Exchange signatures and bundles with apt-offline
+//! - Encrypt cached images at rest
+//! - Converge a fleet of images and targets from a manifest
+//! - Run configured multi-step pipelines
+//! - Refresh update-mode images periodically and push them to their targets
+//! - Follow runs across a fleet on a live dashboard, and keep per-host reports of them
+//! - Serve devices that dial out from behind NAT
+//! - Reach controllers that only have a serial console
+//! - Authenticate with Kerberos through the OpenSSH client
+//! - Record sessions with targets and replay them in tests (`mock` feature)
+//!
+//! ## Library usage
+//! Every subcommand lives in [`commands`] as a `run` function taking the
+//! same arguments struct the CLI parses, so a provisioning tool can drive a
+//! full workflow directly:
+//!
+//! ```no_run
+//! use apt_remote::commands::{get, install, set};
+//! use apt_remote::config::{self, Config};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! // There is no terminal to confirm destructive steps on
+//! config::init(Config { assume_yes: true, ..Config::default() });
+//!
+//! set::run(set::SetArgs {
+//!     name: "web".into(),
+//!     target: Some("admin@kiosk".into()),
+//!     install: vec!["nginx".into()],
+//!     fix: false,
+//!     update: false,
+//!     upgrade: false,
+//!     ppa: Vec::new(),
+//!     snapshot: None,
+//!     preseed: None,
+//!     distro: None,
+//!     meta: Default::default(),
+//! })?;
+//! get::run(get::GetArgs { name: "web".into(), adopt: None, changelogs: false, advisories: false })?;
+//! install::run(install::InstallArgs {
+//!     name: "web".into(),
+//!     target: Some("admin@kiosk".into()),
+//!     reinstall: false,
+//!     verify_hashes: false,
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Frontends that show an operation before running it build a plan of it
+//! instead, such as `InstallPlan::new("web").target("admin@kiosk")`, list its
+//! steps, and run it with an executor streaming its events (see [`plan`]).
+//!
+//! Progress is shown on the terminal by default; a tool with its own display
+//! can receive it instead by installing a [`progress::ProgressReporter`]
+//! with [`progress::set_reporter`].
+//!
+//! Failures that callers may want to handle, such as an SSH authentication
+//! failure or a full disk on the target, are reported as an [`Error`] in the
+//! returned `anyhow::Error`.
+//!
+//! Lower-level building blocks are exposed as well: [`UriFile`] reads and
+//! writes image metadata, and the [`RemoteExecutor`] and [`SecureUpload`]
+//! traits run commands and transfer files over an [`ssh2::Session`] created
+//! by [`create_ssh_session`]. Subcommands use them through the
+//! [`Transport`] trait, whose backend [`transport::connect`] selects from
+//! the target (`user@host` for SSH, `local://` for this machine).
+
+pub mod advisory;
+pub mod apt_auth;
+pub mod apt_config;
+pub mod ansible;
+pub mod apt_offline;
+pub mod archive;
+pub mod batch;
+pub mod changelog;
+pub mod checksum;
+pub mod commands;
+pub mod config;
+pub mod crypt;
+pub mod dashboard;
+pub mod deb;
+pub mod derivative;
+pub mod dry_run;
+pub mod error;
+pub mod exit;
+pub mod flock;
+pub mod footprint;
+pub mod hooks;
+pub mod i18n;
+pub mod inventory;
+pub mod known_hosts;
+pub mod lock;
+pub mod logging;
+pub mod manifest;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod notify;
+pub mod openssh;
+pub mod output;
+pub mod pipeline;
+pub mod plan;
+pub mod policy;
+pub mod ppa;
+pub mod preseed;
+pub mod pool;
+pub mod probe;
+pub mod progress;
+pub mod redact;
+pub mod redirect;
+pub mod reconnect;
+pub mod report;
+pub mod resolve;
+pub mod secret;
+pub mod serial;
+pub mod signing;
+pub mod snapshot;
+pub mod staging;
+pub mod ssh;
+pub mod ssh_config;
+pub mod state;
+pub mod summary;
+pub mod theme;
+pub mod throttle;
+pub mod tls;
+pub mod transport;
+pub mod uri;
+pub mod zip;
+
+pub use error::Error;
+pub use ssh::{RemoteExecutor, SecureUpload, create_ssh_session};
+pub use transport::Transport;
+pub use uri::UriFile;
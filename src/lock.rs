@@ -0,0 +1,347 @@
+//! # Lock files for apt-remote
+//!
+//! `apt-remote set` writes a `uri.lock` next to `uri.toml`. Where `uri.toml`
+//! lists what to download, the lock records how the plan was resolved on the
+//! target: the exact version of every package, the suite and archive it was
+//! taken from, and the SHA256 of every `Release`/`InRelease` file the target
+//! had at the time. Keeping the lock with an image makes it possible to
+//! rebuild the same image later, or on another workstation, and to tell
+//! whether the target's sources have changed since.
+//!
+//! The lock also records which machine the image was resolved on, so that
+//! `install` and `update` can notice when a different machine answers at
+//! the target's address (see the `bind_host` policy in [`crate::policy`]),
+//! and a snapshot of the target's package selections at the time: what was
+//! installed, held or marked for removal, and at which version. A plan
+//! pinned to a point in time of snapshot.debian.org records the snapshot's
+//! URL (see [`crate::snapshot`]).
+//!
+//! ```toml
+//! schema_version = 1
+//! arch = "amd64"
+//! host = { machine_id = "4c4c4544004d3510804cb4c04f4e3732", hostname = "kiosk" }
+//! snapshot = "https://snapshot.debian.org/archive/debian/20250601T000000Z"
+//!
+//! [[releases]]
+//! file = "deb.debian.org_debian_dists_bookworm_InRelease"
+//! sha256 = "9a5b..."
+//!
+//! [packages."curl_7.88.1-10+deb12u5_amd64.deb"]
+//! package = "curl"
+//! version = "7.88.1-10+deb12u5"
+//! archive = "http://deb.debian.org/debian"
+//! suite = "bookworm/main"
+//! sha256 = "3f7e..."
+//!
+//! [selections]
+//! "libc6:amd64" = { want = "install", version = "2.36-9+deb12u4" }
+//! "linux-image-amd64" = { want = "hold", version = "6.1.76-1" }
+//! ```
+
+use crate::{
+    dry_run,
+    ssh::RemoteExecutor,
+    uri::{self, ChecksumKind, UriFile},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// Version of the `uri.lock` format written by this release.
+pub const LOCK_VERSION: u32 = 1;
+
+/// Representation of the full `uri.lock` file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockFile {
+    /// Version of the `uri.lock` format (see [`LOCK_VERSION`]).
+    pub schema_version: u32,
+    /// The architecture the image was resolved for.
+    pub arch: String,
+    /// Every architecture the target's APT installs packages for.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub architectures: Vec<String>,
+    /// The machine the image was resolved on, if it could be identified.
+    #[serde(default)]
+    pub host: Option<HostIdentity>,
+    /// Release files present on the target when the image was resolved.
+    #[serde(default)]
+    pub releases: Vec<ReleaseHash>,
+    /// Resolved packages, keyed by file name.
+    #[serde(default)]
+    pub packages: BTreeMap<String, LockedPackage>,
+    /// Package selections on the target when the image was resolved, keyed
+    /// by package name (with the architecture for multi-arch packages).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub selections: BTreeMap<String, Selection>,
+    /// The snapshot.debian.org archive the plan was pinned to, if any (see
+    /// [`crate::snapshot`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+}
+
+/// Identity of a target machine.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostIdentity {
+    /// Contents of `/etc/machine-id` (or `/var/lib/dbus/machine-id`).
+    pub machine_id: String,
+    /// Host name, for messages.
+    pub hostname: String,
+}
+
+impl HostIdentity {
+    /// Command printing the machine ID.
+    pub const MACHINE_ID_QUERY: &str = "cat /etc/machine-id 2>/dev/null || cat /var/lib/dbus/machine-id 2>/dev/null";
+
+    /// Command printing the host name.
+    pub const HOSTNAME_QUERY: &str = "hostname 2>/dev/null || uname -n";
+
+    /// Identify the machine behind `session`.
+    ///
+    /// Returns `None` if the machine has no machine ID.
+    ///
+    /// # Errors
+    /// Returns an error if the remote commands cannot be run.
+    pub fn query(session: &dyn RemoteExecutor) -> Result<Option<Self>> {
+        let machine_id = session.query(Self::MACHINE_ID_QUERY)?;
+        if machine_id.trim().is_empty() {
+            return Ok(None);
+        }
+        let hostname = session.query(Self::HOSTNAME_QUERY)?;
+        Ok(HostIdentity::from_output(&machine_id, &hostname))
+    }
+
+    /// The identity printed by [`Self::MACHINE_ID_QUERY`] and
+    /// [`Self::HOSTNAME_QUERY`], or `None` if there is no machine ID.
+    pub fn from_output(machine_id: &str, hostname: &str) -> Option<Self> {
+        let machine_id = machine_id.trim();
+        if machine_id.is_empty() {
+            return None;
+        }
+        Some(HostIdentity { machine_id: machine_id.to_string(), hostname: hostname.trim().to_string() })
+    }
+}
+
+/// The hash of a `Release` or `InRelease` file in the target's list directory.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReleaseHash {
+    /// File name in the lists directory.
+    pub file: String,
+    /// Hex-encoded SHA256 of the file.
+    pub sha256: String,
+}
+
+/// Command printing the target's package selections, parsed by
+/// [`parse_selections`].
+pub const SELECTIONS_QUERY: &str =
+    "dpkg-query --show --showformat='${binary:Package}\\t${db:Status-Want}\\t${db:Status-Status}\\t${Version}\\n' 2>/dev/null";
+
+/// The selection state of a package on a target.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Selection {
+    /// What is wanted of the package: `install`, `hold`, `deinstall` or `purge`.
+    pub want: String,
+    /// Installed version, if the package is installed (possibly only
+    /// unpacked or half-configured).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// A package pinned to an exact version.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockedPackage {
+    /// Package name.
+    pub package: String,
+    /// Exact package version, including any epoch.
+    pub version: String,
+    /// Archive the version was published in, if known.
+    pub archive: Option<String>,
+    /// Suite and component the version was published in, if known.
+    pub suite: Option<String>,
+    /// SHA256 of the `.deb`, if the archive provided one.
+    pub sha256: Option<String>,
+}
+
+impl LockFile {
+    /// Build a lock file from a resolved plan and the target's apt state.
+    ///
+    /// # Arguments
+    /// * `uri_file` - The resolved plan.
+    /// * `policy` - Output of `apt-cache policy` for the plan's packages.
+    /// * `release_sums` - Output of a SHA-256 tool (such as `sha256sum`) over the target's Release files.
+    pub fn new(uri_file: &UriFile, policy: &str, release_sums: &str) -> Self {
+        let sources = parse_policy(policy);
+
+        let packages = uri_file
+            .install_order
+            .iter()
+            .filter_map(|fname| {
+                let (package, version) = parse_deb_name(fname)?;
+                let source = sources.get(&(package.clone(), version.clone()));
+                let sha256 = uri_file
+                    .packages
+                    .get(fname)
+                    .and_then(|pkg| pkg.checksum.as_ref())
+                    .filter(|checksum| matches!(checksum.kind, ChecksumKind::SHA256))
+                    .map(|checksum| checksum.value.clone());
+                Some((
+                    fname.clone(),
+                    LockedPackage {
+                        package,
+                        version,
+                        archive: source.map(|(archive, _)| archive.clone()),
+                        suite: source.map(|(_, suite)| suite.clone()),
+                        sha256,
+                    },
+                ))
+            })
+            .collect();
+
+        LockFile {
+            schema_version: LOCK_VERSION,
+            arch: uri_file.arch.clone(),
+            architectures: Vec::new(),
+            host: None,
+            releases: parse_release_sums(release_sums),
+            packages,
+            selections: BTreeMap::new(),
+            snapshot: None,
+        }
+    }
+
+    /// Load a `LockFile` from disk.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read or parsed, or was written
+    /// by a newer release.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.as_ref().display()))?;
+        let lock: LockFile = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse TOML from {}", path.as_ref().display()))?;
+        if lock.schema_version > LOCK_VERSION {
+            anyhow::bail!(
+                "uri.lock has schema version {}, but this apt-remote supports up to {LOCK_VERSION}",
+                lock.schema_version
+            );
+        }
+        Ok(lock)
+    }
+
+    /// Save the `LockFile` to disk as a TOML file.
+    ///
+    /// # Errors
+    /// Returns an error if serialization fails or the file cannot be written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if dry_run::skip("write", path.as_ref().display().to_string()) {
+            return Ok(());
+        }
+        let toml_str = toml::to_string(self).context("Failed to serialize LockFile to TOML")?;
+        fs::write(&path, toml_str)
+            .with_context(|| format!("Failed to write to {}", path.as_ref().display()))?;
+        Ok(())
+    }
+}
+
+/// Parse the output of [`SELECTIONS_QUERY`].
+///
+/// Packages dpkg only knows from an index, neither installed nor selected,
+/// are left out.
+///
+/// ```
+/// use apt_remote::lock::parse_selections;
+///
+/// let selections = parse_selections(
+///     "curl\tinstall\tinstalled\t7.88.1-10\n\
+///      vim\tdeinstall\tconfig-files\t2:9.0.1378-2\n\
+///      zsh\tunknown\tnot-installed\t\n",
+/// );
+/// assert_eq!(selections["curl"].version.as_deref(), Some("7.88.1-10"));
+/// assert_eq!(selections["vim"].want, "deinstall");
+/// assert_eq!(selections["vim"].version, None);
+/// assert!(!selections.contains_key("zsh"));
+/// ```
+pub fn parse_selections(output: &str) -> BTreeMap<String, Selection> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().split('\t');
+            let (package, want, status) = (fields.next()?, fields.next()?, fields.next()?);
+            if package.is_empty() || (status == "not-installed" && !matches!(want, "install" | "hold")) {
+                return None;
+            }
+            let version = fields.next().filter(|v| !matches!(status, "not-installed" | "config-files") && !v.is_empty()).map(str::to_string);
+            Some((package.to_string(), Selection { want: want.to_string(), version }))
+        })
+        .collect()
+}
+
+/// Split a `.deb` file name (`name_version_arch.deb`) into package and version.
+///
+/// apt escapes the epoch separator in file names as `%3a`.
+pub(crate) fn parse_deb_name(fname: &str) -> Option<(String, String)> {
+    let stem = fname.strip_suffix(".deb")?;
+    let mut parts = stem.split('_');
+    let package = uri::decode_file_name(parts.next()?);
+    let version = uri::decode_file_name(parts.next()?);
+    Some((package, version))
+}
+
+/// Parse `apt-cache policy` output into `(package, version) → (archive, suite)`.
+///
+/// Only the first source listed for each version is kept.
+fn parse_policy(output: &str) -> BTreeMap<(String, String), (String, String)> {
+    let mut sources = BTreeMap::new();
+    let mut package = None;
+    let mut version = None;
+
+    for line in output.lines() {
+        // Package header: "curl:" (or "libc6:i386:" for foreign architectures)
+        if !line.starts_with(' ') {
+            package = line
+                .strip_suffix(':')
+                .and_then(|name| name.split(':').next())
+                .map(str::to_string);
+            version = None;
+            continue;
+        }
+
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        match fields.as_slice() {
+            // Version line: " *** 7.88.1-10 500" or "     7.88.1-10 500"
+            ["***", v, priority] | [v, priority] if priority.parse::<i32>().is_ok() && !v.ends_with(':') => {
+                version = Some(v.to_string());
+            }
+            // Source line: "        500 http://deb.debian.org/debian bookworm/main amd64 Packages"
+            [_, archive, suite, ..] if archive.contains("://") => {
+                if let (Some(package), Some(version)) = (&package, &version) {
+                    sources
+                        .entry((package.clone(), version.clone()))
+                        .or_insert((archive.to_string(), suite.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    sources
+}
+
+/// Parse SHA-256 tool output over Release files into [`ReleaseHash`] entries.
+fn parse_release_sums(output: &str) -> Vec<ReleaseHash> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (sha256, path) = line.split_once(char::is_whitespace)?;
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let file = Path::new(path.trim()).file_name()?.to_string_lossy().to_string();
+            Some(ReleaseHash {
+                file,
+                sha256: sha256.to_string(),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,85 @@
+//! # Logging for apt-remote
+//!
+//! Diagnostics are emitted with `tracing` throughout the crate: connection
+//! and authentication steps, every remote command with its exit status, and
+//! every file fetched over HTTP. This module installs the subscriber used by
+//! the CLI:
+//!
+//! - a console layer on stderr whose level follows `-q`/`-v`/`-vv`/`-vvv`
+//!   (errors only, then warnings by default, then info, debug and trace);
+//! - an optional debug-level log file for the whole run, written either to
+//!   an explicit `--log-file` or to a new file in the configured `log_dir`.
+//!
+//! Both layers redact secrets from every line they write (see
+//! [`crate::redact`]). User-facing results still go through
+//! [`crate::output`].
+
+use crate::{redact::Redacting, theme};
+
+use anyhow::{Context, Result};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{filter::Targets, fmt, prelude::*};
+
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Map the `-q`/`-v` flags onto a console log level.
+fn console_level(verbosity: u8, quiet: bool) -> LevelFilter {
+    match (quiet, verbosity) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::WARN,
+        (false, 1) => LevelFilter::INFO,
+        (false, 2) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    }
+}
+
+/// Create a fresh per-run log file name inside `dir`.
+pub fn run_log_path(dir: &Path) -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    dir.join(format!("apt-remote-{secs}-{}.log", std::process::id()))
+}
+
+/// Install the global `tracing` subscriber.
+///
+/// # Arguments
+/// * `verbosity` - Number of `-v` flags given.
+/// * `quiet` - Whether `-q` was given.
+/// * `log_file` - File receiving a debug-level log of the run, if any.
+///
+/// # Errors
+/// Returns an error if the log file cannot be created.
+pub fn init(verbosity: u8, quiet: bool, log_file: Option<&Path>) -> Result<()> {
+    let console = fmt::layer()
+        .with_writer(|| Redacting(std::io::stderr()))
+        .with_target(false)
+        .with_ansi(theme::enabled())
+        .with_filter(Targets::new().with_target("apt_remote", console_level(verbosity, quiet)));
+
+    let file = match log_file {
+        Some(path) => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = File::create(path)
+                .with_context(|| format!("Failed to create log file {}", path.display()))?;
+            Some(
+                fmt::layer()
+                    .with_writer(Mutex::new(Redacting(file)))
+                    .with_ansi(false)
+                    .with_filter(Targets::new().with_target("apt_remote", LevelFilter::DEBUG)),
+            )
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry().with(console).with(file).try_init().ok();
+    Ok(())
+}
@@ -1,23 +1,30 @@
 //! # apt-remote
 //!
-//! `apt-remote` is a CLI tool for managing offline Debian package installation
-//! via SSH. It supports installing packages and updating source lists on a device 
-//! without direct internet access.
-//!
-//! ## Features
-//! - Generate a `uri.toml` configuration file for package sources
-//! - Download packages and source list metadata
-//! - Install packages on a remote system over SSH
-//! - Update package lists on the remote system
+//! Command-line entry point for `apt-remote`, a thin wrapper that parses
+//! arguments and dispatches to the subcommands in [`apt_remote::commands`].
 
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
+use colored::Colorize;
 
-mod commands;
-mod ssh;
-mod uri;
+use apt_remote::commands::{set, get, install, update, clear, check, mirror, export, import, gc, status, list, changelog, advisories, diff_hosts, doctor, encrypt, decrypt, convert, keygen, apply, run, daemon, listen, completions};
+use apt_remote::config::{self, ColorChoice, Config};
+use apt_remote::dashboard::UiMode;
+use apt_remote::Error;
+use apt_remote::exit::ExitCode;
+use apt_remote::hooks::{self, HookContext};
+use apt_remote::known_hosts::HostKeyChecking;
+use apt_remote::ssh::EscalationTool;
+use apt_remote::throttle;
+use apt_remote::logging;
+use apt_remote::notify;
+use apt_remote::output::{self, OutputFormat};
+use apt_remote::policy::Enforcement;
+use apt_remote::progress::ProgressMode;
+use apt_remote::redact::redact;
+use apt_remote::{report, summary, theme, tr};
 
-use commands::{set, get, install, update, clear};
+use std::{path::PathBuf, time::Instant};
 
 /// Command-line interface for the `apt-remote` application.
 ///
@@ -28,13 +35,278 @@ use commands::{set, get, install, update, clear};
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Configuration file (default: ~/.config/apt-remote/config.toml)
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Profile of the configuration file to apply
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Cache directory for images, pool and mirror
+    #[arg(long, global = true, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Number of parallel downloads
+    #[arg(short, long, global = true)]
+    jobs: Option<usize>,
+
+    /// When to colorize output
+    #[arg(long, global = true, value_enum, value_name = "WHEN")]
+    color: Option<ColorChoice>,
+
+    /// Progress display: animated bars, one line per step, or nothing
+    #[arg(long, global = true, value_enum, value_name = "MODE")]
+    progress: Option<ProgressMode>,
+
+    /// Display of @group runs: progress one host after the other, or a live table of the hosts
+    #[arg(long, global = true, value_enum, value_name = "MODE")]
+    ui: Option<UiMode>,
+
+    /// Output format: human-readable text or one JSON event per line
+    #[arg(long, global = true, value_enum, value_name = "FORMAT")]
+    output: Option<OutputFormat>,
+
+    /// Increase log verbosity on stderr (-v info, -vv debug, -vvv trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Do not ask for confirmation before destructive remote steps
+    #[arg(short, long, global = true)]
+    yes: bool,
+
+    /// Wait for an image used by another apt-remote process instead of failing
+    #[arg(long, global = true)]
+    wait: bool,
+
+    /// Never prompt: fail when a credential is not provided, answer confirmations with yes
+    #[arg(long, global = true)]
+    batch: bool,
+
+    /// Stage packages and lists in the target user's home for an administrator, without sudo
+    #[arg(long, global = true)]
+    rootless: bool,
+
+    /// Read the SSH password from the first line of a file (- for stdin)
+    #[arg(long, global = true, value_name = "PATH")]
+    ssh_password_file: Option<PathBuf>,
+
+    /// Read the sudo password from the first line of a file (- for stdin)
+    #[arg(long, global = true, value_name = "PATH")]
+    sudo_password_file: Option<PathBuf>,
+
+    /// Run this program for credentials that are not otherwise provided, like SUDO_ASKPASS
+    #[arg(long, global = true, value_name = "PROGRAM")]
+    askpass: Option<PathBuf>,
+
+    /// Private key to authenticate SSH targets with, after the inventory's and before the agent
+    #[arg(long, global = true, value_name = "PATH")]
+    identity: Option<PathBuf>,
+
+    /// Reach SSH targets through these jump hosts (user@host[:port], comma-separated)
+    #[arg(long, global = true, value_name = "user@host")]
+    jump: Option<String>,
+
+    /// Compress SSH traffic with zlib, for slow links
+    #[arg(long, global = true)]
+    compress: bool,
+
+    /// Keep the connection to SSH targets open this many seconds after the run, for the next one
+    #[arg(long, global = true, value_name = "SECONDS")]
+    persist: Option<u64>,
+
+    /// Upload to SSH targets at most this fast, in bytes per second (500k, 2M)
+    #[arg(long, global = true, value_name = "RATE", value_parser = throttle::parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Refuse SSH servers whose key is not already in known_hosts
+    #[arg(long, global = true)]
+    strict_host_key_checking: bool,
+
+    /// Reach SSH targets through the OpenSSH client with GSSAPI (Kerberos) authentication
+    #[arg(long, global = true)]
+    gssapi: bool,
+
+    /// Read the passphrase of the SSH private key from the first line of a file (- for stdin)
+    #[arg(long, global = true, value_name = "PATH")]
+    key_passphrase_file: Option<PathBuf>,
+
+    /// Run privileged steps on SSH targets as this account, through `su -` (and its sudo unless it is root)
+    #[arg(long, global = true, value_name = "USER")]
+    become_user: Option<String>,
+
+    /// Become root on SSH targets with this tool (default: sudo, else doas, else su)
+    #[arg(long, global = true, value_name = "TOOL")]
+    escalation: Option<EscalationTool>,
+
+    /// Read the passphrase of encrypted images from the first line of a file (- for stdin)
+    #[arg(long, global = true, value_name = "PATH")]
+    passphrase_file: Option<PathBuf>,
+
+    /// Refuse to record or download package URIs that are not https://
+    #[arg(long, global = true)]
+    require_https: bool,
+
+    /// Refuse (deny, the default) or warn about packages without a SHA-256 checksum
+    #[arg(long, global = true, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "deny")]
+    require_strong_hash: Option<Enforcement>,
+
+    /// Refuse (deny), warn about (warn, the default) or allow (off) targets other than the machine an image was set for
+    #[arg(long, global = true, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "deny")]
+    bind_host: Option<Enforcement>,
+
+    /// Refuse to import, install or update images whose bundle was not signed
+    #[arg(long, global = true)]
+    require_signature: bool,
+
+    /// Trust the CA certificates in this PEM file for HTTPS mirrors
+    #[arg(long, global = true, value_name = "PATH")]
+    ca_bundle: Option<PathBuf>,
+
+    /// Present this PEM client certificate to HTTPS mirrors
+    #[arg(long, global = true, value_name = "PATH")]
+    client_cert: Option<PathBuf>,
+
+    /// Private key of the client certificate (default: read from --client-cert)
+    #[arg(long, global = true, value_name = "PATH")]
+    client_key: Option<PathBuf>,
+
+    /// Read credentials of authenticated repositories from this auth.conf file
+    #[arg(long, global = true, value_name = "PATH")]
+    apt_auth: Option<PathBuf>,
+
+    /// Print every remote command and file change instead of performing it
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Write a debug log of this run, including every remote command, to a file
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Write the phases and result of every target to a report file (CSV if it ends in .csv, JSON otherwise)
+    #[arg(long, global = true, value_name = "PATH")]
+    report: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Merge command-line flags over the loaded configuration file.
+    fn apply(&self, mut config: Config) -> Config {
+        if let Some(cache_dir) = &self.cache_dir {
+            config.cache_dir = Some(cache_dir.clone());
+        }
+        if let Some(jobs) = self.jobs {
+            config.jobs = Some(jobs);
+        }
+        if let Some(color) = self.color {
+            config.color = color;
+        }
+        if let Some(progress) = self.progress {
+            config.progress = progress;
+        }
+        if let Some(ui) = self.ui {
+            config.ui = ui;
+        }
+        if let Some(output) = self.output {
+            config.output = output;
+        }
+        if self.quiet {
+            config.quiet = true;
+        }
+        if self.yes {
+            config.assume_yes = true;
+        }
+        if self.wait {
+            config.wait_for_lock = true;
+        }
+        if self.batch {
+            config.batch = true;
+        }
+        if self.rootless {
+            config.rootless = true;
+        }
+        if let Some(path) = &self.ssh_password_file {
+            config.ssh_password_file = Some(path.clone());
+        }
+        if let Some(path) = &self.sudo_password_file {
+            config.sudo_password_file = Some(path.clone());
+        }
+        if let Some(program) = &self.askpass {
+            config.askpass = Some(program.clone());
+        }
+        if let Some(path) = &self.identity {
+            config.ssh.identity = Some(path.clone());
+        }
+        if let Some(jump) = &self.jump {
+            config.ssh.jump = Some(jump.clone());
+        }
+        if self.compress {
+            config.ssh.compress = true;
+        }
+        if let Some(seconds) = self.persist {
+            config.ssh.persist = Some(seconds);
+        }
+        if let Some(rate) = self.limit_rate {
+            config.ssh.limit_rate = Some(rate);
+        }
+        if self.strict_host_key_checking {
+            config.ssh.host_key_checking = HostKeyChecking::Strict;
+        }
+        if self.gssapi {
+            config.ssh.gssapi = true;
+        }
+        if let Some(path) = &self.key_passphrase_file {
+            config.key_passphrase_file = Some(path.clone());
+        }
+        if let Some(user) = &self.become_user {
+            config.become_user = Some(user.clone());
+        }
+        if let Some(tool) = self.escalation {
+            config.escalation = Some(tool);
+        }
+        if let Some(path) = &self.passphrase_file {
+            config.passphrase_file = Some(path.clone());
+        }
+        if self.require_https {
+            config.require_https = true;
+        }
+        if self.require_signature {
+            config.require_signature = true;
+        }
+        if let Some(enforcement) = self.require_strong_hash {
+            config.require_strong_hash = enforcement;
+        }
+        if let Some(enforcement) = self.bind_host {
+            config.bind_host = enforcement;
+        }
+        if let Some(path) = &self.ca_bundle {
+            config.tls.ca_bundle = Some(path.clone());
+        }
+        if let Some(path) = &self.client_cert {
+            config.tls.client_cert = Some(path.clone());
+        }
+        if let Some(path) = &self.client_key {
+            config.tls.client_key = Some(path.clone());
+        }
+        if let Some(path) = &self.apt_auth {
+            config.apt_auth = Some(path.clone());
+        }
+        if self.dry_run {
+            config.dry_run = true;
+        }
+        config
+    }
 }
 
 /// Available subcommands for `apt-remote`.
 #[derive(Subcommand)]
 enum Commands {
     /// Generate uri.toml file
-    Set(set::SetArgs),
+    Set(Box<set::SetArgs>),
 
     /// Download package files and metadata according to uri.toml file
     Get(get::GetArgs),
@@ -47,21 +319,199 @@ enum Commands {
 
     /// Clear all local cache (uri and deb files stored at $HOME/.cache/apt-remote)
     Clear,
+
+    /// Check whether a cached image has been superseded upstream
+    Check(check::CheckArgs),
+
+    /// Maintain a partial mirror of a suite in the local cache
+    Mirror(mirror::MirrorArgs),
+
+    /// Copy a downloaded image to a directory, optionally with an installer script
+    Export(export::ExportArgs),
+
+    /// Copy an exported bundle into the cache, checking its signature
+    Import(import::ImportArgs),
+
+    /// Remove shared pool packages no longer referenced by any image
+    Gc(gc::GcArgs),
+
+    /// Show what has been downloaded and installed for an image
+    Status(status::StatusArgs),
+
+    /// List the cached images with their description and tags
+    List(list::ListArgs),
+
+    /// Show the changelog of a package of an image, fetched by `get --changelogs`
+    Changelog(changelog::ChangelogArgs),
+
+    /// Report the known vulnerabilities an image fixes, from `get --advisories`
+    Advisories(advisories::AdvisoriesArgs),
+
+    /// Compare the installed packages of two targets
+    DiffHosts(diff_hosts::DiffHostsArgs),
+
+    /// Probe a target and report whether it has what apt-remote needs
+    Doctor(doctor::DoctorArgs),
+
+    /// Encrypt a cached image at rest
+    Encrypt(encrypt::EncryptArgs),
+
+    /// Decrypt a cached image and turn its encryption off
+    Decrypt(decrypt::DecryptArgs),
+
+    /// Store an image's metadata as uri.toml or as compact uri.json
+    Convert(convert::ConvertArgs),
+
+    /// Create a key pair for signing exported bundles
+    Keygen(keygen::KeygenArgs),
+
+    /// Converge the images and targets listed in a manifest file
+    Apply(apply::ApplyArgs),
+
+    /// Run a pipeline of steps defined in config.toml
+    Run(run::RunArgs),
+
+    /// Refresh update-mode images periodically and push them to their targets
+    Daemon(daemon::DaemonArgs),
+
+    /// Wait for devices that dial in and push an image to them
+    Listen(listen::ListenArgs),
+
+    /// Print a shell completion script that completes images and targets
+    Completions(completions::CompletionsArgs),
+
+    /// Answer a completion query of the completion script
+    #[command(name = "__complete", hide = true)]
+    Complete(completions::CompleteArgs),
+}
+
+impl Commands {
+    /// The context passed to hooks around this command.
+    fn hook_context(&self) -> HookContext {
+        let (command, image, target) = match self {
+            Commands::Set(args) => ("set", Some(&args.name), Some(&args.target)),
+            Commands::Get(args) => ("get", Some(&args.name), None),
+            Commands::Install(args) => ("install", Some(&args.name), Some(&args.target)),
+            Commands::Update(args) => ("update", Some(&args.name), Some(&args.target)),
+            Commands::Clear => ("clear", None, None),
+            Commands::Check(args) => ("check", Some(&args.name), None),
+            Commands::Mirror(_) => ("mirror", None, None),
+            Commands::Export(args) => ("export", Some(&args.name), None),
+            Commands::Import(args) => ("import", args.name.as_ref(), None),
+            Commands::Gc(_) => ("gc", None, None),
+            Commands::Status(args) => ("status", Some(&args.name), None),
+            Commands::List(_) => ("list", None, None),
+            Commands::Changelog(args) => ("changelog", Some(&args.name), None),
+            Commands::Advisories(args) => ("advisories", Some(&args.name), None),
+            Commands::DiffHosts(_) => ("diff-hosts", None, None),
+            Commands::Doctor(args) => ("doctor", None, Some(&args.target)),
+            Commands::Encrypt(args) => ("encrypt", Some(&args.name), None),
+            Commands::Decrypt(args) => ("decrypt", Some(&args.name), None),
+            Commands::Convert(args) => ("convert", Some(&args.name), None),
+            Commands::Keygen(_) => ("keygen", None, None),
+            Commands::Apply(_) => ("apply", None, None),
+            Commands::Run(args) => ("run", None, Some(&args.target)),
+            Commands::Daemon(_) => ("daemon", None, None),
+            Commands::Listen(args) => ("listen", Some(&args.name), Some(&args.target)),
+            Commands::Completions(_) => ("completions", None, None),
+            Commands::Complete(_) => ("__complete", None, None),
+        };
+        HookContext {
+            command,
+            image: image.cloned(),
+            // Commands with a target fall back to the configured default
+            target: target.and_then(|target| config::target(target.as_ref()).ok()),
+        }
+    }
 }
 
 /// Entry point for the `apt-remote` CLI application.
 ///
-/// Parses command-line arguments, executes the appropriate subcommand
-
-fn main() -> Result<()> {
+/// Parses command-line arguments, loads the configuration file with the
+/// command-line overrides applied, and executes the appropriate subcommand.
+/// Failures exit with the status of their class (see [`apt_remote::exit`]).
+fn main() {
     let cli = Cli::parse();
+    if let Err(e) = start(cli) {
+        tracing::debug!("Command failed: {e:#}");
+        if output::is_json() {
+            output::emit_error(&e);
+        } else {
+            eprintln!("{} {}", tr!("Error:"), redact(&format!("{e:?}")));
+            if let Some(hint) = Error::find(&e).and_then(Error::hint) {
+                eprintln!("\n{} {hint}", theme::accent(tr!("hint:")).bold());
+            }
+        }
+        std::process::exit(ExitCode::of(&e).code());
+    }
+}
+
+/// Installs the configuration and logging, then runs the subcommand and
+/// prints its summary.
+fn start(cli: Cli) -> Result<()> {
+    config::init(cli.apply(Config::load_profile(cli.config.as_deref(), cli.profile.as_deref())?));
+
+    // Completion queries are answered without logs, hooks or a summary
+    if let Commands::Complete(args) = cli.command {
+        return completions::complete(Cli::command(), args);
+    }
+
+    // Log to an explicit file, or to a per-run file in the configured log_dir
+    let log_file = cli
+        .log_file
+        .clone()
+        .or_else(|| config::get().log_dir.as_deref().map(logging::run_log_path));
+    logging::init(cli.verbose, cli.quiet, log_file.as_deref())?;
+
+    // Run the subcommand between its configured hooks
+    let hook_context = cli.command.hook_context();
+    hooks::pre(&hook_context)?;
+    let start = Instant::now();
+    let outcome = run(cli.command);
+    let elapsed = start.elapsed();
+    summary::report(elapsed);
+    hooks::post(&hook_context, &outcome);
+    notify::send(&hook_context, &outcome, &summary::Totals::now(elapsed));
+
+    // A report that cannot be written fails an otherwise successful run
+    match &cli.report {
+        Some(path) => {
+            let written = report::write(path, &hook_context, &outcome);
+            outcome.and(written)
+        }
+        None => outcome,
+    }
+}
 
-    match cli.command {
-        Commands::Set(args) => set::run(args)?,
+/// Executes the selected subcommand.
+fn run(command: Commands) -> Result<()> {
+    match command {
+        Commands::Set(args) => set::run(*args)?,
         Commands::Get(args) => get::run(args)?,
         Commands::Install(args) => install::run(args)?,
         Commands::Update(args) => update::run(args)?,
         Commands::Clear => clear::run()?,
+        Commands::Check(args) => check::run(args)?,
+        Commands::Mirror(args) => mirror::run(args)?,
+        Commands::Export(args) => export::run(args)?,
+        Commands::Import(args) => import::run(args)?,
+        Commands::Gc(args) => gc::run(args)?,
+        Commands::Status(args) => status::run(args)?,
+        Commands::List(args) => list::run(args)?,
+        Commands::Changelog(args) => changelog::run(args)?,
+        Commands::Advisories(args) => advisories::run(args)?,
+        Commands::DiffHosts(args) => diff_hosts::run(args)?,
+        Commands::Doctor(args) => doctor::run(args)?,
+        Commands::Encrypt(args) => encrypt::run(args)?,
+        Commands::Decrypt(args) => decrypt::run(args)?,
+        Commands::Convert(args) => convert::run(args)?,
+        Commands::Keygen(args) => keygen::run(args)?,
+        Commands::Apply(args) => apply::run(args)?,
+        Commands::Run(args) => run::run(args)?,
+        Commands::Daemon(args) => daemon::run(args)?,
+        Commands::Listen(args) => listen::run(args)?,
+        Commands::Completions(args) => completions::run(args)?,
+        Commands::Complete(args) => completions::complete(Cli::command(), args)?,
     }
 
     Ok(())
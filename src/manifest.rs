@@ -0,0 +1,95 @@
+//! # Fleet manifests for apt-remote
+//!
+//! A manifest declares a set of images, the packages each one holds and
+//! the targets it belongs on, so that `apt-remote apply` can converge a
+//! whole fleet in one run:
+//!
+//! ```toml
+//! [images.web]
+//! install = ["nginx", "curl"]
+//! targets = ["admin@kiosk-1", "admin@kiosk-2"]
+//!
+//! [images.lists]
+//! update = true
+//! targets = ["admin@kiosk-1", "admin@kiosk-2"]
+//!
+//! [images.security]
+//! upgrade = true
+//! resolve_on = "admin@kiosk-1"    # default: the first target
+//! targets = ["admin@kiosk-1"]
+//! ```
+//!
+//! Each image uses exactly one of `install`, `fix`, `update` or `upgrade`,
+//! like `apt-remote set`.
+
+use crate::exit::{Classify, ExitCode};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// Representation of a manifest file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Manifest {
+    /// Images to converge, keyed by cache image name.
+    pub images: BTreeMap<String, ImageSpec>,
+}
+
+/// Desired contents and placement of one image.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageSpec {
+    /// Packages to install.
+    pub install: Vec<String>,
+    /// Resolve the packages needed to fix broken dependencies.
+    pub fix: bool,
+    /// Hold up-to-date package lists.
+    pub update: bool,
+    /// Hold the upgradable packages.
+    pub upgrade: bool,
+    /// Target whose apt resolves the plan (the first target by default).
+    pub resolve_on: Option<String>,
+    /// Targets the image is installed on, in `user@host` format.
+    pub targets: Vec<String>,
+}
+
+impl Manifest {
+    /// Load and validate a manifest file.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read or parsed, or if an image
+    /// does not select exactly one mode or has no target.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+            .classify(ExitCode::Config)?;
+        let manifest: Manifest = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse TOML from {}", path.display()))
+            .classify(ExitCode::Config)?;
+
+        for (name, spec) in &manifest.images {
+            let modes = [!spec.install.is_empty(), spec.fix, spec.update, spec.upgrade];
+            if modes.iter().filter(|&&mode| mode).count() != 1 {
+                return Err(anyhow::anyhow!(
+                    "Image '{name}' in {} must set exactly one of install, fix, update or upgrade",
+                    path.display()
+                ))
+                .classify(ExitCode::Config);
+            }
+            if spec.resolve_target().is_none() {
+                return Err(anyhow::anyhow!("Image '{name}' in {} has no targets", path.display()))
+                    .classify(ExitCode::Config);
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+impl ImageSpec {
+    /// The target used to resolve the plan.
+    pub fn resolve_target(&self) -> Option<&String> {
+        self.resolve_on.as_ref().or(self.targets.first())
+    }
+}
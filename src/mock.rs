@@ -0,0 +1,422 @@
+//! # Recorded sessions for testing
+//!
+//! Trying a change against real devices is slow, and a mistake can leave
+//! one broken. With the `mock` feature, a session with a target can be
+//! recorded once and replayed any number of times, without network or
+//! hardware:
+//!
+//! ```text
+//! $ cargo build --features mock
+//! $ APT_REMOTE_RECORD=kiosk.json apt-remote install web --target admin@kiosk
+//! $ apt-remote install web --target mock:kiosk.json
+//! ```
+//!
+//! While `APT_REMOTE_RECORD` names a file, every command run on the target
+//! and every upload to it is written to that file as it happens, with the
+//! output the command returned (see [`Recorder`]). Passwords are not
+//! recorded, and output is [redacted](crate::redact) like the log. The
+//! target `mock:<file>` then plays the recording back ([`MockTransport`]):
+//! each command must be the next one recorded, and gets its recorded output
+//! or error; uploads are checked against their recorded destination and
+//! not performed. A run that goes a different way than the recorded one
+//! fails with the first step that differs, which is what a test of `set`,
+//! `get`, `install` or `update` logic looks for.
+//!
+//! Tests can also script a session directly:
+//!
+//! ```
+//! use apt_remote::mock::{Interaction, MockTransport};
+//! use apt_remote::RemoteExecutor;
+//!
+//! let target = MockTransport::new(vec![Interaction::query("dpkg --print-architecture", "arm64\n")]);
+//! assert_eq!(target.query("dpkg --print-architecture")?, "arm64\n");
+//! assert!(target.query("uname -m").is_err());
+//! target.finish()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use crate::{
+    dry_run,
+    exit::{Classify, ExitCode},
+    redact,
+    ssh::{RemoteExecutor, SudoCheck},
+    transport::Transport,
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Environment variable naming the file sessions are recorded to.
+pub const RECORD_VAR: &str = "APT_REMOTE_RECORD";
+
+/// A recorded session with a target.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    /// The target the session was recorded with.
+    pub target: String,
+    /// Whether commands run as root needed `sudo` on the target.
+    pub needs_sudo: bool,
+    /// Whether privileged commands could switch accounts with `su`.
+    #[serde(default)]
+    pub supports_su: bool,
+    /// Every step of the session, in order.
+    pub interactions: Vec<Interaction>,
+}
+
+/// One step of a recorded session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Interaction {
+    /// A command run with [`RemoteExecutor::exec`].
+    Exec {
+        /// The command.
+        command: String,
+        /// What it returned.
+        #[serde(flatten)]
+        reply: Reply,
+    },
+    /// A read-only command run with [`RemoteExecutor::query`].
+    Query {
+        /// The command.
+        command: String,
+        /// What it returned.
+        #[serde(flatten)]
+        reply: Reply,
+    },
+    /// A command run as root with [`RemoteExecutor::sudo`].
+    Sudo {
+        /// The command.
+        command: String,
+        /// What it returned.
+        #[serde(flatten)]
+        reply: Reply,
+    },
+    /// A check of the sudo password with [`RemoteExecutor::sudo_check`].
+    SudoCheck {
+        /// Whether a password was given, rather than none.
+        password: bool,
+        /// What `sudo` made of it.
+        outcome: SudoCheck,
+    },
+    /// A file or directory uploaded with [`Transport::upload`].
+    Upload {
+        /// Where it was uploaded to.
+        to: PathBuf,
+    },
+}
+
+/// What a recorded command returned.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reply {
+    /// Its output.
+    #[serde(default)]
+    pub output: String,
+    /// Its error, if it failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RecordedError>,
+}
+
+/// A recorded failure.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedError {
+    /// The error message.
+    pub message: String,
+    /// Its class.
+    pub code: ExitCode,
+}
+
+impl Interaction {
+    /// A read-only command returning `output`.
+    pub fn query(command: &str, output: &str) -> Interaction {
+        Interaction::Query { command: command.to_string(), reply: Reply::output(output) }
+    }
+
+    /// A command returning `output`.
+    pub fn exec(command: &str, output: &str) -> Interaction {
+        Interaction::Exec { command: command.to_string(), reply: Reply::output(output) }
+    }
+
+    /// A command run as root returning `output`.
+    pub fn sudo(command: &str, output: &str) -> Interaction {
+        Interaction::Sudo { command: command.to_string(), reply: Reply::output(output) }
+    }
+
+    /// The step as shown in messages.
+    fn describe(&self) -> String {
+        match self {
+            Interaction::Exec { command, .. } => format!("run `{command}`"),
+            Interaction::Query { command, .. } => format!("query `{command}`"),
+            Interaction::Sudo { command, .. } => format!("run as root `{command}`"),
+            Interaction::SudoCheck { password: true, .. } => "check the sudo password".to_string(),
+            Interaction::SudoCheck { password: false, .. } => "check whether sudo needs a password".to_string(),
+            Interaction::Upload { to } => format!("upload to {}", to.display()),
+        }
+    }
+}
+
+impl Reply {
+    /// A successful reply with `output`.
+    pub fn output(output: &str) -> Reply {
+        Reply { output: output.to_string(), error: None }
+    }
+
+    /// The reply to a command that returned `result`.
+    fn of(result: &Result<String>) -> Reply {
+        match result {
+            Ok(output) => Reply::output(&redact::redact(output)),
+            Err(e) => Reply {
+                output: String::new(),
+                error: Some(RecordedError { message: redact::redact(&format!("{e:#}")).into_owned(), code: ExitCode::of(e) }),
+            },
+        }
+    }
+
+    /// What the recorded command returned.
+    fn result(self) -> Result<String> {
+        match self.error {
+            None => Ok(self.output),
+            Some(error) => Err(anyhow!(error.message)).classify(error.code),
+        }
+    }
+}
+
+/// Plays a recorded session back as a target.
+///
+/// Every step must be the next one recorded; see the [module
+/// documentation](self).
+pub struct MockTransport {
+    /// Where the session was read from, for messages.
+    source: String,
+    /// Whether commands run as root need `sudo`.
+    needs_sudo: bool,
+    /// Whether privileged commands can switch accounts with `su`.
+    supports_su: bool,
+    /// The steps not played back yet.
+    remaining: Mutex<VecDeque<Interaction>>,
+}
+
+impl MockTransport {
+    /// A target playing back `interactions`, needing `sudo` as SSH targets do.
+    pub fn new(interactions: Vec<Interaction>) -> MockTransport {
+        MockTransport::from_cassette(
+            "the scripted session",
+            Cassette { target: String::new(), needs_sudo: true, supports_su: false, interactions },
+        )
+    }
+
+    /// A target playing back the session recorded in `path`.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read or is not a recorded
+    /// session.
+    pub fn replay(path: &Path) -> Result<MockTransport> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read recorded session {}", path.display()))
+            .classify(ExitCode::Connect)?;
+        let cassette: Cassette = serde_json::from_str(&content)
+            .with_context(|| format!("{} is not a recorded session", path.display()))
+            .classify(ExitCode::Connect)?;
+        Ok(MockTransport::from_cassette(&path.display().to_string(), cassette))
+    }
+
+    fn from_cassette(source: &str, cassette: Cassette) -> MockTransport {
+        MockTransport {
+            source: source.to_string(),
+            needs_sudo: cassette.needs_sudo,
+            supports_su: cassette.supports_su,
+            remaining: Mutex::new(cassette.interactions.into()),
+        }
+    }
+
+    /// Check that the whole session was played back.
+    ///
+    /// # Errors
+    /// Returns an error naming the first step that was not.
+    pub fn finish(&self) -> Result<()> {
+        match self.remaining.lock().unwrap().front() {
+            Some(next) => Err(anyhow!("{} expected the run to {} next", self.source, next.describe()))
+                .classify(ExitCode::Remote),
+            None => Ok(()),
+        }
+    }
+
+    /// Play back the next step, which must be `step` whatever it returned.
+    fn next(&self, step: Interaction) -> Result<Interaction> {
+        let mut remaining = self.remaining.lock().unwrap();
+        let Some(next) = remaining.pop_front() else {
+            return Err(anyhow!("{} has no step left to {}", self.source, step.describe())).classify(ExitCode::Remote);
+        };
+        if next.describe() != step.describe() {
+            let message = format!("{} expected the run to {}, not to {}", self.source, next.describe(), step.describe());
+            remaining.push_front(next);
+            return Err(anyhow!(message)).classify(ExitCode::Remote);
+        }
+        Ok(next)
+    }
+
+    /// Play back a command of the kind `kind` makes.
+    fn command(&self, kind: fn(&str, &str) -> Interaction, cmd: &str) -> Result<String> {
+        match self.next(kind(cmd, ""))? {
+            Interaction::Exec { reply, .. } | Interaction::Query { reply, .. } | Interaction::Sudo { reply, .. } => {
+                reply.result()
+            }
+            _ => unreachable!("steps are matched by kind"),
+        }
+    }
+}
+
+impl RemoteExecutor for MockTransport {
+    fn exec(&self, cmd: &str) -> Result<String> {
+        if dry_run::skip("run", cmd) {
+            return Ok(String::new());
+        }
+        self.command(Interaction::exec, cmd)
+    }
+
+    fn query(&self, cmd: &str) -> Result<String> {
+        self.command(Interaction::query, cmd)
+    }
+
+    fn sudo(&self, cmd: &str, _password: &str) -> Result<String> {
+        if dry_run::skip("run as root", cmd) {
+            return Ok(String::new());
+        }
+        self.command(Interaction::sudo, cmd)
+    }
+
+    fn sudo_check(&self, password: &str) -> Result<SudoCheck> {
+        let step = Interaction::SudoCheck { password: !password.is_empty(), outcome: SudoCheck::Accepted };
+        match self.next(step)? {
+            Interaction::SudoCheck { outcome, .. } => Ok(outcome),
+            _ => unreachable!("steps are matched by kind"),
+        }
+    }
+
+    fn supports_su(&self) -> bool {
+        self.supports_su
+    }
+}
+
+impl Transport for MockTransport {
+    fn upload(&self, local_path: &Path, remote_path: &Path) -> Result<()> {
+        if dry_run::skip("upload", format!("{} -> {}", local_path.display(), remote_path.display())) {
+            return Ok(());
+        }
+        self.next(Interaction::Upload { to: remote_path.to_path_buf() }).map(drop)
+    }
+
+    fn needs_sudo(&self) -> bool {
+        self.needs_sudo
+    }
+
+    // Uploads are played back in the order they were recorded
+    fn upload_jobs(&self) -> usize {
+        1
+    }
+}
+
+/// Records the session with a target to a file, for [`MockTransport`].
+///
+/// The file is rewritten after every step, so a run that fails still
+/// leaves the steps up to its failure. Steps a dry run skips are not
+/// recorded.
+pub struct Recorder {
+    /// The target recorded.
+    inner: Box<dyn Transport>,
+    /// The file the session is written to.
+    path: PathBuf,
+    /// The session so far.
+    cassette: Mutex<Cassette>,
+}
+
+impl Recorder {
+    /// Record the session with `inner`, reached as `target`, to `path`.
+    pub fn new(inner: Box<dyn Transport>, target: &str, path: &Path) -> Recorder {
+        let cassette = Cassette {
+            target: target.to_string(),
+            needs_sudo: inner.needs_sudo(),
+            supports_su: inner.supports_su(),
+            interactions: Vec::new(),
+        };
+        Recorder { inner, path: path.to_path_buf(), cassette: Mutex::new(cassette) }
+    }
+
+    /// Add `step` to the session and write it out.
+    fn record(&self, step: Interaction) -> Result<()> {
+        let mut cassette = self.cassette.lock().unwrap();
+        cassette.interactions.push(step);
+        let content = serde_json::to_string_pretty(&*cassette)?;
+        fs::write(&self.path, content).with_context(|| format!("Failed to record session to {}", self.path.display()))
+    }
+
+    /// Record a command that returned `result`, unless a dry run skipped it.
+    fn command(
+        &self,
+        kind: fn(String, Reply) -> Interaction,
+        cmd: &str,
+        skipped: bool,
+        result: Result<String>,
+    ) -> Result<String> {
+        if !skipped {
+            self.record(kind(cmd.to_string(), Reply::of(&result)))?;
+        }
+        result
+    }
+}
+
+impl RemoteExecutor for Recorder {
+    fn exec(&self, cmd: &str) -> Result<String> {
+        let exec = |command, reply| Interaction::Exec { command, reply };
+        self.command(exec, cmd, dry_run::enabled(), self.inner.exec(cmd))
+    }
+
+    fn query(&self, cmd: &str) -> Result<String> {
+        let query = |command, reply| Interaction::Query { command, reply };
+        self.command(query, cmd, false, self.inner.query(cmd))
+    }
+
+    fn sudo(&self, cmd: &str, password: &str) -> Result<String> {
+        let sudo = |command, reply| Interaction::Sudo { command, reply };
+        self.command(sudo, cmd, dry_run::enabled(), self.inner.sudo(cmd, password))
+    }
+
+    fn sudo_check(&self, password: &str) -> Result<SudoCheck> {
+        let outcome = self.inner.sudo_check(password)?;
+        let recorded = match &outcome {
+            SudoCheck::Denied(reason) => SudoCheck::Denied(redact::redact(reason).into_owned()),
+            outcome => outcome.clone(),
+        };
+        self.record(Interaction::SudoCheck { password: !password.is_empty(), outcome: recorded })?;
+        Ok(outcome)
+    }
+
+    fn supports_su(&self) -> bool {
+        self.inner.supports_su()
+    }
+}
+
+impl Transport for Recorder {
+    fn upload(&self, local_path: &Path, remote_path: &Path) -> Result<()> {
+        self.inner.upload(local_path, remote_path)?;
+        if !dry_run::enabled() {
+            self.record(Interaction::Upload { to: remote_path.to_path_buf() })?;
+        }
+        Ok(())
+    }
+
+    fn needs_sudo(&self) -> bool {
+        self.inner.needs_sudo()
+    }
+
+    // Uploads are recorded in the order they are played back
+    fn upload_jobs(&self) -> usize {
+        1
+    }
+}
@@ -0,0 +1,250 @@
+//! # Notifications
+//!
+//! Long runs, such as an overnight install across a fleet, can report their
+//! outcome when they finish instead of being watched. Notifications are
+//! configured in the `[notify]` table of `config.toml`:
+//!
+//! ```toml
+//! [notify]
+//! webhook = "https://chat.example.com/hooks/apt-remote"
+//! headers = { Authorization = "Bearer 0123abcd" }
+//! email = ["ops@example.com"]
+//! email_from = "apt-remote@build.example.com"
+//! sendmail = "/usr/sbin/sendmail"   # default: sendmail on the PATH
+//! on = "always"                      # always | failure | never
+//! commands = ["install", "update"]   # default: get, install, update, apply, run, listen
+//! ```
+//!
+//! The webhook receives a JSON `POST` with a one-line `text` (which Slack,
+//! Mattermost and similar chat webhooks display as is), the command, image,
+//! target and profile, the `outcome` (`success` or `failure`), the error
+//! with its exit code, kind and hint, and the run summary (see
+//! [`crate::summary`]) as `summary`:
+//!
+//! ```json
+//! {"text":"apt-remote install web on @kiosks failed: 2 of 30 targets failed",
+//!  "command":"install","image":"web","target":"@kiosks","profile":null,"host":"build",
+//!  "outcome":"failure","exit_code":1,"error":"2 of 30 targets failed","kind":"group_failed",
+//!  "hint":"...","summary":{"succeeded":1260,"failed":84,"skipped":0,...}}
+//! ```
+//!
+//! Emails are handed to `sendmail -t -oi`, so they go through the local
+//! mail transfer agent (Postfix, msmtp, ...) with its relay and credentials.
+//! Like failing hooks (see [`crate::hooks`]), a notification that cannot be
+//! sent is logged and does not change the outcome of the command. Nothing is
+//! sent in a dry run.
+
+use crate::{
+    config, dry_run,
+    error::Error,
+    exit::ExitCode,
+    hooks::HookContext,
+    redact,
+    summary::Totals,
+    tls, tr,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+/// How long the webhook may take to answer.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which outcomes are notified.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyOn {
+    /// Successes and failures.
+    #[default]
+    Always,
+    /// Failures only.
+    Failure,
+    /// Nothing, for example to silence a profile.
+    Never,
+}
+
+/// The `[notify]` table of `config.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotifyConfig {
+    /// URL the outcome is posted to as JSON.
+    pub webhook: Option<String>,
+    /// Extra HTTP headers of the webhook request, such as `Authorization`.
+    pub headers: BTreeMap<String, String>,
+    /// Addresses the outcome is mailed to.
+    pub email: Vec<String>,
+    /// Sender of the emails (default: chosen by the mail transfer agent).
+    pub email_from: Option<String>,
+    /// `sendmail`-compatible command the emails are handed to.
+    pub sendmail: String,
+    /// Which outcomes are notified.
+    pub on: NotifyOn,
+    /// Subcommands whose outcome is notified.
+    pub commands: Vec<String>,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig {
+            webhook: None,
+            headers: BTreeMap::new(),
+            email: Vec::new(),
+            email_from: None,
+            sendmail: "sendmail".to_string(),
+            on: NotifyOn::Always,
+            commands: ["get", "install", "update", "apply", "run", "listen"].map(String::from).to_vec(),
+        }
+    }
+}
+
+/// Notify the outcome of a finished command, if configured.
+///
+/// Failures to notify are logged but do not change the outcome of the
+/// command.
+pub fn send(ctx: &HookContext, outcome: &Result<()>, totals: &Totals) {
+    let notify = &config::get().notify;
+    if notify.webhook.is_none() && notify.email.is_empty() {
+        return;
+    }
+    let wanted = match notify.on {
+        NotifyOn::Always => true,
+        NotifyOn::Failure => outcome.is_err(),
+        NotifyOn::Never => false,
+    };
+    if !wanted || !notify.commands.iter().any(|command| command == ctx.command) {
+        return;
+    }
+
+    let message = Message::new(ctx, outcome, totals);
+    if let Some(url) = &notify.webhook
+        && !dry_run::skip("notify", url)
+        && let Err(e) = post(url, &notify.headers, &message)
+    {
+        tracing::warn!("Failed to notify {}: {e:#}", redact::redact(url));
+    }
+    if !notify.email.is_empty()
+        && !dry_run::skip("notify", notify.email.join(", "))
+        && let Err(e) = mail(notify, &message)
+    {
+        tracing::warn!("Failed to mail the outcome to {}: {e:#}", notify.email.join(", "));
+    }
+}
+
+/// What is notified about a finished command.
+struct Message {
+    /// One line describing the outcome.
+    text: String,
+    /// The run summary, as lines of text.
+    summary: Vec<String>,
+    /// The webhook payload.
+    payload: Value,
+}
+
+impl Message {
+    fn new(ctx: &HookContext, outcome: &Result<()>, totals: &Totals) -> Message {
+        let mut subject = format!("apt-remote {}", ctx.command);
+        if let Some(image) = &ctx.image {
+            subject.push_str(&format!(" {image}"));
+        }
+        if let Some(target) = &ctx.target {
+            subject.push_str(&format!(" on {target}"));
+        }
+        let text = match outcome {
+            Ok(()) => tr!("{subject} succeeded", subject = subject),
+            Err(e) => tr!("{subject} failed: {error}", subject = subject, error = e),
+        };
+
+        let mut summary = Vec::new();
+        if !totals.is_empty() {
+            summary.push(format!("{} · {}", totals.counts(), totals.transfers()));
+            if !totals.phases.is_empty() {
+                summary.push(totals.phase_times());
+            }
+        }
+        let typed = outcome.as_ref().err().and_then(Error::find);
+        if let Err(e) = outcome {
+            if !summary.is_empty() {
+                summary.push(String::new());
+            }
+            summary.push(format!("{} {e:#}", tr!("Error:")));
+            if let Some(hint) = typed.and_then(Error::hint) {
+                summary.push(format!("{} {hint}", tr!("hint:")));
+            }
+        }
+
+        let payload = json!({
+            "text": text,
+            "command": ctx.command,
+            "image": ctx.image,
+            "target": ctx.target,
+            "profile": config::get().profile,
+            "host": fs::read_to_string("/proc/sys/kernel/hostname").ok().map(|host| host.trim().to_string()),
+            "outcome": if outcome.is_ok() { "success" } else { "failure" },
+            "exit_code": outcome.as_ref().err().map_or(0, |e| ExitCode::of(e).code()),
+            "error": outcome.as_ref().err().map(|e| format!("{e:#}")),
+            "kind": typed.map(Error::kind),
+            "hint": typed.and_then(Error::hint),
+            "summary": totals.to_json(),
+        });
+        Message {
+            text: redact::redact(&text).into_owned(),
+            summary: summary.iter().map(|line| redact::redact(line).into_owned()).collect(),
+            payload: redact::value(payload),
+        }
+    }
+}
+
+/// Post the payload of `message` to the webhook at `url`.
+fn post(url: &str, headers: &BTreeMap<String, String>, message: &Message) -> Result<()> {
+    let client = tls::load()?
+        .blocking(reqwest::blocking::Client::builder().timeout(WEBHOOK_TIMEOUT))
+        .build()
+        .context("Failed to create the HTTP client")?;
+    let mut request = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(message.payload.to_string());
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    request.send()?.error_for_status()?;
+    Ok(())
+}
+
+/// Mail `message` to the configured addresses through `sendmail`.
+fn mail(notify: &NotifyConfig, message: &Message) -> Result<()> {
+    let mut email = format!("To: {}\n", notify.email.join(", "));
+    if let Some(from) = &notify.email_from {
+        email.push_str(&format!("From: {from}\n"));
+    }
+    email.push_str(&format!(
+        "Subject: {}\nContent-Type: text/plain; charset=utf-8\n\n{}\n",
+        message.text.replace('\n', " "),
+        message.text
+    ));
+    if !message.summary.is_empty() {
+        email.push_str(&format!("\n{}\n", message.summary.join("\n")));
+    }
+
+    let mut child = Command::new(&notify.sendmail)
+        .args(["-t", "-oi"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", notify.sendmail))?;
+    child.stdin.take().context("sendmail has no stdin")?.write_all(email.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        anyhow::bail!("{} exited with {status}", notify.sendmail);
+    }
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//! # OpenSSH transport for Kerberos and persistent connections
+//!
+//! The built-in SSH client (`libssh2`) authenticates with keys, the agent
+//! and passwords only. Hosts that only accept Kerberos are reached through
+//! the system's OpenSSH client instead, which authenticates with GSSAPI
+//! using the ticket `kinit` obtained, without any password prompt. With
+//! `gssapi = true` in the `[ssh]` table of `config.toml` (or `--gssapi`),
+//! every SSH target goes through it:
+//!
+//! ```toml
+//! [ssh]
+//! gssapi = true
+//! gssapi_delegate = false         # forward the ticket to the target
+//! ```
+//!
+//! The built-in client shares one session per target within a run (see
+//! [`crate::ssh::shared_session`]), but a session cannot outlive the
+//! process. To chain `set`, `get` and `install` without authenticating
+//! for each, `persist = 600` in the `[ssh]` table (or `--persist 600`)
+//! also sends targets through the OpenSSH client, whose shared connection
+//! then stays open for that many seconds after the last command, for the
+//! next run to use. Without `--batch`, `ssh` asks on the terminal for a
+//! password or passphrase it needs when it opens that connection.
+//!
+//! Hosts are resolved from the inventory as for the built-in client (see
+//! [`crate::inventory`]), and so are their jump hosts, given to OpenSSH as
+//! `ProxyJump`. Commands run with `ssh` and uploads go through
+//! `scp`, all over one connection per target (OpenSSH connection sharing,
+//! `ControlMaster`, with its sockets in a directory only this user can
+//! use), so the target authenticates once. Unless the
+//! connection persists, the client never prompts (`BatchMode`): without a
+//! valid ticket or key, the connection fails.
+//!
+//! Commands run without a terminal, so the sudo password is given on
+//! `sudo`'s stdin, and `sudo` on hosts with `requiretty` fails as denied.
+//! Settings this client cannot honour are refused when connecting rather
+//! than ignored: a `become_user`, or an `escalation` other than `sudo` (see
+//! [`crate::ssh::Escalation`]), and an SSH password given in a file or the
+//! environment (see [`crate::secret`]), since `ssh` only asks for one on
+//! the terminal. An askpass helper still provides the sudo password.
+
+use crate::{
+    config, dry_run,
+    error::Error,
+    exit::{Classify, ExitCode},
+    inventory,
+    known_hosts::{self, HostKeyChecking},
+    secret::Credential,
+    ssh::{Escalation, RemoteExecutor, SudoCheck, c_locale, classify_sudo, posix_sh, sudo_line},
+    transport::{Transport, run_process},
+};
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// How long the shared connection outlives the last command, in seconds,
+/// unless `ssh.persist` says otherwise.
+const CONTROL_PERSIST: u64 = 60;
+
+/// Runs commands and uploads files through the OpenSSH client, with
+/// GSSAPI authentication.
+pub struct OpenSshTransport {
+    /// The `user@host` target, for messages.
+    target: String,
+    /// Options shared by `ssh` and `scp`.
+    options: Vec<String>,
+    /// Port of the SSH server.
+    port: u16,
+    /// `user@address` to connect to.
+    destination: String,
+}
+
+impl OpenSshTransport {
+    /// Connect to `target` (`user@host`) and authenticate with GSSAPI, or a
+    /// key of the inventory or the agent.
+    ///
+    /// # Errors
+    /// Returns [`Error::SshConnect`] if the host cannot be reached, and
+    /// [`Error::SshAuth`] if it accepts none of the credentials.
+    pub fn connect(target: &str) -> Result<OpenSshTransport> {
+        let endpoint = inventory::get()?.endpoint(target)?;
+        if let Some(setting) = unsupported(target) {
+            return Err(anyhow::anyhow!(
+                "{target} is reached through the OpenSSH client (`gssapi` or `persist`), which does not support {setting}"
+            ))
+            .classify(ExitCode::Config);
+        }
+        let ssh = &config::get().ssh;
+        let control = control_dir()?.join("%C");
+        // A connection kept for later runs may ask for credentials once, when it is opened
+        let batch = ssh.persist.is_none() || config::get().batch;
+        let mut options = vec![
+            format!("BatchMode={}", if batch { "yes" } else { "no" }),
+            "GSSAPIAuthentication=yes".to_string(),
+            format!("GSSAPIDelegateCredentials={}", if ssh.gssapi_delegate { "yes" } else { "no" }),
+            "ControlMaster=auto".to_string(),
+            format!("ControlPath={}", control.display()),
+            format!("ControlPersist={}", ssh.persist.unwrap_or(CONTROL_PERSIST)),
+        ];
+        // OpenSSH cannot ask in batch mode: unknown keys are then refused
+        options.push(
+            match ssh.host_key_checking {
+                HostKeyChecking::Ask if !batch => "StrictHostKeyChecking=ask",
+                HostKeyChecking::Ask | HostKeyChecking::Strict => "StrictHostKeyChecking=yes",
+                HostKeyChecking::AcceptNew => "StrictHostKeyChecking=accept-new",
+                HostKeyChecking::Off => "StrictHostKeyChecking=no",
+            }
+            .to_string(),
+        );
+        if let Some(path) = known_hosts::path() {
+            options.push(format!("UserKnownHostsFile={}", path.display()));
+        }
+        if ssh.compress {
+            options.push("Compression=yes".to_string());
+        }
+        if let Some(timeout) = ssh.connect_timeout {
+            options.push(format!("ConnectTimeout={timeout}"));
+        }
+        if let Some(identity) = endpoint.identity.clone().or_else(config::identity) {
+            options.push(format!("IdentityFile={}", identity.display()));
+        }
+        if !endpoint.jump.is_empty() {
+            // Hops are resolved here, as OpenSSH does not know the inventory
+            let hops = endpoint
+                .jump
+                .iter()
+                .map(|hop| {
+                    let hop = inventory::get()?.hop(hop, &endpoint.user)?;
+                    let address = if hop.address.contains(':') { format!("[{}]", hop.address) } else { hop.address };
+                    Ok(format!("{}@{address}:{}", hop.user, hop.port))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            options.push(format!("ProxyJump={}", hops.join(",")));
+        }
+        let transport = OpenSshTransport {
+            target: target.to_string(),
+            options,
+            port: endpoint.port,
+            destination: format!("{}@{}", endpoint.user, endpoint.address),
+        };
+
+        // Authenticate now, so failures are reported as such
+        info!("Connecting to {}:{} with GSSAPI", endpoint.address, endpoint.port);
+        let output = transport.ssh("true").output().context("Failed to run `ssh`").classify(ExitCode::Connect)?;
+        if !output.status.success() {
+            let reason = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(if reason.contains("Permission denied") {
+                Error::SshAuth { target: target.to_string() }
+            } else {
+                Error::SshConnect { target: target.to_string(), source: reason.into() }
+            }
+            .into());
+        }
+        Ok(transport)
+    }
+
+    /// The `ssh` command running `cmd` on the target in the C locale.
+    fn ssh(&self, cmd: &str) -> Command {
+        let mut command = Command::new("ssh");
+        command.arg("-T").arg("-p").arg(self.port.to_string());
+        for option in &self.options {
+            command.arg("-o").arg(option);
+        }
+        command.arg(&self.destination).arg("--").arg(posix_sh(&c_locale(cmd)));
+        command
+    }
+
+    /// Run `cmd` on the target, writing `input` to its stdin, and return its
+    /// stdout and exit status.
+    fn run(&self, cmd: &str, input: Option<&str>) -> Result<(String, i32)> {
+        let (output, status) = run_process(self.ssh(cmd), cmd, input)?;
+        if status == 255 {
+            return Err(anyhow::anyhow!("Lost the connection to {}", self.target)).classify(ExitCode::Remote);
+        }
+        Ok((output, status))
+    }
+}
+
+impl RemoteExecutor for OpenSshTransport {
+    fn exec(&self, cmd: &str) -> Result<String> {
+        if dry_run::skip("run", cmd) {
+            return Ok(String::new());
+        }
+        self.query(cmd)
+    }
+
+    fn query(&self, cmd: &str) -> Result<String> {
+        Ok(self.run(cmd, None)?.0)
+    }
+
+    fn sudo(&self, cmd: &str, password: &str) -> Result<String> {
+        if dry_run::skip("run as root", cmd) {
+            return Ok(String::new());
+        }
+        Ok(self.run(&sudo_line(cmd), Some(password))?.0)
+    }
+
+    fn sudo_check(&self, password: &str) -> Result<SudoCheck> {
+        let (output, status) = if password.is_empty() {
+            self.run("sudo -n true 2>&1", None)?
+        } else {
+            self.run("sudo -S -k -v -p '' 2>&1", Some(password))?
+        };
+        Ok(match status {
+            0 => SudoCheck::Accepted,
+            _ => classify_sudo(&output),
+        })
+    }
+}
+
+impl Transport for OpenSshTransport {
+    fn upload(&self, local_path: &Path, remote_path: &Path) -> Result<()> {
+        if dry_run::skip("upload", format!("{} -> {}", local_path.display(), remote_path.display())) {
+            return Ok(());
+        }
+        let mut command = Command::new("scp");
+        command.arg("-q").arg("-r").arg("-P").arg(self.port.to_string());
+        // scp limits in Kbit/s, and each of the concurrent uploads gets its share
+        if let Some(rate) = config::get().ssh.limit_rate {
+            let share = rate / self.upload_jobs() as u64;
+            command.arg("-l").arg((share * 8 / 1000).max(1).to_string());
+        }
+        for option in &self.options {
+            command.arg("-o").arg(option);
+        }
+        let destination = format!("{}:{}", self.destination, remote_path.display());
+        command.arg(local_path).arg(&destination);
+        let output = command
+            .output()
+            .context("Failed to run `scp`")
+            .classify(ExitCode::Upload)?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Failed to copy {} to {destination}: {}",
+                local_path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+            .classify(ExitCode::Upload);
+        }
+        Ok(())
+    }
+}
+
+/// Directory of the sockets of shared connections, which only this user
+/// may use: `apt-remote` in `$XDG_RUNTIME_DIR`, or `~/.cache/apt-remote/ssh`.
+///
+/// # Errors
+/// Returns an error if the directory cannot be created, or belongs to
+/// another user.
+fn control_dir() -> Result<PathBuf> {
+    let dir = match dirs::runtime_dir() {
+        Some(runtime) => runtime.join("apt-remote"),
+        None => dirs::cache_dir().context("No cache directory for SSH control sockets")?.join("apt-remote").join("ssh"),
+    };
+    let refused = || format!("{} must be a directory of this user, for SSH control sockets", dir.display());
+    fs::create_dir_all(&dir).with_context(refused).classify(ExitCode::Config)?;
+    if !fs::symlink_metadata(&dir).with_context(refused)?.is_dir() {
+        return Err(anyhow::anyhow!(refused())).classify(ExitCode::Config);
+    }
+    // Only the owner may change the mode, so this also refuses another user's directory
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).with_context(refused).classify(ExitCode::Config)?;
+    }
+    Ok(dir)
+}
+
+/// The setting of `target` the OpenSSH client cannot honour, if any.
+fn unsupported(target: &str) -> Option<String> {
+    match Escalation::configured(target) {
+        Escalation::Sudo => {}
+        Escalation::Su(account) if account != "root" => return Some(format!("become_user = \"{account}\"")),
+        escalation => return Some(format!("escalation = \"{}\"", escalation.tool())),
+    }
+    let password = Credential::SshPassword;
+    let provided = config::get().ssh_password_file.is_some()
+        || std::env::var(password.env_var()).is_ok_and(|value| !value.is_empty());
+    provided.then(|| format!("an SSH password from {} or {}", password.flag(), password.env_var()))
+}
+
+/// Whether SSH targets are reached through the OpenSSH client.
+pub fn enabled() -> bool {
+    let ssh = &config::get().ssh;
+    ssh.gssapi || ssh.persist.is_some()
+}
@@ -0,0 +1,188 @@
+//! # Output formatting for apt-remote
+//!
+//! Subcommands write two kinds of output: human-readable lines and
+//! structured events. In the default human mode only the former is shown;
+//! with `--output json` human lines, progress bars and colors are
+//! suppressed and every event is written to stdout as one JSON object per
+//! line, with an `event` field naming its kind:
+//!
+//! ```text
+//! {"event":"download","file":"curl_7.88.1-10_amd64.deb","status":"downloaded"}
+//! {"event":"result","command":"get","name":"web","downloaded":12,"failed":0}
+//! ```
+//!
+//! Errors that abort a command are reported as an `error` event. Callers
+//! that report on their own, such as the Ansible module (see
+//! [`crate::ansible`]), [`capture`] the events instead of printing them,
+//! and library users can [`listen`] to them in any output format.
+//!
+//! Lines and events are redacted (see [`crate::redact`]) before they are
+//! written.
+
+use crate::{
+    config, dashboard, dry_run,
+    error::Error,
+    exit::ExitCode,
+    redact, tr,
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use std::{
+    fmt::Display,
+    io::{IsTerminal, Write},
+    sync::{Arc, Mutex},
+};
+
+/// Events collected instead of printed, once [`capture`] is called.
+static CAPTURED: Mutex<Option<Vec<Value>>> = Mutex::new(None);
+
+/// Receiver of every event, installed with [`listen`].
+static LISTENER: Mutex<Option<Listener>> = Mutex::new(None);
+
+/// Receiver of events installed with [`listen`].
+pub type Listener = Arc<dyn Fn(&Map<String, Value>) + Send + Sync>;
+
+/// Format of the output written to stdout.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Human-readable text with progress bars.
+    #[default]
+    Human,
+    /// One JSON event per line.
+    Json,
+}
+
+/// Returns `true` if structured JSON output was requested.
+pub fn is_json() -> bool {
+    config::get().output == OutputFormat::Json
+}
+
+/// Print a human-readable line (suppressed in JSON and quiet mode).
+pub fn print(line: impl Display) {
+    if !is_json() && !config::get().quiet {
+        let line = redact::redact(&line.to_string()).into_owned();
+        if !dashboard::capture(&line) {
+            println!("{line}");
+        }
+    }
+}
+
+/// Emit a structured event (only in JSON mode, or to a [`listen`]er).
+///
+/// # Arguments
+/// * `event` - Kind of the event, stored in its `event` field.
+/// * `data` - A JSON object with the event's fields.
+pub fn emit(event: &str, data: Value) {
+    let listener = LISTENER.lock().unwrap().clone();
+    if !is_json() && listener.is_none() {
+        return;
+    }
+
+    let mut object = Map::new();
+    object.insert("event".to_string(), Value::from(event));
+    if let Value::Object(fields) = redact::value(data) {
+        object.extend(fields);
+    }
+    if let Some(listener) = listener {
+        listener(&object);
+    }
+    if !is_json() {
+        return;
+    }
+    if let Some(captured) = CAPTURED.lock().unwrap().as_mut() {
+        captured.push(Value::Object(object));
+        return;
+    }
+    println!("{}", Value::Object(object));
+}
+
+/// Collect events for [`take_captured`] from now on instead of printing
+/// them.
+pub fn capture() {
+    CAPTURED.lock().unwrap().get_or_insert_with(Vec::new);
+}
+
+/// The events collected since [`capture`] or the last call.
+pub fn take_captured() -> Vec<Value> {
+    CAPTURED.lock().unwrap().as_mut().map(std::mem::take).unwrap_or_default()
+}
+
+/// Hand every event to `listener` from now on, whatever the output format,
+/// or stop with `None`.
+pub fn listen(listener: Option<Listener>) {
+    *LISTENER.lock().unwrap() = listener;
+}
+
+/// Ask the user to confirm a destructive step before it is performed.
+///
+/// Confirmation is implied by `--yes` (or `assume_yes` in config.toml).
+/// Otherwise the question is asked on stderr and answered on stdin.
+///
+/// # Errors
+/// Returns an error if the user declines, or if no answer can be given
+/// because stdin is not a terminal or JSON output is enabled.
+pub fn confirm(question: &str) -> Result<()> {
+    if config::get().assume_yes || config::get().batch || dry_run::enabled() {
+        return Ok(());
+    }
+    ask(question)
+}
+
+/// Whether questions can be asked: stdin is a terminal, the output is not
+/// JSON, and `--batch` is not given.
+pub fn can_ask() -> bool {
+    !config::get().batch && !is_json() && std::io::stdin().is_terminal()
+}
+
+/// Ask the user to confirm on stderr, whatever `--yes` and `--dry-run` say.
+///
+/// For decisions of trust that must not be implied, such as an unknown host
+/// key; destructive steps use [`confirm`].
+///
+/// # Errors
+/// Returns an error if the user declines, or if no answer can be given
+/// (see [`can_ask`]).
+pub fn ask(question: &str) -> Result<()> {
+    if !can_ask() {
+        return Err(Error::ConfirmationRequired.into());
+    }
+
+    let _paused = dashboard::pause();
+    eprint!("{question} {} ", tr!("[y/N]"));
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    // The English answers are always understood
+    let answer = answer.trim().to_lowercase();
+    if ["y", "yes"].contains(&answer.as_str()) || answer == tr!("y") || answer == tr!("yes") {
+        Ok(())
+    } else {
+        Err(Error::Declined.into())
+    }
+}
+
+/// Emit an `error` event for an error that aborted the command.
+///
+/// The event carries the failure class and the process exit code.
+pub fn emit_error(error: &anyhow::Error) {
+    let causes = error.chain().skip(1).map(|cause| cause.to_string()).collect::<Vec<_>>();
+    let class = ExitCode::of(error);
+    let typed = Error::find(error);
+    emit(
+        "error",
+        serde_json::json!({
+            "message": error.to_string(),
+            "causes": causes,
+            "class": class,
+            "exit_code": class.code(),
+            "kind": typed.map(Error::kind),
+            "hint": typed.and_then(Error::hint),
+        }),
+    );
+}
@@ -0,0 +1,111 @@
+//! # Pipelines for apt-remote
+//!
+//! A pipeline is a named routine of subcommands on one image, defined in the
+//! `[pipelines]` table of `config.toml` and run with
+//! `apt-remote run <PIPELINE> --target <user@host>`:
+//!
+//! ```toml
+//! [pipelines.security]
+//! image = "security"              # default: the pipeline name
+//! steps = [
+//!     { step = "set", upgrade = true },
+//!     { step = "get" },
+//!     { step = "check" },
+//!     { step = "install" },
+//!     { step = "export", dest = "/srv/snapshots", installer = true },
+//! ]
+//! ```
+//!
+//! Steps run in order and the pipeline stops at the first one that fails.
+//! The target is passed to the steps that need one (`set`, `install` and
+//! `update`).
+
+use serde::{Deserialize, Serialize};
+
+use std::path::PathBuf;
+
+/// A named sequence of steps on one image.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pipeline {
+    /// Cache image name (default: the pipeline name).
+    pub image: Option<String>,
+    /// Steps to run, in order.
+    pub steps: Vec<Step>,
+}
+
+/// One step of a pipeline, with the options of its subcommand.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Step {
+    /// Resolve the image's plan on the target (`apt-remote set`).
+    Set {
+        /// Packages to install.
+        #[serde(default)]
+        install: Vec<String>,
+        /// Resolve the packages needed to fix broken dependencies.
+        #[serde(default)]
+        fix: bool,
+        /// Hold up-to-date package lists.
+        #[serde(default)]
+        update: bool,
+        /// Hold the upgradable packages.
+        #[serde(default)]
+        upgrade: bool,
+    },
+    /// Download the image (`apt-remote get`).
+    Get {
+        /// Directory to adopt matching packages from.
+        #[serde(default)]
+        adopt: Option<PathBuf>,
+    },
+    /// Check the image against the current mirrors (`apt-remote check`).
+    Check,
+    /// Install the image on the target (`apt-remote install`).
+    Install {
+        /// Install every package again.
+        #[serde(default)]
+        reinstall: bool,
+        /// Compare the checksums of the cached packages before uploading.
+        #[serde(default)]
+        verify_hashes: bool,
+    },
+    /// Upload the image's package lists to the target (`apt-remote update`).
+    Update,
+    /// Copy the image to a directory (`apt-remote export`).
+    Export {
+        /// Destination directory.
+        dest: PathBuf,
+        /// Include the shell installer.
+        #[serde(default)]
+        installer: bool,
+    },
+    /// Show the image's state (`apt-remote status`).
+    Status,
+}
+
+impl Step {
+    /// Name of the step's subcommand.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::Set { .. } => "set",
+            Step::Get { .. } => "get",
+            Step::Check => "check",
+            Step::Install { .. } => "install",
+            Step::Update => "update",
+            Step::Export { .. } => "export",
+            Step::Status => "status",
+        }
+    }
+
+    /// Whether the step, if it is a `set` step, sets exactly one of
+    /// `install`, `fix`, `update` and `upgrade`, as `apt-remote set` requires.
+    pub fn has_one_mode(&self) -> bool {
+        match self {
+            Step::Set { install, fix, update, upgrade } => {
+                [!install.is_empty(), *fix, *update, *upgrade].iter().filter(|&&mode| mode).count() == 1
+            }
+            _ => true,
+        }
+    }
+}
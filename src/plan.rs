@@ -0,0 +1,386 @@
+//! # Plans for library users
+//!
+//! Frontends that drive apt-remote programmatically, such as a GUI, build a
+//! plan of an operation, show its steps before anything is done, and then
+//! hand it to an [`Executor`], which runs it and streams its progress as
+//! [`Event`]s:
+//!
+//! ```no_run
+//! use apt_remote::plan::{Executor, InstallPlan, Plan};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let plan = InstallPlan::new("web").target("admin@kiosk").dry_run(true);
+//! for step in plan.steps()? {
+//!     println!("{}: {}", step.target, step.action);
+//! }
+//! Executor::new()
+//!     .on_event(|event| println!("{} {:?}", event.kind, event.fields))
+//!     .run(&plan)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Steps are worked out from the local cache alone, without connecting to
+//! the target, so they are what the run would do if the target is as the
+//! image's state (see [`crate::state`]) last saw it. Events are those of
+//! `--output json` (see [`crate::output`]), whatever the configured output
+//! format. The configuration is installed once per process (see
+//! [`crate::config::init`]); as a frontend has no terminal to confirm on,
+//! it should set `assume_yes` there.
+
+use crate::{
+    commands::run::run_step,
+    config, dry_run, inventory, output,
+    pipeline::Step,
+    staging,
+    state::ImageState,
+    uri::{self, RemoteMode, UriFile},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// An operation on an image, built before it is run.
+pub trait Plan {
+    /// What the plan would do, in order.
+    ///
+    /// # Errors
+    /// Returns an error if the image or its target cannot be resolved.
+    fn steps(&self) -> Result<Vec<PlannedStep>>;
+
+    /// Whether the plan is only reported, not performed (see [`crate::dry_run`]).
+    fn is_dry_run(&self) -> bool;
+
+    /// The image the plan works on.
+    fn image(&self) -> &str;
+
+    /// The target the plan works on, `None` for the configured default.
+    fn target(&self) -> Option<&String>;
+
+    /// The pipeline step performing the plan.
+    fn step(&self) -> Step;
+}
+
+/// One step of a [`Plan`] on one target.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedStep {
+    /// The `user@host` target the step is done on.
+    pub target: String,
+    /// What is done.
+    pub action: Action,
+}
+
+/// What a [`PlannedStep`] does.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Check the cached packages before anything is sent.
+    CheckCache {
+        /// Number of packages checked.
+        files: usize,
+    },
+    /// Upload a file to the target.
+    Upload {
+        /// Its file name.
+        file: String,
+        /// Its size in bytes.
+        size: u64,
+    },
+    /// Verify the checksum of an uploaded package on the target.
+    Verify {
+        /// Its file name.
+        file: String,
+    },
+    /// Install a package with `dpkg -i`.
+    Install {
+        /// Its file name.
+        file: String,
+    },
+    /// Configure unpacked packages with `dpkg --configure -a`.
+    Configure,
+    /// Replace the target's package lists with the uploaded ones and
+    /// regenerate APT's cache.
+    ReplaceLists,
+    /// Leave the rest to an administrator (see [`crate::staging`]).
+    Stage,
+    /// Remove the uploaded files from the target.
+    Cleanup,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::CheckCache { files } => write!(f, "check {files} cached packages"),
+            Action::Upload { file, .. } => write!(f, "upload {file}"),
+            Action::Verify { file } => write!(f, "verify {file}"),
+            Action::Install { file } => write!(f, "install {file}"),
+            Action::Configure => write!(f, "configure packages"),
+            Action::ReplaceLists => write!(f, "replace package lists"),
+            Action::Stage => write!(f, "stage for an administrator"),
+            Action::Cleanup => write!(f, "clean up"),
+        }
+    }
+}
+
+/// Installing an image on a target (`apt-remote install`).
+#[derive(Debug, Clone)]
+pub struct InstallPlan {
+    image: String,
+    target: Option<String>,
+    dry_run: bool,
+    reinstall: bool,
+    verify_hashes: bool,
+}
+
+impl InstallPlan {
+    /// A plan installing `image` on the configured default target.
+    pub fn new(image: &str) -> InstallPlan {
+        InstallPlan { image: image.to_string(), target: None, dry_run: false, reinstall: false, verify_hashes: false }
+    }
+
+    /// Install on `target` (`user@host` or `@group`) instead.
+    pub fn target(mut self, target: &str) -> InstallPlan {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    /// Only report what would be done.
+    pub fn dry_run(mut self, dry_run: bool) -> InstallPlan {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Install every package, even those already installed on the target.
+    pub fn reinstall(mut self, reinstall: bool) -> InstallPlan {
+        self.reinstall = reinstall;
+        self
+    }
+
+    /// Also compare the checksum of every cached package before uploading.
+    pub fn verify_hashes(mut self, verify_hashes: bool) -> InstallPlan {
+        self.verify_hashes = verify_hashes;
+        self
+    }
+}
+
+impl Plan for InstallPlan {
+    fn steps(&self) -> Result<Vec<PlannedStep>> {
+        let cache_dir = config::cache_dir()?.join(&self.image);
+        let uri_file = UriFile::load(uri::path_in(&cache_dir)).context("Failed to load uri.toml metadata")?;
+        // Images in update mode are left to `update`
+        if uri_file.mode == RemoteMode::Update {
+            return Ok(Vec::new());
+        }
+        let mut state = ImageState::load(&cache_dir)?;
+        let mut steps = Vec::new();
+        for target in targets(self.target.as_ref())? {
+            let target_state = state.target(&target);
+            let files = uri_file
+                .install_order
+                .iter()
+                .filter(|fname| self.reinstall || !target_state.installed.contains(*fname))
+                .collect::<Vec<_>>();
+            if files.is_empty() {
+                continue;
+            }
+            let mut actions = vec![Action::CheckCache { files: files.len() }];
+            for &file in &files {
+                if !target_state.uploaded.contains(file) {
+                    let size = uri_file.packages.get(file).map_or(0, |pkg| pkg.size);
+                    actions.push(Action::Upload { file: file.clone(), size });
+                }
+            }
+            actions.extend(files.iter().map(|&file| Action::Verify { file: file.clone() }));
+            if staging::enabled() {
+                actions.push(Action::Stage);
+            } else {
+                actions.extend(files.iter().map(|&file| Action::Install { file: file.clone() }));
+                actions.extend([Action::Configure, Action::Cleanup]);
+            }
+            steps.extend(actions.into_iter().map(|action| PlannedStep { target: target.clone(), action }));
+        }
+        Ok(steps)
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    fn image(&self) -> &str {
+        &self.image
+    }
+
+    fn target(&self) -> Option<&String> {
+        self.target.as_ref()
+    }
+
+    fn step(&self) -> Step {
+        Step::Install { reinstall: self.reinstall, verify_hashes: self.verify_hashes }
+    }
+}
+
+/// Replacing a target's package lists with an image's (`apt-remote update`).
+#[derive(Debug, Clone)]
+pub struct UpdatePlan {
+    image: String,
+    target: Option<String>,
+    dry_run: bool,
+}
+
+impl UpdatePlan {
+    /// A plan updating the configured default target from `image`.
+    pub fn new(image: &str) -> UpdatePlan {
+        UpdatePlan { image: image.to_string(), target: None, dry_run: false }
+    }
+
+    /// Update `target` (`user@host` or `@group`) instead.
+    pub fn target(mut self, target: &str) -> UpdatePlan {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    /// Only report what would be done.
+    pub fn dry_run(mut self, dry_run: bool) -> UpdatePlan {
+        self.dry_run = dry_run;
+        self
+    }
+}
+
+impl Plan for UpdatePlan {
+    fn steps(&self) -> Result<Vec<PlannedStep>> {
+        let sources = config::cache_dir()?.join(&self.image).join("sources");
+        let mut lists = Vec::new();
+        for entry in sources.read_dir().with_context(|| format!("{} has not been downloaded", self.image))? {
+            let entry = entry?;
+            lists.push((entry.file_name().to_string_lossy().into_owned(), entry.metadata().map_or(0, |m| m.len())));
+        }
+        lists.sort();
+        let mut steps = Vec::new();
+        for target in targets(self.target.as_ref())? {
+            let mut actions = lists
+                .iter()
+                .map(|(file, size)| Action::Upload { file: file.clone(), size: *size })
+                .collect::<Vec<_>>();
+            actions.push(if staging::enabled() { Action::Stage } else { Action::ReplaceLists });
+            steps.extend(actions.into_iter().map(|action| PlannedStep { target: target.clone(), action }));
+        }
+        Ok(steps)
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    fn image(&self) -> &str {
+        &self.image
+    }
+
+    fn target(&self) -> Option<&String> {
+        self.target.as_ref()
+    }
+
+    fn step(&self) -> Step {
+        Step::Update
+    }
+}
+
+/// The `user@host` targets `target` names: itself, or the members of an
+/// `@group`.
+fn targets(target: Option<&String>) -> Result<Vec<String>> {
+    let target = config::target(target)?;
+    match target.strip_prefix('@') {
+        Some(group) => inventory::get()?.group(group),
+        None => Ok(vec![target]),
+    }
+}
+
+/// A structured event of a running plan, as `--output json` prints it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// Kind of the event (`upload`, `verify`, `install`, `result`, ...).
+    pub kind: String,
+    /// The event's other fields.
+    pub fields: Map<String, Value>,
+}
+
+/// Receiver of the [`Event`]s of a running plan.
+type Handler = Arc<dyn Fn(&Event) + Send + Sync>;
+
+/// Runs [`Plan`]s, streaming their events.
+#[derive(Clone, Default)]
+pub struct Executor {
+    handler: Option<Handler>,
+}
+
+impl Executor {
+    /// An executor whose events are not received.
+    pub fn new() -> Executor {
+        Executor::default()
+    }
+
+    /// Hand every event of the plans run to `handler` as it happens.
+    ///
+    /// `handler` must not emit events itself.
+    pub fn on_event(mut self, handler: impl Fn(&Event) + Send + Sync + 'static) -> Executor {
+        self.handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Run `plan`, with its events streamed to the handler.
+    ///
+    /// A dry run plan is reported as such whatever the configuration.
+    /// Dry runs and event listeners are set for the whole process, so plans
+    /// run one at a time: a plan run from another thread waits for the
+    /// running one to finish.
+    ///
+    /// # Errors
+    /// Returns the error of the plan's subcommand.
+    pub fn run(&self, plan: &dyn Plan) -> Result<()> {
+        let _running = RUNNING.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut overrides = Overrides::default();
+        if let Some(handler) = &self.handler {
+            let handler = Arc::clone(handler);
+            output::listen(Some(Arc::new(move |event: &Map<String, Value>| {
+                let mut fields = event.clone();
+                let kind = fields.remove("event").and_then(|kind| kind.as_str().map(String::from)).unwrap_or_default();
+                handler(&Event { kind, fields });
+            })));
+            overrides.listening = true;
+        }
+        if plan.is_dry_run() && !dry_run::enabled() {
+            dry_run::force(true);
+            overrides.forced = true;
+        }
+        run_step(&plan.step(), plan.image(), &plan.target().cloned())
+    }
+}
+
+/// Held while a plan runs.
+static RUNNING: Mutex<()> = Mutex::new(());
+
+/// Process-wide settings a running plan changed, restored when it ends,
+/// even by a panic.
+#[derive(Default)]
+struct Overrides {
+    /// Whether the plan forced a dry run.
+    forced: bool,
+    /// Whether the plan's events are listened to.
+    listening: bool,
+}
+
+impl Drop for Overrides {
+    fn drop(&mut self) {
+        if self.forced {
+            dry_run::force(false);
+        }
+        if self.listening {
+            output::listen(None);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! # Security policies for apt-remote
+//!
+//! Policies are opt-in restrictions for environments with strict
+//! requirements, enabled in `config.toml` or with global flags:
+//!
+//! ```toml
+//! require_https = true            # like --require-https
+//! require_strong_hash = "deny"    # off | warn | deny, like --require-strong-hash
+//! require_signature = true        # like --require-signature
+//! bind_host = "deny"              # off | warn (the default) | deny, like --bind-host
+//! ```
+//!
+//! - `require_https`: package and list URIs must use `https://`. `set`
+//!   refuses to record a plan, and `get` and `mirror` refuse to download,
+//!   when any URI uses plain `http://`; the error lists the offending
+//!   sources so the target's `sources.list` entries can be fixed.
+//! - `require_strong_hash`: every package must carry a SHA-256 checksum, so
+//!   that it is verified with SHA-256 when downloaded and again on the
+//!   target. Packages that only have an MD5 checksum, or none, make `set`
+//!   and `get` fail (`deny`) or are listed as a warning (`warn`). Package
+//!   lists are not affected: their integrity is checked by apt on the
+//!   target against the signed `Release` files.
+//! - `require_signature`: `import` refuses bundles, and `install` and
+//!   `update` refuse images, that are not signed (see [`crate::signing`]).
+//!   It is always on once `trusted_keys` lists a key.
+//! - `bind_host`: `install` and `update` compare the machine ID of the
+//!   target with the one `set` recorded in `uri.lock`, so that an image is
+//!   not pushed onto another device that answers at the same address. This
+//!   policy warns by default; set it to `off` for images shared by a fleet.
+//!
+//! A violated policy fails the command with [`crate::Error::PolicyViolation`]
+//! (exit code 8).
+
+use crate::{
+    config,
+    error::Error,
+    lock::{HostIdentity, LockFile},
+    output,
+    ssh::RemoteExecutor,
+    theme, tr,
+    uri::{ChecksumKind, PackageEntry},
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use std::{collections::BTreeSet, path::Path};
+
+/// How a policy that allows a softer mode is enforced.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Enforcement {
+    /// The policy is not checked.
+    #[default]
+    Off,
+    /// Violations are reported, but the command continues.
+    Warn,
+    /// Violations fail the command.
+    Deny,
+}
+
+/// The archive a URI belongs to: everything before its `/pool/` or
+/// `/dists/` component, or the URI itself.
+fn source_of(uri: &str) -> &str {
+    ["/pool/", "/dists/"]
+        .iter()
+        .find_map(|marker| uri.find(marker).map(|i| &uri[..i]))
+        .unwrap_or(uri)
+}
+
+/// Check the `require_https` policy for a set of URIs.
+///
+/// # Errors
+/// Returns [`Error::PolicyViolation`] listing the plain-HTTP sources if the
+/// policy is enabled and any URI is not `https://`.
+pub fn check_https<'a>(uris: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    if !config::get().require_https {
+        return Ok(());
+    }
+    let insecure = uris
+        .into_iter()
+        .filter(|uri| !uri.starts_with("https://"))
+        .map(|uri| source_of(uri).to_string())
+        .collect::<BTreeSet<_>>();
+    if insecure.is_empty() {
+        return Ok(());
+    }
+    Err(Error::PolicyViolation {
+        policy: "require_https",
+        items: insecure.into_iter().collect(),
+    }
+    .into())
+}
+
+/// Check the `require_strong_hash` policy for the packages of an image.
+///
+/// # Arguments
+/// * `packages` - File names and entries of the packages (not package lists).
+///
+/// # Errors
+/// Returns [`Error::PolicyViolation`] listing the packages without a SHA-256
+/// checksum if the policy is set to `deny`.
+pub fn check_strong_hash<'a>(packages: impl IntoIterator<Item = (&'a String, &'a PackageEntry)>) -> Result<()> {
+    let enforcement = config::get().require_strong_hash;
+    if enforcement == Enforcement::Off {
+        return Ok(());
+    }
+    let weak = packages
+        .into_iter()
+        .filter(|(_, pkg)| !pkg.checksum.as_ref().is_some_and(|c| matches!(c.kind, ChecksumKind::SHA256)))
+        .map(|(fname, _)| fname.clone())
+        .collect::<BTreeSet<_>>();
+    if weak.is_empty() {
+        return Ok(());
+    }
+    if enforcement == Enforcement::Warn {
+        output::print(format!(
+            "{} {}",
+            theme::warning("!").bold(),
+            theme::warning(tr!(
+                "{count} packages have no SHA-256 checksum: {files}",
+                count = weak.len(),
+                files = weak.iter().cloned().collect::<Vec<_>>().join(", ")
+            ))
+        ));
+        output::emit("policy", json!({ "policy": "require_strong_hash", "status": "warning", "items": weak }));
+        return Ok(());
+    }
+    Err(Error::PolicyViolation {
+        policy: "require_strong_hash",
+        items: weak.into_iter().collect(),
+    }
+    .into())
+}
+
+/// Check the `bind_host` policy before image `image_dir` is applied to `target`.
+///
+/// Images without a recorded machine, and targets without a machine ID,
+/// are not checked.
+///
+/// # Errors
+/// Returns [`Error::PolicyViolation`] describing the machine if it is not
+/// the one the image was set for and the policy is set to `deny`.
+pub fn check_host(session: &dyn RemoteExecutor, target: &str, image_dir: &Path) -> Result<()> {
+    let enforcement = config::get().bind_host;
+    let lock_path = image_dir.join("uri.lock");
+    if enforcement == Enforcement::Off || !lock_path.is_file() {
+        return Ok(());
+    }
+    let Some(expected) = LockFile::load(&lock_path)?.host else {
+        return Ok(());
+    };
+    let Some(actual) = HostIdentity::query(session)? else {
+        return Ok(());
+    };
+    if actual.machine_id == expected.machine_id {
+        return Ok(());
+    }
+    let item = tr!(
+        "{target} is {actual} (machine-id {actual_id}), not {expected} (machine-id {expected_id})",
+        target = target,
+        actual = actual.hostname,
+        actual_id = actual.machine_id,
+        expected = expected.hostname,
+        expected_id = expected.machine_id
+    );
+    if enforcement == Enforcement::Warn {
+        output::print(format!(
+            "{} {}",
+            theme::warning("!").bold(),
+            theme::warning(tr!("The image was set for another machine: {item}", item = item))
+        ));
+        output::emit("policy", json!({ "policy": "bind_host", "status": "warning", "items": [item] }));
+        return Ok(());
+    }
+    Err(Error::PolicyViolation { policy: "bind_host", items: vec![item] }.into())
+}
+
+/// Check the `require_signature` policy for an unsigned bundle or image.
+///
+/// Listing `trusted_keys` enables the policy: otherwise removing
+/// `manifest.sig` from a bundle would be enough to get it past the check.
+///
+/// # Errors
+/// Returns [`Error::PolicyViolation`] naming `dir` if the policy is enabled.
+pub fn check_signed(dir: &Path) -> Result<()> {
+    let config = config::get();
+    if !config.require_signature && config.trusted_keys.is_empty() {
+        return Ok(());
+    }
+    Err(Error::PolicyViolation {
+        policy: "require_signature",
+        items: vec![dir.display().to_string()],
+    }
+    .into())
+}
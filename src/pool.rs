@@ -0,0 +1,168 @@
+//! # Shared package pool for apt-remote
+//!
+//! This module implements a content-addressed store of `.deb` files in the
+//! `pool/` directory of the cache, shared by every cache image. Objects are
+//! named after their SHA256 digest (`pool/<aa>/<digest>`), and images
+//! reference them through hard links in their `debs/` directory, so a
+//! package needed by several images is only downloaded and stored once.
+//!
+//! Objects that are no longer referenced by any image's `uri.toml` are
+//! removed by `apt-remote gc`. Encrypted images (see [`crate::crypt`]) hold
+//! their own encrypted copies and reference no objects.
+
+use crate::{
+    archive, config, crypt,
+    uri::{self, ChecksumKind, UriFile},
+};
+
+use anyhow::{Context, Result};
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// An object stored in the pool.
+pub struct PoolObject {
+    /// Hex-encoded SHA256 digest of the object.
+    pub digest: String,
+    /// Location of the object on disk.
+    pub path: PathBuf,
+    /// Size of the object in bytes.
+    pub size: u64,
+}
+
+/// Locate the root directory of the pool.
+///
+/// # Errors
+/// Returns an error if the platform cache directory cannot be determined.
+pub fn root() -> Result<PathBuf> {
+    Ok(config::cache_dir()?.join("pool"))
+}
+
+/// Location of the object with the given SHA256 digest.
+///
+/// # Errors
+/// Returns an error if the digest is not a hex string or the cache directory
+/// cannot be determined.
+pub fn object_path(digest: &str) -> Result<PathBuf> {
+    if digest.len() < 2 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid SHA256 digest: {digest}");
+    }
+    let digest = digest.to_lowercase();
+    Ok(root()?.join(&digest[..2]).join(digest))
+}
+
+/// Link the object with the given digest to `dest`, if the pool has it.
+///
+/// Falls back to copying when hard links are not supported (e.g. the cache
+/// spans filesystems). Returns `true` if `dest` was populated from the pool.
+///
+/// # Errors
+/// Returns an error if the object exists but cannot be linked or copied.
+pub fn link_into(digest: &str, dest: &Path) -> Result<bool> {
+    let object = object_path(digest)?;
+    if !object.is_file() {
+        return Ok(false);
+    }
+    link_or_copy(&object, dest)?;
+    Ok(true)
+}
+
+/// Add a downloaded file to the pool under its SHA256 digest.
+///
+/// The file's content is hashed first, so a corrupt download can never
+/// enter the pool under the expected digest.
+///
+/// # Errors
+/// Returns an error if the file does not match `digest` or cannot be stored.
+pub fn insert(digest: &str, file: &Path) -> Result<()> {
+    let object = object_path(digest)?;
+    if object.is_file() {
+        return Ok(());
+    }
+
+    let actual = archive::sha256_file(file)?;
+    if !actual.eq_ignore_ascii_case(digest) {
+        anyhow::bail!("{} does not match expected SHA256 {digest}", file.display());
+    }
+
+    if let Some(parent) = object.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    link_or_copy(file, &object)
+}
+
+/// Collect the SHA256 digests referenced by every unencrypted image in the cache.
+///
+/// # Errors
+/// Returns an error if any image's `uri.toml` cannot be loaded, since
+/// unreadable images could still reference pool objects.
+pub fn referenced() -> Result<HashSet<String>> {
+    let cache_dir = config::cache_dir()?;
+
+    let mut digests = HashSet::new();
+    let Ok(entries) = fs::read_dir(&cache_dir) else {
+        return Ok(digests);
+    };
+
+    for entry in entries {
+        let image_dir = entry?.path();
+        let uri_path = uri::path_in(&image_dir);
+        // Encrypted images keep their own copies, so plaintext ones can go
+        if !uri_path.is_file() || crypt::is_encrypted(&image_dir) {
+            continue;
+        }
+        let uri_file = UriFile::load(&uri_path)
+            .with_context(|| format!("Failed to load {}", uri_path.display()))?;
+        digests.extend(
+            uri_file
+                .packages
+                .values()
+                .filter_map(|pkg| pkg.checksum.as_ref())
+                .filter(|checksum| matches!(checksum.kind, ChecksumKind::SHA256))
+                .map(|checksum| checksum.value.to_lowercase()),
+        );
+    }
+
+    Ok(digests)
+}
+
+/// List every object currently stored in the pool.
+///
+/// # Errors
+/// Returns an error if the pool directory cannot be read.
+pub fn objects() -> Result<Vec<PoolObject>> {
+    let root = root()?;
+    let mut objects = Vec::new();
+    if !root.is_dir() {
+        return Ok(objects);
+    }
+
+    for prefix in fs::read_dir(&root)? {
+        let prefix = prefix?;
+        if !prefix.file_type()?.is_dir() {
+            continue;
+        }
+        for object in fs::read_dir(prefix.path())? {
+            let object = object?;
+            objects.push(PoolObject {
+                digest: object.file_name().to_string_lossy().to_string(),
+                path: object.path(),
+                size: object.metadata()?.len(),
+            });
+        }
+    }
+
+    Ok(objects)
+}
+
+/// Hard link `src` to `dest`, copying instead if linking fails.
+fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)
+            .with_context(|| format!("Failed to copy {} to {}", src.display(), dest.display()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,153 @@
+//! # Launchpad PPAs
+//!
+//! `apt-remote set <NAME> --update --ppa ppa:<owner>/<name>` adds a
+//! Personal Package Archive to an offline Ubuntu machine's lists, as
+//! `add-apt-repository` would on a connected one. The shorthand expands to
+//! the archive at `https://ppa.launchpadcontent.net/<owner>/<name>/ubuntu`,
+//! for the target's release and the `main` component, and its signing key
+//! is fetched from Launchpad and the Ubuntu keyserver on this machine.
+//!
+//! The target's own sources are left as they are: its package lists are
+//! planned as if the PPA were configured there, and `update` (or the
+//! installer of an exported bundle) adds the PPA's key to
+//! `/etc/apt/keyrings` and its source to `/etc/apt/sources.list.d` before
+//! putting the lists in place (see [`crate::uri::AddedSource`]).
+
+use crate::{
+    exit::{Classify, ExitCode},
+    tls,
+    uri::AddedSource,
+};
+
+use anyhow::{Context, Result, anyhow};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+
+use std::{fmt, path::PathBuf, time::Duration};
+
+/// Base URL of the PPA archives.
+pub const ARCHIVE_URL: &str = "https://ppa.launchpadcontent.net";
+
+/// Launchpad's API, which tells the signing key of a PPA.
+const API_URL: &str = "https://api.launchpad.net/1.0";
+
+/// The keyserver PPA keys are published on.
+const KEYSERVER_URL: &str = "https://keyserver.ubuntu.com/pks/lookup";
+
+/// A PPA, given as `ppa:<owner>/<name>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ppa {
+    /// The Launchpad user or team owning the PPA.
+    pub owner: String,
+    /// Name of the PPA (`ppa` when the shorthand names only the owner).
+    pub name: String,
+}
+
+/// The part of Launchpad's description of a PPA that is used.
+#[derive(Deserialize)]
+struct ArchiveInfo {
+    /// Fingerprint of the key the PPA is signed with.
+    signing_key_fingerprint: Option<String>,
+}
+
+/// Parse a `--ppa` value: `ppa:<owner>/<name>`, or `ppa:<owner>` for the
+/// owner's PPA named `ppa`.
+///
+/// ```
+/// use apt_remote::ppa::parse;
+///
+/// let ppa = parse("ppa:deadsnakes/ppa").unwrap();
+/// assert_eq!(ppa.url(), "https://ppa.launchpadcontent.net/deadsnakes/ppa/ubuntu");
+/// assert_eq!(parse("ppa:mozillateam").unwrap().name, "ppa");
+/// assert!(parse("deadsnakes/ppa").is_err());
+/// assert!(parse("ppa:a/b/c").is_err());
+/// ```
+///
+/// # Errors
+/// Returns a message for the CLI if `value` is not such a shorthand.
+pub fn parse(value: &str) -> Result<Ppa, String> {
+    let invalid = || format!("expected ppa:<owner>/<name> (got {value})");
+    let spec = value.strip_prefix("ppa:").ok_or_else(invalid)?;
+    let (owner, name) = spec.split_once('/').unwrap_or((spec, "ppa"));
+    let valid = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || "-.+".contains(c));
+    if !valid(owner) || !valid(name) {
+        return Err(invalid());
+    }
+    Ok(Ppa { owner: owner.to_string(), name: name.to_string() })
+}
+
+impl fmt::Display for Ppa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ppa:{}/{}", self.owner, self.name)
+    }
+}
+
+impl Ppa {
+    /// The archive of the PPA.
+    pub fn url(&self) -> String {
+        format!("{ARCHIVE_URL}/{}/{}/ubuntu", self.owner, self.name)
+    }
+
+    /// The sources line of the PPA for release `codename`, as listed while
+    /// planning (without its key, which the target does not have yet).
+    pub fn plan_line(&self, codename: &str) -> String {
+        format!("deb {} {codename} main", self.url())
+    }
+
+    /// The source `update` adds to targets running release `codename`, with
+    /// the armored signing `key`. Files are named as `add-apt-repository`
+    /// names them.
+    pub fn source(&self, codename: &str, key: &str) -> AddedSource {
+        let keyring = PathBuf::from(format!("/etc/apt/keyrings/{}-ubuntu-{}.asc", self.owner, self.name));
+        AddedSource {
+            file: format!("{}-ubuntu-{}-{codename}.list", self.owner, self.name),
+            line: format!("deb [signed-by={}] {} {codename} main", keyring.display(), self.url()),
+            keyring,
+            key: key.trim().to_string(),
+        }
+    }
+
+    /// Fetch the armored signing key of the PPA from Launchpad and the
+    /// Ubuntu keyserver.
+    ///
+    /// # Errors
+    /// Returns an error if Launchpad has no such PPA or it has no key yet,
+    /// or either service cannot be reached.
+    pub fn fetch_key(&self) -> Result<String> {
+        let client = tls::load()?
+            .blocking(Client::builder().timeout(Duration::from_secs(30)))
+            .build()
+            .context("Failed to build client")?;
+
+        let api = format!("{API_URL}/~{}/+archive/ubuntu/{}", self.owner, self.name);
+        tracing::info!("Looking up the signing key of {self} at {api}");
+        let info = client
+            .get(&api)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .with_context(|| format!("Failed to look up {self} on Launchpad"))
+            .classify(ExitCode::Download)?;
+        let info: ArchiveInfo = serde_json::from_str(&info)
+            .with_context(|| format!("Launchpad's description of {self} is not valid"))
+            .classify(ExitCode::Download)?;
+        let fingerprint = info
+            .signing_key_fingerprint
+            .filter(|fingerprint| !fingerprint.is_empty())
+            .ok_or_else(|| anyhow!("{self} has no signing key yet"))
+            .classify(ExitCode::Download)?;
+
+        let key = client
+            .get(KEYSERVER_URL)
+            .query(&[("op", "get"), ("options", "mr"), ("search", &format!("0x{fingerprint}"))])
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .with_context(|| format!("Failed to fetch key {fingerprint} of {self}"))
+            .classify(ExitCode::Download)?;
+        if !key.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+            return Err(anyhow!("The keyserver returned no key {fingerprint} for {self}")).classify(ExitCode::Download);
+        }
+        Ok(key)
+    }
+}
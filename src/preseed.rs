@@ -0,0 +1,171 @@
+//! # Debconf preseeding
+//!
+//! Packages such as `tzdata` or `mysql-server` ask their questions through
+//! debconf while they are installed, and only take the defaults, or stop,
+//! without an answer. `apt-remote set <NAME> --preseed <FILE>` attaches a
+//! preseed file to the image, in the format of `debconf-set-selections`:
+//!
+//! ```text
+//! # owner  question                type     value
+//! tzdata   tzdata/Areas            select   Europe
+//! tzdata   tzdata/Zones/Europe     select   Berlin
+//! ```
+//!
+//! `install` uploads it, loads it with `debconf-set-selections` as root
+//! before the first package is installed, and checks with `debconf-show`
+//! that every answer was recorded (password answers are not shown, and not
+//! checked). Exported bundles and staged images carry it as `preseed.cfg`,
+//! which their `install.sh` loads the same way. The file is kept in the
+//! cache as given, so do not preseed passwords in images others can read.
+
+use crate::{
+    dry_run,
+    exit::{Classify, ExitCode},
+    ssh::shell_quote,
+    transport::Transport,
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use std::{collections::BTreeSet, fs, path::Path};
+
+/// File of an image or bundle holding its preseed.
+pub const FILE_NAME: &str = "preseed.cfg";
+
+/// One answer of a preseed file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    /// Package owning the question.
+    pub owner: String,
+    /// The question (`tzdata/Areas`).
+    pub question: String,
+    /// Its type (`select`, `string`, `boolean`, `password`, ...).
+    pub kind: String,
+    /// The answer.
+    pub value: String,
+}
+
+/// Parse a preseed file as `debconf-set-selections` reads it: comments,
+/// blank lines and lines continued with `\` are allowed, and the value is
+/// the rest of the line.
+///
+/// ```
+/// use apt_remote::preseed::parse;
+///
+/// let answers = parse("# Berlin\ntzdata tzdata/Areas select Europe\n\ntzdata\ttzdata/Zones/Europe select \\\n Berlin\n").unwrap();
+/// assert_eq!(answers.len(), 2);
+/// assert_eq!(answers[1].question, "tzdata/Zones/Europe");
+/// assert_eq!(answers[1].value, "Berlin");
+/// assert!(parse("tzdata tzdata/Areas\n").is_err());
+/// ```
+///
+/// # Errors
+/// Returns an error naming the first line that is not an answer.
+pub fn parse(content: &str) -> Result<Vec<Answer>> {
+    let mut answers = Vec::new();
+    let mut pending = String::new();
+    for (number, line) in content.lines().enumerate() {
+        if let Some(continued) = line.strip_suffix('\\') {
+            pending.push_str(continued);
+            continue;
+        }
+        pending.push_str(line);
+        let line = std::mem::take(&mut pending);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // The value is the rest of the line after three fields, and may be empty
+        let mut rest = line;
+        let mut field = || {
+            let (field, after) = rest.split_once([' ', '\t']).unwrap_or((rest, ""));
+            rest = after.trim_start();
+            (!field.is_empty()).then(|| field.to_string())
+        };
+        let (Some(owner), Some(question), Some(kind)) = (field(), field(), field()) else {
+            return Err(anyhow!("Line {} of the preseed is not `owner question type value`: {line}", number + 1));
+        };
+        answers.push(Answer { owner, question, kind, value: rest.trim().to_string() });
+    }
+    Ok(answers)
+}
+
+/// Attach the preseed file at `path` to the image in `cache_dir`.
+///
+/// # Errors
+/// Returns an error if the file cannot be read, has no valid answers, or
+/// cannot be copied.
+pub fn attach(cache_dir: &Path, path: &Path) -> Result<()> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))
+        .classify(ExitCode::Config)?;
+    let answers = parse(&content).classify(ExitCode::Config)?;
+    if answers.is_empty() {
+        return Err(anyhow!("{} has no answers", path.display())).classify(ExitCode::Config);
+    }
+    let dest = cache_dir.join(FILE_NAME);
+    if dry_run::skip("write", dest.display().to_string()) {
+        return Ok(());
+    }
+    fs::write(&dest, content).with_context(|| format!("Failed to write {}", dest.display()))
+}
+
+/// Upload the preseed of the image in `cache_dir`, if it has one, to
+/// `remote_dir` on the target and load it into debconf as root. Returns
+/// whether the image has a preseed.
+///
+/// # Errors
+/// Returns an error if the preseed cannot be uploaded or loaded, or the
+/// target's debconf does not record one of its answers.
+pub fn apply(session: &dyn Transport, password: &str, cache_dir: &Path, remote_dir: &Path) -> Result<bool> {
+    let local = cache_dir.join(FILE_NAME);
+    if !local.is_file() {
+        return Ok(false);
+    }
+    let answers = parse(&fs::read_to_string(&local)?).classify(ExitCode::Cache)?;
+    let remote = remote_dir.join(FILE_NAME);
+    session.upload(&local, &remote)?;
+    let remote = shell_quote(&remote.display().to_string());
+    session.sudo(&format!("debconf-set-selections {remote}"), password)?;
+
+    // Answers are read back from the owners' debconf databases
+    let owners = answers.iter().map(|answer| answer.owner.as_str()).collect::<BTreeSet<_>>();
+    let shown = session.sudo(
+        &format!("debconf-show {}", owners.iter().map(|owner| shell_quote(owner)).collect::<Vec<_>>().join(" ")),
+        password,
+    )?;
+    session.exec(&format!("rm -f {remote}"))?;
+    if dry_run::enabled() {
+        return Ok(true);
+    }
+    let missing = unrecorded(&answers, &shown);
+    if !missing.is_empty() {
+        return Err(anyhow!("debconf did not record the preseeded answers to {}", missing.join(", ")))
+            .classify(ExitCode::Install);
+    }
+    Ok(true)
+}
+
+/// The questions of `answers` whose answer `debconf-show` does not show,
+/// given its output. Password answers are not shown, and not checked.
+///
+/// ```
+/// use apt_remote::preseed::{parse, unrecorded};
+///
+/// let answers = parse("tzdata tzdata/Areas select Europe\ntzdata tzdata/Zones/Europe select Berlin\n").unwrap();
+/// let shown = "* tzdata/Areas: Europe\n  tzdata/Zones/Europe: Paris\n";
+/// assert_eq!(unrecorded(&answers, shown), ["tzdata/Zones/Europe"]);
+/// ```
+pub fn unrecorded(answers: &[Answer], shown: &str) -> Vec<String> {
+    // "* question: value", the star marking questions already asked
+    let recorded = shown
+        .lines()
+        .filter_map(|line| line.trim_start_matches(['*', ' ']).split_once(':'))
+        .collect::<Vec<_>>();
+    answers
+        .iter()
+        .filter(|answer| answer.kind != "password")
+        .filter(|answer| !recorded.iter().any(|(question, value)| *question == answer.question && value.trim() == answer.value))
+        .map(|answer| answer.question.clone())
+        .collect()
+}
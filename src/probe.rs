@@ -0,0 +1,240 @@
+//! # Probing targets
+//!
+//! What apt-remote needs to know about a target (its user, APT and `dpkg`
+//! versions, APT's directories and architectures, the tools it has, free
+//! space, init system, release codename and derivative distribution) is
+//! gathered by one composite
+//! query (see [`crate::batch`]) rather than by each command on its own. The
+//! result is cached per target in `<cache>/.state/targets/<target>.toml` and reused
+//! for [`MAX_AGE`]:
+//!
+//! ```toml
+//! user = "admin"
+//! apt_version = "2.6.1"
+//! dpkg_version = "1.21.22"
+//! init = "systemd"
+//! codename = "bookworm"
+//! derivative = "raspios"
+//! keyrings = ["raspberrypi-archive-keyring"]
+//! tools = ["sha256sum", "md5sum", "python3", "sudo", "gzip", "xz"]
+//! probed_at = 1760457600
+//!
+//! [apt]
+//! lists = "/var/lib/apt/lists"
+//! archives = "/var/cache/apt/archives"
+//! arch = "amd64"
+//! architectures = ["amd64"]
+//!
+//! [free_kib]
+//! "/tmp" = 10485760
+//! ```
+//!
+//! `apt-remote doctor` probes a target again and reports the result, and
+//! `apt-remote status` shows the cached probe of every target an image was
+//! used on. Free space is only informative: `install` checks it again right
+//! before uploading.
+
+use crate::{
+    apt_config::{self, AptConfig},
+    batch::{Batch, Sections},
+    checksum, config, derivative::{self, Derivative}, dry_run,
+    ssh::RemoteExecutor,
+    state,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+};
+
+/// Seconds a cached probe is used for before the target is probed again.
+pub const MAX_AGE: u64 = 24 * 60 * 60;
+
+/// Tools looked for besides the checksum tools (see [`checksum::CANDIDATES`]).
+const TOOLS: [&str; 7] = ["sudo", "doas", "gzip", "xz", "zstd", "df", "systemctl"];
+
+/// Directories whose free space is reported.
+const SPACE_PATHS: [&str; 2] = ["/tmp", "/var/cache/apt/archives"];
+
+/// What is known about a target.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Probe {
+    /// The remote user.
+    pub user: String,
+    /// Version of APT (empty if `apt-get` is missing).
+    pub apt_version: String,
+    /// Version of `dpkg` (empty if it is missing).
+    pub dpkg_version: String,
+    /// Name of the process running as PID 1 (`systemd`, `init`, ...).
+    pub init: String,
+    /// Release codename from `/etc/os-release` (`bookworm`, `noble`, ...).
+    pub codename: String,
+    /// Derivative distribution, if the target runs a known one (see [`crate::derivative`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub derivative: Option<Derivative>,
+    /// Keyring packages of known derivatives installed on the target.
+    pub keyrings: Vec<String>,
+    /// Tools found on the target's `PATH`.
+    pub tools: Vec<String>,
+    /// Unix time of the probe.
+    pub probed_at: u64,
+    /// APT's directories and architectures.
+    pub apt: AptConfig,
+    /// Free space in KiB, by directory.
+    pub free_kib: BTreeMap<String, u64>,
+}
+
+impl Probe {
+    /// Probe the target in a single round trip.
+    ///
+    /// # Errors
+    /// Returns an error if the target cannot be queried.
+    pub fn run(session: &dyn RemoteExecutor) -> Result<Probe> {
+        let mut batch = Batch::new();
+        Probe::add_to(&mut batch);
+        Ok(Probe::from_sections(&batch.run(session)?))
+    }
+
+    /// Add the probe's commands to `batch`, to probe the target in the same
+    /// round trip as other queries.
+    pub fn add_to(batch: &mut Batch) {
+        let tools = checksum::CANDIDATES.iter().chain(&TOOLS);
+        batch
+            .add("user", "id -un 2>/dev/null || whoami")
+            .add("apt", "apt-get --version 2>/dev/null | head -n 1")
+            .add("dpkg", "dpkg-query --showformat='${Version}' --show dpkg 2>/dev/null")
+            .add("init", "cat /proc/1/comm 2>/dev/null")
+            .add("os", "cat /etc/os-release 2>/dev/null")
+            .add("vendor", derivative::QUERY)
+            .add("aptconfig", apt_config::QUERY)
+            .add("arch", "dpkg --print-architecture 2>/dev/null")
+            .add("foreign", "dpkg --print-foreign-architectures 2>/dev/null")
+            .add(
+                "tools",
+                tools.map(|tool| format!("command -v {tool} >/dev/null 2>&1 && echo {tool}")).collect::<Vec<_>>().join("; "),
+            )
+            .add("df", format!("df -Pk {} 2>/dev/null", SPACE_PATHS.join(" ")));
+    }
+
+    /// Interpret the output of the commands added by [`Probe::add_to`].
+    pub fn from_sections(sections: &Sections) -> Probe {
+        let section = |name: &str| sections.get(name).trim();
+
+        let apt = AptConfig::parse(section("aptconfig")).complete(section("arch"), section("foreign"));
+        let codename = section("os")
+            .lines()
+            .find_map(|line| line.strip_prefix("VERSION_CODENAME="))
+            .unwrap_or_default()
+            .trim_matches('"')
+            .to_string();
+
+        // `df -P` lines read "<fs> <blocks> <used> <available> <capacity> <mount>", in the order asked
+        let free_kib = section("df")
+            .lines()
+            .skip(1)
+            .zip(SPACE_PATHS)
+            .filter_map(|(line, path)| Some((path.to_string(), line.split_whitespace().nth(3)?.parse().ok()?)))
+            .collect();
+
+        Probe {
+            user: section("user").to_string(),
+            // "apt 2.6.1 (amd64)"
+            apt_version: section("apt").split_whitespace().nth(1).unwrap_or_default().to_string(),
+            dpkg_version: section("dpkg").to_string(),
+            init: section("init").to_string(),
+            codename,
+            derivative: Derivative::detect(section("os"), section("vendor")),
+            keyrings: derivative::installed_keyrings(section("vendor")),
+            tools: section("tools").lines().map(str::trim).map(str::to_string).collect(),
+            probed_at: state::now(),
+            apt,
+            free_kib,
+        }
+    }
+
+    /// Whether the target has `tool` on its `PATH`.
+    pub fn has(&self, tool: &str) -> bool {
+        self.tools.iter().any(|t| t == tool)
+    }
+
+    /// The keyring of the target's derivative distribution, if it needs one
+    /// and it is not installed.
+    pub fn missing_keyring(&self) -> Option<&'static str> {
+        self.derivative?.keyring().filter(|keyring| !self.keyrings.iter().any(|k| k == keyring))
+    }
+
+    /// The checksum tools of the target.
+    pub fn checksum_tools(&self) -> checksum::Tools {
+        checksum::Tools::from_available(&self.tools)
+    }
+
+    /// The cached probe of `target`, if there is one.
+    ///
+    /// # Errors
+    /// Returns an error if an existing cached probe cannot be read or parsed.
+    pub fn cached(target: &str) -> Result<Option<Probe>> {
+        let path = path(target)?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let probe = toml::from_str(&content).with_context(|| format!("Failed to parse TOML from {}", path.display()))?;
+        Ok(Some(probe))
+    }
+
+    /// Cache the probe of `target`.
+    ///
+    /// # Errors
+    /// Returns an error if the probe cannot be written.
+    pub fn save(&self, target: &str) -> Result<()> {
+        // Nothing is written in a dry run
+        if dry_run::enabled() {
+            return Ok(());
+        }
+        let path = path(target)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        let toml_str = toml::to_string(self).context("Failed to serialize Probe to TOML")?;
+        fs::write(&path, toml_str).with_context(|| format!("Failed to write to {}", path.display()))
+    }
+}
+
+/// The probe of `target`: the cached one if it is recent enough, or a new one.
+///
+/// # Errors
+/// Returns an error if the target cannot be queried, or the probe cannot be
+/// cached.
+pub fn get(session: &dyn RemoteExecutor, target: &str) -> Result<Probe> {
+    // A cached probe that cannot be read is simply replaced
+    if let Ok(Some(probe)) = Probe::cached(target)
+        && state::now().saturating_sub(probe.probed_at) < MAX_AGE
+    {
+        return Ok(probe);
+    }
+    refresh(session, target)
+}
+
+/// Probe `target` again and cache the result.
+///
+/// # Errors
+/// Returns an error if the target cannot be queried, or the probe cannot be
+/// cached.
+pub fn refresh(session: &dyn RemoteExecutor, target: &str) -> Result<Probe> {
+    let probe = Probe::run(session)?;
+    tracing::debug!(target, ?probe, "Probed target");
+    probe.save(target)?;
+    Ok(probe)
+}
+
+/// Location of the cached probe of `target`.
+fn path(target: &str) -> Result<PathBuf> {
+    let file = target
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "@.-_".contains(c) { c } else { '_' })
+        .collect::<String>();
+    Ok(config::state_dir()?.join("targets").join(format!("{file}.toml")))
+}
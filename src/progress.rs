@@ -0,0 +1,264 @@
+//! # Progress display helpers for apt-remote
+//!
+//! Commands report progress through a [`ProgressReporter`], which hands out
+//! [`Progress`] handles for bars (counting the steps of a phase), tasks (one
+//! item within a phase) and standalone spinners. The built-in reporters
+//! follow the configured [`ProgressMode`]:
+//!
+//! - `fancy` draws animated `indicatif` bars and spinners;
+//! - `plain` writes each status message as its own line on stderr, which
+//!   stays readable in cron mails and CI logs;
+//! - `none` shows no progress at all.
+//!
+//! The default, `auto`, picks `fancy` when stdout and stderr are terminals
+//! and `plain` otherwise. Progress is always off in quiet and JSON mode.
+//!
+//! Runs on an `@group` target shown on the fleet dashboard (see
+//! [`crate::dashboard`]) report progress in the dashboard's rows instead.
+//!
+//! Library users can route progress elsewhere (a GUI, a log, a web
+//! socket) by installing their own reporter with [`set_reporter`].
+
+use crate::{config, dashboard, output, redact, theme};
+
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    io::IsTerminal,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+/// Reporter installed with [`set_reporter`], replacing the built-in ones.
+static REPORTER: OnceLock<Arc<dyn ProgressReporter>> = OnceLock::new();
+
+/// How progress is displayed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressMode {
+    /// `fancy` on an interactive terminal, `plain` otherwise.
+    #[default]
+    Auto,
+    /// Animated progress bars and spinners.
+    Fancy,
+    /// One line per status message.
+    Plain,
+    /// No progress output.
+    None,
+}
+
+/// The progress mode in effect, with `auto` resolved.
+pub fn mode() -> ProgressMode {
+    let config = config::get();
+    if config.quiet || output::is_json() {
+        return ProgressMode::None;
+    }
+    match config.progress {
+        ProgressMode::Auto if std::io::stdout().is_terminal() && std::io::stderr().is_terminal() => {
+            ProgressMode::Fancy
+        }
+        ProgressMode::Auto => ProgressMode::Plain,
+        mode => mode,
+    }
+}
+
+/// Source of progress displays for a command.
+pub trait ProgressReporter: Send + Sync {
+    /// A bar counting the `len` steps of a phase.
+    fn bar(&self, len: u64) -> Arc<dyn Progress>;
+
+    /// A spinner for one item within a phase (shown below its bar).
+    fn task(&self) -> Arc<dyn Progress>;
+
+    /// A standalone spinner for a step without a known length.
+    fn spinner(&self) -> Arc<dyn Progress>;
+
+    /// A bar for one file of `len` bytes being transferred within a phase,
+    /// advanced by bytes (see [`bytes`]). Reporters without one show a task.
+    fn transfer(&self, _len: u64) -> Arc<dyn Progress> {
+        self.task()
+    }
+}
+
+/// A single bar or spinner handed out by a [`ProgressReporter`].
+pub trait Progress: Send + Sync {
+    /// Describe what is currently being done.
+    fn message(&self, msg: &str);
+
+    /// Advance a bar by `delta` steps.
+    fn inc(&self, delta: u64);
+
+    /// Stop, leaving a final message in place.
+    fn finish(&self, msg: &str);
+
+    /// Stop and remove the display.
+    fn clear(&self);
+}
+
+/// A callback for [`crate::transport::Transport::upload_with_progress`]
+/// advancing `progress` by the bytes sent. An upload started again after a
+/// lost connection is not counted twice.
+pub fn bytes(progress: &dyn Progress) -> impl Fn(u64) + '_ {
+    let reported = std::cell::Cell::new(0);
+    move |sent| {
+        if sent > reported.get() {
+            progress.inc(sent - reported.get());
+            reported.set(sent);
+        }
+    }
+}
+
+/// Install a reporter used by every following command instead of the
+/// built-in ones. Only the first call has an effect.
+pub fn set_reporter(reporter: Arc<dyn ProgressReporter>) {
+    REPORTER.set(reporter).ok();
+}
+
+/// The reporter for a new command: the installed one, or the built-in
+/// reporter for the current [`mode`].
+pub fn reporter() -> Arc<dyn ProgressReporter> {
+    if let Some(reporter) = REPORTER.get() {
+        return Arc::clone(reporter);
+    }
+    if let Some(reporter) = dashboard::reporter() {
+        return reporter;
+    }
+    match mode() {
+        ProgressMode::Fancy => Arc::new(Bars { multi: MultiProgress::new() }),
+        ProgressMode::Plain => Arc::new(Lines),
+        ProgressMode::Auto | ProgressMode::None => Arc::new(Silent),
+    }
+}
+
+/// Animated `indicatif` bars and spinners (`fancy` mode).
+struct Bars {
+    /// Container keeping the bars of a command together.
+    multi: MultiProgress,
+}
+
+impl Bars {
+    /// Add a bar or spinner with the given template and tick rate.
+    fn add(&self, bar: ProgressBar, style: ProgressStyle, tick: u64) -> Arc<dyn Progress> {
+        let bar = self.multi.add(bar);
+        bar.set_style(style);
+        bar.enable_steady_tick(Duration::from_millis(tick));
+        Arc::new(Bar(bar))
+    }
+}
+
+impl ProgressReporter for Bars {
+    fn bar(&self, len: u64) -> Arc<dyn Progress> {
+        let style = ProgressStyle::default_bar()
+            .template(&theme::template(
+                "[{elapsed_precise}] {msg:25} [{wide_bar:.bold.accent}] {pos}/{len} ({eta} remaining)",
+            ))
+            .unwrap()
+            .progress_chars("##-");
+        self.add(ProgressBar::new(len), style, 100)
+    }
+
+    fn task(&self) -> Arc<dyn Progress> {
+        let style = ProgressStyle::with_template(&theme::template("\t{spinner:.bold.accent} {msg}"))
+            .unwrap()
+            .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ");
+        self.add(ProgressBar::new_spinner(), style, 80)
+    }
+
+    fn spinner(&self) -> Arc<dyn Progress> {
+        let style = ProgressStyle::with_template(&theme::template("{spinner:.accent} {msg}"))
+            .unwrap()
+            .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏");
+        self.add(ProgressBar::new_spinner(), style, 200)
+    }
+
+    fn transfer(&self, len: u64) -> Arc<dyn Progress> {
+        let style = ProgressStyle::with_template(&theme::template(
+            "\t{spinner:.bold.accent} {msg:25} [{bar:20.accent}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+        ))
+        .unwrap()
+        .progress_chars("##-")
+        .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ");
+        self.add(ProgressBar::new(len), style, 80)
+    }
+}
+
+/// A single `indicatif` bar or spinner.
+struct Bar(ProgressBar);
+
+impl Progress for Bar {
+    fn message(&self, msg: &str) {
+        self.0.set_message(redact::redact(msg).into_owned());
+    }
+
+    fn inc(&self, delta: u64) {
+        self.0.inc(delta);
+    }
+
+    fn finish(&self, msg: &str) {
+        self.0.finish_with_message(redact::redact(msg).into_owned());
+    }
+
+    fn clear(&self) {
+        self.0.finish_and_clear();
+    }
+}
+
+/// One line on stderr per status message (`plain` mode).
+struct Lines;
+
+impl ProgressReporter for Lines {
+    fn bar(&self, _len: u64) -> Arc<dyn Progress> {
+        Arc::new(Lines)
+    }
+
+    fn task(&self) -> Arc<dyn Progress> {
+        Arc::new(Lines)
+    }
+
+    fn spinner(&self) -> Arc<dyn Progress> {
+        Arc::new(Lines)
+    }
+}
+
+impl Progress for Lines {
+    fn message(&self, msg: &str) {
+        eprintln!("{}", redact::redact(msg.trim_start()));
+    }
+
+    fn inc(&self, _delta: u64) {}
+
+    fn finish(&self, msg: &str) {
+        eprintln!("{}", redact::redact(msg.trim_start()));
+    }
+
+    fn clear(&self) {}
+}
+
+/// No progress output (`none` mode, quiet and JSON output).
+struct Silent;
+
+impl ProgressReporter for Silent {
+    fn bar(&self, _len: u64) -> Arc<dyn Progress> {
+        Arc::new(Silent)
+    }
+
+    fn task(&self) -> Arc<dyn Progress> {
+        Arc::new(Silent)
+    }
+
+    fn spinner(&self) -> Arc<dyn Progress> {
+        Arc::new(Silent)
+    }
+}
+
+impl Progress for Silent {
+    fn message(&self, _msg: &str) {}
+
+    fn inc(&self, _delta: u64) {}
+
+    fn finish(&self, _msg: &str) {}
+
+    fn clear(&self) {}
+}
@@ -0,0 +1,152 @@
+//! # Surviving dropped SSH connections
+//!
+//! Devices on flaky links lose their connection in the middle of long
+//! installs. Connecting is tried `connect_attempts` times (see
+//! [`crate::ssh::create_ssh_session`]), and SSH targets are reached through
+//! a [`ReconnectingSession`]: when a command or upload fails and the
+//! connection turns out to be gone, a new session is established and the
+//! step runs again, up to `reconnect_attempts` times per command:
+//!
+//! ```toml
+//! [ssh]
+//! connect_attempts = 3
+//! reconnect_attempts = 3
+//! ```
+//!
+//! A step that failed on a live connection fails as it is: only lost
+//! connections are retried. Steps are run again from their start, so an
+//! upload is sent again, and a command that was cut off runs again; the
+//! steps of `install` and `update` can be repeated (`dpkg` finishes what an
+//! interrupted run left configured halfway). The SSH client is blocking:
+//! concurrent uploads are threads sharing the session, not asynchronous
+//! I/O.
+
+use crate::{
+    config, output,
+    ssh::{RemoteExecutor, SecureUpload, SudoCheck, renew_session, shared_session},
+    theme,
+    transport::Transport,
+};
+
+use anyhow::Result;
+use colored::Colorize;
+use serde_json::json;
+use ssh2::Session;
+
+use std::{
+    path::Path,
+    sync::{Mutex, mpsc},
+    thread,
+    time::Duration,
+};
+
+/// How long a liveness check of a session may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An SSH session that is re-established when its connection is lost.
+///
+/// Concurrent uploads (see [`Transport::upload_jobs`]) run on threads
+/// sharing it: the SSH client blocks, so each thread waits for its own
+/// channel. When several fail on the same lost connection, the first to
+/// take the lock checks it and reconnects, and the others run their step
+/// again on the new session.
+pub struct ReconnectingSession {
+    /// The `user@host` target, to connect to again.
+    target: String,
+    /// The current session, and how many times it was renewed.
+    session: Mutex<(Session, u32)>,
+}
+
+impl ReconnectingSession {
+    /// Connect to `target` (`user@host`), or reuse the session already
+    /// established with it (see [`shared_session`]).
+    ///
+    /// # Errors
+    /// Returns an error if the session cannot be established (see
+    /// [`crate::ssh::create_ssh_session`]).
+    pub fn connect(target: &str) -> Result<ReconnectingSession> {
+        Ok(ReconnectingSession { target: target.to_string(), session: Mutex::new((shared_session(target)?, 0)) })
+    }
+
+    /// Run `step` on the session, reconnecting and running it again if it
+    /// fails because the connection was lost.
+    fn retry<T>(&self, step: impl Fn(&Session) -> Result<T>) -> Result<T> {
+        let attempts = config::get().ssh.reconnect_attempts;
+        let mut reconnects = 0;
+        loop {
+            let (session, generation) = self.session.lock().unwrap().clone();
+            let error = match step(&session) {
+                Ok(value) => return Ok(value),
+                Err(e) if reconnects < attempts => e,
+                Err(e) => return Err(e),
+            };
+            reconnects += 1;
+            // Held until the session is renewed, so that it is only renewed once
+            let mut current = self.session.lock().unwrap();
+            if current.1 != generation {
+                // Another step already reconnected
+                continue;
+            }
+            if alive(&session) {
+                return Err(error);
+            }
+            let message = format!("Lost the connection to {}, reconnecting ({reconnects}/{attempts})", self.target);
+            output::print(format!("{} {}", theme::warning("!").bold(), theme::warning(&message)));
+            output::emit("reconnect", json!({ "target": self.target, "attempt": reconnects, "error": format!("{error:#}") }));
+            *current = (renew_session(&self.target)?, generation + 1);
+        }
+    }
+}
+
+/// Whether the connection of `session` still carries commands.
+///
+/// The check runs on a thread of its own, so that the timeout of the
+/// session, which other steps are using, stays as it is. A check that does
+/// not finish in time is left to fail with the connection.
+fn alive(session: &Session) -> bool {
+    let (sender, receiver) = mpsc::channel();
+    let session = session.clone();
+    thread::spawn(move || {
+        let alive = session
+            .channel_session()
+            .and_then(|mut channel| {
+                channel.exec("true")?;
+                channel.wait_close()
+            })
+            .is_ok();
+        let _ = sender.send(alive);
+    });
+    receiver.recv_timeout(PROBE_TIMEOUT).unwrap_or(false)
+}
+
+impl RemoteExecutor for ReconnectingSession {
+    fn exec(&self, cmd: &str) -> Result<String> {
+        self.retry(|session| session.exec(cmd))
+    }
+
+    fn query(&self, cmd: &str) -> Result<String> {
+        self.retry(|session| session.query(cmd))
+    }
+
+    fn sudo(&self, cmd: &str, password: &str) -> Result<String> {
+        self.retry(|session| session.sudo(cmd, password))
+    }
+
+    fn sudo_check(&self, password: &str) -> Result<SudoCheck> {
+        self.retry(|session| session.sudo_check(password))
+    }
+
+    fn supports_su(&self) -> bool {
+        true
+    }
+}
+
+impl Transport for ReconnectingSession {
+    fn upload(&self, local_path: &Path, remote_path: &Path) -> Result<()> {
+        self.retry(|session| session.scp_upload(local_path, remote_path))
+    }
+
+    fn upload_with_progress(&self, local_path: &Path, remote_path: &Path, sent: &dyn Fn(u64)) -> Result<()> {
+        self.retry(|session| session.scp_upload_with_progress(local_path, remote_path, sent))
+    }
+}
@@ -0,0 +1,160 @@
+//! # Mirror redirectors for apt-remote
+//!
+//! Sources such as `deb.debian.org` are redirectors: they answer each
+//! request with a redirect to one of many mirrors, not always the same one.
+//! `get` follows redirects itself instead of leaving them to the HTTP
+//! client, so that:
+//!
+//! - a chain is followed for at most [`MAX_REDIRECTS`] hops, and a loop is
+//!   reported as one;
+//! - a redirect to plain HTTP is refused under the `require_https` policy;
+//! - the mirror a redirector chose is remembered for the rest of the run
+//!   (see [`Mirrors`]), and later files of the same source are requested
+//!   from it directly;
+//! - a failure at a mirror (an error status, or a connection error) is
+//!   retried once through the redirector, which may choose another mirror.
+//!
+//! Every request of a chain carries the credentials its own URL has in
+//! [`crate::apt_auth`], so they are not sent on to another host.
+
+use crate::{apt_auth::AuthFile, config};
+
+use anyhow::{Result, anyhow};
+use reqwest::{Client, Response, Url, header::LOCATION};
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+/// Longest redirect chain followed for one request.
+pub const MAX_REDIRECTS: usize = 10;
+
+/// Mirrors that redirectors sent requests to during a run.
+#[derive(Debug, Default)]
+pub struct Mirrors {
+    /// Mirror URL prefix by redirector URL prefix, such as
+    /// `http://deb.debian.org/` → `http://ftp.de.debian.org/`.
+    resolved: Mutex<HashMap<String, String>>,
+}
+
+impl Mirrors {
+    /// `uri` on the mirror an earlier request of its source was sent to.
+    fn rewrite(&self, uri: &str) -> Option<String> {
+        let resolved = self.resolved.lock().unwrap();
+        resolved
+            .iter()
+            .find(|(from, _)| uri.starts_with(from.as_str()))
+            .map(|(from, to)| format!("{to}{}", &uri[from.len()..]))
+    }
+
+    /// Remember that `uri` was redirected to `url`.
+    ///
+    /// The path segments they end with are taken to be the same file on both
+    /// hosts, and what precedes them the redirector's and the mirror's roots.
+    fn remember(&self, uri: &Url, url: &Url) {
+        let (from, to) = (uri.path().split('/').collect::<Vec<_>>(), url.path().split('/').collect::<Vec<_>>());
+        // Keep the leading slash in the prefixes, so they end on a segment boundary
+        let shared = from
+            .iter()
+            .rev()
+            .zip(to.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(from.len().min(to.len()) - 1);
+        if shared == 0 || uri.query().is_some() || url.query().is_some() {
+            return;
+        }
+        let suffix = from[from.len() - shared..].join("/");
+        if let (Some(from), Some(to)) = (uri.as_str().strip_suffix(&suffix), url.as_str().strip_suffix(&suffix))
+            && from != to
+        {
+            tracing::debug!(from, to, "remembering mirror");
+            self.resolved.lock().unwrap().insert(from.to_string(), to.to_string());
+        }
+    }
+
+    /// Stop sending requests for `uri`'s source to its mirror.
+    fn forget(&self, uri: &str) {
+        self.resolved.lock().unwrap().retain(|from, _| !uri.starts_with(from.as_str()));
+    }
+}
+
+/// Request `uri`, following redirects, from the mirror remembered for its
+/// source if there is one.
+///
+/// Returns the final response, which may have an error status.
+///
+/// # Errors
+/// Returns an error if `uri` is not a URL, the request fails, or its
+/// redirects loop, are too many or are refused.
+pub async fn get(client: &Client, mirrors: &Mirrors, auth: &AuthFile, uri: &str) -> Result<Response> {
+    let original = Url::parse(uri)?;
+
+    // Go straight to the mirror chosen earlier in the run
+    if let Some(mirrored) = mirrors.rewrite(uri) {
+        let (url, result) = follow(client, auth, Url::parse(&mirrored)?).await;
+        match result {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => tracing::info!("{url} answered {}, asking the redirector again", response.status()),
+            Err(e) => tracing::info!("{url} failed ({e}), asking the redirector again"),
+        }
+        mirrors.forget(uri);
+    }
+
+    // A redirector may choose another mirror when asked again
+    for attempt in 1.. {
+        let (url, result) = follow(client, auth, original.clone()).await;
+        let redirected = url != original;
+        match result {
+            Ok(response) if response.status().is_success() => {
+                if redirected {
+                    mirrors.remember(&original, &url);
+                }
+                return Ok(response);
+            }
+            Ok(response) if redirected && attempt < 2 => {
+                tracing::info!("{url} answered {}, asking the redirector again", response.status());
+            }
+            Err(e) if redirected && attempt < 2 => tracing::info!("{url} failed ({e}), asking the redirector again"),
+            result => return result,
+        }
+    }
+    unreachable!()
+}
+
+/// Request `url`, following at most [`MAX_REDIRECTS`] redirects.
+///
+/// Returns the last URL requested, with its response or the reason the
+/// chain was abandoned.
+async fn follow(client: &Client, auth: &AuthFile, mut url: Url) -> (Url, Result<Response>) {
+    let mut visited = HashSet::new();
+    loop {
+        // Credentials go to the URLs they are for, wherever the chain leads
+        let response = match auth.authorize(client.get(url.clone()), url.as_str()).send().await {
+            Ok(response) => response,
+            Err(e) => return (url, Err(e.into())),
+        };
+        let location = response.headers().get(LOCATION).and_then(|l| l.to_str().ok()).map(str::to_string);
+        let Some(location) = location.filter(|_| response.status().is_redirection()) else {
+            return (url, Ok(response));
+        };
+
+        let next = match url.join(&location) {
+            Ok(next) => next,
+            Err(e) => return (url, Err(anyhow!("invalid redirect to {location}: {e}"))),
+        };
+        visited.insert(url.clone());
+        if visited.contains(&next) {
+            return (next.clone(), Err(anyhow!("redirect loop at {next}")));
+        }
+        if visited.len() > MAX_REDIRECTS {
+            return (url, Err(anyhow!("more than {MAX_REDIRECTS} redirects")));
+        }
+        if config::get().require_https && next.scheme() != "https" {
+            return (next.clone(), Err(anyhow!("redirect to plain HTTP refused by require_https: {next}")));
+        }
+        tracing::debug!(from = %url, to = %next, "redirect");
+        url = next;
+    }
+}
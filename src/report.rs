@@ -0,0 +1,267 @@
+//! # Per-host run reports
+//!
+//! Every target a command runs on is recorded with the phases it went through
+//! (see [`crate::summary::phase`]), how long each took, and its result. At the
+//! end of a run on an `@group` target the hosts are shown as a matrix:
+//!
+//! ```text
+//! @kiosks:
+//!                 prepare   upload   verify   install   cleanup   result
+//!     kiosk-01    ·         ✓ 2.1s   ✓ 0.3s   ✓ 12.0s   ✓ 0.1s    ok
+//!     kiosk-02    ·         ✓ 2.0s   ✗ 0.2s   ·         ·         failed: Checksum mismatch for curl_7.88.1-10_amd64.deb
+//!     kiosk-03    ✗         ·        ·        ·         ·         failed: Failed to connect to kiosk-03:22
+//! ```
+//!
+//! A failed host failed in the last phase it went through, or before its
+//! first one: in `prepare`, which covers connecting, probing and confirming.
+//!
+//! With `--report <FILE>` the results are also written to a file for the
+//! records, as JSON, or as CSV if its name ends in `.csv`, with one row per
+//! host and phase and a `result` row per host:
+//!
+//! ```text
+//! target,group,phase,status,elapsed_ms,error
+//! kiosk-01,kiosks,upload,ok,2104,
+//! kiosk-01,kiosks,result,ok,14502,
+//! kiosk-02,kiosks,verify,failed,204,Checksum mismatch for curl_7.88.1-10_amd64.deb
+//! ```
+//!
+//! The JSON report also names the command, image, target, profile, host name
+//! and outcome of the run, like notifications do (see [`crate::notify`]).
+
+use crate::{config, dry_run, hooks::HookContext, output, redact, state, summary, theme};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde_json::{Value, json};
+
+use std::{
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Results of the targets of this run, in the order they finished.
+static RESULTS: Mutex<Vec<HostResult>> = Mutex::new(Vec::new());
+
+/// Name of the pseudo-phase of failures before a target's first phase.
+pub const PREPARE: &str = "prepare";
+
+/// Widest result shown in the matrix, in characters.
+const RESULT_WIDTH: usize = 80;
+
+/// What became of one target.
+#[derive(Debug, Clone)]
+pub struct HostResult {
+    /// The target.
+    pub target: String,
+    /// The group the target was run as a member of.
+    pub group: Option<String>,
+    /// When the target started, in seconds since the Unix epoch.
+    pub started_at: u64,
+    /// How long the target took.
+    pub elapsed: Duration,
+    /// The phases the target went through, in order.
+    pub phases: Vec<PhaseResult>,
+    /// The error the target failed with.
+    pub error: Option<String>,
+}
+
+/// One phase of a target.
+#[derive(Debug, Clone)]
+pub struct PhaseResult {
+    /// Name of the phase, such as `upload`.
+    pub name: &'static str,
+    /// How long the phase took.
+    pub elapsed: Duration,
+    /// Whether the target failed in this phase.
+    pub failed: bool,
+}
+
+impl HostResult {
+    /// Whether the target succeeded.
+    pub fn ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The result as the fields of a report entry.
+    fn to_json(&self) -> Value {
+        json!({
+            "target": self.target,
+            "group": self.group,
+            "status": if self.ok() { "ok" } else { "failed" },
+            "error": self.error,
+            "started_at": self.started_at,
+            "elapsed_ms": self.elapsed.as_millis() as u64,
+            "phases": self
+                .phases
+                .iter()
+                .map(|phase| json!({
+                    "name": phase.name,
+                    "status": if phase.failed { "failed" } else { "ok" },
+                    "elapsed_ms": phase.elapsed.as_millis() as u64,
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Run `f` for `target`, a member of `group` if any, and record its result.
+///
+/// Returns the outcome of `f`, and the result recorded for it.
+pub fn track(group: Option<&str>, target: &str, f: impl FnOnce() -> Result<()>) -> (Result<()>, HostResult) {
+    let known = summary::phases().len();
+    let started_at = state::now();
+    let start = Instant::now();
+    let outcome = f();
+
+    let mut phases = summary::phases()
+        .into_iter()
+        .skip(known)
+        .map(|(name, elapsed)| PhaseResult { name, elapsed, failed: false })
+        .collect::<Vec<_>>();
+    if outcome.is_err() {
+        match phases.last_mut() {
+            Some(last) => last.failed = true,
+            None => phases.push(PhaseResult { name: PREPARE, elapsed: start.elapsed(), failed: true }),
+        }
+    }
+    let result = HostResult {
+        target: target.to_string(),
+        group: group.map(str::to_string),
+        started_at,
+        elapsed: start.elapsed(),
+        phases,
+        error: outcome.as_ref().err().map(|e| redact::redact(&format!("{e:#}")).into_owned()),
+    };
+    if let Ok(mut results) = RESULTS.lock() {
+        results.push(result.clone());
+    }
+    (outcome, result)
+}
+
+/// Every result recorded so far.
+pub fn results() -> Vec<HostResult> {
+    RESULTS.lock().map(|results| results.clone()).unwrap_or_default()
+}
+
+/// Print the results of the members of `group` as a matrix of hosts and
+/// phases.
+pub fn matrix(group: &str, results: &[HostResult]) {
+    let mut phases = Vec::new();
+    for phase in results.iter().flat_map(|result| &result.phases) {
+        if !phases.contains(&phase.name) {
+            phases.push(phase.name);
+        }
+    }
+    // Failures before the first phase come first
+    phases.sort_by_key(|&name| name != PREPARE);
+
+    let cell = |result: &HostResult, name: &str| match result.phases.iter().find(|phase| phase.name == name) {
+        Some(phase) if phase.name == PREPARE => "✗".to_string(),
+        Some(phase) => format!("{} {:.1}s", if phase.failed { "✗" } else { "✓" }, phase.elapsed.as_secs_f64()),
+        None => "·".to_string(),
+    };
+    let name_width = results.iter().map(|result| result.target.chars().count()).max().unwrap_or_default();
+    let widths = phases
+        .iter()
+        .map(|name| results.iter().map(|result| cell(result, name).chars().count()).max().unwrap_or_default().max(name.len()))
+        .collect::<Vec<_>>();
+
+    output::print(format!("\n{}", format!("@{group}:").bold()));
+    let mut header = format!("\t{:name_width$}", "");
+    for (name, width) in phases.iter().zip(&widths) {
+        header.push_str(&format!("   {name:width$}"));
+    }
+    output::print(format!("{}", format!("{header}   result").dimmed()));
+    for result in results {
+        let mut line = format!("\t{:name_width$}", result.target);
+        for (name, width) in phases.iter().zip(&widths) {
+            let text = format!("{:width$}", cell(result, name));
+            let text = match text.chars().next() {
+                Some('✓') => theme::success(text),
+                Some('✗') => theme::failure(text),
+                _ => text.dimmed(),
+            };
+            line.push_str(&format!("   {text}"));
+        }
+        let outcome = match &result.error {
+            None => theme::success("ok"),
+            Some(error) => {
+                let error = error.lines().next().unwrap_or_default();
+                theme::failure(console::truncate_str(&format!("failed: {error}"), RESULT_WIDTH, "…"))
+            }
+        };
+        output::print(format!("{line}   {outcome}"));
+    }
+}
+
+/// Write the results of the run of `ctx` to `path`, as CSV if its name ends
+/// in `.csv` and as JSON otherwise.
+///
+/// # Errors
+/// Returns an error if the file cannot be written.
+pub fn write(path: &Path, ctx: &HookContext, outcome: &Result<()>) -> Result<()> {
+    let results = results();
+    let content = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) {
+        csv(&results)
+    } else {
+        let report = json!({
+            "command": ctx.command,
+            "image": ctx.image,
+            "target": ctx.target,
+            "profile": config::get().profile,
+            "host": fs::read_to_string("/proc/sys/kernel/hostname").ok().map(|host| host.trim().to_string()),
+            "dry_run": dry_run::enabled(),
+            "finished_at": state::now(),
+            "outcome": if outcome.is_ok() { "success" } else { "failure" },
+            "error": outcome.as_ref().err().map(|e| format!("{e:#}")),
+            "hosts": results.iter().map(HostResult::to_json).collect::<Vec<_>>(),
+        });
+        format!("{:#}\n", redact::value(report))
+    };
+    fs::write(path, content).with_context(|| format!("Failed to write the report {}", path.display()))
+}
+
+/// The results as CSV, one row per target and phase and a `result` row per
+/// target.
+fn csv(results: &[HostResult]) -> String {
+    let mut content = String::from("target,group,phase,status,elapsed_ms,error\n");
+    for result in results {
+        let group = result.group.as_deref().unwrap_or_default();
+        let rows = result.phases.iter().map(|phase| {
+            let error = if phase.failed { result.error.as_deref() } else { None };
+            (phase.name, !phase.failed, phase.elapsed, error)
+        });
+        for (phase, ok, elapsed, error) in rows.chain([("result", result.ok(), result.elapsed, result.error.as_deref())]) {
+            let fields = [
+                result.target.as_str(),
+                group,
+                phase,
+                if ok { "ok" } else { "failed" },
+                &elapsed.as_millis().to_string(),
+                error.unwrap_or_default(),
+            ];
+            content.push_str(&fields.map(csv_field).join(","));
+            content.push('\n');
+        }
+    }
+    content
+}
+
+/// `field` quoted for CSV if it needs to be.
+///
+/// ```
+/// use apt_remote::report::csv_field;
+///
+/// assert_eq!(csv_field("kiosk-01"), "kiosk-01");
+/// assert_eq!(csv_field("Failed: \"curl\", 2 files"), "\"Failed: \"\"curl\"\", 2 files\"");
+/// ```
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
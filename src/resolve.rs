@@ -0,0 +1,390 @@
+//! # Resolving plans from package indices
+//!
+//! `apt-remote set --distro` plans an image for a machine that cannot be
+//! queried, such as a device that is not set up yet, from the `Packages`
+//! indices of its suite (see [`crate::commands::mirror`]) instead of the
+//! target's `apt-get`.
+//!
+//! The resolver is deliberately simpler than APT's:
+//!
+//! - the target is assumed to have the base system debootstrap installs,
+//!   so `Essential: yes` and `Priority: required` packages are only planned
+//!   when asked for by name;
+//! - `Pre-Depends` and `Depends` are followed, `Recommends` and `Suggests`
+//!   are not (as with `--no-install-recommends`);
+//! - of alternatives (`a | b`), the first one already planned or available
+//!   is taken, and of the packages providing a virtual package, the first by
+//!   name;
+//! - `Conflicts` and `Breaks` are not checked.
+
+use std::{cmp::Ordering, collections::HashMap};
+
+/// A binary package listed in a `Packages` index.
+#[derive(Debug, Clone)]
+pub struct Package {
+    /// `Package` field.
+    pub name: String,
+    /// `Version` field, including any epoch.
+    pub version: String,
+    /// `Architecture` field.
+    pub architecture: String,
+    /// Path of the `.deb` relative to the archive root (`Filename` field).
+    pub filename: String,
+    /// Size of the `.deb` in bytes.
+    pub size: u64,
+    /// Hex-encoded SHA256 of the `.deb`, if listed.
+    pub sha256: Option<String>,
+    /// Space the package takes once installed, in bytes, if listed.
+    pub installed_size: Option<u64>,
+    /// Whether the package is part of the base system.
+    pub base: bool,
+    /// Dependencies (`Pre-Depends`, then `Depends`), each a list of alternatives.
+    pub depends: Vec<Vec<Relation>>,
+    /// Virtual packages provided, with the provided version if any.
+    pub provides: Vec<(String, Option<String>)>,
+    /// Archive root the package is downloaded from.
+    pub archive: String,
+    /// Component of the index the package was listed in.
+    pub component: String,
+}
+
+impl Package {
+    /// The name apt gives the package's file in its archive directory.
+    pub fn file_name(&self) -> String {
+        crate::uri::archive_file_name(&format!("{}_{}_{}.deb", self.name, self.version, self.architecture))
+    }
+
+    /// The URI of the package's `.deb`.
+    pub fn uri(&self) -> String {
+        format!("{}/{}", self.archive.trim_end_matches('/'), self.filename)
+    }
+}
+
+/// One alternative of a dependency, such as `libc6 (>= 2.34)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relation {
+    /// Package name, without any architecture qualifier.
+    pub name: String,
+    /// Version constraint: an operator (`<<`, `<=`, `=`, `>=`, `>>`) and a version.
+    pub constraint: Option<(String, String)>,
+}
+
+impl Relation {
+    /// Parse one alternative of a dependency field.
+    ///
+    /// ```
+    /// use apt_remote::resolve::Relation;
+    ///
+    /// let relation = Relation::parse("python3:any (>= 3.11~)").unwrap();
+    /// assert_eq!(relation.name, "python3");
+    /// assert_eq!(relation.constraint, Some((">=".to_string(), "3.11~".to_string())));
+    /// ```
+    pub fn parse(text: &str) -> Option<Relation> {
+        // Anything after the constraint, or after the name without one, is an
+        // architecture restriction ("[amd64]") or build profile ("<!nocheck>")
+        let (name, constraint) = match text.split_once('(') {
+            Some((name, rest)) => (name, rest.split(')').next().map(str::trim)),
+            None => (text.split(['[', '<']).next()?, None),
+        };
+        let name = name.split(':').next()?.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let constraint = constraint.map(|constraint| {
+            let split = constraint.find(|c: char| !"<=>".contains(c)).unwrap_or(constraint.len());
+            let (op, version) = constraint.split_at(split);
+            // "<" and ">" are obsolete spellings of "<=" and ">="
+            let op = match op {
+                "<" => "<=",
+                ">" => ">=",
+                op => op,
+            };
+            (op.to_string(), version.trim().to_string())
+        });
+        Some(Relation { name: name.to_string(), constraint })
+    }
+
+    /// Whether `version` satisfies the relation's constraint.
+    pub fn allows(&self, version: &str) -> bool {
+        let Some((op, wanted)) = &self.constraint else { return true };
+        let ordering = compare_versions(version, wanted);
+        match op.as_str() {
+            "<<" => ordering == Ordering::Less,
+            "<=" => ordering != Ordering::Greater,
+            "=" => ordering == Ordering::Equal,
+            ">=" => ordering != Ordering::Less,
+            ">>" => ordering == Ordering::Greater,
+            _ => false,
+        }
+    }
+}
+
+/// The packages of one or more `Packages` indices, by name.
+#[derive(Debug, Default)]
+pub struct Index {
+    packages: HashMap<String, Vec<Package>>,
+    providers: HashMap<String, Vec<(String, Option<String>)>>,
+}
+
+impl Index {
+    /// An empty index.
+    pub fn new() -> Index {
+        Index::default()
+    }
+
+    /// Add the packages of a `Packages` index of `component`, downloaded
+    /// from the archive rooted at `archive`.
+    pub fn add(&mut self, content: &str, archive: &str, component: &str) {
+        for paragraph in content.split("\n\n") {
+            let mut fields = HashMap::new();
+            for line in paragraph.lines() {
+                // Continuation lines only appear in fields that are not read here
+                if line.starts_with([' ', '\t']) {
+                    continue;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    fields.insert(name, value.trim());
+                }
+            }
+            let (Some(name), Some(version), Some(filename)) =
+                (fields.get("Package"), fields.get("Version"), fields.get("Filename"))
+            else {
+                continue;
+            };
+            let field = |name: &str| fields.get(name).copied().unwrap_or_default();
+            let depends = [field("Pre-Depends"), field("Depends")]
+                .iter()
+                .flat_map(|field| field.split(','))
+                .map(|group| group.split('|').filter_map(Relation::parse).collect::<Vec<_>>())
+                .filter(|group| !group.is_empty())
+                .collect();
+            let provides = field("Provides")
+                .split(',')
+                .filter_map(Relation::parse)
+                .map(|relation| (relation.name, relation.constraint.map(|(_, version)| version)))
+                .collect::<Vec<_>>();
+
+            let package = Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                architecture: field("Architecture").to_string(),
+                filename: filename.to_string(),
+                size: field("Size").parse().unwrap_or(0),
+                sha256: fields.get("SHA256").map(|sha256| sha256.to_string()),
+                installed_size: field("Installed-Size").parse::<u64>().ok().map(|kib| kib * 1024),
+                base: field("Essential") == "yes" || field("Priority") == "required",
+                depends,
+                provides: provides.clone(),
+                archive: archive.to_string(),
+                component: component.to_string(),
+            };
+            for (virtual_name, version) in provides {
+                self.providers.entry(virtual_name).or_default().push((package.name.clone(), version));
+            }
+            self.packages.entry(package.name.clone()).or_default().push(package);
+        }
+    }
+
+    /// Number of packages in the index.
+    pub fn len(&self) -> usize {
+        self.packages.len()
+    }
+
+    /// Whether the index has no packages.
+    pub fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+    }
+
+    /// The newest version of `name` satisfying `relation`, if any.
+    fn candidate(&self, name: &str, relation: &Relation) -> Option<&Package> {
+        self.packages
+            .get(name)?
+            .iter()
+            .filter(|package| relation.allows(&package.version))
+            .max_by(|a, b| compare_versions(&a.version, &b.version))
+    }
+
+    /// The packages to install for `requested` (names, optionally as
+    /// `name=version`), dependencies first.
+    ///
+    /// ```
+    /// use apt_remote::resolve::Index;
+    ///
+    /// let mut index = Index::new();
+    /// index.add(
+    ///     "Package: curl\nVersion: 7.88.1-10\nArchitecture: arm64\nDepends: libcurl4 (= 7.88.1-10), libc6 (>= 2.34)\nFilename: pool/main/c/curl/curl_7.88.1-10_arm64.deb\nSize: 315000\n\n\
+    ///      Package: libcurl4\nVersion: 7.88.1-10\nArchitecture: arm64\nFilename: pool/main/c/curl/libcurl4_7.88.1-10_arm64.deb\nSize: 370000\n\n\
+    ///      Package: libc6\nVersion: 2.36-9\nArchitecture: arm64\nPriority: required\nFilename: pool/main/g/glibc/libc6_2.36-9_arm64.deb\nSize: 2500000\n",
+    ///     "http://deb.debian.org/debian",
+    ///     "main",
+    /// );
+    /// let plan = index.resolve(&["curl".to_string()]).unwrap();
+    /// assert_eq!(plan.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), ["libcurl4", "curl"]);
+    /// assert!(index.resolve(&["wget".to_string()]).is_err());
+    /// ```
+    ///
+    /// # Errors
+    /// Returns the problems found if a requested package is unknown or a
+    /// dependency cannot be satisfied.
+    pub fn resolve(&self, requested: &[String]) -> Result<Vec<&Package>, Vec<String>> {
+        let mut resolution = Resolution { index: self, planned: HashMap::new(), order: Vec::new(), errors: Vec::new() };
+        for request in requested {
+            let relation = match request.split_once('=') {
+                Some((name, version)) => Relation { name: name.to_string(), constraint: Some(("=".to_string(), version.to_string())) },
+                None => Relation { name: request.clone(), constraint: None },
+            };
+            match self.candidate(&relation.name, &relation) {
+                Some(package) => resolution.plan(package),
+                None if relation.constraint.is_some() => resolution.errors.push(format!("Version '{}' for '{}' was not found", request.split_once('=').unwrap().1, relation.name)),
+                None => resolution.errors.push(format!("Unable to locate package {request}")),
+            }
+        }
+        match resolution.errors.is_empty() {
+            true => Ok(resolution.order),
+            false => Err(resolution.errors),
+        }
+    }
+}
+
+/// State of [`Index::resolve`].
+struct Resolution<'a> {
+    index: &'a Index,
+    planned: HashMap<&'a str, &'a Package>,
+    order: Vec<&'a Package>,
+    errors: Vec<String>,
+}
+
+impl<'a> Resolution<'a> {
+    /// Plan `package`, after its dependencies.
+    fn plan(&mut self, package: &'a Package) {
+        // Planned before its dependencies, so dependency cycles end here
+        if self.planned.insert(&package.name, package).is_some() {
+            return;
+        }
+        for group in &package.depends {
+            if group.iter().any(|relation| self.satisfied(relation)) {
+                continue;
+            }
+            match group.iter().find_map(|relation| self.available(relation)) {
+                Some(dependency) => self.plan(dependency),
+                None => {
+                    let wanted = group.iter().map(describe).collect::<Vec<_>>().join(" | ");
+                    self.errors.push(format!("{} depends on {wanted}, but it is not installable", package.name));
+                }
+            }
+        }
+        self.order.push(package);
+    }
+
+    /// Whether `relation` is met by a planned or base package.
+    fn satisfied(&self, relation: &Relation) -> bool {
+        let planned = |name: &str| self.planned.get(name).is_some_and(|package| relation.allows(&package.version));
+        let base = |name: &str| self.index.candidate(name, relation).is_some_and(|package| package.base);
+        planned(&relation.name)
+            || base(&relation.name)
+            || self.providers(relation).any(|name| self.planned.contains_key(name) || base(name))
+    }
+
+    /// The package to plan for `relation`: the real package, or else the
+    /// first provider by name.
+    fn available(&self, relation: &Relation) -> Option<&'a Package> {
+        if let Some(package) = self.index.candidate(&relation.name, relation) {
+            return Some(package);
+        }
+        let mut providers = self.providers(relation).collect::<Vec<_>>();
+        providers.sort();
+        providers
+            .into_iter()
+            .find_map(|name| self.index.candidate(name, &Relation { name: name.to_string(), constraint: None }))
+    }
+
+    /// Names of the packages providing `relation`: any provider of an
+    /// unversioned relation, and those providing a suitable version otherwise.
+    fn providers<'r>(&'r self, relation: &'r Relation) -> impl Iterator<Item = &'a str> + 'r {
+        let index = self.index;
+        index.providers.get(&relation.name).into_iter().flatten().filter_map(move |(name, version)| {
+            let allowed = match version {
+                Some(version) => relation.allows(version),
+                None => relation.constraint.is_none(),
+            };
+            allowed.then_some(name.as_str())
+        })
+    }
+}
+
+/// A relation as written in a dependency field.
+fn describe(relation: &Relation) -> String {
+    match &relation.constraint {
+        Some((op, version)) => format!("{} ({op} {version})", relation.name),
+        None => relation.name.clone(),
+    }
+}
+
+/// Compare two Debian package versions as `dpkg --compare-versions` does.
+///
+/// ```
+/// use apt_remote::resolve::compare_versions;
+/// use std::cmp::Ordering;
+///
+/// assert_eq!(compare_versions("1:1.0", "2.0"), Ordering::Greater);
+/// assert_eq!(compare_versions("1.0~rc1", "1.0"), Ordering::Less);
+/// assert_eq!(compare_versions("2.36-9+deb12u4", "2.36-9"), Ordering::Greater);
+/// assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+/// assert_eq!(compare_versions("0:1.0-1", "1.0-1"), Ordering::Equal);
+/// ```
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (epoch_a, upstream_a, revision_a) = split_version(a);
+    let (epoch_b, upstream_b, revision_b) = split_version(b);
+    epoch_a
+        .cmp(&epoch_b)
+        .then_with(|| compare_part(upstream_a, upstream_b))
+        .then_with(|| compare_part(revision_a, revision_b))
+}
+
+/// Split a version into epoch, upstream version and Debian revision.
+fn split_version(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+        None => (0, version),
+    };
+    match rest.rsplit_once('-') {
+        Some((upstream, revision)) => (epoch, upstream, revision),
+        None => (epoch, rest, ""),
+    }
+}
+
+/// Compare upstream versions or revisions: alternately the non-digit parts,
+/// character by character, and the digit parts, numerically.
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    while !a.is_empty() || !b.is_empty() {
+        // '~' sorts before anything, even the end of the part, and letters before other characters
+        let order = |c: Option<&u8>| match c {
+            Some(b'~') => -1,
+            None => 0,
+            Some(c) if c.is_ascii_digit() => 0,
+            Some(c) if c.is_ascii_alphabetic() => i32::from(*c),
+            Some(c) => i32::from(*c) + 256,
+        };
+        while a.first().is_some_and(|c| !c.is_ascii_digit()) || b.first().is_some_and(|c| !c.is_ascii_digit()) {
+            let ordering = order(a.first()).cmp(&order(b.first()));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            a = &a[1.min(a.len())..];
+            b = &b[1.min(b.len())..];
+        }
+
+        let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+        let (digits_a, digits_b) = (digits(a), digits(b));
+        let number = |s: &[u8]| s.iter().skip_while(|&&c| c == b'0').copied().collect::<Vec<_>>();
+        let (number_a, number_b) = (number(&a[..digits_a]), number(&b[..digits_b]));
+        let ordering = number_a.len().cmp(&number_b.len()).then_with(|| number_a.cmp(&number_b));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        a = &a[digits_a..];
+        b = &b[digits_b..];
+    }
+    Ordering::Equal
+}
@@ -0,0 +1,350 @@
+//! # Credentials for apt-remote
+//!
+//! The SSH password (when neither a key nor the agent is accepted), the
+//! sudo password, the passphrase of an encrypted SSH private key and the
+//! passphrase of encrypted images are asked for on the terminal by
+//! default. For unattended runs, each can be provided instead, in this
+//! order:
+//!
+//! | Credential     | File option (or config key)                     | Environment variable        |
+//! |----------------|-------------------------------------------------|-----------------------------|
+//! | SSH password   | `--ssh-password-file` (`ssh_password_file`)     | `APT_REMOTE_SSH_PASSWORD`   |
+//! | sudo password  | `--sudo-password-file` (`sudo_password_file`)   | `APT_REMOTE_SUDO_PASSWORD`  |
+//! | Key passphrase | `--key-passphrase-file` (`key_passphrase_file`) | `APT_REMOTE_KEY_PASSPHRASE` |
+//! | Passphrase     | `--passphrase-file` (`passphrase_file`)         | `APT_REMOTE_PASSPHRASE`     |
+//!
+//! Only the first line of a file is used; a file named `-` is read from
+//! stdin (one line per credential, in the order they are needed).
+//!
+//! Credentials that are neither in a file nor in the environment can come
+//! from a helper program, as with `SUDO_ASKPASS`: `--askpass <PROGRAM>`
+//! (`askpass`, or `APT_REMOTE_ASKPASS`) is run with the prompt as its
+//! argument and `APT_REMOTE_CREDENTIAL` set to `ssh_password`,
+//! `sudo_password`, `key_passphrase` or `passphrase`, and the first line it
+//! prints is the credential. With
+//! `--batch` (`batch = true`) nothing is ever prompted for: a credential
+//! that is not provided fails the command with
+//! [`crate::Error::CredentialRequired`], and confirmations are answered
+//! with "yes".
+//!
+//! Every credential obtained is registered with [`crate::redact`], so it is
+//! masked wherever it would appear in output or logs.
+//!
+//! The sudo password is checked with `sudo -v` before it is used (see
+//! [`sudo_password`]), so a wrong password is reported as such rather than
+//! as a failing command.
+
+use crate::{
+    config, dashboard,
+    error::Error,
+    exit::{Classify, ExitCode},
+    output, redact,
+    ssh::{Escalation, RemoteExecutor, SudoCheck, escalation_settings},
+    tr,
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Mutex, OnceLock},
+};
+
+/// A secret apt-remote may need during a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Credential {
+    /// Password of the SSH user on the target.
+    SshPassword,
+    /// Password for `sudo` on the target.
+    SudoPassword,
+    /// Passphrase of an encrypted SSH private key.
+    KeyPassphrase,
+    /// Passphrase of an encrypted image (see [`crate::crypt`]).
+    Passphrase,
+}
+
+/// Credentials already read from stdin, since it can only be read once.
+static FROM_STDIN: OnceLock<Mutex<HashMap<Credential, String>>> = OnceLock::new();
+
+/// Passphrases that decrypted SSH private keys, by key file.
+static KEY_PASSPHRASES: OnceLock<Mutex<HashMap<PathBuf, String>>> = OnceLock::new();
+
+impl Credential {
+    /// Human-readable name of the credential.
+    pub fn label(self) -> String {
+        match self {
+            Credential::SshPassword => tr!("SSH password"),
+            Credential::SudoPassword => tr!("sudo password"),
+            Credential::KeyPassphrase => tr!("key passphrase"),
+            Credential::Passphrase => tr!("image passphrase"),
+        }
+    }
+
+    /// Command-line option naming a file with the credential.
+    pub fn flag(self) -> &'static str {
+        match self {
+            Credential::SshPassword => "--ssh-password-file",
+            Credential::SudoPassword => "--sudo-password-file",
+            Credential::KeyPassphrase => "--key-passphrase-file",
+            Credential::Passphrase => "--passphrase-file",
+        }
+    }
+
+    /// Environment variable holding the credential.
+    pub fn env_var(self) -> &'static str {
+        match self {
+            Credential::SshPassword => "APT_REMOTE_SSH_PASSWORD",
+            Credential::SudoPassword => "APT_REMOTE_SUDO_PASSWORD",
+            Credential::KeyPassphrase => "APT_REMOTE_KEY_PASSPHRASE",
+            Credential::Passphrase => "APT_REMOTE_PASSPHRASE",
+        }
+    }
+
+    /// Name of the credential given to askpass helpers.
+    pub fn name(self) -> &'static str {
+        match self {
+            Credential::SshPassword => "ssh_password",
+            Credential::SudoPassword => "sudo_password",
+            Credential::KeyPassphrase => "key_passphrase",
+            Credential::Passphrase => "passphrase",
+        }
+    }
+
+    /// Configured file holding the credential.
+    fn file(self) -> Option<PathBuf> {
+        let config = config::get();
+        match self {
+            Credential::SshPassword => config.ssh_password_file.clone(),
+            Credential::SudoPassword => config.sudo_password_file.clone(),
+            Credential::KeyPassphrase => config.key_passphrase_file.clone(),
+            Credential::Passphrase => config.passphrase_file.clone(),
+        }
+    }
+}
+
+/// The credential from its file, environment variable or askpass helper,
+/// if provided.
+///
+/// # Errors
+/// Returns an error if the configured file cannot be read, or the askpass
+/// helper fails.
+pub fn provided(credential: Credential) -> Result<Option<String>> {
+    let secret = match credential.file() {
+        Some(path) => Some(read_file(credential, &path)?),
+        None => match std::env::var(credential.env_var()).ok().filter(|value| !value.is_empty()) {
+            Some(secret) => Some(secret),
+            None => askpass(credential)?,
+        },
+    };
+    if let Some(secret) = &secret {
+        redact::register(secret);
+    }
+    Ok(secret)
+}
+
+/// The credential from its file or environment variable, or else asked for
+/// on the terminal with `prompt`.
+///
+/// # Errors
+/// Returns [`Error::CredentialRequired`] if the credential is not provided
+/// and cannot be prompted for (with `--batch`, or without a terminal).
+pub fn obtain(credential: Credential, prompt: &str) -> Result<String> {
+    match provided(credential)? {
+        Some(secret) => Ok(secret),
+        None => ask(credential, prompt),
+    }
+}
+
+/// Number of times a mistyped sudo password is asked for again.
+const SUDO_ATTEMPTS: usize = 3;
+
+/// The sudo password of `user` on `target`, checked against `sudo` there.
+///
+/// Returns an empty password if `sudo` needs none. A prompted password is
+/// asked for again when `sudo` rejects it, like `sudo` itself does.
+///
+/// Privileged commands become root as configured (see [`Escalation`]); if
+/// the user may not use `sudo` and no tool is configured, targets that
+/// support it fall back to `doas` where it is installed, then to `su -` to
+/// root, and the password asked for is root's.
+///
+/// # Errors
+/// Returns [`Error::SudoAuth`] if `sudo` rejects a provided password, or
+/// every prompted attempt, [`Error::SudoDenied`] if the user may not use
+/// `sudo` at all, and [`Error::CredentialRequired`] if a password is needed
+/// but can be neither found nor asked for.
+pub fn sudo_password(session: &dyn RemoteExecutor, user: &str, target: &str) -> Result<String> {
+    // Whether sudo took the password, with refusals of the user reported as such
+    let accepts = |password: &str| match session.sudo_check(password)? {
+        SudoCheck::Accepted => Ok(true),
+        SudoCheck::WrongPassword => Ok(false),
+        SudoCheck::Denied(reason) => Err(anyhow::Error::from(Error::SudoDenied { target: target.to_string(), reason })),
+    };
+    let mut escalation = if session.supports_su() { Escalation::configured(target) } else { Escalation::Sudo };
+    let detect = session.supports_su() && escalation_settings(target).1.is_none();
+    loop {
+        escalation.clone().set();
+        match accepts("") {
+            Ok(true) => return Ok(String::new()),
+            Ok(false) => break,
+            Err(e) if detect && let Some(fallback) = fallback(session, &escalation) => {
+                tracing::info!("{e}, switching to root with {}", fallback.tool());
+                escalation = fallback;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let escalation = Escalation::current();
+    let user = escalation.account(user);
+    let rejected = || Error::SudoAuth { target: target.to_string() };
+    if let Some(password) = provided(Credential::SudoPassword)? {
+        return if accepts(&password)? { Ok(password) } else { Err(rejected().into()) };
+    }
+    for attempt in 1..=SUDO_ATTEMPTS {
+        let password = ask(Credential::SudoPassword, &tr!("[sudo] password for {user}: ", user = user))?;
+        if accepts(&password)? {
+            return Ok(password);
+        }
+        if attempt < SUDO_ATTEMPTS {
+            output::print(tr!("Sorry, try again."));
+        }
+    }
+    Err(rejected().into())
+}
+
+/// How privileged commands become root on `session` when `escalation` is
+/// refused: `doas` if it is installed, then `su -` to root.
+fn fallback(session: &dyn RemoteExecutor, escalation: &Escalation) -> Option<Escalation> {
+    match escalation {
+        Escalation::Sudo if session.query("command -v doas").is_ok_and(|path| !path.trim().is_empty()) => {
+            Some(Escalation::Doas)
+        }
+        Escalation::Sudo | Escalation::Doas => Some(Escalation::Su("root".to_string())),
+        Escalation::Su(_) => None,
+    }
+}
+
+/// Number of times a mistyped key passphrase is asked for again.
+const KEY_ATTEMPTS: usize = 3;
+
+/// The passphrase of the encrypted SSH private key `identity`, checked with
+/// `decrypts`.
+///
+/// A passphrase that decrypted the key is kept for the rest of the run, so
+/// it is asked for once however many targets the key is used for. A
+/// prompted passphrase is asked for again when it does not decrypt the key,
+/// like `ssh` does.
+///
+/// # Errors
+/// Returns [`Error::WrongKeyPassphrase`] if a provided passphrase, or every
+/// prompted attempt, does not decrypt the key, and
+/// [`Error::CredentialRequired`] if the passphrase can be neither found nor
+/// asked for.
+pub fn key_passphrase(identity: &Path, decrypts: impl Fn(&str) -> Result<bool>) -> Result<String> {
+    let known = KEY_PASSPHRASES.get_or_init(Default::default);
+    if let Some(passphrase) = known.lock().unwrap().get(identity) {
+        return Ok(passphrase.clone());
+    }
+    let decrypted = |passphrase: String| {
+        known.lock().unwrap().insert(identity.to_path_buf(), passphrase.clone());
+        Ok(passphrase)
+    };
+    let rejected = || Error::WrongKeyPassphrase { identity: identity.to_path_buf() };
+    if let Some(passphrase) = provided(Credential::KeyPassphrase)? {
+        return if decrypts(&passphrase)? { decrypted(passphrase) } else { Err(rejected().into()) };
+    }
+    for attempt in 1..=KEY_ATTEMPTS {
+        let prompt = tr!("Enter passphrase for key {path}: ", path = identity.display());
+        let passphrase = ask(Credential::KeyPassphrase, &prompt)?;
+        if decrypts(&passphrase)? {
+            return decrypted(passphrase);
+        }
+        if attempt < KEY_ATTEMPTS {
+            output::print(tr!("Sorry, try again."));
+        }
+    }
+    Err(rejected().into())
+}
+
+/// Ask for the credential on the terminal.
+///
+/// # Errors
+/// Returns [`Error::CredentialRequired`] in batch mode, or if there is no
+/// terminal to ask on.
+pub fn ask(credential: Credential, prompt: &str) -> Result<String> {
+    if config::get().batch {
+        return Err(Error::CredentialRequired { credential }.into());
+    }
+    let _paused = dashboard::pause();
+    let secret = rpassword::prompt_password(prompt).map_err(|_| Error::CredentialRequired { credential })?;
+    redact::register(&secret);
+    Ok(secret)
+}
+
+/// Environment variable naming the askpass helper.
+pub const ASKPASS_VAR: &str = "APT_REMOTE_ASKPASS";
+
+/// The credential printed by the configured askpass helper, if there is one.
+fn askpass(credential: Credential) -> Result<Option<String>> {
+    let Some(program) = config::get().askpass.clone().or_else(|| std::env::var_os(ASKPASS_VAR).map(PathBuf::from)) else {
+        return Ok(None);
+    };
+    let output = Command::new(&program)
+        .arg(format!("{}: ", credential.label()))
+        .env("APT_REMOTE_CREDENTIAL", credential.name())
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run the askpass helper {}", program.display()))
+        .classify(ExitCode::Auth)?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "The askpass helper {} gave no {} ({})",
+            program.display(),
+            credential.label(),
+            output.status
+        ))
+        .classify(ExitCode::Auth);
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(Some(stdout.lines().next().unwrap_or_default().to_string()))
+}
+
+/// Read the first line of a credential file, or of stdin for `-`.
+fn read_file(credential: Credential, path: &Path) -> Result<String> {
+    if path == Path::new("-") {
+        let mut read = FROM_STDIN.get_or_init(Default::default).lock().unwrap();
+        if let Some(secret) = read.get(&credential) {
+            return Ok(secret.clone());
+        }
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).classify(ExitCode::Auth)?;
+        let secret = line.trim_end_matches(['\r', '\n']).to_string();
+        read.insert(credential, secret.clone());
+        return Ok(secret);
+    }
+
+    warn_if_readable(path);
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))
+        .classify(ExitCode::Config)?;
+    Ok(content.lines().next().unwrap_or_default().to_string())
+}
+
+/// Warn about credential files other users can read.
+#[cfg(unix)]
+fn warn_if_readable(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(metadata) = fs::metadata(path)
+        && metadata.permissions().mode() & 0o077 != 0
+    {
+        tracing::warn!("{} is accessible by other users (chmod 600 it)", path.display());
+    }
+}
+
+/// Warn about credential files other users can read.
+#[cfg(not(unix))]
+fn warn_if_readable(_path: &Path) {}
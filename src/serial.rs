@@ -0,0 +1,494 @@
+//! # Serial console transport
+//!
+//! Some air-gapped controllers have no network at all, only a serial
+//! console. The target `serial:/dev/ttyUSB0` reaches such a system through
+//! the shell on its console, with options after a `?`:
+//!
+//! ```text
+//! serial:/dev/ttyUSB0                      # a shell is open at 115200 baud
+//! serial:/dev/ttyS1?baud=9600&user=root    # log in as root first if asked
+//! ```
+//!
+//! The line is configured with `stty` (8 data bits, no parity, raw mode)
+//! and locked for the duration of the command, so two runs never talk over
+//! each other. Each command is framed by markers the shell prints before
+//! and after it, with its exit status; the markers are built when the
+//! command runs, so a console that echoes its input cannot fake them. Its
+//! standard error is discarded, as over SSH. When the console asks for a
+//! login, apt-remote logs in as `user`, with the password from the SSH
+//! password file or variable, or asked for on the terminal.
+//!
+//! Files are sent in chunks of [`CHUNK_SIZE`] bytes, as base64 lines the
+//! console shell decodes with `base64 -d`. The SHA-256 of every chunk is
+//! checked on the target before it is appended to `<file>.part`, and a
+//! chunk that arrived damaged, or not at all, is sent again after the shell
+//! is brought back to its prompt. The complete file is checked again before
+//! it replaces `<file>`. An interrupted transfer resumes where it stopped:
+//! the next upload keeps the `.part` file if it still matches the start of
+//! the local file, and skips files the target already has. The target needs
+//! `stty`, `base64` and `sha256sum`, which coreutils and BusyBox provide.
+//!
+//! At 115200 baud a line moves about 8 KiB of file data per second, so
+//! images meant for a serial console should be kept small.
+
+use crate::{
+    archive::hex,
+    dry_run,
+    exit::{Classify, ExitCode},
+    secret::{self, Credential},
+    ssh::{C_LOCALE, RemoteExecutor, SudoCheck, classify_sudo, shell_quote, sudo_line},
+    transport::Transport,
+};
+
+use anyhow::{Context, Result, anyhow};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Speed of the line unless the target sets `baud`.
+pub const DEFAULT_BAUD: u32 = 115_200;
+
+/// Bytes of file data sent, and checked, at a time. Small enough for the
+/// chunk to fit the console's input buffer while the shell is busy.
+pub const CHUNK_SIZE: usize = 2048;
+
+/// Times a chunk is sent before the upload fails.
+const CHUNK_ATTEMPTS: usize = 5;
+
+/// How long the shell may take to answer a chunk, or the prompt to return.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the console is given to show a prompt.
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest line sent to the console as is. Longer commands are sent as a
+/// script, as terminals drop what exceeds their line buffer (4 KiB on Linux).
+const MAX_LINE: usize = 1024;
+
+/// Prefix of the markers framing each command's output.
+const MARKER: &str = "__apt_remote_";
+
+/// Runs commands in the shell on a serial console, and sends files through
+/// it.
+pub struct SerialTransport {
+    /// The serial device, for messages.
+    device: PathBuf,
+    /// The open line and what was read from it.
+    console: Mutex<Console>,
+    /// Whether the console shell runs as root, so no `sudo` is needed.
+    root: bool,
+}
+
+/// An open serial line.
+struct Console {
+    /// The device, locked while open.
+    line: File,
+    /// Bytes read but not consumed yet.
+    buffer: Vec<u8>,
+    /// Number of the last command framed, for unique markers.
+    sequence: u64,
+}
+
+impl SerialTransport {
+    /// Open the serial console named by `spec` (`/dev/ttyUSB0?baud=9600&user=root`),
+    /// log in if it asks for a login, and set up its shell.
+    ///
+    /// # Errors
+    /// Returns an error if an option is unknown, the line cannot be
+    /// configured, opened or locked, a login is asked for without a `user`,
+    /// or no shell answers on the console.
+    pub fn connect(spec: &str) -> Result<SerialTransport> {
+        // `serial:///dev/ttyUSB0` also names `/dev/ttyUSB0`
+        let spec = spec.strip_prefix("//").unwrap_or(spec);
+        let (path, options) = spec.split_once('?').unwrap_or((spec, ""));
+        let device = PathBuf::from(path);
+        let mut baud = DEFAULT_BAUD;
+        let mut user = None;
+        for option in options.split('&').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("baud", value)) => {
+                    baud = value.parse().with_context(|| format!("Invalid baud rate {value}")).classify(ExitCode::Usage)?;
+                }
+                Some(("user", value)) => user = Some(value.to_string()),
+                _ => {
+                    return Err(anyhow!("Unknown serial option {option} (expected baud=RATE or user=NAME)"))
+                        .classify(ExitCode::Usage);
+                }
+            }
+        }
+
+        // 8N1 in raw mode, with reads returning after half a second of silence
+        let output = Command::new("stty")
+            .arg("-F")
+            .arg(&device)
+            .args([&baud.to_string(), "raw", "-echo", "cs8", "-cstopb", "-parenb", "clocal", "min", "0", "time", "5"])
+            .output()
+            .context("Failed to run `stty`")
+            .classify(ExitCode::Connect)?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Cannot configure {}: {}",
+                device.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+            .classify(ExitCode::Connect);
+        }
+        let line = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&device)
+            .with_context(|| format!("Failed to open {}", device.display()))
+            .classify(ExitCode::Connect)?;
+        match line.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(anyhow!("{} is in use by another process", device.display())).classify(ExitCode::Connect);
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {}", device.display())).classify(ExitCode::Connect);
+            }
+        }
+        let mut console = Console { line, buffer: Vec::new(), sequence: 0 };
+
+        // Wake the console up, and log in if it shows a login prompt
+        console.send("\n")?;
+        console.settle();
+        if console.prompt().ends_with("login:") {
+            let user = user
+                .ok_or_else(|| anyhow!("{} asks for a login: name the user with ?user=NAME", device.display()))
+                .classify(ExitCode::Connect)?;
+            console.buffer.clear();
+            console.send(&format!("{user}\n"))?;
+            console.settle();
+            if console.prompt().ends_with("assword:") {
+                let prompt = format!("Password for {user} on {}: ", device.display());
+                let password = secret::obtain(Credential::SshPassword, &prompt)?;
+                console.buffer.clear();
+                console.send(&format!("{password}\n"))?;
+                console.settle();
+                if console.prompt().ends_with("login:") {
+                    return Err(anyhow!("{} rejected the login of {user}", device.display())).classify(ExitCode::Auth);
+                }
+            }
+        }
+
+        // No echo, prompts or line ending translation, and untranslated output
+        let settings = C_LOCALE.map(|(name, value)| format!("{name}={value}")).join(" ");
+        console.send(&format!("stty -echo -onlcr 2>/dev/null; PS1=''; PS2=''; export {settings}\n"))?;
+        let (uid, _) = match console.run("id -u", Some(ANSWER_TIMEOUT)) {
+            Ok(answer) => answer,
+            // A command left running, or a half-sent chunk, is interrupted
+            Err(_) => console
+                .recover()
+                .and_then(|()| console.run("id -u", Some(ANSWER_TIMEOUT)))
+                .with_context(|| format!("No shell answers on {}", device.display()))
+                .classify(ExitCode::Connect)?,
+        };
+        debug!(device = %device.display(), baud, uid = uid.trim(), "Serial console ready");
+
+        Ok(SerialTransport { device, root: uid.trim() == "0", console: Mutex::new(console) })
+    }
+
+    /// Run `cmd` in the console shell, and return its stdout and exit status.
+    fn run(&self, cmd: &str) -> Result<(String, i32)> {
+        let mut console = self.console.lock().unwrap_or_else(|e| e.into_inner());
+        let (output, status) = console
+            .run(cmd, None)
+            .with_context(|| format!("Lost the console on {}", self.device.display()))
+            .classify(ExitCode::Remote)?;
+        debug!(command = cmd, exit_status = status, "Console command");
+        Ok((output, status))
+    }
+
+    /// Send the file at `local` to `remote`, resuming an interrupted
+    /// transfer.
+    fn upload_file(&self, local: &Path, remote: &Path) -> Result<()> {
+        let data = fs::read(local)?;
+        let digest = hex(&Sha256::digest(&data));
+        let path = remote.to_string_lossy();
+        let (remote, part, chunk) =
+            (shell_quote(&path), shell_quote(&format!("{path}.part")), shell_quote(&format!("{path}.chunk")));
+        let mut console = self.console.lock().unwrap_or_else(|e| e.into_inner());
+
+        // A complete copy from an earlier run is kept
+        let (existing, _) = console.run(&format!("sha256sum < {remote} 2>/dev/null"), Some(ANSWER_TIMEOUT))?;
+        if sum(&existing) == digest {
+            debug!("{} is already on the target", local.display());
+            return Ok(());
+        }
+        let mut offset = received(&mut console, &part, &data)?;
+        if offset > 0 {
+            debug!("Resuming {} at {offset} of {} bytes", local.display(), data.len());
+        }
+
+        let mut attempts = 0;
+        while offset < data.len() {
+            let end = (offset + CHUNK_SIZE).min(data.len());
+            let bytes = &data[offset..end];
+            let cmd = format!(
+                "base64 -d > {chunk} <<'{MARKER}eof'\n{}\n{MARKER}eof\n[ \"$(sha256sum < {chunk})\" = '{}  -' ] && cat {chunk} >> {part} && echo ok",
+                base64(bytes),
+                hex(&Sha256::digest(bytes))
+            );
+            match console.run(&cmd, Some(ANSWER_TIMEOUT)) {
+                Ok((answer, 0)) if answer.trim() == "ok" => {
+                    offset = end;
+                    attempts = 0;
+                    continue;
+                }
+                Ok(_) => {}
+                // The shell may still wait for the rest of the chunk
+                Err(_) => console.recover()?,
+            }
+            attempts += 1;
+            if attempts >= CHUNK_ATTEMPTS {
+                return Err(anyhow!(
+                    "{} was damaged {CHUNK_ATTEMPTS} times at byte {offset} on {}",
+                    local.display(),
+                    self.device.display()
+                ));
+            }
+            debug!("Chunk at {offset} of {} damaged, sending it again", local.display());
+            offset = received(&mut console, &part, &data)?;
+        }
+
+        // The whole file is checked before it replaces the destination
+        let (answer, _) = console.run(
+            &format!("rm -f {chunk}; [ \"$(sha256sum < {part})\" = '{digest}  -' ] && mv {part} {remote} && echo ok"),
+            Some(ANSWER_TIMEOUT),
+        )?;
+        if answer.trim() != "ok" {
+            console.run(&format!("rm -f {part}"), Some(ANSWER_TIMEOUT))?;
+            return Err(anyhow!("{} arrived damaged on {}", local.display(), self.device.display()));
+        }
+        Ok(())
+    }
+
+    /// Send the directory at `local` and its contents to `remote`.
+    fn upload_dir(&self, local: &Path, remote: &Path) -> Result<()> {
+        let (_, status) = self.run(&format!("mkdir -p {}", shell_quote(&remote.to_string_lossy())))?;
+        if status != 0 {
+            return Err(anyhow!("Failed to create {} on {}", remote.display(), self.device.display()));
+        }
+        for entry in fs::read_dir(local)? {
+            let entry = entry?;
+            let (local, remote) = (entry.path(), remote.join(entry.file_name()));
+            if entry.file_type()?.is_dir() {
+                self.upload_dir(&local, &remote)?;
+            } else {
+                self.upload_file(&local, &remote)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Console {
+    /// Write `text` to the line.
+    fn send(&mut self, text: &str) -> Result<()> {
+        self.line.write_all(text.as_bytes())?;
+        self.line.flush()?;
+        Ok(())
+    }
+
+    /// Read from the line until `done` holds for what was read, or
+    /// `deadline` passes. Returns whether `done` held.
+    fn read_until(&mut self, deadline: Option<Instant>, done: impl Fn(&[u8]) -> bool) -> Result<bool> {
+        let mut bytes = [0u8; 4096];
+        while !done(&self.buffer) {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(false);
+            }
+            // Returns nothing after half a second of silence (see `connect`)
+            let read = self.line.read(&mut bytes)?;
+            self.buffer.extend_from_slice(&bytes[..read]);
+        }
+        Ok(true)
+    }
+
+    /// Read what the console prints until it has been silent for a while.
+    fn settle(&mut self) {
+        let deadline = Instant::now() + PROMPT_TIMEOUT;
+        let _ = self.read_until(Some(deadline), |_| false);
+    }
+
+    /// The last line the console printed, such as a prompt.
+    fn prompt(&self) -> String {
+        let text = String::from_utf8_lossy(&self.buffer);
+        text.lines().rev().map(str::trim).find(|line| !line.is_empty()).unwrap_or_default().to_string()
+    }
+
+    /// Run `cmd` in the shell, and return its stdout and exit status.
+    ///
+    /// Fails if the shell does not answer before `timeout`.
+    fn run(&mut self, cmd: &str, timeout: Option<Duration>) -> Result<(String, i32)> {
+        self.sequence += 1;
+        let sequence = self.sequence;
+        // Split in two in the command, so an echo of it does not match
+        let (begin, end) = (format!("{MARKER}{sequence}_begin"), format!("\n{MARKER}{sequence}_end "));
+
+        let (body, cleanup) = if cmd.lines().all(|line| line.len() <= MAX_LINE) {
+            (cmd.to_string(), String::new())
+        } else {
+            let script = format!("/tmp/.apt-remote-{}-{sequence}.sh", std::process::id());
+            (
+                format!("(umask 077 && base64 -d > {script}) <<'{MARKER}eof'\n{}\n{MARKER}eof\nsh {script}", base64(cmd.as_bytes())),
+                format!("rm -f {script}; "),
+            )
+        };
+        self.send(&format!(
+            "printf '%s%s\\n' {MARKER} {sequence}_begin; {{ {body}\n}} </dev/null 2>/dev/null; s=$?; {cleanup}printf '\\n%s%s %s\\n' {MARKER} {sequence}_end $s\n"
+        ))?;
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let framed = |buffer: &[u8]| {
+            find(buffer, end.as_bytes()).is_some_and(|at| buffer[at + end.len()..].contains(&b'\n'))
+        };
+        if !self.read_until(deadline, framed)? {
+            return Err(anyhow!("the console did not answer within {}s", timeout.unwrap_or_default().as_secs()));
+        }
+
+        // Output sits between the markers; what follows is kept for the next command
+        let at = find(&self.buffer, end.as_bytes()).unwrap_or_default();
+        let rest = at + end.len() + self.buffer[at + end.len()..].iter().position(|&b| b == b'\n').unwrap_or_default();
+        let status = String::from_utf8_lossy(&self.buffer[at + end.len()..rest]).trim().parse().unwrap_or(-1);
+        let start = find(&self.buffer[..at], begin.as_bytes()).map_or(0, |start| {
+            start + self.buffer[start..at].iter().position(|&b| b == b'\n').map_or(at - start, |newline| newline + 1)
+        });
+        let output = String::from_utf8_lossy(&self.buffer[start..at.max(start)]).replace("\r\n", "\n");
+        let output = output.strip_suffix('\r').unwrap_or(&output).to_string();
+        self.buffer.drain(..=rest);
+        Ok((output, status))
+    }
+
+    /// Bring the shell back to its prompt after a command that did not
+    /// answer, by interrupting what it runs and ending the here-documents
+    /// it may still read.
+    fn recover(&mut self) -> Result<()> {
+        let mut attempts = 0;
+        loop {
+            self.send(&format!("\x03\n{MARKER}eof\n{MARKER}pw\n"))?;
+            self.settle();
+            self.buffer.clear();
+            match self.run("true", Some(PROMPT_TIMEOUT)) {
+                Ok(_) => return Ok(()),
+                Err(e) if attempts + 1 >= CHUNK_ATTEMPTS => return Err(e),
+                Err(_) => attempts += 1,
+            }
+        }
+    }
+}
+
+impl RemoteExecutor for SerialTransport {
+    fn exec(&self, cmd: &str) -> Result<String> {
+        if dry_run::skip("run", cmd) {
+            return Ok(String::new());
+        }
+        self.query(cmd)
+    }
+
+    fn query(&self, cmd: &str) -> Result<String> {
+        Ok(self.run(cmd)?.0)
+    }
+
+    fn sudo(&self, cmd: &str, password: &str) -> Result<String> {
+        if dry_run::skip("run as root", cmd) {
+            return Ok(String::new());
+        }
+        if self.root {
+            return self.query(cmd);
+        }
+        // The password is read from a here-document, never from the console itself
+        let line = sudo_line(cmd).replacen("sudo ", &format!("sudo <<'{MARKER}pw' "), 1);
+        Ok(self.run(&format!("{line}\n{password}\n{MARKER}pw"))?.0)
+    }
+
+    fn sudo_check(&self, password: &str) -> Result<SudoCheck> {
+        if self.root {
+            return Ok(SudoCheck::Accepted);
+        }
+        let cmd = if password.is_empty() {
+            "sudo -n true 2>&1".to_string()
+        } else {
+            format!("sudo -S -k -v -p '' 2>&1 <<'{MARKER}pw'\n{password}\n{MARKER}pw")
+        };
+        let (output, status) = self.run(&cmd)?;
+        Ok(match status {
+            0 => SudoCheck::Accepted,
+            _ => classify_sudo(&output),
+        })
+    }
+}
+
+impl Transport for SerialTransport {
+    fn upload(&self, local_path: &Path, remote_path: &Path) -> Result<()> {
+        if dry_run::skip("upload", format!("{} -> {}", local_path.display(), remote_path.display())) {
+            return Ok(());
+        }
+        if local_path.is_dir() {
+            self.upload_dir(local_path, remote_path)
+        } else {
+            self.upload_file(local_path, remote_path)
+        }
+        .with_context(|| format!("Failed to send {} to {}", local_path.display(), remote_path.display()))
+        .classify(ExitCode::Upload)
+    }
+
+    fn needs_sudo(&self) -> bool {
+        !self.root
+    }
+
+    // The console carries one file at a time
+    fn upload_jobs(&self) -> usize {
+        1
+    }
+}
+
+/// How much of `data` the target holds in `part`: its size, if it still
+/// matches the start of `data`. A `part` that does not is emptied.
+fn received(console: &mut Console, part: &str, data: &[u8]) -> Result<usize> {
+    let (answer, _) = console.run(
+        &format!("[ -f {part} ] && {{ wc -c < {part}; sha256sum < {part}; }}"),
+        Some(ANSWER_TIMEOUT),
+    )?;
+    let mut lines = answer.lines();
+    let size = lines.next().and_then(|size| size.trim().parse::<usize>().ok()).unwrap_or(0);
+    if size > 0 && size <= data.len() && lines.next().map(sum) == Some(hex(&Sha256::digest(&data[..size]))) {
+        return Ok(size);
+    }
+    console.run(&format!(": > {part}"), Some(ANSWER_TIMEOUT))?;
+    Ok(0)
+}
+
+/// The checksum in a line printed by `sha256sum`.
+fn sum(line: &str) -> String {
+    line.split_whitespace().next().unwrap_or_default().to_string()
+}
+
+/// The position of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// `bytes` in base64, as lines of 76 characters like `base64` writes them,
+/// so no line outgrows the console's canonical input buffer.
+///
+/// ```
+/// use apt_remote::serial::base64;
+///
+/// assert_eq!(base64(b"apt-remote"), "YXB0LXJlbW90ZQ==");
+/// assert_eq!(base64(&[0xff; 60]).lines().map(str::len).collect::<Vec<_>>(), [76, 4]);
+/// ```
+pub fn base64(bytes: &[u8]) -> String {
+    let encoded = openssl::base64::encode_block(bytes);
+    let lines: Vec<&str> = encoded.as_bytes().chunks(76).map(|line| std::str::from_utf8(line).unwrap_or_default()).collect();
+    lines.join("\n")
+}
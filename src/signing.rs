@@ -0,0 +1,281 @@
+//! # Signed image bundles
+//!
+//! A bundle written by `apt-remote export` can be signed, so that a bundle
+//! carried to the offline side (on a courier USB stick, say) cannot be
+//! modified on the way without `import` and `install` noticing:
+//!
+//! - `manifest.toml` lists every file of the bundle with its SHA256 digest;
+//! - `manifest.sig` holds an Ed25519 signature of the manifest and the ID of
+//!   the key that made it.
+//!
+//! Key pairs are created with `apt-remote keygen`. The exporting machine
+//! signs with its private key (`signing_key` in `config.toml`, or `export
+//! --sign <KEY>`); the importing machine lists the public keys it accepts
+//! in `trusted_keys`:
+//!
+//! ```toml
+//! signing_key = "~/.config/apt-remote/signing.key"  # export side
+//! trusted_keys = ["/etc/apt-remote/courier.pub"]     # import side
+//! ```
+//!
+//! `import` checks the signature and every file before copying a bundle
+//! into the cache, and keeps the manifest with the image. `install` and
+//! `update` check the signature again and that `uri.toml` still matches it;
+//! packages are then verified on the target against the checksums in
+//! `uri.toml`, and package lists against the manifest before they are
+//! uploaded. The `require_signature` policy refuses unsigned bundles and
+//! images altogether; it is implied by a non-empty `trusted_keys`, so that
+//! a bundle cannot pass by having its signature stripped.
+
+use crate::{
+    archive::{self, hex, unhex},
+    config,
+    error::Error,
+    exit::{Classify, ExitCode},
+    policy, uri,
+};
+
+use anyhow::{Context, Result};
+use openssl::{
+    pkey::{HasPublic, PKey, Public},
+    sign::{Signer, Verifier},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Name of the file listing the files of a bundle.
+pub const MANIFEST_FILE: &str = "manifest.toml";
+/// Name of the file holding the manifest's signature.
+pub const SIGNATURE_FILE: &str = "manifest.sig";
+
+/// Representation of `manifest.toml`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Format version.
+    pub version: u32,
+    /// Name of the exported image.
+    pub name: String,
+    /// When the bundle was exported, as seconds since the Unix epoch.
+    pub created: u64,
+    /// SHA256 digest of every file, keyed by its `/`-separated path in the bundle.
+    pub files: BTreeMap<String, String>,
+}
+
+/// Representation of `manifest.sig`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Signature {
+    /// ID of the signing key (see [`key_id`]).
+    key: String,
+    /// Hex-encoded Ed25519 signature of `manifest.toml`.
+    signature: String,
+}
+
+/// Short identifier of a key: the first 8 bytes of the SHA256 of its raw
+/// public key, hex-encoded.
+///
+/// # Errors
+/// Returns an error if the key is not an Ed25519 key.
+pub fn key_id<T: HasPublic>(key: &PKey<T>) -> Result<String> {
+    let raw = key.raw_public_key().context("Not an Ed25519 key")?;
+    Ok(hex(&Sha256::digest(raw)[..8]))
+}
+
+/// Create a key pair: the private key at `path`, the public key at
+/// `<path>.pub`. Returns the key ID.
+///
+/// # Errors
+/// Returns an error if either file already exists or cannot be written.
+pub fn generate(path: &Path) -> Result<String> {
+    let public_path = public_path(path);
+    if let Some(existing) = [path, public_path.as_path()].into_iter().find(|p| p.exists()) {
+        return Err(anyhow::anyhow!("{} already exists", existing.display())).classify(ExitCode::Config);
+    }
+
+    let key = PKey::generate_ed25519()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_private(path, &key.private_key_to_pem_pkcs8()?)?;
+    fs::write(&public_path, key.public_key_to_pem()?)
+        .with_context(|| format!("Failed to write {}", public_path.display()))?;
+    key_id(&key)
+}
+
+/// Location of the public key belonging to the private key at `path`.
+pub fn public_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".pub");
+    path.with_file_name(name)
+}
+
+/// Write a private key readable only by this user.
+fn write_private(path: &Path, pem: &[u8]) -> Result<()> {
+    fs::write(path, pem).with_context(|| format!("Failed to write {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+/// Expand a leading `~/` in a configured path.
+fn expand(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Write `manifest.toml` for every file in `bundle_dir` and sign it with the
+/// private key at `key_path`. Returns the key ID.
+///
+/// # Errors
+/// Returns an error if the key cannot be loaded or a file cannot be read.
+pub fn sign_bundle(bundle_dir: &Path, name: &str, key_path: &Path) -> Result<String> {
+    let key_path = expand(key_path);
+    let pem = fs::read(&key_path)
+        .with_context(|| format!("Failed to read signing key {}", key_path.display()))
+        .classify(ExitCode::Config)?;
+    let key = PKey::private_key_from_pem(&pem)
+        .with_context(|| format!("{} is not a private key", key_path.display()))
+        .classify(ExitCode::Config)?;
+
+    let mut files = BTreeMap::new();
+    collect(bundle_dir, bundle_dir, &mut files)?;
+    let manifest = Manifest { version: 1, name: name.to_string(), created: crate::state::now(), files };
+    let content = toml::to_string_pretty(&manifest)?;
+    fs::write(bundle_dir.join(MANIFEST_FILE), &content)?;
+
+    let signature = Signer::new_without_digest(&key)?.sign_oneshot_to_vec(content.as_bytes())?;
+    let signature = Signature { key: key_id(&key)?, signature: hex(&signature) };
+    fs::write(bundle_dir.join(SIGNATURE_FILE), toml::to_string_pretty(&signature)?)?;
+    Ok(signature.key)
+}
+
+/// Record the digest of every file below `dir`, except the manifest itself.
+fn collect(root: &Path, dir: &Path, files: &mut BTreeMap<String, String>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(root, &path, files)?;
+            continue;
+        }
+        let relative = path.strip_prefix(root)?;
+        if dir == root && [MANIFEST_FILE, SIGNATURE_FILE].iter().any(|f| relative == Path::new(f)) {
+            continue;
+        }
+        let key = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        files.insert(key, archive::sha256_file(&path)?);
+    }
+    Ok(())
+}
+
+/// The public keys listed in `trusted_keys`, by key ID.
+fn trusted_keys() -> Result<BTreeMap<String, PKey<Public>>> {
+    let mut keys = BTreeMap::new();
+    for path in config::get().trusted_keys.iter().map(|p| expand(p)) {
+        let pem = fs::read(&path)
+            .with_context(|| format!("Failed to read trusted key {}", path.display()))
+            .classify(ExitCode::Config)?;
+        let key = PKey::public_key_from_pem(&pem)
+            .with_context(|| format!("{} is not a public key", path.display()))
+            .classify(ExitCode::Config)?;
+        keys.insert(key_id(&key)?, key);
+    }
+    Ok(keys)
+}
+
+/// Check the signature of the manifest in `dir` (a bundle or an image).
+///
+/// Returns the manifest and the ID of the key that signed it, or `None` if
+/// `dir` is not signed and the `require_signature` policy allows that (it
+/// does not once `trusted_keys` is set).
+///
+/// # Errors
+/// Returns [`Error::BadSignature`] if the signature is not made by a trusted
+/// key or does not match the manifest, and [`Error::PolicyViolation`] if
+/// `dir` is unsigned but signatures are required.
+pub fn verify(dir: &Path) -> Result<Option<(Manifest, String)>> {
+    let bad = |reason: String| Error::BadSignature { path: dir.to_path_buf(), reason };
+
+    let signature_path = dir.join(SIGNATURE_FILE);
+    if !signature_path.is_file() {
+        policy::check_signed(dir)?;
+        return Ok(None);
+    }
+    let signature: Signature = toml::from_str(&fs::read_to_string(&signature_path)?)
+        .map_err(|e| bad(format!("{SIGNATURE_FILE} is unreadable: {e}")))?;
+    let content = fs::read(dir.join(MANIFEST_FILE)).map_err(|e| bad(format!("{MANIFEST_FILE}: {e}")))?;
+
+    let keys = trusted_keys()?;
+    let key = keys
+        .get(&signature.key)
+        .ok_or_else(|| bad(format!("signed by key {}, which is not in trusted_keys", signature.key)))?;
+    let valid = unhex(&signature.signature)
+        .ok()
+        .map(|raw| Verifier::new_without_digest(key).and_then(|mut v| v.verify_oneshot(&raw, &content)));
+    if !matches!(valid, Some(Ok(true))) {
+        return Err(bad(format!("the signature does not match {MANIFEST_FILE}")).into());
+    }
+
+    let manifest: Manifest = toml::from_str(&String::from_utf8_lossy(&content))
+        .map_err(|e| bad(format!("{MANIFEST_FILE} is unreadable: {e}")))?;
+    Ok(Some((manifest, signature.key)))
+}
+
+impl Manifest {
+    /// Check that the file at `path` is the one listed as `relative`.
+    ///
+    /// # Errors
+    /// Returns [`Error::BadSignature`] naming the file if it is not listed
+    /// or its digest differs.
+    pub fn check_file(&self, dir: &Path, relative: &str, path: &Path) -> Result<()> {
+        let matches = match self.files.get(relative) {
+            Some(digest) => archive::sha256_file(path)?.eq_ignore_ascii_case(digest),
+            None => false,
+        };
+        if !matches {
+            return Err(Error::BadSignature {
+                path: dir.to_path_buf(),
+                reason: format!("{relative} does not match the signed manifest"),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Check the signature of an imported image before it is used.
+///
+/// Returns the manifest of a signed image, after checking that its
+/// `uri.toml` is the signed one.
+///
+/// # Errors
+/// Returns an error if the signature or `uri.toml` does not check out, or
+/// if the image is unsigned but signatures are required.
+pub fn verify_image(image_dir: &Path) -> Result<Option<Manifest>> {
+    let Some((manifest, _)) = verify(image_dir)? else {
+        return Ok(None);
+    };
+    let uri_path = uri::path_in(image_dir);
+    let uri_name = uri_path.file_name().unwrap_or_default().to_string_lossy();
+    manifest.check_file(image_dir, &uri_name, &uri_path)?;
+    Ok(Some(manifest))
+}
+
+/// Drop the signature of an image whose plan is being replaced.
+pub fn forget(image_dir: &Path) -> Result<()> {
+    for file in [MANIFEST_FILE, SIGNATURE_FILE].map(|f| image_dir.join(f)) {
+        if file.is_file() {
+            fs::remove_file(&file)?;
+        }
+    }
+    Ok(())
+}
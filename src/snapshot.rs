@@ -0,0 +1,99 @@
+//! # Point-in-time plans from snapshot.debian.org
+//!
+//! [snapshot.debian.org](https://snapshot.debian.org) keeps every file the
+//! Debian archives have published, under
+//! `https://snapshot.debian.org/archive/<archive>/<time>/`, where `<time>`
+//! is a UTC timestamp such as `20250601T000000Z`. `apt-remote set
+//! --snapshot <time>` pins a plan to that time: packages the target would
+//! download from a Debian archive are downloaded from the snapshot instead,
+//! so `get` fetches the exact same files months later, after the archive
+//! itself has moved on. With `--distro`, the suite's indices are read from
+//! the snapshot too. The snapshot URL is recorded in `uri.lock` (see
+//! [`crate::lock`]).
+//!
+//! `now` stands for the current time, which pins the versions the target
+//! resolves today. The snapshot only serves what the archive had published
+//! at the given time, so a time before the target's last `apt-get update`
+//! may lack the versions it resolves.
+
+use crate::state;
+
+use url::Url;
+
+/// Base URL of the snapshot archives.
+pub const SNAPSHOT_URL: &str = "https://snapshot.debian.org/archive";
+
+/// Debian archives snapshot.debian.org keeps, by their path on the mirrors.
+const ARCHIVES: [&str; 4] = ["debian", "debian-security", "debian-debug", "debian-ports"];
+
+/// Parse a `--snapshot` value: a `YYYYMMDDTHHMMSSZ` timestamp, or `now`.
+///
+/// ```
+/// use apt_remote::snapshot::parse_time;
+///
+/// assert_eq!(parse_time("20250601T000000Z").unwrap(), "20250601T000000Z");
+/// assert_eq!(parse_time("now").unwrap().len(), 16);
+/// assert!(parse_time("2025-06-01").is_err());
+/// ```
+///
+/// # Errors
+/// Returns a message for the CLI if `value` is neither.
+pub fn parse_time(value: &str) -> Result<String, String> {
+    if value == "now" {
+        return Ok(timestamp(state::now()));
+    }
+    let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    match value.strip_suffix('Z').and_then(|time| time.split_once('T')) {
+        Some((date, time)) if date.len() == 8 && time.len() == 6 && digits(date) && digits(time) => {
+            Ok(value.to_string())
+        }
+        _ => Err(format!("expected a UTC time such as 20250601T000000Z, or now (got {value})")),
+    }
+}
+
+/// The snapshot of the main Debian archive at `time`.
+pub fn archive_url(time: &str) -> String {
+    format!("{SNAPSHOT_URL}/debian/{time}")
+}
+
+/// Where `uri`, a file of a Debian archive, was as of `time`, or `None` if
+/// it is not from an archive snapshot.debian.org keeps.
+///
+/// ```
+/// use apt_remote::snapshot::rewrite;
+///
+/// assert_eq!(
+///     rewrite("http://deb.debian.org/debian/pool/main/c/curl/curl_7.88.1-10_amd64.deb", "20250601T000000Z").as_deref(),
+///     Some("https://snapshot.debian.org/archive/debian/20250601T000000Z/pool/main/c/curl/curl_7.88.1-10_amd64.deb"),
+/// );
+/// assert!(rewrite("http://security.debian.org/debian-security/pool/updates/main/c/curl/x.deb", "20250601T000000Z")
+///     .is_some_and(|uri| uri.contains("/archive/debian-security/20250601T000000Z/pool/")));
+/// assert_eq!(rewrite("http://archive.ubuntu.com/ubuntu/pool/main/c/curl/x.deb", "20250601T000000Z"), None);
+/// ```
+pub fn rewrite(uri: &str, time: &str) -> Option<String> {
+    let url = Url::parse(uri).ok()?;
+    let host = url.host_str()?;
+    let debian = matches!(host, "deb.debian.org" | "ftp.debian.org" | "httpredir.debian.org" | "security.debian.org")
+        || host.strip_prefix("ftp.").is_some_and(|rest| rest.ends_with(".debian.org") && rest.matches('.').count() == 2);
+    if !debian {
+        return None;
+    }
+    let (archive, rest) = url.path().trim_start_matches('/').split_once('/')?;
+    ARCHIVES.contains(&archive).then(|| format!("{SNAPSHOT_URL}/{archive}/{time}/{rest}"))
+}
+
+/// Format Unix time `secs` as a snapshot timestamp.
+fn timestamp(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z", secs / 3600, secs / 60 % 60, secs % 60)
+}
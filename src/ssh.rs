@@ -5,21 +5,52 @@
 //! or directories securely. It abstracts away low-level details of
 //! the `ssh2` crate to simplify common SSH and SFTP workflows.
 
-use anyhow::{Context, Result};
-use ssh2::{Session, Sftp};
+use crate::{
+    archive,
+    checksum::{Tools, parse_digest},
+    config, dashboard, dry_run,
+    error::{Error, Source},
+    exit::{Classify, ExitCode},
+    inventory::{self, Endpoint},
+    known_hosts,
+    secret::{self, Credential},
+    throttle::Throttled,
+    tr,
+    uri::ChecksumKind,
+};
+
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use ssh2::{Channel, KeyboardInteractivePrompt, OpenFlags, OpenType, Prompt, PtyModeOpcode, PtyModes, Session, Sftp};
+use tracing::{debug, info};
 use std::{
+    collections::BTreeMap,
     fs::{self, File},
-    io::{Read, Write},
-    net::TcpStream,
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
     path::Path,
+    sync::Mutex,
+    thread,
+    time::Duration,
 };
 
 /// Establish an SSH session with the given target in the form `user@host`.
 ///
 /// This function:
-/// 1. Connects to the host via TCP on port 22.
-/// 2. Attempts to authenticate via SSH agent.
-/// 3. Falls back to password authentication if necessary.
+/// 1. Connects to the host via TCP on the configured port (22 by default),
+///    using the address and port of the host's inventory entry or its
+///    `~/.ssh/config` block if it has one (see [`crate::ssh_config`]), and
+///    tries again with a growing delay if the connection or handshake fails
+///    (`ssh.connect_attempts` times in all).
+/// 2. Attempts to authenticate with the inventory identity file and the
+///    `--identity` file (`ssh.identity`, which must exist), if any, and via
+///    SSH agent. Keys
+///    encrypted with a passphrase are decrypted with it (see
+///    [`secret::key_passphrase`]).
+/// 3. Falls back to password authentication if necessary, and to
+///    keyboard-interactive authentication (PAM, one-time passwords) on
+///    servers offering it.
 ///
 /// # Arguments
 /// * `target` - The SSH target in `user@host` format.
@@ -36,51 +67,340 @@ use std::{
 ///
 /// # Examples
 /// ```no_run
+/// # use apt_remote::ssh::create_ssh_session;
+/// # fn main() -> anyhow::Result<()> {
 /// let session = create_ssh_session("user@example.com")?;
+/// # Ok(())
+/// # }
 /// ```
 pub fn create_ssh_session(target: &str) -> Result<Session> {
-    // Split `user@host` into username and hostname parts
-    let mut parts = target.split('@');
-    let user = parts.next().context("Missing user")?;
-    let host = parts.next().context("Missing host")?;
+    // Resolve `user@host`, with the host's settings from the inventory
+    let endpoint = inventory::get()?.endpoint(target)?;
+
+    // A key given with --identity is not silently replaced by the agent or a password
+    if let Some(identity) = config::identity().filter(|identity| !identity.is_file()) {
+        return Err(anyhow!("Private key {} does not exist", identity.display())).classify(ExitCode::Config);
+    }
+
+    // Connections that fail before authenticating are tried again, waiting longer each time
+    let attempts = config::get().ssh.connect_attempts.max(1);
+    let (mut attempt, mut delay) = (1, Duration::from_secs(1));
+    loop {
+        info!("Connecting to {}:{} as {}", endpoint.address, endpoint.port, endpoint.user);
+        let session = connect_stream(target, &endpoint.address, endpoint.port, &endpoint.jump, &endpoint.user)
+            .and_then(|tcp| authenticate(target, &endpoint, tcp));
+        match session {
+            Err(e) if attempt < attempts && matches!(Error::find(&e), Some(Error::SshConnect { .. })) => {
+                info!("{e:#}, trying again in {}s ({attempt}/{attempts})", delay.as_secs());
+                thread::sleep(delay);
+                (attempt, delay) = (attempt + 1, delay * 2);
+            }
+            session => return session,
+        }
+    }
+}
+
+/// Authenticated sessions by target, shared by the commands of a run.
+static SESSIONS: Mutex<BTreeMap<String, Session>> = Mutex::new(BTreeMap::new());
+
+/// An authenticated session with `target`: the one already established
+/// during this run, so steps chained in one run (`run`, `apply`, `daemon`)
+/// connect and authenticate once, or else a new one (see
+/// [`create_ssh_session`]).
+///
+/// # Errors
+/// Returns an error if a new session cannot be established.
+pub fn shared_session(target: &str) -> Result<Session> {
+    if let Some(session) = SESSIONS.lock().unwrap().get(target) {
+        debug!("Reusing the session with {target}");
+        return Ok(session.clone());
+    }
+    renew_session(target)
+}
+
+/// Establish a new session with `target` and share it in place of the
+/// previous one, whose connection was lost.
+///
+/// # Errors
+/// Returns an error if the session cannot be established.
+pub fn renew_session(target: &str) -> Result<Session> {
+    let session = create_ssh_session(target)?;
+    SESSIONS.lock().unwrap().insert(target.to_string(), session.clone());
+    Ok(session)
+}
+
+/// Open a TCP connection to `address:port` for `target`, tunneled through
+/// the jump hosts `jump` (first hop first), each reached as `user` unless
+/// it names or has another user.
+fn connect_stream(target: &str, address: &str, port: u16, jump: &[String], user: &str) -> Result<TcpStream> {
+    let connect_error = |source: Source| Error::SshConnect { target: target.to_string(), source };
+    let Some((hop, before)) = jump.split_last() else {
+        let addr = (address, port)
+            .to_socket_addrs()
+            .map_err(|e| connect_error(format!("Failed to resolve {address}: {e}").into()))?
+            .next()
+            .ok_or_else(|| connect_error(format!("{address} has no address").into()))?;
+        return match config::connect_timeout() {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        }
+        .map_err(|e| connect_error(e.into()).into());
+    };
+
+    // The last hop is reached through the ones before it
+    let endpoint = inventory::get()?.hop(hop, user)?;
+    let tcp = connect_stream(hop, &endpoint.address, endpoint.port, before, &endpoint.user)?;
+    let session = authenticate(hop, &endpoint, tcp)?;
+    info!("Tunneling to {address}:{port} through {hop}");
+    tunnel(session, address, port).map_err(|e| connect_error(format!("{hop} could not open a tunnel: {e}").into()).into())
+}
+
+/// Open a `direct-tcpip` channel from `session` to `address:port`, and
+/// return a local TCP stream relayed through it, for another session.
+///
+/// The relay runs on a thread of its own, owning `session`, until either
+/// side closes.
+fn tunnel(session: Session, address: &str, port: u16) -> Result<TcpStream> {
+    let channel = session.channel_direct_tcpip(address, port, None)?;
+
+    // libssh2 needs a socket, so the channel is relayed over a loopback connection
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let local = TcpStream::connect(listener.local_addr()?)?;
+    let relay = loop {
+        let (relay, peer) = listener.accept()?;
+        if peer == local.local_addr()? {
+            break relay;
+        }
+    };
+    relay.set_nonblocking(true)?;
+    relay.set_nodelay(true)?;
+    local.set_nodelay(true)?;
+    session.set_blocking(false);
+    thread::spawn(move || relay_channel(&session, channel, relay));
+    Ok(local)
+}
+
+/// Copy data both ways between a tunnel's channel and its loopback
+/// connection, with `session` in non-blocking mode.
+fn relay_channel(_session: &Session, mut channel: Channel, mut relay: TcpStream) {
+    let (mut up, mut down) = (Vec::new(), Vec::new());
+    let mut buffer = [0; 32 * 1024];
+    let pending = |result: std::io::Result<usize>| match result {
+        Ok(n) => Ok(n),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(0),
+        Err(e) => Err(e),
+    };
+    loop {
+        let mut moved = 0;
+        if up.is_empty() {
+            match relay.read(&mut buffer) {
+                Ok(0) => break,
+                result => match pending(result) {
+                    Ok(n) => up.extend_from_slice(&buffer[..n]),
+                    Err(_) => break,
+                },
+            }
+        }
+        if !up.is_empty() {
+            match pending(channel.write(&up)) {
+                Ok(n) => moved += up.drain(..n).len(),
+                Err(_) => break,
+            }
+        }
+        if down.is_empty() {
+            match pending(channel.read(&mut buffer)) {
+                Ok(0) if channel.eof() => break,
+                Ok(n) => down.extend_from_slice(&buffer[..n]),
+                Err(_) => break,
+            }
+        }
+        if !down.is_empty() {
+            match pending(relay.write(&down)) {
+                Ok(n) => moved += down.drain(..n).len(),
+                Err(_) => break,
+            }
+        }
+        if moved == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    channel.close().ok();
+}
 
-    // Connect to the SSH server on port 22
-    let tcp = TcpStream::connect(format!("{host}:22")).context("Failed to connect to SSH")?;
+/// Complete the SSH handshake on `tcp` and authenticate as the endpoint's
+/// user, for `target`.
+fn authenticate(target: &str, endpoint: &Endpoint, tcp: TcpStream) -> Result<Session> {
+    let user = endpoint.user.as_str();
+    let connect_error = |source: Source| Error::SshConnect { target: target.to_string(), source };
 
     // Create a new SSH session and attach the TCP stream
     let mut session = Session::new().context("Failed to create SSH session")?;
     session.set_tcp_stream(tcp);
+    // zlib compression is negotiated in the handshake
+    session.set_compress(config::get().ssh.compress);
 
     // Perform the SSH handshake
-    session.handshake()?;
+    session
+        .handshake()
+        .map_err(|e| connect_error(format!("SSH handshake failed: {e}").into()))?;
+    known_hosts::verify(&session, target, &endpoint.address, endpoint.port)?;
 
     // If already authenticated (unlikely at this point), return early
     if session.authenticated() {
         return Ok(session);
     }
 
-    // Attempt to authenticate using the SSH agent
+    // Attempt to authenticate with the host's identity file and the configured one, then the SSH agent
+    for identity in endpoint.identity.iter().chain(&config::identity()) {
+        if authenticate_key(&session, user, identity)? {
+            debug!("Authenticated {user} with {}", identity.display());
+            return Ok(session);
+        }
+    }
     session.userauth_agent(user).ok();
     if session.authenticated() {
+        debug!("Authenticated {user} via SSH agent");
         return Ok(session);
     }
 
-    // Prompt for password if agent authentication failed
-    let password = rpassword::prompt_password(format!("Enter SSH password for {target}:"))?;
-    session.userauth_password(user, &password)?;
+    // Fall back to a provided or prompted password if agent authentication failed,
+    // unless the server only takes keyboard-interactive answers (PAM, one-time passwords)
+    let methods = session.auth_methods(user).unwrap_or_default().to_string();
+    let interactive = methods.split(',').any(|method| method == "keyboard-interactive");
+    if !interactive || methods.split(',').any(|method| method == "password") {
+        let password = secret::obtain(
+            Credential::SshPassword,
+            &tr!("Enter SSH password for {target}:", target = target),
+        )?;
+        session.userauth_password(user, &password).ok();
+        if session.authenticated() {
+            debug!("Authenticated {user} via password");
+            return Ok(session);
+        }
+    }
+    if interactive {
+        let mut prompter = Prompter { target, failed: None };
+        session.userauth_keyboard_interactive(user, &mut prompter).ok();
+        if let Some(e) = prompter.failed {
+            return Err(e);
+        }
+        if session.authenticated() {
+            debug!("Authenticated {user} via keyboard-interactive");
+            return Ok(session);
+        }
+    }
+    Err(Error::SshAuth { target: target.to_string() }.into())
+}
 
-    // Final authentication check
-    if session.authenticated() {
-        Ok(session)
-    } else {
-        Err(anyhow::anyhow!("Authentication failed"))
+/// Answers the questions of keyboard-interactive authentication on the
+/// terminal.
+///
+/// Password prompts are answered with the provided SSH password, if there
+/// is one (see [`secret`]); other questions, such as one-time passwords,
+/// are asked, hidden unless the server wants them echoed.
+struct Prompter<'a> {
+    /// The target, for messages.
+    target: &'a str,
+    /// Why a question could not be answered.
+    failed: Option<anyhow::Error>,
+}
+
+impl Prompter<'_> {
+    /// The answer to the question `text`.
+    fn answer(&self, text: &str, echo: bool) -> Result<String> {
+        if !echo
+            && text.to_lowercase().contains("password")
+            && let Some(password) = secret::provided(Credential::SshPassword)?
+        {
+            return Ok(password);
+        }
+        if echo && !config::get().batch {
+            let _paused = dashboard::pause();
+            eprint!("{text}");
+            std::io::stderr().flush()?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            return Ok(answer.trim_end_matches(['\r', '\n']).to_string());
+        }
+        secret::ask(Credential::SshPassword, text)
+    }
+}
+
+impl KeyboardInteractivePrompt for Prompter<'_> {
+    fn prompt<'b>(&mut self, _username: &str, instructions: &str, prompts: &[Prompt<'b>]) -> Vec<String> {
+        if self.failed.is_some() {
+            return vec![String::new(); prompts.len()];
+        }
+        if !instructions.trim().is_empty() && !config::get().batch {
+            eprintln!("{} ({}): {}", self.target, tr!("keyboard-interactive"), instructions.trim());
+        }
+        let mut answers = Vec::new();
+        for prompt in prompts {
+            match self.answer(&prompt.text, prompt.echo) {
+                Ok(answer) => answers.push(answer),
+                Err(e) => {
+                    self.failed = Some(e);
+                    answers.resize(prompts.len(), String::new());
+                    break;
+                }
+            }
+        }
+        answers
+    }
+}
+
+/// Authenticate `user` with the private key file `identity`, decrypting it
+/// with its passphrase if it has one.
+///
+/// Returns whether the server accepted the key.
+fn authenticate_key(session: &Session, user: &str, identity: &Path) -> Result<bool> {
+    if !is_encrypted_key(identity) {
+        session.userauth_pubkey_file(user, None, identity, None).ok();
+        return Ok(session.authenticated());
+    }
+    // libssh2 reports a key it cannot decrypt as a file error, and a key the
+    // server refuses as an authentication failure
+    let passphrase = secret::key_passphrase(identity, |passphrase| {
+        match session.userauth_pubkey_file(user, None, identity, Some(passphrase)) {
+            Err(e) if e.code() == ssh2::ErrorCode::Session(LIBSSH2_ERROR_FILE) => Ok(false),
+            _ => Ok(true),
+        }
+    })?;
+    if !session.authenticated() {
+        session.userauth_pubkey_file(user, None, identity, Some(&passphrase)).ok();
+    }
+    Ok(session.authenticated())
+}
+
+/// libssh2's error for a private key it cannot read or decrypt.
+const LIBSSH2_ERROR_FILE: i32 = -16;
+
+/// Whether the private key file at `path` is encrypted with a passphrase.
+///
+/// PEM keys say so in their header; OpenSSH keys name the cipher they are
+/// encrypted with (`none` if they are not) after their magic.
+fn is_encrypted_key(path: &Path) -> bool {
+    let Ok(content) = fs::read_to_string(path) else {
+        return false;
+    };
+    if content.contains("ENCRYPTED") {
+        return true;
     }
+    let body = content.lines().filter(|line| !line.starts_with("-----")).collect::<String>();
+    let Ok(key) = openssl::base64::decode_block(&body) else {
+        return false;
+    };
+    const MAGIC: &[u8] = b"openssh-key-v1\0";
+    key.starts_with(MAGIC) && key.get(MAGIC.len() + 4..MAGIC.len() + 8) != Some(b"none".as_slice())
 }
 
 /// A trait for executing commands on a remote SSH session.
 pub trait RemoteExecutor {
     /// Execute a shell command on the remote host.
     ///
+    /// In a dry run the command is only reported, and an empty output is
+    /// returned.
+    ///
     /// # Arguments
     /// * `cmd` - The command string to run.
     ///
@@ -88,8 +408,22 @@ pub trait RemoteExecutor {
     /// The captured stdout and stderr from the remote command.
     fn exec(&self, cmd: &str) -> Result<String>;
 
+    /// Execute a read-only shell command on the remote host.
+    ///
+    /// Unlike [`RemoteExecutor::exec`], queries also run in a dry run, so
+    /// they must not change the remote system.
+    ///
+    /// # Arguments
+    /// * `cmd` - The command string to run.
+    ///
+    /// # Returns
+    /// The captured stdout and stderr from the remote command.
+    fn query(&self, cmd: &str) -> Result<String>;
+
     /// Execute a command with `sudo` privileges on the remote host.
     ///
+    /// In a dry run the command is only reported.
+    ///
     /// # Arguments
     /// * `cmd` - The command string to run with `sudo`.
     /// * `password` - The sudo password for the remote user.
@@ -97,6 +431,352 @@ pub trait RemoteExecutor {
     /// # Returns
     /// The captured stdout and stderr from the remote command.
     fn sudo(&self, cmd: &str, password: &str) -> Result<String>;
+
+    /// Check whether `sudo` accepts `password` on the remote host.
+    ///
+    /// An empty password checks whether `sudo` works without one. Unlike
+    /// [`RemoteExecutor::sudo`], this also runs in a dry run, as it does not
+    /// change the remote system.
+    ///
+    /// # Arguments
+    /// * `password` - The sudo password for the remote user, or `""`.
+    fn sudo_check(&self, password: &str) -> Result<SudoCheck>;
+
+    /// Whether privileged commands can switch accounts with `su` (see
+    /// [`Escalation`]) on the remote host, rather than only use `sudo`.
+    fn supports_su(&self) -> bool {
+        false
+    }
+
+    /// Execute a shell command in directory `dir` on the remote host.
+    ///
+    /// Every command runs on a channel of its own, starting in the user's
+    /// home directory, so a separate `cd` has no effect on later commands;
+    /// this runs `cd <dir> && <cmd>` as one command line instead.
+    ///
+    /// # Arguments
+    /// * `dir` - Working directory for the command.
+    /// * `cmd` - The command string to run.
+    fn exec_in(&self, dir: &str, cmd: &str) -> Result<String> {
+        self.exec(&in_dir(dir, cmd))
+    }
+
+    /// Execute a command with `sudo` privileges in directory `dir` on the
+    /// remote host.
+    ///
+    /// The `cd` and the command run together in a root shell, as with
+    /// [`RemoteExecutor::exec_in`].
+    ///
+    /// # Arguments
+    /// * `dir` - Working directory for the command.
+    /// * `cmd` - The command string to run with `sudo`.
+    /// * `password` - The sudo password for the remote user.
+    fn sudo_in(&self, dir: &str, cmd: &str, password: &str) -> Result<String> {
+        self.sudo(&format!("sh -c {}", shell_quote(&in_dir(dir, cmd))), password)
+    }
+}
+
+/// Locale settings for remote commands, so apt, dpkg and coreutils print
+/// untranslated output that can be parsed on every target.
+pub const C_LOCALE: [(&str, &str); 2] = [("LC_ALL", "C"), ("LANG", "C")];
+
+/// `cmd` as a command line that runs with the [`C_LOCALE`].
+///
+/// ```
+/// use apt_remote::ssh::c_locale;
+///
+/// assert_eq!(c_locale("df -Pk /tmp"), "export LC_ALL=C LANG=C; df -Pk /tmp");
+/// ```
+pub fn c_locale(cmd: &str) -> String {
+    let settings = C_LOCALE.map(|(name, value)| format!("{name}={value}")).join(" ");
+    format!("export {settings}; {cmd}")
+}
+
+/// `cmd` as a command line for the remote user's login shell, which runs it
+/// with `sh -c`.
+///
+/// sshd hands every command to the login shell, which may be `fish` or
+/// `csh` rather than a POSIX shell. The quoting here means the same to all
+/// of them: single quotes, with `'` and `\` written outside the quotes.
+///
+/// ```
+/// use apt_remote::ssh::posix_sh;
+///
+/// assert_eq!(posix_sh("cd $HOME && ls"), "sh -c 'cd $HOME && ls'");
+/// assert_eq!(posix_sh(r"echo 'a\b'"), r"sh -c 'echo '\''a'\\'b'\'''");
+/// ```
+pub fn posix_sh(cmd: &str) -> String {
+    let mut quoted = String::from("sh -c '");
+    for c in cmd.chars() {
+        match c {
+            '\'' => quoted.push_str(r"'\''"),
+            '\\' => quoted.push_str(r"'\\'"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Password prompt apt-remote asks `sudo` to show, so it can tell when (and
+/// whether) `sudo` reads the password.
+pub const SUDO_PROMPT: &str = "[apt-remote] sudo password:";
+
+/// The `sudo` command line for `cmd`, reading the password from stdin.
+///
+/// The [`C_LOCALE`] is passed through `env`, as `sudo` may reset the
+/// environment.
+pub fn sudo_line(cmd: &str) -> String {
+    format!("sudo -S -p {} {}", shell_quote(SUDO_PROMPT), c_locale_env(cmd))
+}
+
+/// `cmd` run through `env` with the [`C_LOCALE`].
+fn c_locale_env(cmd: &str) -> String {
+    let settings = C_LOCALE.map(|(name, value)| format!("{name}={value}")).join(" ");
+    format!("env {settings} {cmd}")
+}
+
+/// How privileged commands become root on a target.
+///
+/// By default they run with `sudo` as the login user. On appliances where
+/// that user may not use `sudo` but a maintenance account may, `su -` to
+/// that account comes first (`--become-user`, `become_user`). Without
+/// `sudo`, or if the user may not use it, `doas` takes its place where it is
+/// installed, and `su -` to root otherwise: root's password is then asked
+/// for as the sudo password (see [`crate::secret::sudo_password`]).
+/// `--escalation` (`escalation`) picks the tool instead. `doas` and `su`
+/// need a terminal for the password, so only SSH targets use them (see
+/// [`RemoteExecutor::supports_su`]).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Escalation {
+    /// `sudo` as the login user.
+    Sudo,
+    /// `doas` as the login user.
+    Doas,
+    /// `su -` to an account, then `sudo` as that account unless it is root.
+    Su(String),
+}
+
+/// Tool privileged commands become root with (`--escalation`).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum EscalationTool {
+    /// `sudo`.
+    Sudo,
+    /// OpenBSD's `doas`.
+    Doas,
+    /// `su -` to root.
+    Su,
+}
+
+/// The account to become and the tool to become root with on `target`:
+/// from its inventory entry, the inventory's defaults or config.toml.
+pub fn escalation_settings(target: &str) -> (Option<String>, Option<EscalationTool>) {
+    match inventory::get().and_then(|inventory| inventory.endpoint(target)) {
+        Ok(endpoint) => (endpoint.become_user, endpoint.escalation),
+        Err(_) => (config::get().become_user.clone(), config::get().escalation),
+    }
+}
+
+/// How privileged commands become root on the current target.
+static ESCALATION: Mutex<Option<Escalation>> = Mutex::new(None);
+
+impl Escalation {
+    /// How privileged commands become root on the current target: as last
+    /// set with [`Escalation::set`], or as configured.
+    pub fn current() -> Escalation {
+        match ESCALATION.lock().ok().and_then(|current| current.clone()) {
+            Some(escalation) => escalation,
+            None => Escalation::from_settings(config::get().become_user.clone(), config::get().escalation),
+        }
+    }
+
+    /// How privileged commands become root on `target`, as configured for
+    /// it (see [`crate::inventory`]): an account to become comes before the
+    /// tool.
+    pub fn configured(target: &str) -> Escalation {
+        let (account, tool) = escalation_settings(target);
+        Escalation::from_settings(account, tool)
+    }
+
+    /// How privileged commands become root with the account `account` to
+    /// become and the tool `tool`.
+    fn from_settings(account: Option<String>, tool: Option<EscalationTool>) -> Escalation {
+        match (account, tool) {
+            (Some(account), _) => Escalation::Su(account),
+            (None, Some(EscalationTool::Doas)) => Escalation::Doas,
+            (None, Some(EscalationTool::Su)) => Escalation::Su("root".to_string()),
+            (None, Some(EscalationTool::Sudo) | None) => Escalation::Sudo,
+        }
+    }
+
+    /// The tool run first: `sudo`, `doas` or `su`.
+    pub fn tool(&self) -> &'static str {
+        match self {
+            Escalation::Sudo => "sudo",
+            Escalation::Doas => "doas",
+            Escalation::Su(_) => "su",
+        }
+    }
+
+    /// Make `self` how privileged commands become root from now on.
+    pub fn set(self) {
+        if let Ok(mut current) = ESCALATION.lock() {
+            *current = Some(self);
+        }
+    }
+
+    /// The account whose password is asked for: the login user `user` for
+    /// `sudo`, or the account switched to.
+    pub fn account<'a>(&'a self, user: &'a str) -> &'a str {
+        match self {
+            Escalation::Sudo | Escalation::Doas => user,
+            Escalation::Su(account) => account,
+        }
+    }
+
+    /// The command line running `cmd` as root, reading passwords from the
+    /// terminal.
+    ///
+    /// ```
+    /// use apt_remote::ssh::Escalation;
+    ///
+    /// assert_eq!(Escalation::Doas.line("apt-get clean"), "doas env LC_ALL=C LANG=C apt-get clean");
+    /// assert_eq!(Escalation::Su("root".to_string()).line("apt-get clean"), "su - 'root' -c 'env LC_ALL=C LANG=C apt-get clean'");
+    /// assert_eq!(
+    ///     Escalation::Su("maint".to_string()).line("apt-get clean"),
+    ///     r"su - 'maint' -c 'sudo -S -p '\''[apt-remote] sudo password:'\'' env LC_ALL=C LANG=C apt-get clean'",
+    /// );
+    /// ```
+    pub fn line(&self, cmd: &str) -> String {
+        match self {
+            Escalation::Sudo => sudo_line(cmd),
+            Escalation::Doas => format!("doas {}", c_locale_env(cmd)),
+            Escalation::Su(account) if account == "root" => {
+                format!("su - {} -c {}", shell_quote(account), shell_quote(&c_locale_env(cmd)))
+            }
+            Escalation::Su(account) => format!("su - {} -c {}", shell_quote(account), shell_quote(&sudo_line(cmd))),
+        }
+    }
+
+    /// The command line checking the password, or, for an empty one,
+    /// whether none is needed.
+    ///
+    /// With `sudo`, `-k` ignores cached credentials, so the password itself
+    /// is checked; `doas` has no such option, and a `persist` rule may let
+    /// a wrong password through.
+    fn check_line(&self, password: &str) -> String {
+        let sudo = if password.is_empty() {
+            "sudo -n true".to_string()
+        } else {
+            format!("sudo -S -k -v -p {}", shell_quote(SUDO_PROMPT))
+        };
+        match self {
+            Escalation::Sudo => sudo,
+            Escalation::Doas if password.is_empty() => "doas -n true".to_string(),
+            Escalation::Doas => "doas true".to_string(),
+            Escalation::Su(account) if account == "root" => format!("su - {} -c true", shell_quote(account)),
+            Escalation::Su(account) => format!("su - {} -c {}", shell_quote(account), shell_quote(&sudo)),
+        }
+    }
+
+    /// How many password prompts are answered: `su` and `sudo` both ask for
+    /// the password of the account switched to.
+    fn prompts(&self) -> usize {
+        match self {
+            Escalation::Su(account) if account != "root" => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Outcome of [`RemoteExecutor::sudo_check`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SudoCheck {
+    /// `sudo` accepted the password, or needed none.
+    Accepted,
+    /// `sudo` rejected the password (or, for an empty one, needs one).
+    WrongPassword,
+    /// The user may not use `sudo` at all, with `sudo`'s explanation.
+    Denied(String),
+}
+
+/// Messages of `sudo` refusing a user whatever the password.
+const SUDO_DENIALS: [&str; 11] = [
+    "is not in the sudoers file",
+    "may not run sudo",
+    "is not allowed to run sudo",
+    "must have a tty",
+    "a terminal is required",
+    "account validation failure",
+    "sudo: not found",
+    "sudo: command not found",
+    // `doas` without a rule permitting the user
+    "operation not permitted",
+    // `su` to an account that cannot log in
+    "does not exist",
+    "is currently not available",
+];
+
+/// Tell a refused user from a wrong password in the output of a failed
+/// `sudo` check.
+///
+/// ```
+/// use apt_remote::ssh::{SudoCheck, classify_sudo};
+///
+/// assert_eq!(classify_sudo("Sorry, try again.\nsudo: no password was provided\n"), SudoCheck::WrongPassword);
+/// assert_eq!(classify_sudo("sh: 1: sudo: not found\n"), SudoCheck::Denied("sudo is not installed".to_string()));
+/// assert_eq!(
+///     classify_sudo("sudo: sorry, you must have a tty to run sudo\n"),
+///     SudoCheck::Denied("sorry, you must have a tty to run sudo".to_string()),
+/// );
+/// ```
+pub fn classify_sudo(output: &str) -> SudoCheck {
+    output
+        .lines()
+        .map(str::trim)
+        .find(|line| {
+            let line = line.to_lowercase();
+            SUDO_DENIALS.iter().any(|denial| line.contains(denial))
+        })
+        .map_or(SudoCheck::WrongPassword, |line| match line {
+            line if line.ends_with("not found") => SudoCheck::Denied("sudo is not installed".to_string()),
+            line => SudoCheck::Denied(line.strip_prefix("sudo: ").unwrap_or(line).to_string()),
+        })
+}
+
+/// Whether the output `sudo` printed since its last prompt (or since it
+/// started) ends with a password prompt.
+///
+/// Besides [`SUDO_PROMPT`], a `passprompt_override` in the sudoers file makes
+/// `sudo` show its own prompt, such as `Password:`. Such a prompt is only
+/// taken as one before the first prompt, or after `sudo` rejected a password,
+/// since the command's own output could end the same way.
+fn is_sudo_prompt(output: &str, prompts: usize) -> bool {
+    let line = output.rsplit('\n').next().unwrap_or_default().trim();
+    if line.ends_with(SUDO_PROMPT) {
+        return true;
+    }
+    let lower = line.to_lowercase();
+    let custom = line.ends_with(':') && (lower.contains("password") || lower.contains("passphrase"));
+    custom && (prompts == 0 || output.contains("try again"))
+}
+
+/// `cmd` as a command line that first changes to directory `dir`.
+///
+/// ```
+/// use apt_remote::ssh::in_dir;
+///
+/// assert_eq!(in_dir("/tmp/apt-remote/web", "dpkg -i ./nginx.deb"), "cd '/tmp/apt-remote/web' && dpkg -i ./nginx.deb");
+/// ```
+pub fn in_dir(dir: &str, cmd: &str) -> String {
+    format!("cd {} && {cmd}", shell_quote(dir))
+}
+
+/// Quote a string for safe use as a single POSIX shell word.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
 }
 
 /// A trait for securely uploading files and directories to a remote SSH host.
@@ -106,8 +786,15 @@ pub trait SecureUpload {
     /// If `local_path` is a directory, uploads recursively.
     fn scp_upload(&self, local_path: &Path, remote_path: &Path) -> Result<()>;
 
-    /// Upload a single file to the remote host using SCP.
-    fn upload_file(&self, local_path: &Path, remote_path: &Path) -> Result<()>;
+    /// Upload a file or directory like [`SecureUpload::scp_upload`],
+    /// calling `sent` with the number of bytes of a file on the remote host
+    /// as they are written (nothing is reported for directories).
+    fn scp_upload_with_progress(&self, local_path: &Path, remote_path: &Path, sent: &dyn Fn(u64)) -> Result<()>;
+
+    /// Upload a single file to the remote host using SCP, or complete the
+    /// partial copy an interrupted upload left there over SFTP, calling
+    /// `sent` with the number of its bytes on the remote host so far.
+    fn upload_file(&self, local_path: &Path, remote_path: &Path, sent: &dyn Fn(u64)) -> Result<()>;
 
     /// Recursively upload a directory to the remote host using SFTP.
     fn upload_recursive(&self, sftp: &Sftp, local: &Path, remote: &Path) -> Result<()>;
@@ -115,67 +802,166 @@ pub trait SecureUpload {
 
 
 impl RemoteExecutor for Session {
-fn exec(&self, cmd: &str) -> Result<String> {
+    fn exec(&self, cmd: &str) -> Result<String> {
+        if dry_run::skip("run", cmd) {
+            return Ok(String::new());
+        }
+        self.query(cmd)
+    }
+
+    fn query(&self, cmd: &str) -> Result<String> {
         // Create a new SSH channel for the command
-        let mut channel = self.channel_session()?;
-        // Execute the command on the remote host
-        channel.exec(cmd)?;
+        let mut channel = self.channel_session().classify(ExitCode::Remote)?;
+        // Execute the command on the remote host, with untranslated output
+        channel.exec(&posix_sh(&c_locale(cmd))).classify(ExitCode::Remote)?;
         // Capture the command output
         let mut output = String::new();
-        channel.read_to_string(&mut output)?;
+        channel.read_to_string(&mut output).classify(ExitCode::Remote)?;
         // Wait for the command to finish
-        channel.wait_close()?;
+        channel.wait_close().classify(ExitCode::Remote)?;
+        debug!(command = cmd, exit_status = channel.exit_status()?, "Remote command");
         Ok(output)
     }
 
     fn sudo(&self, cmd: &str, password: &str) -> Result<String> {
-        // Create a new SSH channel with a pseudo-terminal (required for sudo)
-        let mut channel = self.channel_session()?;
-        channel.request_pty("xterm", None, None)?;
+        if dry_run::skip("run as root", cmd) {
+            return Ok(String::new());
+        }
+        let escalation = Escalation::current();
+        let run = run_sudo(self, &escalation.line(cmd), password, escalation.prompts())?;
+        if run.rejected {
+            return Err(anyhow!("sudo rejected the password")).classify(ExitCode::Auth);
+        }
+        Ok(run.output)
+    }
+
+    fn sudo_check(&self, password: &str) -> Result<SudoCheck> {
+        let escalation = Escalation::current();
+        let run = run_sudo(self, &escalation.check_line(password), password, escalation.prompts())?;
+        Ok(match run.status {
+            0 => SudoCheck::Accepted,
+            _ => classify_sudo(&run.output),
+        })
+    }
+
+    fn supports_su(&self) -> bool {
+        true
+    }
+}
 
-        // Format the sudo command to suppress password prompt text
-        let sudo_cmd = format!("sudo -S -p '' {cmd}");
-        channel.exec(&sudo_cmd)?;
+/// Output of a `sudo` command line run by [`run_sudo`].
+struct SudoRun {
+    /// What the command printed after `sudo` read the password.
+    output: String,
+    /// Exit status of the command line.
+    status: i32,
+    /// Whether `sudo` asked again for a password, or asked for one when none
+    /// was given.
+    rejected: bool,
+}
 
-        // Send the password to sudo
-        write!(channel, "{}\n", password)?;
-        channel.flush()?;
+/// Run a `sudo` command line, answering its password prompt with `password`.
+///
+/// The command runs on a pseudo-terminal (required by `requiretty` and by
+/// `su`) with echo turned off. The password is only written once `sudo`
+/// prompts for it (see [`is_sudo_prompt`]), so it never reaches the command
+/// itself when `sudo` needs none; a prompt beyond the first `answers` (one
+/// per `su` or `sudo` on the line), or a prompt without a password to give,
+/// is answered with end-of-file, so a wrong password fails instead of
+/// waiting for another attempt. What `sudo` printed up to its prompt (such
+/// as a lecture) is left out of the output, and the password is removed
+/// from it in case the terminal echoed it anyway.
+fn run_sudo(session: &Session, sudo_cmd: &str, password: &str, answers: usize) -> Result<SudoRun> {
+    let mut modes = PtyModes::new();
+    modes.set_boolean(PtyModeOpcode::ECHO, false);
+    let mut channel = session.channel_session().classify(ExitCode::Remote)?;
+    channel.request_pty("xterm", Some(modes), None).classify(ExitCode::Remote)?;
+    channel.exec(&posix_sh(&c_locale(sudo_cmd))).classify(ExitCode::Remote)?;
 
-        // Capture the sudo command output
-        let mut output = String::new();
-        channel.read_to_string(&mut output)?;
-        channel.wait_close()?;
-        Ok(output)
+    let mut raw = Vec::new();
+    let mut buf = [0; 4096];
+    let (mut prompts, mut start) = (0, 0);
+    loop {
+        let n = channel.read(&mut buf).classify(ExitCode::Remote)?;
+        if n == 0 {
+            break;
+        }
+        raw.extend_from_slice(&buf[..n]);
+        if is_sudo_prompt(&String::from_utf8_lossy(&raw[start..]), prompts) {
+            prompts += 1;
+            start = raw.len();
+            if prompts <= answers && !password.is_empty() {
+                writeln!(channel, "{password}").classify(ExitCode::Remote)?;
+            } else {
+                // Ctrl-D: end of input, there is no other password to give
+                channel.write_all(b"\x04").classify(ExitCode::Remote)?;
+            }
+            channel.flush().classify(ExitCode::Remote)?;
+        }
+    }
+    channel.wait_close().classify(ExitCode::Remote)?;
+    let status = channel.exit_status()?;
+    debug!(command = sudo_cmd, exit_status = status, prompts, "Remote command");
+
+    // The output of a rejected password is sudo's explanation of it; su
+    // and doas only explain, without asking again
+    let mut rejected = prompts > answers || (prompts > 0 && password.is_empty());
+    let from = if (1..=answers).contains(&prompts) { start } else { 0 };
+    let mut output = String::from_utf8_lossy(&raw[from..]).into_owned();
+    rejected |= prompts > 0
+        && output.lines().any(|line| matches!(line.trim(), "su: Authentication failure" | "doas: Authentication failed"));
+    if !password.is_empty() {
+        output = output.replace(password, "********");
     }
+    Ok(SudoRun { output, status, rejected })
 }
 
 impl SecureUpload for Session {
     fn scp_upload(&self, local_path: &Path, remote_path: &Path) -> Result<()> {
+        self.scp_upload_with_progress(local_path, remote_path, &|_| {})
+    }
+
+    fn scp_upload_with_progress(&self, local_path: &Path, remote_path: &Path, sent: &dyn Fn(u64)) -> Result<()> {
+        if dry_run::skip("upload", format!("{} -> {}", local_path.display(), remote_path.display())) {
+            return Ok(());
+        }
+
         // Start an SFTP session
-        let sftp = self.sftp().context("failed to create SFTP session")?;
+        let sftp = self
+            .sftp()
+            .context("failed to create SFTP session")
+            .classify(ExitCode::Upload)?;
 
         // Upload either a directory (recursive) or a single file
         if local_path.is_dir() {
             self.upload_recursive(&sftp, local_path, remote_path)
         } else {
-            self.upload_file(local_path, remote_path)
+            self.upload_file(local_path, remote_path, sent)
         }
+        .classify(ExitCode::Upload)
     }
 
-    fn upload_file(&self, local_path: &Path, remote_path: &Path) -> anyhow::Result<()> {
+    fn upload_file(&self, local_path: &Path, remote_path: &Path, sent: &dyn Fn(u64)) -> anyhow::Result<()> {
         // Open the local file for reading
         let mut local_file = File::open(local_path)?;
         let metadata = local_file.metadata()?;
         let file_size = metadata.len();
 
+        // A copy left by an interrupted upload is completed rather than sent again
+        if resume_upload(self, &mut local_file, file_size, local_path, remote_path, sent)? {
+            return Ok(());
+        }
+        local_file.rewind()?;
+
         // Ensure the remote file exists before SCP (touch creates it)
-        self.exec(&format!("touch {}", remote_path.to_str().unwrap()))?;
+        self.exec(&format!("touch {}", shell_quote(&remote_path.display().to_string())))?;
 
         // Open remote file for writing via SCP
+        debug!("Uploading {} to {} ({file_size} bytes)", local_path.display(), remote_path.display());
         let mut remote_file = self.scp_send(remote_path, 0o644, file_size, None)?;
 
         // Copy the local file's contents to the remote file
-        std::io::copy(&mut local_file, &mut remote_file)?;
+        send(&mut local_file, &mut remote_file, 0, sent)?;
 
         Ok(())
     }
@@ -196,9 +982,86 @@ impl SecureUpload for Session {
                 self.upload_recursive(sftp, &local_entry, &remote_entry)?;
             } else if file_type.is_file() {
                 // Upload files
-                self.upload_file(&local_entry, &remote_entry)?;
+                self.upload_file(&local_entry, &remote_entry, &|_| {})?;
             }
         }
         Ok(())
     }
 }
+
+/// Complete the upload of `local_file` (`size` bytes) to `remote_path` from
+/// where an interrupted upload stopped, and confirm the remote copy with its
+/// SHA-256 digest. Returns whether the remote file is now a full copy.
+///
+/// Nothing is resumed if the remote file is missing, empty or larger, and a
+/// copy whose digest differs or cannot be computed on the target is left to
+/// be uploaded again from the start.
+fn resume_upload(
+    session: &Session,
+    local_file: &mut File,
+    size: u64,
+    local_path: &Path,
+    remote_path: &Path,
+    sent: &dyn Fn(u64),
+) -> Result<bool> {
+    let Ok(sftp) = session.sftp() else {
+        return Ok(false);
+    };
+    let uploaded = match sftp.stat(remote_path) {
+        Ok(stat) => stat.size.unwrap_or(0),
+        Err(_) => return Ok(false),
+    };
+    if uploaded == 0 || uploaded > size {
+        return Ok(false);
+    }
+    if uploaded < size {
+        debug!("Resuming the upload of {} at {uploaded} of {size} bytes", local_path.display());
+        let mut remote_file = sftp.open_mode(remote_path, OpenFlags::WRITE, 0o644, OpenType::File)?;
+        remote_file.seek(SeekFrom::Start(uploaded))?;
+        local_file.seek(SeekFrom::Start(uploaded))?;
+        send(local_file, &mut remote_file, uploaded, sent)?;
+        remote_file.fsync().ok();
+    } else {
+        sent(size);
+    }
+
+    let remote = shell_quote(&remote_path.display().to_string());
+    let output = session.query(&Tools::first_found(&ChecksumKind::SHA256, &remote))?;
+    let complete = parse_digest(&output, &ChecksumKind::SHA256) == Some(archive::sha256_file(local_path)?);
+    if !complete {
+        debug!("The partial upload of {} does not match, sending it again", local_path.display());
+    }
+    Ok(complete)
+}
+
+/// Copy the rest of `local_file` to `remote`, at the configured rate limit
+/// (see [`crate::throttle`]), calling `sent` with the bytes on the remote
+/// host after each write, the first `offset` of them already there.
+fn send(local_file: &mut File, remote: &mut impl Write, offset: u64, sent: &dyn Fn(u64)) -> std::io::Result<u64> {
+    sent(offset);
+    let mut remote = Reporting { inner: remote, sent, written: offset };
+    match config::get().ssh.limit_rate {
+        Some(rate) => std::io::copy(local_file, &mut Throttled::new(&mut remote, rate)),
+        None => std::io::copy(local_file, &mut remote),
+    }
+}
+
+/// A writer calling `sent` with the number of bytes written through it.
+struct Reporting<'a, W> {
+    inner: W,
+    sent: &'a dyn Fn(u64),
+    written: u64,
+}
+
+impl<W: Write> Write for Reporting<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        (self.sent)(self.written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
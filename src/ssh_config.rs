@@ -0,0 +1,287 @@
+//! # OpenSSH client configuration
+//!
+//! Hosts already set up for `ssh mydevice` need no inventory entry: their
+//! `Host` blocks in `~/.ssh/config` (or the file named by `config` in the
+//! `[ssh]` table of `config.toml`) are read when a target is resolved, so
+//! `apt-remote install <NAME> --target mydevice` connects as `ssh` would:
+//!
+//! ```text
+//! Host mydevice
+//!     HostName 10.0.0.42
+//!     User admin
+//!     Port 2222
+//!     IdentityFile ~/.ssh/mydevice_ed25519
+//! ```
+//!
+//! `HostName`, `User`, `Port`, `IdentityFile` and `ProxyJump` are used, with `Include`
+//! and the `*`, `?` and `!` patterns of `Host` lines; as in OpenSSH, the
+//! first value found for the host wins. Other keywords, and `Match`
+//! blocks, are ignored. The inventory's settings for a host come before
+//! those of the OpenSSH configuration (see [`crate::inventory`]).
+
+use crate::{
+    config,
+    exit::{Classify, ExitCode},
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// OpenSSH configuration loaded on first use.
+static CONFIG: OnceLock<OpenSshConfig> = OnceLock::new();
+
+/// Deepest nesting of `Include` directives followed, as in OpenSSH.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Settings of the OpenSSH configuration for one host.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostConfig {
+    /// Address to connect to (`HostName`).
+    pub host_name: Option<String>,
+    /// SSH user name (`User`).
+    pub user: Option<String>,
+    /// TCP port of the SSH server (`Port`).
+    pub port: Option<u16>,
+    /// First private key file (`IdentityFile`).
+    pub identity: Option<PathBuf>,
+    /// Jump hosts, comma-separated (`ProxyJump`, `none` for none).
+    pub proxy_jump: Option<String>,
+}
+
+/// A `Host` block: its patterns, and its settings in order.
+#[derive(Debug, Clone)]
+struct Block {
+    /// Patterns of the `Host` line (`None` for a `Match` block, never applied).
+    patterns: Option<Vec<String>>,
+    /// Lower-cased keywords and their values.
+    options: Vec<(String, String)>,
+}
+
+/// The `Host` blocks of an OpenSSH client configuration.
+#[derive(Debug, Clone, Default)]
+pub struct OpenSshConfig {
+    blocks: Vec<Block>,
+}
+
+impl OpenSshConfig {
+    /// Default location of the OpenSSH client configuration.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::home_dir().map(|home| home.join(".ssh").join("config"))
+    }
+
+    /// Load the configuration file at `path`, following its `Include`s.
+    ///
+    /// # Errors
+    /// Returns an error if the file or an included file cannot be read, or
+    /// `Include` directives nest too deeply.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut config = OpenSshConfig::default();
+        config.include(path, 0)?;
+        Ok(config)
+    }
+
+    /// Parse a configuration. `Include` directives are read relative to
+    /// `~/.ssh`.
+    ///
+    /// ```
+    /// use apt_remote::ssh_config::OpenSshConfig;
+    ///
+    /// let config = OpenSshConfig::parse("Host kiosk-* !kiosk-9\n  User ops\n  Port=2222\n\nHost *\n  User root\n  HostName %h.fleet.example\n").unwrap();
+    /// let host = config.host("kiosk-1");
+    /// assert_eq!(host.user.as_deref(), Some("ops"));
+    /// assert_eq!(host.port, Some(2222));
+    /// assert_eq!(host.host_name.as_deref(), Some("kiosk-1.fleet.example"));
+    /// assert_eq!(config.host("kiosk-9").user.as_deref(), Some("root"));
+    /// assert!(OpenSshConfig::parse("Host a\n  Port ssh\n").is_err());
+    /// ```
+    ///
+    /// # Errors
+    /// Returns an error if a `Port` is not a number, or an included file
+    /// cannot be read.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut config = OpenSshConfig::default();
+        config.read(content, 0)?;
+        Ok(config)
+    }
+
+    /// Read the file at `path` into the configuration.
+    fn include(&mut self, path: &Path, depth: usize) -> Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(anyhow!("Include directives of {} nest too deeply", path.display())).classify(ExitCode::Config);
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+            .classify(ExitCode::Config)?;
+        self.read(&content, depth)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Read the lines of a configuration file into the configuration.
+    fn read(&mut self, content: &str, depth: usize) -> Result<()> {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // "Keyword value" or "Keyword=value"
+            let (keyword, value) = line.split_once(|c: char| c.is_whitespace() || c == '=').unwrap_or((line, ""));
+            let value = value.trim_start_matches(|c: char| c.is_whitespace() || c == '=').trim();
+            let keyword = keyword.to_ascii_lowercase();
+            match keyword.as_str() {
+                "host" => {
+                    let patterns = value.split_whitespace().map(unquote).collect();
+                    self.blocks.push(Block { patterns: Some(patterns), options: Vec::new() });
+                }
+                "match" => self.blocks.push(Block { patterns: None, options: Vec::new() }),
+                "include" => {
+                    for pattern in value.split_whitespace().map(unquote) {
+                        for path in included(&pattern) {
+                            self.include(&path, depth + 1)?;
+                        }
+                    }
+                }
+                _ => {
+                    if keyword == "port" && unquote(value).parse::<u16>().is_err() {
+                        return Err(anyhow!("Invalid Port '{value}'")).classify(ExitCode::Config);
+                    }
+                    // Settings before the first Host line apply to every host
+                    if self.blocks.is_empty() {
+                        self.blocks.push(Block { patterns: Some(vec!["*".to_string()]), options: Vec::new() });
+                    }
+                    let block = self.blocks.last_mut().unwrap();
+                    block.options.push((keyword, unquote(value)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The settings for the host named `alias`.
+    pub fn host(&self, alias: &str) -> HostConfig {
+        let mut host = HostConfig::default();
+        let blocks = self.blocks.iter().filter(|block| block.patterns.as_ref().is_some_and(|patterns| matches(patterns, alias)));
+        for (keyword, value) in blocks.flat_map(|block| &block.options) {
+            match keyword.as_str() {
+                "hostname" if host.host_name.is_none() => host.host_name = Some(value.replace("%h", alias)),
+                "user" if host.user.is_none() => host.user = Some(value.clone()),
+                "port" if host.port.is_none() => host.port = value.parse().ok(),
+                "identityfile" if host.identity.is_none() => host.identity = Some(expand(value, alias)),
+                "proxyjump" if host.proxy_jump.is_none() => host.proxy_jump = Some(value.clone()),
+                _ => {}
+            }
+        }
+        host
+    }
+}
+
+/// Whether the patterns of a `Host` line match `alias`: one pattern matches it,
+/// and no negated pattern does.
+fn matches(patterns: &[String], alias: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard(negated, alias) => return false,
+            Some(_) => {}
+            None => matched |= wildcard(pattern, alias),
+        }
+    }
+    matched
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any characters
+/// and `?` for one.
+///
+/// ```
+/// use apt_remote::ssh_config::wildcard;
+///
+/// assert!(wildcard("kiosk-?", "kiosk-1"));
+/// assert!(wildcard("*.example.com", "db.example.com"));
+/// assert!(!wildcard("kiosk-?", "kiosk-10"));
+/// ```
+pub fn wildcard(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and the text position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Strip the double quotes around a value.
+fn unquote(value: &str) -> String {
+    value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value).to_string()
+}
+
+/// Expand `~`, `%d` (the home directory) and `%h` (the host) in a path.
+fn expand(value: &str, alias: &str) -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_default().display().to_string();
+    let value = match value.strip_prefix('~') {
+        Some(rest) => format!("{home}{rest}"),
+        None => value.to_string(),
+    };
+    PathBuf::from(value.replace("%d", &home).replace("%h", alias))
+}
+
+/// The files an `Include` pattern names, relative to `~/.ssh`, with a
+/// wildcard allowed in the file name.
+fn included(pattern: &str) -> Vec<PathBuf> {
+    let path = expand(pattern, "");
+    let path = if path.is_absolute() { path } else { dirs::home_dir().unwrap_or_default().join(".ssh").join(path) };
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return if path.is_file() { vec![path] } else { Vec::new() };
+    }
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let mut paths = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| wildcard(&name, &entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+/// The configured OpenSSH client configuration (empty if there is none).
+///
+/// # Errors
+/// Returns an error if the file exists but cannot be read or parsed.
+pub fn get() -> Result<&'static OpenSshConfig> {
+    if let Some(config) = CONFIG.get() {
+        return Ok(config);
+    }
+    let config = match &config::get().ssh.config {
+        Some(path) => OpenSshConfig::load(&expand(&path.to_string_lossy(), ""))?,
+        None => match OpenSshConfig::default_path() {
+            Some(path) if path.is_file() => OpenSshConfig::load(&path)?,
+            _ => OpenSshConfig::default(),
+        },
+    };
+    Ok(CONFIG.get_or_init(|| config))
+}
@@ -0,0 +1,81 @@
+//! # Rootless staging
+//!
+//! Some targets only grant an unprivileged account. With `--rootless`
+//! (`rootless = true`), `install` and `update` never run `sudo`: they upload
+//! the packages or lists into `~/apt-remote/<NAME>` on the target, verify
+//! them there, and add the same `install.sh` that `apt-remote export
+//! --installer` writes. A local administrator then applies the image with
+//!
+//! ```sh
+//! sudo sh ~user/apt-remote/<NAME>/install.sh
+//! ```
+//!
+//! Staged packages are not recorded as installed, since apt-remote cannot
+//! tell whether the script was run.
+
+use crate::{
+    commands::export::{INSTALLER_NAME, installer_script},
+    config, output,
+    ssh::shell_quote,
+    theme,
+    transport::Transport,
+    uri::UriFile,
+};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde_json::json;
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// Whether `install` and `update` stage files instead of using `sudo`.
+pub fn enabled() -> bool {
+    config::get().rootless
+}
+
+/// Directory of image `name` in the home directory of the remote user.
+///
+/// # Errors
+/// Returns an error if the remote home directory cannot be determined.
+pub fn dir(session: &dyn Transport, name: &str) -> Result<String> {
+    let home = session.query("printf %s \"$HOME\"")?;
+    let home = home.trim();
+    if home.is_empty() {
+        return Err(anyhow::anyhow!("Cannot determine the home directory on the target"));
+    }
+    Ok(format!("{home}/apt-remote/{name}"))
+}
+
+/// Upload the installer of `uri_file` into `stage_dir` and tell the user how
+/// to have it run. `lists` are the digests of the package lists of an
+/// update-mode image, by file name. Returns the installer's path
+/// on the target.
+///
+/// # Errors
+/// Returns an error if the installer cannot be written or uploaded.
+pub fn finish(
+    session: &dyn Transport,
+    name: &str,
+    target: &str,
+    uri_file: &UriFile,
+    lists: &BTreeMap<String, String>,
+    stage_dir: &str,
+) -> Result<String> {
+    let script = format!("{stage_dir}/{INSTALLER_NAME}");
+    let local = std::env::temp_dir().join(format!("apt-remote-{}-{INSTALLER_NAME}", std::process::id()));
+    fs::write(&local, installer_script(name, uri_file, lists))
+        .with_context(|| format!("Failed to write {}", local.display()))?;
+    let uploaded = session.upload(&local, Path::new(&script));
+    fs::remove_file(&local).ok();
+    uploaded?;
+    session.exec(&format!("chmod 755 {}", shell_quote(&script)))?;
+
+    output::print(format!(
+        "{} {}",
+        theme::success("✓").bold(),
+        theme::success(format!("Staged {name} in {stage_dir} on {target}"))
+    ));
+    output::print(format!("\tAsk an administrator of {target} to run: sudo sh {script}\n"));
+    output::emit("staged", json!({ "name": name, "target": target, "path": stage_dir, "installer": script }));
+    Ok(script)
+}
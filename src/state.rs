@@ -0,0 +1,130 @@
+//! # Per-image state for apt-remote
+//!
+//! Each cache image keeps a `state.toml` next to its `uri.toml`, recording
+//! what has been done with it: which files are in the local cache, and for
+//! every target which packages have been uploaded, verified and installed.
+//!
+//! ```toml
+//! [files]
+//! "curl_7.88.1-10_amd64.deb" = "downloaded"
+//!
+//! [fetched]
+//! "curl_7.88.1-10_amd64.deb" = "2a6bb1e1c5f1a2b5…"
+//!
+//! [targets."admin@kiosk"]
+//! uploaded = []
+//! verified = []
+//! installed = ["curl_7.88.1-10_amd64.deb"]
+//! last_run = 1760457600
+//! ```
+//!
+//! `install` uses it to skip packages already installed on a target and to
+//! resume interrupted uploads, and `apt-remote status` reports it. `set`
+//! forgets entries for files that are no longer part of the plan, and `get`
+//! uses `fetched` to tell which cached files a regenerated plan changed.
+
+use crate::{dry_run, uri::UriFile};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Representation of an image's `state.toml`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageState {
+    /// Files in the local cache, with how they were obtained
+    /// (`downloaded`, `pooled`, `adopted` or `mirrored`).
+    pub files: BTreeMap<String, String>,
+    /// Checksum each cached file was fetched for (its URI if the plan has
+    /// no checksum for it).
+    pub fetched: BTreeMap<String, String>,
+    /// Progress on each target, keyed by `user@host`.
+    pub targets: BTreeMap<String, TargetState>,
+}
+
+/// What has been done with an image on one target.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TargetState {
+    /// Files uploaded to the target's temporary directory.
+    pub uploaded: BTreeSet<String>,
+    /// Uploaded files whose checksum was verified on the target.
+    pub verified: BTreeSet<String>,
+    /// Packages installed on the target.
+    pub installed: BTreeSet<String>,
+    /// Unix time at which the target's package lists were last replaced.
+    pub lists_updated: Option<u64>,
+    /// Unix time of the last `install` or `update` run against the target.
+    pub last_run: Option<u64>,
+}
+
+impl ImageState {
+    /// Load the state of the image stored in `dir` (empty if there is none).
+    ///
+    /// # Errors
+    /// Returns an error if an existing `state.toml` cannot be read or parsed.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join("state.toml");
+        if !path.is_file() {
+            return Ok(ImageState::default());
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Failed to parse TOML from {}", path.display()))
+    }
+
+    /// Save the state of the image stored in `dir`.
+    ///
+    /// # Errors
+    /// Returns an error if serialization fails or the file cannot be written.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        // Nothing was done in a dry run, so there is nothing to record
+        if dry_run::enabled() {
+            return Ok(());
+        }
+        let path = dir.join("state.toml");
+        let toml_str = toml::to_string(self).context("Failed to serialize ImageState to TOML")?;
+        fs::write(&path, toml_str).with_context(|| format!("Failed to write to {}", path.display()))
+    }
+
+    /// The state of `target`, created if the image was never used on it.
+    pub fn target(&mut self, target: &str) -> &mut TargetState {
+        self.targets.entry(target.to_string()).or_default()
+    }
+
+    /// Forget every file that is not part of `uri_file`.
+    pub fn retain_plan(&mut self, uri_file: &UriFile) {
+        self.files.retain(|fname, _| uri_file.packages.contains_key(fname));
+        self.fetched.retain(|fname, _| uri_file.packages.contains_key(fname));
+        for target in self.targets.values_mut() {
+            target.uploaded.retain(|fname| uri_file.packages.contains_key(fname));
+            target.verified.retain(|fname| uri_file.packages.contains_key(fname));
+            target.installed.retain(|fname| uri_file.packages.contains_key(fname));
+        }
+    }
+
+    /// Forget a cached file that is about to be replaced, and its uploads.
+    pub fn forget_file(&mut self, fname: &str) {
+        self.files.remove(fname);
+        self.fetched.remove(fname);
+        for target in self.targets.values_mut() {
+            target.uploaded.remove(fname);
+            target.verified.remove(fname);
+        }
+    }
+}
+
+/// The current Unix time in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
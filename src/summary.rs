@@ -0,0 +1,203 @@
+//! # End-of-run summary for apt-remote
+//!
+//! Subcommands record what they did while they run: bytes transferred,
+//! how long each phase took, and how many files succeeded, failed or were
+//! skipped. The CLI prints the totals once the command has finished (with
+//! `--output json`, as a `summary` event):
+//!
+//! ```text
+//! 12 succeeded, 1 failed, 3 skipped · 45.2 MB downloaded · 0 B uploaded · 12.8s
+//!     download 12.3s, pool 0.5s
+//! ```
+//!
+//! Recording is cheap and thread-safe, so parallel downloads can record
+//! directly.
+
+use crate::{commands::set::format_size, output, theme, tr};
+
+use colored::Colorize;
+use serde_json::{Value, json};
+
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// Bytes received over the network.
+static DOWNLOADED: AtomicU64 = AtomicU64::new(0);
+/// Bytes sent to remote hosts.
+static UPLOADED: AtomicU64 = AtomicU64::new(0);
+/// Files or packages processed successfully.
+static SUCCEEDED: AtomicUsize = AtomicUsize::new(0);
+/// Files or packages that failed.
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+/// Files or packages that needed no work.
+static SKIPPED: AtomicUsize = AtomicUsize::new(0);
+/// Completed phases in order, with their duration.
+static PHASES: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+
+/// Outcome of processing one file or package.
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    /// Processed successfully.
+    Succeeded,
+    /// Processing failed.
+    Failed,
+    /// Nothing had to be done.
+    Skipped,
+}
+
+/// A running phase, recorded when dropped.
+pub struct Phase {
+    name: &'static str,
+    start: Instant,
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        if let Ok(mut phases) = PHASES.lock() {
+            phases.push((self.name, self.start.elapsed()));
+        }
+    }
+}
+
+/// Start timing a phase; it ends when the returned guard is dropped.
+pub fn phase(name: &'static str) -> Phase {
+    Phase {
+        name,
+        start: Instant::now(),
+    }
+}
+
+/// Record bytes received over the network.
+pub fn downloaded(bytes: u64) {
+    DOWNLOADED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Record bytes sent to a remote host.
+pub fn uploaded(bytes: u64) {
+    UPLOADED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Record the outcome of processing one file or package.
+pub fn record(outcome: Outcome) {
+    let counter = match outcome {
+        Outcome::Succeeded => &SUCCEEDED,
+        Outcome::Failed => &FAILED,
+        Outcome::Skipped => &SKIPPED,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The phases completed so far, in order, with their duration.
+pub fn phases() -> Vec<(&'static str, Duration)> {
+    PHASES.lock().map(|phases| phases.clone()).unwrap_or_default()
+}
+
+/// Totals recorded so far.
+#[derive(Debug, Clone)]
+pub struct Totals {
+    /// Files or packages processed successfully.
+    pub succeeded: usize,
+    /// Files or packages that failed.
+    pub failed: usize,
+    /// Files or packages that needed no work.
+    pub skipped: usize,
+    /// Bytes received over the network.
+    pub downloaded: u64,
+    /// Bytes sent to remote hosts.
+    pub uploaded: u64,
+    /// Total run time of the command.
+    pub elapsed: Duration,
+    /// Completed phases in order, with their duration.
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl Totals {
+    /// What has been recorded, for a command that ran for `elapsed`.
+    pub fn now(elapsed: Duration) -> Totals {
+        Totals {
+            succeeded: SUCCEEDED.load(Ordering::Relaxed),
+            failed: FAILED.load(Ordering::Relaxed),
+            skipped: SKIPPED.load(Ordering::Relaxed),
+            downloaded: DOWNLOADED.load(Ordering::Relaxed),
+            uploaded: UPLOADED.load(Ordering::Relaxed),
+            elapsed,
+            phases: phases(),
+        }
+    }
+
+    /// Whether nothing was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.phases.is_empty() && self.succeeded + self.failed + self.skipped == 0
+    }
+
+    /// The file counts, such as `12 succeeded, 1 failed, 3 skipped`.
+    pub fn counts(&self) -> String {
+        tr!(
+            "{succeeded} succeeded, {failed} failed, {skipped} skipped",
+            succeeded = self.succeeded,
+            failed = self.failed,
+            skipped = self.skipped
+        )
+    }
+
+    /// Transfer sizes and run time, such as `45.2 MB downloaded · 0 B uploaded · 12.8s`.
+    pub fn transfers(&self) -> String {
+        format!(
+            "{} · {} · {:.1}s",
+            tr!("{size} downloaded", size = format_size(self.downloaded)),
+            tr!("{size} uploaded", size = format_size(self.uploaded)),
+            self.elapsed.as_secs_f64()
+        )
+    }
+
+    /// The duration of each phase, such as `download 12.3s, pool 0.5s`.
+    pub fn phase_times(&self) -> String {
+        let times = self
+            .phases
+            .iter()
+            .map(|(name, time)| format!("{name} {:.1}s", time.as_secs_f64()))
+            .collect::<Vec<_>>();
+        times.join(", ")
+    }
+
+    /// The totals as the fields of a `summary` event.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "succeeded": self.succeeded,
+            "failed": self.failed,
+            "skipped": self.skipped,
+            "downloaded_bytes": self.downloaded,
+            "uploaded_bytes": self.uploaded,
+            "elapsed_ms": self.elapsed.as_millis() as u64,
+            "phases": self
+                .phases
+                .iter()
+                .map(|(name, time)| json!({ "name": name, "elapsed_ms": time.as_millis() as u64 }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Print the summary of the run, if anything was recorded.
+///
+/// # Arguments
+/// * `elapsed` - Total run time of the command.
+pub fn report(elapsed: Duration) {
+    let totals = Totals::now(elapsed);
+    if totals.is_empty() {
+        return;
+    }
+
+    let counts = if totals.failed > 0 { theme::failure(totals.counts()) } else { theme::success(totals.counts()) };
+    output::print(format!("{counts} · {}", totals.transfers()));
+    if !totals.phases.is_empty() {
+        output::print(format!("\t{}", totals.phase_times().dimmed()));
+    }
+
+    output::emit("summary", totals.to_json());
+}
@@ -0,0 +1,142 @@
+//! # Output theme for apt-remote
+//!
+//! Human-readable output uses four roles instead of hardcoded colors:
+//! `accent` for steps in progress and progress bars, `success` and
+//! `failure` for results, and `warning` for things that could not be
+//! determined. Each role can be set to another color, or to `none` to keep
+//! that text unstyled, in the `[theme]` table of `config.toml`:
+//!
+//! ```toml
+//! [theme]
+//! accent = "blue"
+//! success = "green"
+//! failure = "magenta"
+//! warning = "none"
+//! ```
+//!
+//! Colors are turned off entirely with `--color never`, with a non-empty
+//! `NO_COLOR` environment variable (unless `--color always` is given), when
+//! stdout is not a terminal, and in JSON mode.
+
+use crate::config;
+
+use colored::{Color, ColoredString, Colorize};
+use serde::{Deserialize, Serialize};
+
+/// A color usable in the theme.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThemeColor {
+    /// Leave the text unstyled.
+    None,
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl ThemeColor {
+    /// The corresponding terminal color.
+    fn color(self) -> Option<Color> {
+        match self {
+            ThemeColor::None => None,
+            ThemeColor::Black => Some(Color::Black),
+            ThemeColor::Red => Some(Color::Red),
+            ThemeColor::Green => Some(Color::Green),
+            ThemeColor::Yellow => Some(Color::Yellow),
+            ThemeColor::Blue => Some(Color::Blue),
+            ThemeColor::Magenta => Some(Color::Magenta),
+            ThemeColor::Cyan => Some(Color::Cyan),
+            ThemeColor::White => Some(Color::White),
+        }
+    }
+
+    /// The color's name in an `indicatif` template style.
+    fn style_name(self) -> &'static str {
+        match self {
+            ThemeColor::None => "",
+            ThemeColor::Black => "black",
+            ThemeColor::Red => "red",
+            ThemeColor::Green => "green",
+            ThemeColor::Yellow => "yellow",
+            ThemeColor::Blue => "blue",
+            ThemeColor::Magenta => "magenta",
+            ThemeColor::Cyan => "cyan",
+            ThemeColor::White => "white",
+        }
+    }
+}
+
+/// Colors of the output roles.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    /// Steps in progress and progress bars.
+    pub accent: ThemeColor,
+    /// Successful results.
+    pub success: ThemeColor,
+    /// Failed results.
+    pub failure: ThemeColor,
+    /// Results that could not be determined.
+    pub warning: ThemeColor,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            accent: ThemeColor::Cyan,
+            success: ThemeColor::Green,
+            failure: ThemeColor::Red,
+            warning: ThemeColor::Yellow,
+        }
+    }
+}
+
+/// Whether output is colorized in this process.
+pub fn enabled() -> bool {
+    colored::control::SHOULD_COLORIZE.should_colorize()
+}
+
+/// Style `text` in `color`.
+fn paint(text: &str, color: ThemeColor) -> ColoredString {
+    match color.color() {
+        Some(color) => text.color(color),
+        None => text.normal(),
+    }
+}
+
+/// Style `text` as a step in progress.
+pub fn accent(text: impl AsRef<str>) -> ColoredString {
+    paint(text.as_ref(), config::get().theme.accent)
+}
+
+/// Style `text` as a successful result.
+pub fn success(text: impl AsRef<str>) -> ColoredString {
+    paint(text.as_ref(), config::get().theme.success)
+}
+
+/// Style `text` as a failed result.
+pub fn failure(text: impl AsRef<str>) -> ColoredString {
+    paint(text.as_ref(), config::get().theme.failure)
+}
+
+/// Style `text` as a result that could not be determined.
+pub fn warning(text: impl AsRef<str>) -> ColoredString {
+    paint(text.as_ref(), config::get().theme.warning)
+}
+
+/// Adapt an `indicatif` template to the theme.
+///
+/// Styles in the template name the accent role (`{spinner:.bold.accent}`),
+/// which is replaced by the configured accent color, or dropped when the
+/// accent is `none`.
+pub fn template(template: &str) -> String {
+    match config::get().theme.accent {
+        ThemeColor::None => template.replace(".accent", ""),
+        color => template.replace(".accent", &format!(".{}", color.style_name())),
+    }
+}
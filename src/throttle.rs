@@ -0,0 +1,119 @@
+//! # Upload bandwidth limit
+//!
+//! Devices sharing a constrained uplink should not have it filled by an
+//! install. `--limit-rate 500k` (or `limit_rate` in the `[ssh]` table of
+//! `config.toml`) caps the rate at which files are uploaded to SSH targets,
+//! in bytes per second with an optional `k`, `M` or `G` suffix (powers of
+//! 1024), as `curl --limit-rate` does:
+//!
+//! ```toml
+//! [ssh]
+//! limit_rate = "500k"
+//! ```
+//!
+//! Uploads through the built-in client are paced with [`Throttled`], which
+//! shares the limit between the files `install` uploads at once (see
+//! `ssh.upload_jobs`); through the OpenSSH client (see [`crate::openssh`]),
+//! each `scp -l` is given its share of the limit. Commands and their output
+//! are not limited.
+
+use serde::{Deserialize, Deserializer};
+
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// When the bytes written so far by every [`Throttled`] writer are due, so
+/// that concurrent uploads share the rate.
+static SCHEDULE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Parse a rate in bytes per second, such as `500k` or `2M`.
+///
+/// ```
+/// use apt_remote::throttle::parse_rate;
+///
+/// assert_eq!(parse_rate("500k"), Ok(512_000));
+/// assert_eq!(parse_rate("2M"), Ok(2 * 1024 * 1024));
+/// assert_eq!(parse_rate("1500"), Ok(1500));
+/// assert!(parse_rate("0").is_err());
+/// assert!(parse_rate("fast").is_err());
+/// ```
+///
+/// # Errors
+/// Returns an error if `rate` is not a positive number with an optional
+/// `k`, `M` or `G` suffix.
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    let rate = rate.trim();
+    let (number, unit) = match rate.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&rate[..i], c.to_ascii_lowercase()),
+        _ => (rate, 'b'),
+    };
+    let scale: u64 = match unit {
+        'b' => 1,
+        'k' => 1024,
+        'm' => 1024 * 1024,
+        'g' => 1024 * 1024 * 1024,
+        _ => return Err(format!("'{rate}' is not a rate: use bytes per second, with k, M or G")),
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(number.saturating_mul(scale)),
+        _ => Err(format!("'{rate}' is not a rate: use bytes per second, with k, M or G")),
+    }
+}
+
+/// Deserialize a rate given as a number of bytes per second or as a string
+/// for [`parse_rate`].
+pub(crate) fn deserialize_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Rate {
+        Bytes(u64),
+        Text(String),
+    }
+    match Option::<Rate>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Rate::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Rate::Text(text)) => parse_rate(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// A writer that writes at most `rate` bytes per second on average,
+/// together with the other `Throttled` writers in use.
+pub struct Throttled<W> {
+    inner: W,
+    rate: u64,
+}
+
+impl<W: Write> Throttled<W> {
+    /// Pace the writes to `inner` to `rate` bytes per second.
+    pub fn new(inner: W, rate: u64) -> Throttled<W> {
+        Throttled { inner, rate: rate.max(1) }
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Large buffers are written in slices of a tenth of a second, so the pace stays even
+        let slice = (self.rate / 10).max(1) as usize;
+        let written = self.inner.write(&buf[..buf.len().min(slice)])?;
+
+        // The slice is due after everything written before it, by any writer
+        let now = Instant::now();
+        let due = {
+            let mut schedule = SCHEDULE.lock().unwrap_or_else(|e| e.into_inner());
+            let start = schedule.filter(|due| *due > now).unwrap_or(now);
+            let due = start + Duration::from_secs_f64(written as f64 / self.rate as f64);
+            *schedule = Some(due);
+            due
+        };
+        thread::sleep(due - now);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,117 @@
+//! # TLS settings for HTTPS mirrors
+//!
+//! Mirrors behind a private CA, or requiring mutual TLS, are reached by
+//! configuring the HTTP client used by `get`, `check` and `mirror`:
+//!
+//! ```toml
+//! [tls]
+//! ca_bundle = "/etc/ssl/certs/internal-ca.pem"   # trusted besides the system CAs
+//! client_cert = "/etc/apt-remote/client.pem"     # certificate (and chain) to present
+//! client_key = "/etc/apt-remote/client.key"      # default: the key in client_cert
+//! ```
+//!
+//! The same settings are available as `--ca-bundle`, `--client-cert` and
+//! `--client-key`. The private key may be a PKCS#8, PKCS#1 (RSA) or SEC1
+//! (EC) PEM key; it must not be encrypted.
+
+use crate::{
+    config,
+    exit::{Classify, ExitCode},
+};
+
+use anyhow::{Context, Result, anyhow};
+use openssl::{pkey::PKey, x509::X509};
+use reqwest::{Certificate, Identity};
+
+use std::{fs, path::Path};
+
+/// CA certificates and client identity loaded from the `[tls]` settings.
+#[derive(Default)]
+pub struct Tls {
+    /// Additional trusted CA certificates.
+    pub roots: Vec<Certificate>,
+    /// Client certificate and key presented to the server.
+    pub identity: Option<Identity>,
+}
+
+/// Load the configured CA bundle and client certificate.
+///
+/// # Errors
+/// Returns an error if a configured file cannot be read or holds no
+/// usable certificate or key, or if a client key is given without a client
+/// certificate.
+pub fn load() -> Result<Tls> {
+    let settings = &config::get().tls;
+    let mut tls = Tls::default();
+
+    if let Some(path) = &settings.ca_bundle {
+        tls.roots = Certificate::from_pem_bundle(&read(path)?)
+            .with_context(|| format!("{} is not a PEM certificate bundle", path.display()))
+            .classify(ExitCode::Config)?;
+        if tls.roots.is_empty() {
+            return Err(anyhow!("{} holds no certificate", path.display())).classify(ExitCode::Config);
+        }
+    }
+
+    match (&settings.client_cert, &settings.client_key) {
+        (Some(cert_path), key_path) => {
+            let key_path = key_path.as_deref().unwrap_or(cert_path);
+            tls.identity = Some(identity(cert_path, key_path).classify(ExitCode::Config)?);
+        }
+        (None, Some(_)) => {
+            return Err(anyhow!("client_key is set without client_cert")).classify(ExitCode::Config);
+        }
+        (None, None) => {}
+    }
+    Ok(tls)
+}
+
+/// Client identity from a certificate chain and a private key, both PEM.
+fn identity(cert_path: &Path, key_path: &Path) -> Result<Identity> {
+    let chain = X509::stack_from_pem(&read(cert_path)?)
+        .with_context(|| format!("{} is not a PEM certificate", cert_path.display()))?;
+    if chain.is_empty() {
+        return Err(anyhow!("{} holds no certificate", cert_path.display()));
+    }
+    let mut cert_pem = Vec::new();
+    for cert in &chain {
+        cert_pem.extend(cert.to_pem()?);
+    }
+
+    // The TLS backend only takes PKCS#8 keys, so convert other formats
+    let key = PKey::private_key_from_pem(&read(key_path)?)
+        .with_context(|| format!("{} holds no unencrypted PEM private key", key_path.display()))?;
+    Identity::from_pkcs8_pem(&cert_pem, &key.private_key_to_pem_pkcs8()?)
+        .with_context(|| format!("{} and {} do not form a client identity", cert_path.display(), key_path.display()))
+}
+
+/// Read a configured PEM file.
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path)
+        .with_context(|| format!("Failed to read {}", path.display()))
+        .classify(ExitCode::Config)
+}
+
+impl Tls {
+    /// Apply the settings to a client for `tokio` downloads.
+    pub fn client(self, mut builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        for root in self.roots {
+            builder = builder.add_root_certificate(root);
+        }
+        if let Some(identity) = self.identity {
+            builder = builder.identity(identity);
+        }
+        builder
+    }
+
+    /// Apply the settings to a blocking client.
+    pub fn blocking(self, mut builder: reqwest::blocking::ClientBuilder) -> reqwest::blocking::ClientBuilder {
+        for root in self.roots {
+            builder = builder.add_root_certificate(root);
+        }
+        if let Some(identity) = self.identity {
+            builder = builder.identity(identity);
+        }
+        builder
+    }
+}
@@ -0,0 +1,519 @@
+//! # Transports for apt-remote
+//!
+//! Subcommands reach the target system through a [`Transport`]: a
+//! [`RemoteExecutor`] that can also upload files. The backend is selected
+//! from the form of the target:
+//!
+//! | Target                           | Backend                                  |
+//! |----------------------------------|------------------------------------------|
+//! | `user@host`, `ssh://user@host`   | SSH ([`ReconnectingSession`])            |
+//! | the same, with `ssh.gssapi`      | SSH with Kerberos ([`OpenSshTransport`]) |
+//! | `local://`, `local`              | This machine ([`LocalTransport`])        |
+//! | `docker://name`, `podman://name` | A container ([`ContainerTransport`])     |
+//! | `lxd://instance`                 | An LXD instance ([`ContainerTransport`]) |
+//! | `chroot:/mnt/rootfs`             | A root filesystem ([`ChrootTransport`])  |
+//! | `serial:/dev/ttyUSB0?baud=9600`  | A serial console ([`SerialTransport`])   |
+//! | `mock:session.json`              | A recorded session (`mock` feature)      |
+//!
+//! The local backend runs commands with `sh -c` and copies files, which is
+//! useful to prepare the machine apt-remote runs on, or to test a routine
+//! before pointing it at a real target. The container backend does the same
+//! inside a running container with `docker exec` and `docker cp`, their
+//! `podman` equivalents, or `lxc exec` and `lxc file push` for LXD system
+//! containers and virtual machines. The chroot backend runs commands in a
+//! chroot of a root filesystem mounted on this machine, such as an SD card
+//! image being prepared, and copies uploads straight into the tree. The
+//! serial backend drives the shell on a serial console and sends files
+//! through it in checked chunks (see [`crate::serial`]). With the `mock`
+//! feature, sessions with any backend can be recorded and played back
+//! without the target (see `crate::mock`). New backends
+//! implement [`RemoteExecutor`] and [`Transport`] and are added to
+//! [`connect`].
+
+use crate::{
+    config, dry_run,
+    exit::{Classify, ExitCode},
+    openssh::{self, OpenSshTransport},
+    serial::SerialTransport,
+    reconnect::ReconnectingSession,
+    ssh::{C_LOCALE, RemoteExecutor, SecureUpload, SudoCheck, classify_sudo, sudo_line},
+};
+
+use anyhow::{Context, Result};
+use ssh2::Session;
+use tracing::debug;
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// A connection to a target system, which may upload several files at
+/// once from different threads.
+pub trait Transport: RemoteExecutor + Sync {
+    /// Upload a file or directory to the target.
+    ///
+    /// If `local_path` is a directory, uploads recursively. In a dry run
+    /// the upload is only reported.
+    ///
+    /// # Arguments
+    /// * `local_path` - File or directory on this machine.
+    /// * `remote_path` - Destination path on the target.
+    fn upload(&self, local_path: &Path, remote_path: &Path) -> Result<()>;
+
+    /// Upload a file like [`Transport::upload`], calling `sent` with the
+    /// number of its bytes on the target as they are written, for progress
+    /// bars (see [`crate::progress::bytes`]). Backends that cannot tell
+    /// report the whole file once it is uploaded.
+    fn upload_with_progress(&self, local_path: &Path, remote_path: &Path, sent: &dyn Fn(u64)) -> Result<()> {
+        self.upload(local_path, remote_path)?;
+        sent(fs::metadata(local_path).map_or(0, |metadata| metadata.len()));
+        Ok(())
+    }
+
+    /// Whether commands run as root need `sudo` on the target.
+    fn needs_sudo(&self) -> bool {
+        true
+    }
+
+    /// Number of files that may be uploaded at once (`ssh.upload_jobs`).
+    fn upload_jobs(&self) -> usize {
+        config::upload_jobs()
+    }
+}
+
+impl Transport for Session {
+    fn upload(&self, local_path: &Path, remote_path: &Path) -> Result<()> {
+        self.scp_upload(local_path, remote_path)
+    }
+
+    fn upload_with_progress(&self, local_path: &Path, remote_path: &Path, sent: &dyn Fn(u64)) -> Result<()> {
+        self.scp_upload_with_progress(local_path, remote_path, sent)
+    }
+}
+
+/// Connect to a target with the backend its form selects.
+///
+/// # Arguments
+/// * `target` - `user@host`, `ssh://user@host`, `local://`, `docker://name`,
+///   `podman://name`, `lxd://instance`, `chroot:/path/to/rootfs`,
+///   `serial:/dev/ttyUSB0` or, with the `mock` feature, `mock:session.json`.
+///
+/// With the `mock` feature, the session is recorded to the file
+/// `APT_REMOTE_RECORD` names, if set.
+///
+/// # Errors
+/// Returns an error if the connection cannot be established.
+pub fn connect(target: &str) -> Result<Box<dyn Transport>> {
+    #[cfg(feature = "mock")]
+    {
+        use crate::mock::{MockTransport, RECORD_VAR, Recorder};
+        if let Some(cassette) = target.strip_prefix("mock:") {
+            return Ok(Box::new(MockTransport::replay(Path::new(cassette))?));
+        }
+        if let Some(path) = std::env::var_os(RECORD_VAR).filter(|path| !path.is_empty()) {
+            return Ok(Box::new(Recorder::new(open(target)?, target, Path::new(&path))));
+        }
+    }
+    open(target)
+}
+
+/// Whether `target` is reached over SSH, rather than with another backend.
+///
+/// ```
+/// use apt_remote::transport::is_ssh;
+///
+/// assert!(is_ssh("admin@kiosk:2222"));
+/// assert!(is_ssh("ssh://admin@kiosk"));
+/// assert!(!is_ssh("local"));
+/// assert!(!is_ssh("docker://web"));
+/// assert!(!is_ssh("serial:/dev/ttyUSB0"));
+/// ```
+pub fn is_ssh(target: &str) -> bool {
+    let engines = [Engine::Docker, Engine::Podman, Engine::Lxd].map(Engine::scheme);
+    target != "local"
+        && !engines.into_iter().chain(["local://", "chroot:", "serial:", "mock:"]).any(|scheme| target.starts_with(scheme))
+}
+
+/// Connect to a target with the backend its form selects, without recording.
+fn open(target: &str) -> Result<Box<dyn Transport>> {
+    for engine in [Engine::Docker, Engine::Podman, Engine::Lxd] {
+        if let Some(container) = target.strip_prefix(engine.scheme()) {
+            return Ok(Box::new(ContainerTransport::connect(engine, container)?));
+        }
+    }
+    if let Some(root) = target.strip_prefix("chroot:") {
+        return Ok(Box::new(ChrootTransport::new(root)?));
+    }
+    if let Some(device) = target.strip_prefix("serial:") {
+        return Ok(Box::new(SerialTransport::connect(device)?));
+    }
+    match target {
+        "local" | "local://" => Ok(Box::new(LocalTransport)),
+        target if openssh::enabled() => Ok(Box::new(OpenSshTransport::connect(target.strip_prefix("ssh://").unwrap_or(target))?)),
+        target => Ok(Box::new(ReconnectingSession::connect(target.strip_prefix("ssh://").unwrap_or(target))?)),
+    }
+}
+
+/// Run `command`, writing `input` to its stdin, and return its stdout and
+/// exit status.
+///
+/// `cmd` is the shell command it runs, for messages and the debug log.
+pub(crate) fn run_process(mut command: Command, cmd: &str, input: Option<&str>) -> Result<(String, i32)> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run `{cmd}`"))
+        .classify(ExitCode::Remote)?;
+    // Closing stdin after the input makes a second password prompt fail
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        writeln!(stdin, "{input}").classify(ExitCode::Remote)?;
+    }
+    let output = child.wait_with_output().classify(ExitCode::Remote)?;
+    debug!(
+        command = cmd,
+        program = ?command.get_program(),
+        exit_status = output.status.code().unwrap_or(-1),
+        stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+        "Local command"
+    );
+    Ok((String::from_utf8_lossy(&output.stdout).into_owned(), output.status.code().unwrap_or(-1)))
+}
+
+/// Runs commands and copies files on the machine apt-remote runs on.
+pub struct LocalTransport;
+
+impl LocalTransport {
+    /// Run `cmd` with `sh -c` in the C locale, writing `input` to its stdin,
+    /// and return its stdout and exit status.
+    fn run(&self, cmd: &str, input: Option<&str>) -> Result<(String, i32)> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd).envs(C_LOCALE);
+        run_process(command, cmd, input)
+    }
+}
+
+impl RemoteExecutor for LocalTransport {
+    fn exec(&self, cmd: &str) -> Result<String> {
+        if dry_run::skip("run", cmd) {
+            return Ok(String::new());
+        }
+        self.query(cmd)
+    }
+
+    fn query(&self, cmd: &str) -> Result<String> {
+        Ok(self.run(cmd, None)?.0)
+    }
+
+    fn sudo(&self, cmd: &str, password: &str) -> Result<String> {
+        if dry_run::skip("run as root", cmd) {
+            return Ok(String::new());
+        }
+        Ok(self.run(&sudo_line(cmd), Some(password))?.0)
+    }
+
+    fn sudo_check(&self, password: &str) -> Result<SudoCheck> {
+        let (output, status) = if password.is_empty() {
+            self.run("sudo -n true 2>&1", None)?
+        } else {
+            self.run("sudo -S -k -v -p '' 2>&1", Some(password))?
+        };
+        Ok(match status {
+            0 => SudoCheck::Accepted,
+            _ => classify_sudo(&output),
+        })
+    }
+}
+
+impl Transport for LocalTransport {
+    fn upload(&self, local_path: &Path, remote_path: &Path) -> Result<()> {
+        if dry_run::skip("upload", format!("{} -> {}", local_path.display(), remote_path.display())) {
+            return Ok(());
+        }
+        copy_recursive(local_path, remote_path)
+            .with_context(|| format!("Failed to copy {} to {}", local_path.display(), remote_path.display()))
+            .classify(ExitCode::Upload)
+    }
+}
+
+/// The command line tool a container is managed with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    /// Docker (`docker://name`).
+    Docker,
+    /// Podman (`podman://name`).
+    Podman,
+    /// LXD (`lxd://instance`), through its `lxc` client.
+    Lxd,
+}
+
+impl Engine {
+    /// The prefix of targets in containers of this engine.
+    pub fn scheme(self) -> &'static str {
+        match self {
+            Engine::Docker => "docker://",
+            Engine::Podman => "podman://",
+            Engine::Lxd => "lxd://",
+        }
+    }
+
+    /// The engine's command line tool.
+    pub fn program(self) -> &'static str {
+        match self {
+            Engine::Docker => "docker",
+            Engine::Podman => "podman",
+            Engine::Lxd => "lxc",
+        }
+    }
+}
+
+/// Runs commands and copies files in a running container, through the
+/// `docker`, `podman` or `lxc` command line.
+///
+/// Commands run as the container's default user (root for LXD); those
+/// apt-remote runs with `sudo` elsewhere run as root through
+/// `exec --user`, so the container needs neither `sudo` nor a password.
+pub struct ContainerTransport {
+    /// The tool managing the container.
+    engine: Engine,
+    /// Name or ID of the container.
+    container: String,
+}
+
+impl ContainerTransport {
+    /// Check that `container` is running, and return a transport into it.
+    ///
+    /// # Errors
+    /// Returns an error if the engine's tool cannot be run, or the container
+    /// does not exist or is not running.
+    pub fn connect(engine: Engine, container: &str) -> Result<ContainerTransport> {
+        let program = engine.program();
+        let mut command = Command::new(program);
+        match engine {
+            Engine::Docker | Engine::Podman => command.args(["inspect", "--format", "{{.State.Running}}", container]),
+            Engine::Lxd => command.args(["info", container]),
+        };
+        let output = command
+            .output()
+            .with_context(|| format!("Failed to run `{program}`"))
+            .classify(ExitCode::Connect)?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let running = match engine {
+            Engine::Docker | Engine::Podman => stdout.trim() == "true",
+            Engine::Lxd => stdout.lines().any(|line| line.trim().eq_ignore_ascii_case("status: running")),
+        };
+        if !output.status.success() || !running {
+            let reason = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            let reason = if reason.is_empty() { "it is not running".to_string() } else { reason };
+            return Err(anyhow::anyhow!("Cannot use container {container}: {reason}")).classify(ExitCode::Connect);
+        }
+        Ok(ContainerTransport { engine, container: container.to_string() })
+    }
+
+    /// Run `cmd` with `sh -c` in the container and the C locale, as root if
+    /// `root`, and return its stdout and exit status.
+    fn run(&self, cmd: &str, root: bool) -> Result<(String, i32)> {
+        let mut command = Command::new(self.engine.program());
+        let env = C_LOCALE.map(|(name, value)| ["--env".to_string(), format!("{name}={value}")]);
+        match self.engine {
+            Engine::Docker | Engine::Podman => {
+                command.arg("exec");
+                if root {
+                    command.args(["--user", "root"]);
+                }
+                command.args(env.iter().flatten()).arg(&self.container);
+            }
+            // `lxc exec` takes a numeric user, and the command after `--`
+            Engine::Lxd => {
+                command.args(["exec", &self.container]);
+                if root {
+                    command.args(["--user", "0"]);
+                }
+                command.args(env.iter().flatten()).arg("--");
+            }
+        }
+        command.args(["sh", "-c", cmd]);
+        run_process(command, cmd, None)
+    }
+}
+
+impl RemoteExecutor for ContainerTransport {
+    fn exec(&self, cmd: &str) -> Result<String> {
+        if dry_run::skip("run", cmd) {
+            return Ok(String::new());
+        }
+        self.query(cmd)
+    }
+
+    fn query(&self, cmd: &str) -> Result<String> {
+        Ok(self.run(cmd, false)?.0)
+    }
+
+    fn sudo(&self, cmd: &str, _password: &str) -> Result<String> {
+        if dry_run::skip("run as root", cmd) {
+            return Ok(String::new());
+        }
+        Ok(self.run(cmd, true)?.0)
+    }
+
+    fn sudo_check(&self, _password: &str) -> Result<SudoCheck> {
+        Ok(SudoCheck::Accepted)
+    }
+}
+
+impl Transport for ContainerTransport {
+    fn needs_sudo(&self) -> bool {
+        false
+    }
+
+    fn upload(&self, local_path: &Path, remote_path: &Path) -> Result<()> {
+        if dry_run::skip("upload", format!("{} -> {}", local_path.display(), remote_path.display())) {
+            return Ok(());
+        }
+        let program = self.engine.program();
+        let mut command = Command::new(program);
+        let destination = match self.engine {
+            Engine::Docker | Engine::Podman => {
+                command.arg("cp");
+                format!("{}:{}", self.container, remote_path.display())
+            }
+            // Remote paths are absolute, so they follow the instance name
+            // directly; a directory is pushed into the parent of its destination
+            Engine::Lxd if local_path.is_dir() => {
+                command.args(["file", "push", "--recursive"]);
+                format!("{}{}/", self.container, remote_path.parent().unwrap_or(remote_path).display())
+            }
+            Engine::Lxd => {
+                command.args(["file", "push"]);
+                format!("{}{}", self.container, remote_path.display())
+            }
+        };
+        let output = command
+            .arg(local_path)
+            .arg(&destination)
+            .output()
+            .with_context(|| format!("Failed to run `{program}`"))
+            .classify(ExitCode::Upload)?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Failed to copy {} to {destination}: {}",
+                local_path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+            .classify(ExitCode::Upload);
+        }
+        Ok(())
+    }
+}
+
+/// Runs commands in a chroot of a root filesystem mounted on this machine,
+/// and copies uploads into it.
+///
+/// `chroot` needs apt-remote to run as root, so commands apt-remote runs
+/// with `sudo` elsewhere run the same way. Maintainer scripts of some
+/// packages also expect `/proc` and `/dev` to be mounted in the tree, and
+/// running the tree's programs on another architecture needs
+/// `qemu-user-static`.
+pub struct ChrootTransport {
+    /// The root of the mounted filesystem.
+    root: PathBuf,
+}
+
+impl ChrootTransport {
+    /// Command search path inside the chroot, which this machine's `PATH`
+    /// may not match.
+    const PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+    /// A transport into the root filesystem at `root`.
+    ///
+    /// # Errors
+    /// Returns an error if `root` has no `/bin/sh`, or no `/dev/null`, which
+    /// every command's redirections rely on.
+    pub fn new(root: &str) -> Result<ChrootTransport> {
+        // `chroot:///mnt/rootfs` also names `/mnt/rootfs`
+        let root = PathBuf::from(root.strip_prefix("//").unwrap_or(root));
+        if !root.join("bin/sh").exists() {
+            return Err(anyhow::anyhow!("{} is not a root filesystem: it has no /bin/sh", root.display()))
+                .classify(ExitCode::Connect);
+        }
+        if !root.join("dev/null").exists() {
+            return Err(anyhow::anyhow!(
+                "{root} has no /dev/null: mount /dev in it first (mount --bind /dev {root}/dev)",
+                root = root.display()
+            ))
+            .classify(ExitCode::Connect);
+        }
+        Ok(ChrootTransport { root })
+    }
+
+    /// The path of `remote_path` in the chroot on this machine.
+    fn host_path(&self, remote_path: &Path) -> PathBuf {
+        self.root.join(remote_path.strip_prefix("/").unwrap_or(remote_path))
+    }
+
+    /// Run `cmd` with `sh -c` in the chroot and the C locale, and return its
+    /// stdout and exit status.
+    fn run(&self, cmd: &str) -> Result<(String, i32)> {
+        let mut command = Command::new("chroot");
+        command.arg(&self.root).args(["sh", "-c", cmd]).envs(C_LOCALE).env("PATH", Self::PATH);
+        run_process(command, cmd, None)
+    }
+}
+
+impl RemoteExecutor for ChrootTransport {
+    fn exec(&self, cmd: &str) -> Result<String> {
+        if dry_run::skip("run", cmd) {
+            return Ok(String::new());
+        }
+        self.query(cmd)
+    }
+
+    fn query(&self, cmd: &str) -> Result<String> {
+        Ok(self.run(cmd)?.0)
+    }
+
+    fn sudo(&self, cmd: &str, _password: &str) -> Result<String> {
+        if dry_run::skip("run as root", cmd) {
+            return Ok(String::new());
+        }
+        Ok(self.run(cmd)?.0)
+    }
+
+    fn sudo_check(&self, _password: &str) -> Result<SudoCheck> {
+        Ok(SudoCheck::Accepted)
+    }
+}
+
+impl Transport for ChrootTransport {
+    fn upload(&self, local_path: &Path, remote_path: &Path) -> Result<()> {
+        if dry_run::skip("upload", format!("{} -> {}", local_path.display(), remote_path.display())) {
+            return Ok(());
+        }
+        let destination = self.host_path(remote_path);
+        copy_recursive(local_path, &destination)
+            .with_context(|| format!("Failed to copy {} to {}", local_path.display(), destination.display()))
+            .classify(ExitCode::Upload)
+    }
+
+    fn needs_sudo(&self) -> bool {
+        false
+    }
+}
+
+/// Copy a file, or a directory and its contents.
+fn copy_recursive(from: &Path, to: &Path) -> Result<()> {
+    if !from.is_dir() {
+        fs::copy(from, to)?;
+        return Ok(());
+    }
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
@@ -3,19 +3,46 @@
 //! This module defines data structures for representing package sources,
 //! download metadata, and integrity checks. It also provides utilities for
 //! loading and saving `uri.toml` files, as well as validating package URIs.
+//!
+//! Every `uri.toml` carries a `schema_version`. Files written by older
+//! releases are migrated in memory when loaded (files without the field are
+//! version 0), and files from a newer release are rejected with a clear
+//! error instead of being misread.
+//!
+//! Images with more than [`JSON_THRESHOLD`] packages, such as a full
+//! dist-upgrade, are stored as compact JSON in `uri.json` instead, which
+//! parses many times faster; [`path_in`] finds whichever file an image has,
+//! and `apt-remote convert` switches between the two.
+//!
+//! Packages are named as apt names them in `/var/cache/apt/archives`
+//! (see [`archive_file_name`]): percent-encoding is undone, and only the
+//! epoch separator, `/` and unprintable characters are escaped, so the package
+//! at `.../g%2b%2b_12.2.0-3_amd64.deb` is stored as `g++_4%3a12.2.0-3_amd64.deb`.
+//! Version 1 files used the file name of the URI; they are renamed on load,
+//! but keep lacking the epoch that pool URIs leave out.
+
+use crate::{
+    archive, dry_run,
+    error::Error,
+    exit::{Classify, ExitCode},
+    ssh::shell_quote,
+};
 
 use anyhow::{Context, Result};
+use md5::Md5;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use url::Url;
 
 use std::{
-    fs,
+    fs::{self, File},
     collections::HashMap,
-    path::Path,
+    io,
+    path::{Path, PathBuf},
 };
 
 /// The type of checksum used to verify package integrity.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum ChecksumKind {
     /// SHA256 checksum.
     SHA256,
@@ -44,7 +71,7 @@ impl ChecksumKind {
 }
 
 /// A checksum record for a package.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Checksum {
     /// The checksum algorithm.
     pub kind: ChecksumKind,
@@ -52,6 +79,36 @@ pub struct Checksum {
     pub value: String,
 }
 
+impl Checksum {
+    /// Check whether a local file matches this checksum.
+    ///
+    /// # Arguments
+    /// * `path` - Path to the file to hash.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read.
+    pub fn matches_file<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
+        let mut file = File::open(&path)
+            .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+
+        // Hash the file with the recorded algorithm
+        let actual = match self.kind {
+            ChecksumKind::SHA256 => {
+                let mut hasher = Sha256::new();
+                io::copy(&mut file, &mut hasher)?;
+                archive::hex(&hasher.finalize())
+            }
+            ChecksumKind::MD5 => {
+                let mut hasher = Md5::new();
+                io::copy(&mut file, &mut hasher)?;
+                archive::hex(&hasher.finalize())
+            }
+        };
+
+        Ok(actual.eq_ignore_ascii_case(&self.value))
+    }
+}
+
 /// Information about a single package entry in the `uri.toml` file.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PackageEntry {
@@ -61,10 +118,22 @@ pub struct PackageEntry {
     pub size: u64,
     /// Optional checksum for verifying file integrity.
     pub checksum: Option<Checksum>,
+    /// Space the package takes once installed, in bytes (`Installed-Size`),
+    /// if known (see [`crate::footprint`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed_size: Option<u64>,
+}
+
+impl PackageEntry {
+    /// What identifies the file's content: its checksum, or its URI if the
+    /// plan has no checksum for it.
+    pub fn fingerprint(&self) -> &str {
+        self.checksum.as_ref().map_or(&self.uri, |c| &c.value)
+    }
 }
 
 /// The mode of operation for remote installation.
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum RemoteMode {
     /// Install packages on the remote host.
     Install,
@@ -74,9 +143,30 @@ pub enum RemoteMode {
     Upgrade,
 }
 
+/// File name of image metadata in TOML.
+pub const TOML_FILE: &str = "uri.toml";
+
+/// File name of image metadata in JSON.
+pub const JSON_FILE: &str = "uri.json";
+
+/// Number of packages above which [`UriFile::save_in`] writes JSON.
+pub const JSON_THRESHOLD: usize = 2000;
+
+/// The metadata file of the image or bundle in `dir`: `uri.json` if there
+/// is one, `uri.toml` otherwise (whether or not it exists).
+pub fn path_in(dir: &Path) -> PathBuf {
+    let json = dir.join(JSON_FILE);
+    if json.is_file() { json } else { dir.join(TOML_FILE) }
+}
+
+/// Version of the `uri.toml` format written by this release.
+pub const SCHEMA_VERSION: u32 = 2;
+
 /// Representation of the full `uri.toml` file.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UriFile {
+    /// Version of the `uri.toml` format (see [`SCHEMA_VERSION`]).
+    pub schema_version: u32,
     /// The remote operation mode.
     pub mode: RemoteMode,
     /// The architecture for which the packages are intended.
@@ -85,29 +175,104 @@ pub struct UriFile {
     pub total_size: Option<u64>,
     /// The order in which packages should be installed.
     pub install_order: Vec<String>,
+    /// What the image is for, and who made it.
+    #[serde(default, skip_serializing_if = "ImageMeta::is_empty")]
+    pub meta: ImageMeta,
     /// Mapping of package name → package metadata.
     pub packages: HashMap<String, PackageEntry>,
+    /// Sources the lists of an update image need on the target, added
+    /// before the lists are put in place (see [`crate::ppa`]).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<AddedSource>,
+}
+
+/// Descriptive metadata of an image, given to `set` and shown by `list`
+/// and `status`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageMeta {
+    /// What the image is for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Labels to find the image by.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The target or `@group` the image is meant for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub intended_target: Option<String>,
+    /// Who created the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    /// When the image was created (Unix time).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+}
+
+impl ImageMeta {
+    /// Whether nothing is recorded.
+    pub fn is_empty(&self) -> bool {
+        *self == ImageMeta::default()
+    }
+}
+
+/// An APT source, with its signing key, added to targets by `update`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AddedSource {
+    /// Name of the file in `/etc/apt/sources.list.d`.
+    pub file: String,
+    /// Its sources line.
+    pub line: String,
+    /// Where the key is written.
+    pub keyring: PathBuf,
+    /// The armored signing key.
+    pub key: String,
+}
+
+impl AddedSource {
+    /// The shell command, run as root, writing the key and the source.
+    pub fn install_command(&self) -> String {
+        format!(
+            "mkdir -p {keyring_dir} && printf '%s\\n' {key} > {keyring} && printf '%s\\n' {line} > {list}",
+            keyring_dir = shell_quote(&self.keyring.parent().unwrap_or(Path::new("/")).display().to_string()),
+            key = shell_quote(&self.key),
+            keyring = shell_quote(&self.keyring.display().to_string()),
+            line = shell_quote(&self.line),
+            list = shell_quote(&format!("/etc/apt/sources.list.d/{}", self.file)),
+        )
+    }
 }
 
 impl UriFile {
     /// Load a `UriFile` from disk, validating URIs as it parses.
     ///
     /// # Arguments
-    /// * `path` - Path to the TOML file.
+    /// * `path` - Path to the TOML file, or to a JSON file (`.json`).
     ///
     /// # Errors
     /// Returns an error if:
     /// - The file cannot be read.
-    /// - TOML parsing fails.
+    /// - Parsing fails.
+    /// - The file was written by a newer, incompatible release.
     /// - One or more package URIs are invalid.
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        // Read the TOML file into a string
+        // Read the file into a string
         let content = fs::read_to_string(&path)
-            .with_context(|| format!("Failed to read {}", path.as_ref().display()))?;
+            .with_context(|| format!("Failed to read {}", path.as_ref().display()))
+            .classify(ExitCode::Cache)?;
 
-        // Deserialize the TOML content into a UriFile struct
-        let parsed: UriFile = toml::from_str(&content)
-            .with_context(|| format!("Failed to parse TOML from {}", path.as_ref().display()))?;
+        // Parse it, bring it up to the current schema, then deserialize it
+        let (parsed, version): (UriFile, u32) = if is_json(path.as_ref()) {
+            parse_json(&content).with_context(|| format!("Failed to parse JSON from {}", path.as_ref().display()))
+        } else {
+            toml::from_str::<toml::Table>(&content)
+                .map_err(anyhow::Error::from)
+                .and_then(|table| {
+                    let version = schema_version(&table)?;
+                    Ok((migrate(table)?.try_into()?, version))
+                })
+                .with_context(|| format!("Failed to parse TOML from {}", path.as_ref().display()))
+        }
+        .classify(ExitCode::Cache)?;
 
         // Validate that each package URI uses a supported scheme
         for (pkg_name, pkg) in &parsed.packages {
@@ -115,10 +280,30 @@ impl UriFile {
                 .with_context(|| format!("Invalid URI for package {}: {}", pkg_name, pkg.uri))?;
         }
 
+        // Packages downloaded under schema 1 are kept under their URI name
+        if version < 2
+            && parsed.mode != RemoteMode::Update
+            && !dry_run::enabled()
+            && let Some(debs) = path.as_ref().parent().map(|dir| dir.join("debs"))
+        {
+            for (fname, pkg) in &parsed.packages {
+                let Some(legacy) = Url::parse(&pkg.uri).ok().and_then(|url| Some(url.path_segments()?.next_back()?.to_string()))
+                else {
+                    continue;
+                };
+                let (legacy, current) = (debs.join(legacy), debs.join(fname));
+                if legacy != current && legacy.is_file() && !current.exists() {
+                    fs::rename(&legacy, &current)
+                        .with_context(|| format!("Failed to rename {} to {fname}", legacy.display()))?;
+                }
+            }
+        }
+
         Ok(parsed)
     }
 
-    /// Save the `UriFile` to disk as a TOML file.
+    /// Save the `UriFile` to disk as a TOML file, or as JSON if `path` ends
+    /// in `.json`.
     ///
     /// # Arguments
     /// * `path` - Destination file path.
@@ -126,15 +311,240 @@ impl UriFile {
     /// # Errors
     /// Returns an error if serialization fails or the file cannot be written.
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        // Serialize this struct into a TOML string
-        let toml_str =
-            toml::to_string(self).context("Failed to serialize UriFile to TOML")?;
+        if dry_run::skip("write", path.as_ref().display().to_string()) {
+            return Ok(());
+        }
+
+        // Serialize this struct into a TOML or compact JSON string
+        let content = if is_json(path.as_ref()) {
+            serde_json::to_string(self).context("Failed to serialize UriFile to JSON")?
+        } else {
+            toml::to_string(self).context("Failed to serialize UriFile to TOML")?
+        };
 
-        // Write the TOML string to the specified path
-        fs::write(&path, toml_str)
+        // Write the string to the specified path
+        fs::write(&path, content)
             .with_context(|| format!("Failed to write to {}", path.as_ref().display()))?;
         Ok(())
     }
+
+    /// Save the `UriFile` as the metadata of the image in `dir`, in JSON if
+    /// it has more than [`JSON_THRESHOLD`] packages and in TOML otherwise.
+    /// Returns the path written.
+    ///
+    /// # Errors
+    /// Returns an error if serialization fails or the file cannot be written.
+    pub fn save_in(&self, dir: &Path) -> Result<PathBuf> {
+        let name = if self.packages.len() > JSON_THRESHOLD { JSON_FILE } else { TOML_FILE };
+        self.save_as(dir, name)
+    }
+
+    /// Save the `UriFile` as `dir`/`name` ([`TOML_FILE`] or [`JSON_FILE`]),
+    /// removing the image's metadata in the other format. Returns the path
+    /// written.
+    ///
+    /// # Errors
+    /// Returns an error if serialization fails or a file cannot be written
+    /// or removed.
+    pub fn save_as(&self, dir: &Path, name: &str) -> Result<PathBuf> {
+        let path = dir.join(name);
+        self.save(&path)?;
+        let other = dir.join(if name == JSON_FILE { TOML_FILE } else { JSON_FILE });
+        if other.is_file() && !dry_run::enabled() {
+            fs::remove_file(&other).with_context(|| format!("Failed to remove {}", other.display()))?;
+        }
+        Ok(path)
+    }
+}
+
+/// Whether `path` is metadata in JSON.
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// Parse `uri.json` content, with the schema version it was written with.
+/// Files of the current schema are deserialized directly; older ones go
+/// through the same migration as TOML.
+fn parse_json(content: &str) -> Result<(UriFile, u32)> {
+    let value: serde_json::Value = serde_json::from_str(content)?;
+    if value.get("schema_version").and_then(|v| v.as_u64()) == Some(SCHEMA_VERSION.into()) {
+        return Ok((serde_json::from_value(value)?, SCHEMA_VERSION));
+    }
+    let table = toml::Table::try_from(value)?;
+    let version = schema_version(&table)?;
+    Ok((migrate(table)?.try_into()?, version))
+}
+
+/// The `schema_version` of a parsed `uri.toml` (0 if it has none).
+///
+/// # Errors
+/// Returns an error if the version is not a non-negative integer.
+fn schema_version(table: &toml::Table) -> Result<u32> {
+    match table.get("schema_version") {
+        None => Ok(0),
+        Some(value) => value
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .context("schema_version must be a non-negative integer"),
+    }
+}
+
+/// Upgrade a parsed `uri.toml` to [`SCHEMA_VERSION`].
+///
+/// Each step migrates the table from one version to the next, so files from
+/// any older release can still be loaded.
+///
+/// # Errors
+/// Returns an error if the version is invalid or newer than supported.
+fn migrate(mut table: toml::Table) -> Result<toml::Table> {
+    let version = schema_version(&table)?;
+    if version > SCHEMA_VERSION {
+        anyhow::bail!(
+            "uri.toml has schema version {version}, but this apt-remote supports up to {SCHEMA_VERSION}: upgrade apt-remote or re-run 'apt-remote set'"
+        );
+    }
+
+    for from in version..SCHEMA_VERSION {
+        match from {
+            // Version 0 (before schema_version existed) has the same layout
+            0 => {}
+            // Version 1 named packages after the percent-encoded URI
+            1 => rename_packages(&mut table)?,
+            _ => unreachable!("no migration from schema version {from}"),
+        }
+    }
+    if version < SCHEMA_VERSION {
+        tracing::debug!("Migrated uri.toml from schema version {version} to {SCHEMA_VERSION}");
+    }
+
+    table.insert("schema_version".to_string(), toml::Value::Integer(SCHEMA_VERSION.into()));
+    Ok(table)
+}
+
+/// Rename the packages of a version 1 table to their apt archive names.
+///
+/// # Errors
+/// Returns an error if two packages get the same name.
+fn rename_packages(table: &mut toml::Table) -> Result<()> {
+    if table.get("mode").and_then(|mode| mode.as_str()) == Some("Update") {
+        return Ok(());
+    }
+    if let Some(toml::Value::Table(packages)) = table.get_mut("packages") {
+        let mut renamed = toml::Table::new();
+        for (fname, pkg) in std::mem::take(packages) {
+            let name = archive_file_name(&fname);
+            if let Some(other) = renamed.get(&name).filter(|other| **other != pkg) {
+                let uri = |pkg: &toml::Value| pkg.get("uri").and_then(|u| u.as_str()).unwrap_or_default().to_string();
+                return Err(Error::NameCollision { file: name, uris: vec![uri(other), uri(&pkg)] }.into());
+            }
+            renamed.insert(name, pkg);
+        }
+        *packages = renamed;
+    }
+    if let Some(toml::Value::Array(order)) = table.get_mut("install_order") {
+        for fname in order.iter_mut() {
+            if let Some(name) = fname.as_str() {
+                *fname = toml::Value::String(archive_file_name(name));
+            }
+        }
+        order.dedup();
+    }
+    Ok(())
+}
+
+/// Add `entry` to `packages` as file `fname`.
+///
+/// Returns `false` if the same file was already added. Two different files
+/// cannot be stored under one name, so a name taken by another URI or
+/// checksum is an error rather than silently replacing the first entry.
+///
+/// # Errors
+/// Returns [`Error::NameCollision`] if `fname` is taken by a different file.
+pub fn add_package(packages: &mut HashMap<String, PackageEntry>, fname: String, entry: PackageEntry) -> Result<bool> {
+    match packages.get(&fname) {
+        Some(known) if known.uri == entry.uri && known.checksum == entry.checksum => Ok(false),
+        Some(known) => Err(Error::NameCollision { uris: vec![known.uri.clone(), entry.uri], file: fname }.into()),
+        None => {
+            packages.insert(fname, entry);
+            Ok(true)
+        }
+    }
+}
+
+/// The name apt gives the package file at `uri` in its archive directory,
+/// if `uri` has a file name.
+///
+/// ```
+/// use apt_remote::uri::package_file_name;
+///
+/// let uri = "http://deb.debian.org/debian/pool/main/g/gcc-12/g%2b%2b-12_12.2.0-14_amd64.deb";
+/// assert_eq!(package_file_name(uri).as_deref(), Some("g++-12_12.2.0-14_amd64.deb"));
+/// ```
+pub fn package_file_name(uri: &str) -> Option<String> {
+    let url = Url::parse(uri).ok()?;
+    let segment = url.path_segments()?.next_back().filter(|s| !s.is_empty())?;
+    Some(archive_file_name(segment))
+}
+
+/// Characters escaped in archive file names because a shell treats them
+/// specially. Debian package names and versions never contain them.
+pub const SHELL_SPECIAL: &[u8] = b"!\"#$&'()*;<>?[\\]`{|}";
+
+/// Normalize a percent-encoded package file name to apt's archive naming:
+/// everything is decoded, then `:`, `%` and unprintable characters are
+/// escaped again as lowercase `%xx`. So are `/`, and the dots of `.` and
+/// `..`, so the name never leaves the directory it is stored in, and the
+/// characters a shell treats specially (see [`SHELL_SPECIAL`]), so a
+/// mirror cannot smuggle commands into the names used on the target.
+///
+/// ```
+/// use apt_remote::uri::archive_file_name;
+///
+/// assert_eq!(archive_file_name("libc6_2.36-9%2bdeb12u4_amd64.deb"), "libc6_2.36-9+deb12u4_amd64.deb");
+/// assert_eq!(archive_file_name("vim_2%3A9.0.1378-2_amd64.deb"), "vim_2%3a9.0.1378-2_amd64.deb");
+/// assert_eq!(archive_file_name("..%2f..%2fx.deb"), "..%2f..%2fx.deb");
+/// assert_eq!(archive_file_name("../x.deb"), "..%2fx.deb");
+/// assert_eq!(archive_file_name(".."), "%2e%2e");
+/// assert_eq!(archive_file_name("x%24(reboot)%3b.deb"), "x%24%28reboot%29%3b.deb");
+/// assert_eq!(archive_file_name("a b'c`.deb"), "a%20b%27c%60.deb");
+/// ```
+pub fn archive_file_name(name: &str) -> String {
+    let decoded = decode_file_name(name);
+    let dots = decoded == "." || decoded == "..";
+    let mut out = String::with_capacity(name.len());
+    for byte in decoded.bytes() {
+        let special = SHELL_SPECIAL.contains(&byte);
+        if byte == b':' || byte == b'%' || byte == b'/' || (dots && byte == b'.') || special || !byte.is_ascii_graphic() {
+            out.push_str(&format!("%{byte:02x}"));
+        } else {
+            out.push(byte as char);
+        }
+    }
+    out
+}
+
+/// Undo all `%xx` escapes of a file name or URI path segment.
+pub fn decode_file_name(name: &str) -> String {
+    let bytes = name.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| name.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
 }
 
 /// Validate that a URI is well-formed and uses a supported scheme.
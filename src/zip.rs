@@ -0,0 +1,276 @@
+//! # ZIP archives
+//!
+//! Just enough of the ZIP format to exchange bundles with apt-offline (see
+//! [`crate::apt_offline`]): [`write`] stores files uncompressed, and
+//! [`Archive`] reads stored entries itself and leaves deflated ones to
+//! `unzip`, the way [`crate::deb`] leaves `zstd` members to `zstd`. ZIP64
+//! archives (more than 4 GiB) are not supported.
+
+use anyhow::{Context, Result, anyhow};
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Largest size or offset a ZIP archive without ZIP64 records can hold.
+const MAX_SIZE: u64 = u32::MAX as u64;
+
+/// CRC-32 lookup table (IEEE polynomial, as used by ZIP and gzip).
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+/// Running CRC-32 of the bytes written to it.
+struct Crc32(u32);
+
+impl Write for Crc32 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.0 = CRC_TABLE[((self.0 ^ u32::from(*byte)) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// CRC-32 of everything `reader` yields.
+///
+/// ```
+/// use apt_remote::zip::crc32;
+///
+/// assert_eq!(crc32(&b"123456789"[..]).unwrap(), 0xcbf4_3926);
+/// ```
+///
+/// # Errors
+/// Returns an error if reading fails.
+pub fn crc32(mut reader: impl Read) -> Result<u32> {
+    let mut crc = Crc32(!0);
+    io::copy(&mut reader, &mut crc)?;
+    Ok(!crc.0)
+}
+
+/// Write a ZIP archive at `path` storing each `(name, file)` uncompressed.
+///
+/// # Errors
+/// Returns an error if a file cannot be read, the archive cannot be
+/// written, or it would exceed 4 GiB.
+pub fn write(path: &Path, entries: &[(String, PathBuf)]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path).with_context(|| format!("Failed to create {}", path.display()))?);
+    let mut central = Vec::new();
+    let mut offset = 0u64;
+
+    for (name, file) in entries {
+        let size = file.metadata().with_context(|| format!("Failed to read {}", file.display()))?.len();
+        if offset + size > MAX_SIZE {
+            anyhow::bail!("{} would exceed 4 GiB, which is more than a ZIP archive without ZIP64 holds", path.display());
+        }
+        let crc = crc32(BufReader::new(File::open(file)?))?;
+
+        // Local header, then the file as it is
+        let header = Header { name, crc, size: size as u32 };
+        out.write_all(&header.local())?;
+        io::copy(&mut File::open(file)?, &mut out).with_context(|| format!("Failed to add {}", file.display()))?;
+        central.extend(header.central(offset as u32));
+        offset += 30 + name.len() as u64 + size;
+    }
+
+    // Central directory, then its end record
+    let mut end = Vec::with_capacity(22);
+    end.extend(0x0605_4b50u32.to_le_bytes());
+    end.extend([0u8; 4]);
+    end.extend((entries.len() as u16).to_le_bytes());
+    end.extend((entries.len() as u16).to_le_bytes());
+    end.extend((central.len() as u32).to_le_bytes());
+    end.extend((offset as u32).to_le_bytes());
+    end.extend([0u8; 2]);
+    out.write_all(&central)?;
+    out.write_all(&end)?;
+    out.flush().with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// The fields shared by the local and central headers of a stored entry.
+struct Header<'a> {
+    name: &'a str,
+    crc: u32,
+    size: u32,
+}
+
+impl Header<'_> {
+    /// Version needed (2.0), flags (UTF-8 names), method (stored) and a
+    /// fixed 1980-01-01 timestamp, followed by the CRC and sizes.
+    fn common(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(24);
+        bytes.extend(20u16.to_le_bytes());
+        bytes.extend(0x0800u16.to_le_bytes());
+        bytes.extend([0u8; 2]);
+        bytes.extend([0u8, 0, 0x21, 0]);
+        bytes.extend(self.crc.to_le_bytes());
+        bytes.extend(self.size.to_le_bytes());
+        bytes.extend(self.size.to_le_bytes());
+        bytes.extend((self.name.len() as u16).to_le_bytes());
+        bytes
+    }
+
+    fn local(&self) -> Vec<u8> {
+        let mut bytes = 0x0403_4b50u32.to_le_bytes().to_vec();
+        bytes.extend(self.common());
+        bytes.extend([0u8; 2]);
+        bytes.extend(self.name.as_bytes());
+        bytes
+    }
+
+    fn central(&self, offset: u32) -> Vec<u8> {
+        let mut bytes = 0x0201_4b50u32.to_le_bytes().to_vec();
+        bytes.extend(20u16.to_le_bytes());
+        bytes.extend(self.common());
+        // Extra field, comment, disk, internal and external attributes
+        bytes.extend([0u8; 12]);
+        bytes.extend(offset.to_le_bytes());
+        bytes.extend(self.name.as_bytes());
+        bytes
+    }
+}
+
+/// One file of a ZIP archive.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Name of the file, `/`-separated.
+    pub name: String,
+    /// Compression method (0 for stored, 8 for deflated).
+    method: u16,
+    /// Size of the file once extracted.
+    pub size: u64,
+    /// Size of the file in the archive.
+    compressed_size: u64,
+    /// Offset of the entry's local header.
+    offset: u64,
+}
+
+/// A ZIP archive opened for reading.
+pub struct Archive {
+    path: PathBuf,
+    /// The files of the archive, without directories.
+    pub entries: Vec<Entry>,
+}
+
+impl Archive {
+    /// Open the archive at `path` and read its central directory.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read, is not a ZIP archive or
+    /// is a ZIP64 archive.
+    pub fn open(path: &Path) -> Result<Archive> {
+        let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+        // The end record is in the last 22 bytes, before a comment of up to 64 KiB
+        let len = file.metadata()?.len();
+        let tail_len = len.min(22 + 0xffff);
+        file.seek(SeekFrom::Start(len - tail_len))?;
+        let mut tail = vec![0; tail_len as usize];
+        file.read_exact(&mut tail)?;
+        let end = (0..tail.len().saturating_sub(21))
+            .rev()
+            .find(|&i| tail[i..i + 4] == 0x0605_4b50u32.to_le_bytes())
+            .ok_or_else(|| anyhow!("{} is not a ZIP archive", path.display()))?;
+        let count = le16(&tail[end + 10..]);
+        let directory_size = le32(&tail[end + 12..]);
+        let directory_offset = le32(&tail[end + 16..]);
+        if count == 0xffff || directory_offset == u32::MAX {
+            anyhow::bail!("{} is a ZIP64 archive, which is not supported", path.display());
+        }
+
+        let mut directory = vec![0; directory_size as usize];
+        file.seek(SeekFrom::Start(directory_offset.into()))?;
+        file.read_exact(&mut directory).with_context(|| format!("{} is truncated", path.display()))?;
+
+        let mut entries = Vec::with_capacity(count.into());
+        let mut at = 0;
+        for _ in 0..count {
+            let record = directory.get(at..at + 46).filter(|r| le32(r) == 0x0201_4b50);
+            let record = record.ok_or_else(|| anyhow!("{} has a damaged central directory", path.display()))?;
+            let (name_len, extra_len, comment_len) = (le16(&record[28..]), le16(&record[30..]), le16(&record[32..]));
+            let name = directory
+                .get(at + 46..at + 46 + usize::from(name_len))
+                .ok_or_else(|| anyhow!("{} has a damaged central directory", path.display()))?;
+            let entry = Entry {
+                name: String::from_utf8_lossy(name).into_owned(),
+                method: le16(&record[10..]),
+                compressed_size: le32(&record[20..]).into(),
+                size: le32(&record[24..]).into(),
+                offset: le32(&record[42..]).into(),
+            };
+            if entry.size == MAX_SIZE || entry.compressed_size == MAX_SIZE || entry.offset == MAX_SIZE {
+                anyhow::bail!("{} is a ZIP64 archive, which is not supported", path.display());
+            }
+            if !entry.name.ends_with('/') {
+                entries.push(entry);
+            }
+            at += 46 + usize::from(name_len) + usize::from(extra_len) + usize::from(comment_len);
+        }
+
+        Ok(Archive { path: path.to_path_buf(), entries })
+    }
+
+    /// Extract `entry` to `dest`.
+    ///
+    /// # Errors
+    /// Returns an error if the entry cannot be read, uses a compression
+    /// method other than stored or deflated, or is deflated and `unzip` is
+    /// not installed.
+    pub fn extract(&self, entry: &Entry, dest: &Path) -> Result<()> {
+        match entry.method {
+            0 => {
+                let mut file = File::open(&self.path)?;
+                let mut local = [0u8; 30];
+                file.seek(SeekFrom::Start(entry.offset))?;
+                file.read_exact(&mut local)?;
+                let data = entry.offset + 30 + u64::from(le16(&local[26..])) + u64::from(le16(&local[28..]));
+                file.seek(SeekFrom::Start(data))?;
+                let mut out = File::create(dest).with_context(|| format!("Failed to create {}", dest.display()))?;
+                io::copy(&mut file.take(entry.size), &mut out).with_context(|| format!("Failed to extract {}", entry.name))?;
+                Ok(())
+            }
+            8 => {
+                let out = File::create(dest).with_context(|| format!("Failed to create {}", dest.display()))?;
+                let status = Command::new("unzip")
+                    .arg("-p")
+                    .arg(&self.path)
+                    .arg(&entry.name)
+                    .stdout(out)
+                    .status()
+                    .with_context(|| format!("{} is compressed: install unzip to extract it", entry.name))?;
+                if !status.success() {
+                    anyhow::bail!("unzip failed to extract {}", entry.name);
+                }
+                Ok(())
+            }
+            method => Err(anyhow!("{} uses an unsupported compression method ({method})", entry.name)),
+        }
+    }
+}
+
+fn le16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
@@ -0,0 +1,299 @@
+//! Runs of `set`, `install` and `update` played back against scripted
+//! sessions (see [`apt_remote::mock`]). Run with `cargo test --features mock`.
+
+use apt_remote::archive;
+use apt_remote::batch::Batch;
+use apt_remote::checksum::Tools;
+use apt_remote::commands::install::{self, InstallArgs};
+use apt_remote::commands::set::{self, SetArgs};
+use apt_remote::commands::update::{self, UpdateArgs};
+use apt_remote::config::{self, Config};
+use apt_remote::error::Error;
+use apt_remote::footprint;
+use apt_remote::lock::{self, HostIdentity};
+use apt_remote::mock::{Cassette, Interaction};
+use apt_remote::probe::Probe;
+use apt_remote::progress::ProgressMode;
+use apt_remote::ssh::{SudoCheck, in_dir, shell_quote};
+use apt_remote::state::{self, ImageState};
+use apt_remote::uri::{self, Checksum, ChecksumKind, PackageEntry, RemoteMode, SCHEMA_VERSION, UriFile};
+
+use sha2::{Digest, Sha256};
+
+use std::{collections::HashMap, fs, path::PathBuf, sync::OnceLock};
+
+/// The cache directory of the tests, set up on first use.
+fn cache_dir() -> &'static PathBuf {
+    static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+    CACHE_DIR.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("apt-remote-replay-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let inventory = root.join("inventory.toml");
+        fs::write(&inventory, "").unwrap();
+        config::init(Config {
+            assume_yes: true,
+            cache_dir: Some(root.join("cache")),
+            inventory: Some(inventory),
+            progress: ProgressMode::None,
+            ..Config::default()
+        });
+        root.join("cache")
+    })
+}
+
+/// An image `name` with the package lists `lists`, and the `mock:` target
+/// playing back `interactions`, probed as a Debian host.
+fn setup(name: &str, lists: &[&str], interactions: Vec<Interaction>) -> String {
+    let image_dir = cache_dir().join(name);
+    fs::create_dir_all(image_dir.join("sources")).unwrap();
+    for list in lists {
+        fs::write(image_dir.join("sources").join(list), "Package: hello\n").unwrap();
+    }
+    save_image(name, RemoteMode::Update, Vec::new(), HashMap::new());
+    mock_target(name, interactions)
+}
+
+/// An image `name` with the packages `debs`, and the `mock:` target playing
+/// back `interactions`, probed as a Debian host.
+fn setup_packages(name: &str, debs: &[&str], interactions: Vec<Interaction>) -> String {
+    let image_dir = cache_dir().join(name);
+    fs::create_dir_all(image_dir.join("debs")).unwrap();
+    let mut packages = HashMap::new();
+    for deb in debs {
+        let contents = deb_contents(deb);
+        fs::write(image_dir.join("debs").join(deb), &contents).unwrap();
+        let entry = PackageEntry {
+            uri: format!("http://deb.debian.org/debian/pool/main/h/hello/{deb}"),
+            size: contents.len() as u64,
+            checksum: Some(Checksum { kind: ChecksumKind::SHA256, value: digest_of(deb) }),
+            installed_size: None,
+        };
+        packages.insert(deb.to_string(), entry);
+    }
+    save_image(name, RemoteMode::Install, debs.iter().map(|deb| deb.to_string()).collect(), packages);
+    mock_target(name, interactions)
+}
+
+/// A minimal Debian package named `deb`: an ar archive of `debian-binary`,
+/// a control archive that is not inspected and a data archive.
+fn deb_contents(deb: &str) -> Vec<u8> {
+    let mut ar = b"!<arch>\n".to_vec();
+    for (member, data) in [("debian-binary", b"2.0\n".as_slice()), ("control.tar.xz", b""), ("data.tar.xz", deb.as_bytes())] {
+        ar.extend(format!("{member:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", 0, 0, 0, 100644, data.len()).as_bytes());
+        ar.extend(data);
+        if data.len() % 2 == 1 {
+            ar.push(b'\n');
+        }
+    }
+    ar
+}
+
+/// The SHA256 digest of the package `deb`.
+fn digest_of(deb: &str) -> String {
+    archive::hex(&Sha256::digest(deb_contents(deb)))
+}
+
+/// Write the `uri.toml` of image `name`.
+fn save_image(name: &str, mode: RemoteMode, install_order: Vec<String>, packages: HashMap<String, PackageEntry>) {
+    let uri_file = UriFile {
+        schema_version: SCHEMA_VERSION,
+        mode,
+        arch: "amd64".to_string(),
+        total_size: None,
+        install_order,
+        meta: Default::default(),
+        packages,
+        sources: Vec::new(),
+    };
+    uri_file.save_in(&cache_dir().join(name)).unwrap();
+}
+
+/// The `mock:` target playing back `interactions` for the tests of image
+/// `name`, probed as a Debian host.
+fn mock_target(name: &str, interactions: Vec<Interaction>) -> String {
+    let session = cache_dir().parent().unwrap().join(format!("{name}.json"));
+    let cassette = Cassette { target: "admin@kiosk".to_string(), needs_sudo: true, supports_su: false, interactions };
+    fs::write(&session, serde_json::to_string_pretty(&cassette).unwrap()).unwrap();
+
+    let target = format!("mock:{}", session.display());
+    let probe = Probe {
+        user: "admin".to_string(),
+        tools: vec!["sha256sum".to_string()],
+        probed_at: state::now(),
+        ..Probe::default()
+    };
+    probe.save(&target).unwrap();
+    target
+}
+
+/// The commands `update` runs to replace the lists of a target with the
+/// list `list` of image `name`.
+fn update_session(name: &str, list: &str) -> Vec<Interaction> {
+    let upload_dir = format!("/tmp/apt-remote/{name}");
+    vec![
+        Interaction::SudoCheck { password: false, outcome: SudoCheck::Accepted },
+        Interaction::exec(&format!("mkdir -p {}", shell_quote(&upload_dir)), ""),
+        Interaction::sudo("mv '/var/lib/apt/lists' '/var/lib/apt/lists.old'", ""),
+        Interaction::sudo("mkdir -p '/var/lib/apt/lists'/partial", ""),
+        Interaction::sudo("touch '/var/lib/apt/lists'/lock", ""),
+        Interaction::Upload { to: PathBuf::from(&upload_dir).join(list) },
+        Interaction::sudo(&format!("sh -c {}", shell_quote(&in_dir(&upload_dir, "mv ./* '/var/lib/apt/lists'"))), ""),
+        Interaction::sudo("apt-cache gencaches", ""),
+    ]
+}
+
+/// The commands `install` runs to install the package `deb` of image
+/// `name`, whose SHA256 digest is `digest`.
+fn install_session(name: &str, deb: &str, digest: &str) -> Vec<Interaction> {
+    let upload_dir = format!("/tmp/apt-remote/{name}");
+    let in_upload_dir = |cmd: &str| format!("sh -c {}", shell_quote(&in_dir(&upload_dir, cmd)));
+    let df = "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+              /dev/sda1 8000000 2000000 6000000 25% /\n\
+              /dev/sda1 8000000 2000000 6000000 25% /\n";
+    vec![
+        Interaction::SudoCheck { password: false, outcome: SudoCheck::Accepted },
+        Interaction::exec(&format!("mkdir -p {}", shell_quote(&upload_dir)), ""),
+        Interaction::query(&format!("df -Pk {} '/'", shell_quote(&upload_dir)), df),
+        Interaction::Upload { to: PathBuf::from(&upload_dir).join(deb) },
+        Interaction::exec(&in_dir(&upload_dir, &format!("sha256sum ./{}", shell_quote(deb))), &format!("{digest}  ./{deb}\n")),
+        Interaction::sudo(&in_upload_dir(&format!("dpkg -i ./{}", shell_quote(deb))), ""),
+        Interaction::sudo("dpkg --configure -a", ""),
+        Interaction::sudo(&in_upload_dir("mv ./* '/var/cache/apt/archives'"), ""),
+        Interaction::exec(&format!("rm -rf {}", shell_quote(&upload_dir)), ""),
+    ]
+}
+
+/// The query `set` resolves a plan to install `packages` with, answered
+/// with `plan` as `apt-get --print-uris` prints it on an amd64 target.
+fn set_session(packages: &str, plan: &str) -> Vec<Interaction> {
+    let cmd = format!("apt-get install --print-uris -qqq  {packages} 2>&1");
+    let mut batch = Batch::new();
+    Probe::add_to(&mut batch);
+    batch
+        .add("plan", format!("plan=$({cmd}); printf '%s\\n' \"$plan\""))
+        .add("policy", "printf '%s\\n' \"$plan\" | sed -n \"s/^'[^ ]* \\([^_ ]*\\)_.*/\\1/p\" | xargs -r apt-cache policy")
+        .add("candidates", footprint::CANDIDATES_QUERY)
+        .add(
+            "releases",
+            format!(
+                "(LISTS=/var/lib/apt/lists/; eval \"$(apt-config shell LISTS Dir::State::lists/d 2>/dev/null)\"; {}) 2>/dev/null",
+                Tools::first_found(&ChecksumKind::SHA256, "\"$LISTS\"*Release")
+            ),
+        )
+        .add("machine-id", HostIdentity::MACHINE_ID_QUERY)
+        .add("hostname", HostIdentity::HOSTNAME_QUERY)
+        .add("selections", lock::SELECTIONS_QUERY);
+    let output = format!("\n@@apt-remote:user\nadmin\n@@apt-remote:arch\namd64\n@@apt-remote:plan\n{plan}\n");
+    vec![Interaction::query(&batch.script(), &output)]
+}
+
+/// The arguments of `set` planning to install `packages` as image `name`.
+fn set_args(name: &str, target: &str, packages: &[&str]) -> SetArgs {
+    SetArgs {
+        name: name.to_string(),
+        target: Some(target.to_string()),
+        install: packages.iter().map(|package| package.to_string()).collect(),
+        fix: false,
+        update: false,
+        upgrade: false,
+        ppa: Vec::new(),
+        snapshot: None,
+        preseed: None,
+        distro: None,
+        meta: Default::default(),
+    }
+}
+
+#[test]
+fn set_records_the_plan() {
+    let deb = "hello_2.10-3_amd64.deb";
+    let digest = digest_of(deb);
+    let uri = format!("https://deb.debian.org/debian/pool/main/h/hello/{deb}");
+    let target = mock_target("plan", set_session("hello", &format!("'{uri}' {deb} 53240 SHA256:{digest}")));
+
+    set::run(set_args("plan", &target, &["hello"])).unwrap();
+
+    let uri_file = UriFile::load(uri::path_in(&cache_dir().join("plan"))).unwrap();
+    assert_eq!(uri_file.mode, RemoteMode::Install);
+    assert_eq!(uri_file.arch, "amd64");
+    assert_eq!(uri_file.install_order, [deb]);
+    assert_eq!(uri_file.total_size, Some(53240));
+    let package = &uri_file.packages[deb];
+    assert_eq!(package.uri, uri);
+    assert_eq!(package.checksum, Some(Checksum { kind: ChecksumKind::SHA256, value: digest }));
+}
+
+#[test]
+fn set_reports_unresolvable_packages() {
+    let target = mock_target("unknown", set_session("nosuchpackage", "E: Unable to locate package nosuchpackage"));
+
+    let e = set::run(set_args("unknown", &target, &["nosuchpackage"])).unwrap_err();
+    assert!(
+        matches!(e.downcast_ref::<Error>(), Some(Error::PlanResolution { errors, .. }) if errors == &["Unable to locate package nosuchpackage"]),
+        "{e:#}"
+    );
+    assert!(!uri::path_in(&cache_dir().join("unknown")).is_file());
+}
+
+#[test]
+fn update_replaces_the_lists() {
+    let list = "deb.debian.org_debian_dists_bookworm_main_binary-amd64_Packages";
+    let target = setup("lists", &[list], update_session("lists", list));
+
+    update::run(UpdateArgs { name: "lists".to_string(), target: Some(target.clone()) }).unwrap();
+
+    // Each command the run sent was the next one recorded
+    let state = ImageState::load(&cache_dir().join("lists")).unwrap();
+    let target_state = &state.targets[&target];
+    assert!(target_state.lists_updated.is_some());
+    assert_eq!(target_state.last_run, target_state.lists_updated);
+}
+
+#[test]
+fn update_stops_at_an_unexpected_command() {
+    let list = "deb.debian.org_debian_dists_bookworm_main_binary-arm64_Packages";
+    let mut session = update_session("diverged", list);
+    session[2] = Interaction::sudo("rm -rf '/var/lib/apt/lists'", "");
+    let target = setup("diverged", &[list], session);
+
+    let e = update::run(UpdateArgs { name: "diverged".to_string(), target: Some(target.clone()) }).unwrap_err();
+    assert!(format!("{e:#}").contains("expected the run to run as root `rm -rf '/var/lib/apt/lists'`"), "{e:#}");
+
+    // Nothing is recorded for a run that did not finish
+    let state = ImageState::load(&cache_dir().join("diverged")).unwrap();
+    assert!(!state.targets.contains_key(&target));
+}
+
+#[test]
+fn install_uploads_verifies_and_installs() {
+    let deb = "hello_2.10-3_amd64.deb";
+    let target = setup_packages("hello", &[deb], install_session("hello", deb, &digest_of(deb)));
+
+    install::run(InstallArgs { name: "hello".to_string(), target: Some(target.clone()), reinstall: false, verify_hashes: true })
+        .unwrap();
+
+    let state = ImageState::load(&cache_dir().join("hello")).unwrap();
+    let target_state = &state.targets[&target];
+    assert!(target_state.installed.contains(deb));
+    assert!(target_state.uploaded.is_empty());
+}
+
+#[test]
+fn install_stops_at_an_unexpected_command() {
+    let deb = "hello_2.10-3_arm64.deb";
+    let mut session = install_session("dpkg", deb, &digest_of(deb));
+    let dpkg = format!("sh -c {}", shell_quote(&in_dir("/tmp/apt-remote/dpkg", "apt-get install -y ./hello_2.10-3_arm64.deb")));
+    session[5] = Interaction::sudo(&dpkg, "");
+    let target = setup_packages("dpkg", &[deb], session);
+
+    let e = install::run(InstallArgs { name: "dpkg".to_string(), target: Some(target.clone()), reinstall: false, verify_hashes: true })
+        .unwrap_err();
+    assert!(format!("{e:#}").contains(&format!("expected the run to run as root `{dpkg}`")), "{e:#}");
+
+    // The package was uploaded and verified, but is not recorded as installed
+    let state = ImageState::load(&cache_dir().join("dpkg")).unwrap();
+    let target_state = &state.targets[&target];
+    assert!(target_state.installed.is_empty());
+    assert!(target_state.verified.contains(deb));
+}
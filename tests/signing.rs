@@ -0,0 +1,50 @@
+//! Checks of signed bundles against the `trusted_keys` of the importing
+//! side (see [`apt_remote::signing`]).
+
+use apt_remote::config::{self, Config};
+use apt_remote::error::Error;
+use apt_remote::signing::{self, SIGNATURE_FILE};
+
+use std::{fs, path::PathBuf, sync::OnceLock};
+
+/// The directory of the tests, with a key pair trusted by the configuration.
+fn root() -> &'static PathBuf {
+    static ROOT: OnceLock<PathBuf> = OnceLock::new();
+    ROOT.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("apt-remote-signing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let key = root.join("courier.key");
+        signing::generate(&key).unwrap();
+        config::init(Config { trusted_keys: vec![signing::public_path(&key)], ..Config::default() });
+        root
+    })
+}
+
+/// A bundle `name` signed with the trusted key.
+fn signed_bundle(name: &str) -> PathBuf {
+    let bundle = root().join(name);
+    fs::create_dir_all(&bundle).unwrap();
+    fs::write(bundle.join("uri.toml"), "arch = \"amd64\"\n").unwrap();
+    signing::sign_bundle(&bundle, name, &root().join("courier.key")).unwrap();
+    bundle
+}
+
+#[test]
+fn signed_bundle_is_accepted() {
+    let bundle = signed_bundle("signed");
+    let (manifest, _) = signing::verify(&bundle).unwrap().unwrap();
+    assert!(manifest.files.contains_key("uri.toml"));
+}
+
+#[test]
+fn stripped_bundle_is_refused() {
+    let bundle = signed_bundle("stripped");
+    fs::remove_file(bundle.join(SIGNATURE_FILE)).unwrap();
+
+    let e = signing::verify(&bundle).unwrap_err();
+    assert!(
+        matches!(e.downcast_ref::<Error>(), Some(Error::PolicyViolation { policy: "require_signature", .. })),
+        "{e:#}"
+    );
+}